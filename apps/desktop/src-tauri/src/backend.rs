@@ -9,8 +9,12 @@ use super::TELEMETRY;
 static TELEMETRY: std::sync::RwLock<bool> = std::sync::RwLock::new(false);
 
 use bleep::{analytics, Application, Configuration, Environment};
+use once_cell::sync::OnceCell;
 use tracing::info;
 
+/// Set once the configuration allows telemetry
+pub(super) static TELEMETRY_PERMIT: OnceCell<analytics::Permit> = OnceCell::new();
+
 pub(super) fn bleep<R>(app: &mut App<R>) -> plugin::Result<()>
 where
     R: Runtime,
@@ -35,13 +39,19 @@ where
     let cache_dir = app.path_resolver().app_cache_dir().unwrap();
     configuration.index_dir = cache_dir.join("bleep");
 
-    if let (Some(key), Some(data_plane)) = (
+    let permit = analytics::Permit::from_config(&configuration);
+    if let Some(permit) = permit {
+        _ = TELEMETRY_PERMIT.set(permit);
+    }
+
+    if let (Some(permit), Some(key), Some(data_plane)) = (
+        permit,
         &configuration.analytics_key,
         &configuration.analytics_data_plane,
     ) {
-        initialize_analytics(key.to_owned(), data_plane.to_owned());
+        initialize_analytics(permit, key.to_owned(), data_plane.to_owned());
     } else {
-        info!("Analytics not configured, skipping initialization...")
+        info!("Analytics disabled or not configured, skipping initialization...")
     }
 
     let app = app.handle();
@@ -73,7 +83,7 @@ where
     Ok(())
 }
 
-pub fn initialize_analytics(key: String, data_plane: String) {
+pub fn initialize_analytics(permit: analytics::Permit, key: String, data_plane: String) {
     if analytics::RudderHub::get().is_some() {
         info!("analytics has already been initialized");
         return;
@@ -84,6 +94,7 @@ pub fn initialize_analytics(key: String, data_plane: String) {
             true => Some(event),
             false => None,
         })),
+        index_filter: Some(Arc::new(|_| *TELEMETRY.read().unwrap())),
         package_metadata: Some(analytics::PackageMetadata {
             name: env!("CARGO_CRATE_NAME"),
             version: env!("CARGO_PKG_VERSION"),
@@ -91,6 +102,6 @@ pub fn initialize_analytics(key: String, data_plane: String) {
         }),
     };
     tokio::task::block_in_place(|| {
-        analytics::RudderHub::new_with_options(key, data_plane, options)
+        analytics::RudderHub::new_with_options(permit, key, data_plane, options)
     });
 }
@@ -59,6 +59,11 @@ async fn main() {
 
 #[tauri::command]
 fn initialize_sentry(dsn: String, environment: String) {
+    if backend::TELEMETRY_PERMIT.get().is_none() {
+        tracing::info!("Telemetry is disabled, skipping Sentry initialization");
+        return;
+    }
+
     if sentry::Hub::current().client().is_some() {
         tracing::info!("Sentry has already been initialized");
         return;
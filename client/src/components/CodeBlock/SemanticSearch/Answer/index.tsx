@@ -1,11 +1,10 @@
-import { useCallback, useContext, useMemo, useState } from 'react';
+import { useCallback, useMemo, useState } from 'react';
 import { Remarkable } from 'remarkable';
 import hljs from 'highlight.js';
 import { useRive } from '@rive-app/react-canvas';
 import 'highlight.js/styles/vs2015.css';
 import Button from '../../../Button';
 import { ArrowRotate } from '../../../../icons';
-import { DeviceContext } from '../../../../context/deviceContext';
 import useAppNavigation from '../../../../hooks/useAppNavigation';
 import useAnalytics from '../../../../hooks/useAnalytics';
 import { saveUpvote } from '../../../../services/api';
@@ -31,7 +30,6 @@ type Props = {
 };
 
 const Answer = ({ handleRetry, searchId, answer, error }: Props) => {
-  const { deviceId } = useContext(DeviceContext);
   const { query } = useAppNavigation();
   const [isUpvote, setIsUpvote] = useState(false);
   const [isDownvote, setIsDownvote] = useState(false);
@@ -72,14 +70,11 @@ const Answer = ({ handleRetry, searchId, answer, error }: Props) => {
       setIsDownvote(!isUpvote);
       trackUpvote(isUpvote, query, answer || '', searchId);
       return saveUpvote({
-        unique_id: deviceId,
+        query_id: searchId,
         is_upvote: isUpvote,
-        query: query,
-        snippet_id: searchId,
-        text: answer || '',
       });
     },
-    [deviceId, query, answer, RiveUpvote, RiveDownvote],
+    [query, answer, searchId, RiveUpvote, RiveDownvote],
   );
 
   return (
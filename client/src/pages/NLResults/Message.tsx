@@ -1,11 +1,10 @@
-import React, { useCallback, useContext, useMemo, useState } from 'react';
+import React, { useCallback, useMemo, useState } from 'react';
 import { useRive } from '@rive-app/react-canvas';
 import { Remarkable } from 'remarkable';
 import Button from '../../components/Button';
 import { Checkmark } from '../../icons';
 import ThreeDotsLoader from '../../components/Loaders/ThreeDotsLoader';
 import { saveUpvote } from '../../services/api';
-import { DeviceContext } from '../../context/deviceContext';
 import useAppNavigation from '../../hooks/useAppNavigation';
 import useAnalytics from '../../hooks/useAnalytics';
 import { ConversationMessage } from '../../types/general';
@@ -30,7 +29,6 @@ const Message = ({
   currentlyViewedSnippets,
   onViewSnippetsClick,
 }: Props) => {
-  const { deviceId } = useContext(DeviceContext);
   const { query } = useAppNavigation();
   const [isUpvote, setIsUpvote] = useState(false);
   const [isDownvote, setIsDownvote] = useState(false);
@@ -73,15 +71,15 @@ const Message = ({
       setIsUpvote(isUpvote);
       setIsDownvote(!isUpvote);
       trackUpvote(isUpvote, query, answer || '', searchId);
+      if (!message.queryId) {
+        return;
+      }
       return saveUpvote({
-        unique_id: deviceId,
+        query_id: message.queryId,
         is_upvote: isUpvote,
-        query: query,
-        snippet_id: searchId,
-        text: answer || '',
       });
     },
-    [deviceId, query, RiveUpvote, RiveDownvote],
+    [query, message.queryId, RiveUpvote, RiveDownvote],
   );
   return (
     <div
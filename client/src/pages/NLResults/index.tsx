@@ -166,6 +166,7 @@ const ResultsPage = ({ query, threadId }: Props) => {
               const lastMessage = {
                 ...prev.slice(-1)[0],
                 isLoading: true,
+                queryId: newData?.query_id,
                 snippets:
                   newData?.snippets?.matches?.map((item: NLSnippet) => ({
                     path: item.relative_path,
@@ -135,12 +135,9 @@ export const saveCrashReport = (report: {
 }) => axios.post(`${DB_API}/crash_reports`, report).then((r) => r.data);
 
 export const saveUpvote = (upvote: {
-  unique_id: string;
-  snippet_id: string;
-  query: string;
-  text: string;
+  query_id: string;
   is_upvote: boolean;
-}) => axios.post(`${DB_API}/upvotes`, upvote).then((r) => r.data);
+}) => http.post('/answer/feedback', upvote).then((r) => r.data);
 
 export const getUpvote = (params: {
   unique_id: string;
@@ -105,6 +105,7 @@ export type ConversationMessage = {
   author: 'user' | 'server';
   text?: string;
   isLoading: boolean;
+  queryId?: string;
   snippets?: {
     path: string;
     code: string;
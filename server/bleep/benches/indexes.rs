@@ -1,6 +1,6 @@
 use bleep::{
     indexes::{reader::ContentReader, DocumentRead, File},
-    semantic::Semantic,
+    semantic::SemanticLoader,
     symbol::{Symbol, SymbolLocations},
     Application, Configuration, Environment,
 };
@@ -56,10 +56,10 @@ pub fn criterion_benchmark(c: &mut Criterion) {
 
         let file = File::new(
             app.config.clone(),
-            Some(
-                Semantic::initialize(&model_dir, "http://127.0.0.1:6334", Arc::clone(&app.config))
-                    .await
-                    .unwrap(),
+            SemanticLoader::load(
+                model_dir.clone(),
+                "http://127.0.0.1:6334".to_owned(),
+                Arc::clone(&app.config),
             ),
         );
 
@@ -77,7 +77,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
                     file.repo_ref => "local//bloop",
                     file.repo_name => "bloop",
                     file.relative_path => "js-sample-big-symbols.js",
-                    file.content => include_str!("./js-sample-big-symbols.js"),
+                    file.stored_content => include_str!("./js-sample-big-symbols.js"),
                     file.line_end_indices => Vec::new(),
                     file.lang => &b"JavaScript"[..],
                     file.avg_line_length => 42.0,
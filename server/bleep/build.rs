@@ -14,9 +14,44 @@ struct Language {
 }
 fn main() {
     set_index_version();
+    set_git_commit();
     process_languages();
 }
 
+fn set_git_commit() {
+    // Builds made outside of a git checkout can provide the commit instead
+    println!("cargo:rerun-if-env-changed=BLEEP_GIT_COMMIT");
+
+    // Checking out another branch changes `HEAD`, and committing changes the ref it points to,
+    // which may also have been packed
+    let mut watched = vec!["HEAD".to_owned(), "packed-refs".to_owned()];
+    watched.extend(git(&["symbolic-ref", "-q", "HEAD"]));
+    for name in watched {
+        if let Some(path) = git(&["rev-parse", "--git-path", &name]) {
+            if Path::new(&path).exists() {
+                println!("cargo:rerun-if-changed={path}");
+            }
+        }
+    }
+
+    let commit = env::var("BLEEP_GIT_COMMIT")
+        .ok()
+        .or_else(|| git(&["rev-parse", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_owned());
+
+    println!("cargo:rustc-env=BLEEP_GIT_COMMIT={commit}");
+}
+
+/// The trimmed output of a successful git command
+fn git(args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new("git").args(args).output().ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
 fn set_index_version() {
     use std::fs::{read_dir, read_to_string};
 
@@ -1,27 +1,136 @@
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::time::Duration;
 
-use crate::semantic::chunk::OverlapStrategy;
+pub use crate::background::RepoEvent;
+use crate::{metrics::AnswerStage, semantic::chunk::OverlapStrategy, Configuration};
 
 use once_cell::sync::OnceCell;
+use ring::hmac;
 use rudderanalytics::{
     client::RudderAnalytics,
-    message::{Message, Track},
+    message::{Batch, BatchMessage, Message, Track},
 };
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use tracing::{info, warn};
 
-#[derive(Debug, Default, Clone)]
+mod delivery;
+mod jsonl;
+mod posthog;
+mod query_log;
+mod sqlite;
+
+use delivery::Delivery;
+pub use delivery::DeliveryStats;
+pub use jsonl::JsonlSink;
+pub use posthog::{PosthogSink, DEFAULT_POSTHOG_HOST};
+pub use query_log::{
+    DailyUsage, QueryCount, QueryKind, QueryLog, QueryLogEntry, QueryLogParams, QueryLogResponse,
+    QueryStats, RepoCount, SelectedSnippet, UsageParams, UsageResponse,
+};
+pub use sqlite::SqliteSink;
+
+/// Where analytics events are sent
+#[derive(clap::ValueEnum, Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnalyticsSink {
+    /// The RudderStack data plane in `analytics_data_plane`
+    #[default]
+    Rudder,
+    /// A file with one JSON event per line
+    Jsonl,
+    /// A SQLite database
+    Sqlite,
+    /// The PostHog instance in `posthog_host`
+    Posthog,
+}
+
+/// A destination for analytics events.
+///
+/// Sinks that send events off the machine must require a [`Permit`] to be created.
+pub trait Analytics: Send + Sync {
+    fn track_query(&self, event: QueryEvent);
+
+    /// Record a step in the lifecycle of a repository's index
+    fn track_index(&self, event: &RepoEvent);
+
+    /// Events sent, dropped, and waiting to be sent so far, by sinks that queue them
+    fn delivery_stats(&self) -> Option<DeliveryStats> {
+        None
+    }
+}
+
+static SINK: OnceCell<Arc<dyn Analytics>> = OnceCell::new();
+
+static USER_ID_KEY: OnceCell<hmac::Key> = OnceCell::new();
+
+/// Send events to `sink` from now on. Only the first sink installed takes effect.
+fn install(sink: Arc<dyn Analytics>) {
+    _ = SINK.set(sink);
+}
+
+/// Replace the user ids of events with an HMAC of them under `key` from now on. Only the
+/// first key set takes effect.
+pub fn pseudonymize_user_ids(key: hmac::Key) {
+    _ = USER_ID_KEY.set(key);
+}
+
+/// Record a query with the installed sink, if there is one
+pub fn track_query(mut event: QueryEvent) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+
+    if let Some(key) = USER_ID_KEY.get() {
+        event.user_id = pseudonym(key, &event.user_id);
+    }
+
+    sink.track_query(event);
+}
+
+/// Record an index lifecycle event with the installed sink, if there is one
+pub fn track_index(event: &RepoEvent) {
+    if let Some(sink) = SINK.get() {
+        sink.track_index(event);
+    }
+}
+
+/// Delivery of events by the installed sink, if it queues them
+pub fn delivery_stats() -> Option<DeliveryStats> {
+    SINK.get().and_then(|sink| sink.delivery_stats())
+}
+
+fn pseudonym(key: &hmac::Key, user_id: &str) -> String {
+    // Anonymous users stay anonymous, rather than sharing a pseudonym
+    if user_id.is_empty() {
+        return String::new();
+    }
+
+    hex::encode(hmac::sign(key, user_id.as_bytes()))
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct QueryEvent {
     pub user_id: String,
+    pub tenant: Option<String>,
     pub session_id: String,
     pub query_id: uuid::Uuid,
     pub overlap_strategy: OverlapStrategy,
     pub stages: Vec<Stage>,
+    /// Time taken by each stage of answering, in milliseconds
+    pub latency_ms: BTreeMap<&'static str, u64>,
+}
+
+impl QueryEvent {
+    /// Record how long a stage of answering took, in the event and in the latency metrics
+    pub fn time(&mut self, stage: AnswerStage, elapsed: Duration) {
+        crate::metrics::observe_answer(stage, elapsed);
+        *self.latency_ms.entry(stage.name()).or_default() += elapsed.as_millis() as u64;
+    }
 }
 
 /// Represents a single stage of the Answer API pipeline
-#[derive(Debug, serde::Serialize, Clone)]
+#[derive(Debug, Serialize, Clone)]
 pub struct Stage {
     /// The name of this stage, e.g.: "filtered semantic results"
     pub name: &'static str,
@@ -37,7 +146,7 @@ pub struct Stage {
     pub time_elapsed: Option<u128>,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, Serialize)]
 pub struct PackageMetadata {
     pub name: &'static str,
     pub version: &'static str,
@@ -46,68 +155,128 @@ pub struct PackageMetadata {
 
 static HUB: OnceCell<Arc<RudderHub>> = OnceCell::new();
 
+/// Proof that telemetry is allowed to leave the machine.
+///
+/// Every telemetry client needs one to start, and `Permit::from_config` is the only way to
+/// get one. With telemetry disabled, there is no client for any `track_*` call to send
+/// through.
+#[derive(Clone, Copy, Debug)]
+pub struct Permit(());
+
+impl Permit {
+    pub fn from_config(config: &Configuration) -> Option<Self> {
+        (!config.disable_telemetry()).then_some(Self(()))
+    }
+}
+
 pub struct RudderHub {
     options: Option<HubOptions>,
-    client: RudderAnalytics,
+    delivery: Delivery<Track>,
 }
 
 #[derive(Default)]
 pub struct HubOptions {
     pub event_filter: Option<Arc<dyn Fn(QueryEvent) -> Option<QueryEvent> + Send + Sync + 'static>>,
+    /// Whether to send an index lifecycle event
+    pub index_filter: Option<Arc<dyn Fn(&RepoEvent) -> bool + Send + Sync + 'static>>,
     pub package_metadata: Option<PackageMetadata>,
 }
 
 impl RudderHub {
-    pub fn new(key: String, data_plane: String) -> Arc<Self> {
-        let client = RudderAnalytics::load(key, data_plane);
+    pub fn new(_: Permit, key: String, data_plane: String) -> Arc<Self> {
         let hub = Self {
-            client,
+            delivery: Self::start_delivery(key, data_plane),
             options: None,
         };
         let _ = HUB.set(Arc::new(hub));
-        RudderHub::get().unwrap()
+
+        let hub = RudderHub::get().unwrap();
+        install(hub.clone());
+        hub
     }
 
-    pub fn new_with_options(key: String, data_plane: String, options: HubOptions) -> Arc<Self> {
-        let client = RudderAnalytics::load(key, data_plane);
+    pub fn new_with_options(
+        _: Permit,
+        key: String,
+        data_plane: String,
+        options: HubOptions,
+    ) -> Arc<Self> {
         let hub = Self {
-            client,
+            delivery: Self::start_delivery(key, data_plane),
             options: Some(options),
         };
         let _ = HUB.set(Arc::new(hub));
-        RudderHub::get().unwrap()
+
+        let hub = RudderHub::get().unwrap();
+        install(hub.clone());
+        hub
     }
 
     pub fn get() -> Option<Arc<Self>> {
         HUB.get().map(Arc::clone)
     }
 
-    pub fn track_query(event: QueryEvent) {
-        if let Some(hub) = Self::get() {
-            if let Some(options) = &hub.options {
-                if let Some(filter) = &options.event_filter {
-                    if let Some(ev) = (filter)(event) {
-                        if let Err(e) = hub.client.send(&Message::Track(Track {
-                            user_id: Some(ev.user_id),
-                            event: "openai query".to_owned(),
-                            properties: Some(json!({
-                                "query_id": ev.query_id,
-                                "session_id": ev.session_id,
-                                "overlap_strategy": ev.overlap_strategy,
-                                "stages": ev.stages,
-                                "package_metadata": options.package_metadata,
-                            })),
-                            ..Default::default()
-                        })) {
-                            warn!("failed to send analytics event: {:?}", e);
-                        } else {
-                            info!("sent analytics event ...");
-                        }
-                    }
+    fn start_delivery(key: String, data_plane: String) -> Delivery<Track> {
+        let client = RudderAnalytics::load(key, data_plane);
+        Delivery::start(delivery::Policy::default(), move |tracks: &[Track]| {
+            client.send(&Message::Batch(Batch {
+                batch: tracks.iter().cloned().map(BatchMessage::Track).collect(),
+                ..Default::default()
+            }))
+        })
+    }
+}
+
+impl Analytics for RudderHub {
+    fn track_query(&self, event: QueryEvent) {
+        if let Some(options) = &self.options {
+            if let Some(filter) = &options.event_filter {
+                if let Some(ev) = (filter)(event) {
+                    self.delivery.send(Track {
+                        user_id: Some(ev.user_id),
+                        event: "openai query".to_owned(),
+                        properties: Some(json!({
+                            "query_id": ev.query_id,
+                            "session_id": ev.session_id,
+                            "tenant": ev.tenant,
+                            "overlap_strategy": ev.overlap_strategy,
+                            "stages": ev.stages,
+                            "latency_ms": ev.latency_ms,
+                            "package_metadata": options.package_metadata,
+                        })),
+                        ..Default::default()
+                    });
                 }
             }
         }
     }
+
+    fn track_index(&self, event: &RepoEvent) {
+        let Some(options) = &self.options else {
+            return;
+        };
+
+        let Some(filter) = &options.index_filter else {
+            return;
+        };
+
+        if filter(event) {
+            let mut properties = json!(event);
+            properties["package_metadata"] = json!(options.package_metadata);
+
+            self.delivery.send(Track {
+                // Index events don't belong to any user
+                anonymous_id: Some("bleep".to_owned()),
+                event: "index lifecycle".to_owned(),
+                properties: Some(properties),
+                ..Default::default()
+            });
+        }
+    }
+
+    fn delivery_stats(&self) -> Option<DeliveryStats> {
+        Some(self.delivery.stats())
+    }
 }
 
 impl Stage {
@@ -134,3 +303,21 @@ impl Stage {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pseudonyms() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"instance secret");
+        let other = hmac::Key::new(hmac::HMAC_SHA256, b"another secret");
+
+        let alice = pseudonym(&key, "alice");
+        assert_eq!(alice.len(), 64);
+        assert_eq!(alice, pseudonym(&key, "alice"));
+        assert_ne!(alice, pseudonym(&key, "bob"));
+        assert_ne!(alice, pseudonym(&other, "alice"));
+        assert_eq!(pseudonym(&key, ""), "");
+    }
+}
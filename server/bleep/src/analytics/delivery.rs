@@ -0,0 +1,236 @@
+//! Delivery of analytics events off the request path.
+//!
+//! Events are queued, and sent in batches from a thread of their own, so a slow or failing
+//! analytics endpoint never holds up a request. Batches that fail are retried with
+//! exponential backoff. When the queue is full, new events are dropped and counted, rather
+//! than waited on.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tracing::{debug, warn};
+use utoipa::ToSchema;
+
+/// How events are queued and retried
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Policy {
+    /// Events waiting to be sent before new ones are dropped
+    pub capacity: usize,
+    /// The most events sent at once
+    pub batch_size: usize,
+    /// How long to wait for a batch to fill up
+    pub flush_interval: Duration,
+    /// Attempts to send a batch after the first, before it's given up on
+    pub retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            batch_size: 100,
+            flush_interval: Duration::from_secs(5),
+            retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Events handled since startup
+#[derive(Serialize, ToSchema, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct DeliveryStats {
+    /// Events waiting to be sent
+    pub queued: u64,
+    pub sent: u64,
+    /// Events dropped because the queue was full
+    pub dropped: u64,
+    /// Events given up on after every retry failed
+    pub failed: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+pub(super) struct Delivery<T> {
+    sender: flume::Sender<T>,
+    counters: Arc<Counters>,
+}
+
+impl<T: Send + 'static> Delivery<T> {
+    /// Start sending queued events in batches with `send`
+    pub(super) fn start<E>(
+        policy: Policy,
+        send: impl FnMut(&[T]) -> Result<(), E> + Send + 'static,
+    ) -> Self
+    where
+        E: std::fmt::Debug,
+    {
+        let (sender, receiver) = flume::bounded(policy.capacity);
+        let counters = Arc::<Counters>::default();
+
+        let worker = Arc::clone(&counters);
+        thread::Builder::new()
+            .name("analytics-delivery".into())
+            .spawn(move || run(policy, receiver, worker, send))
+            .expect("failed to start analytics delivery");
+
+        Self { sender, counters }
+    }
+
+    /// Queue an event, dropping it if the queue is full
+    pub(super) fn send(&self, event: T) {
+        if self.sender.try_send(event).is_err() {
+            let dropped = self.counters.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+
+            // Every drop is counted, but only some are worth a line in the logs
+            if dropped.is_power_of_two() {
+                warn!(dropped, "analytics queue is full; dropping events");
+            }
+        }
+    }
+
+    pub(super) fn stats(&self) -> DeliveryStats {
+        DeliveryStats {
+            queued: self.sender.len() as u64,
+            sent: self.counters.sent.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn run<T, E: std::fmt::Debug>(
+    policy: Policy,
+    receiver: flume::Receiver<T>,
+    counters: Arc<Counters>,
+    mut send: impl FnMut(&[T]) -> Result<(), E>,
+) {
+    // Stops once every sender is gone, and the queue is drained
+    while let Ok(first) = receiver.recv() {
+        let mut batch = vec![first];
+        let deadline = Instant::now() + policy.flush_interval;
+
+        while batch.len() < policy.batch_size {
+            match receiver.recv_deadline(deadline) {
+                Ok(event) => batch.push(event),
+                Err(_) => break,
+            }
+        }
+
+        let mut backoff = policy.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            match send(&batch) {
+                Ok(()) => {
+                    debug!(count = batch.len(), "sent analytics events");
+                    counters
+                        .sent
+                        .fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    break;
+                }
+                Err(err) if attempt < policy.retries => {
+                    warn!(?err, attempt, "failed to send analytics events; retrying");
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(policy.max_backoff);
+                    attempt += 1;
+                }
+                Err(err) => {
+                    warn!(?err, count = batch.len(), "giving up on analytics events");
+                    counters
+                        .failed
+                        .fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    fn policy() -> Policy {
+        Policy {
+            capacity: 4,
+            batch_size: 3,
+            flush_interval: Duration::from_millis(50),
+            retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        }
+    }
+
+    fn wait_for(delivery: &Delivery<u32>, done: impl Fn(DeliveryStats) -> bool) -> DeliveryStats {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let stats = delivery.stats();
+            if done(stats) || Instant::now() > deadline {
+                return stats;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn batches_and_retries() {
+        let batches = Arc::new(Mutex::new(vec![]));
+        let mut failures = 1;
+
+        let sent = Arc::clone(&batches);
+        let delivery = Delivery::start(policy(), move |batch: &[u32]| {
+            if failures > 0 {
+                failures -= 1;
+                return Err("unavailable");
+            }
+
+            sent.lock().unwrap().push(batch.to_vec());
+            Ok(())
+        });
+
+        for event in 0..3 {
+            delivery.send(event);
+        }
+
+        let stats = wait_for(&delivery, |stats| stats.sent == 3);
+        assert_eq!(stats.sent, 3);
+        assert_eq!(stats.failed, 0);
+        assert_eq!(*batches.lock().unwrap(), vec![vec![0, 1, 2]]);
+    }
+
+    #[test]
+    fn drops_on_overflow_and_gives_up() {
+        let (unblock, blocked) = flume::bounded::<()>(0);
+        let delivery = Delivery::start(policy(), move |_: &[u32]| {
+            _ = blocked.recv();
+            Err("unavailable")
+        });
+
+        // One batch is taken off the queue and stuck sending, and the rest fill it up
+        for event in 0..10 {
+            delivery.send(event);
+        }
+        drop(unblock);
+
+        let stats = wait_for(&delivery, |stats| stats.failed + stats.dropped == 10);
+        assert_eq!(stats.sent, 0);
+        assert_eq!(stats.failed + stats.dropped, 10);
+        assert!(stats.dropped > 0);
+    }
+}
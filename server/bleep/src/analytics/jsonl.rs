@@ -0,0 +1,76 @@
+//! Analytics events appended to a local file, one JSON object per line.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::warn;
+
+use super::{install, Analytics, QueryEvent, RepoEvent};
+
+pub struct JsonlSink {
+    file: Mutex<File>,
+}
+
+#[derive(Serialize)]
+struct Record<'a, T> {
+    timestamp: DateTime<Utc>,
+    /// Index events name themselves
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<&'static str>,
+    #[serde(flatten)]
+    data: &'a T,
+}
+
+impl JsonlSink {
+    /// Append events to the file at `path` from now on, creating it if it doesn't exist
+    pub fn new(path: &Path) -> anyhow::Result<Arc<Self>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open analytics file {}", path.display()))?;
+
+        let sink = Arc::new(Self {
+            file: Mutex::new(file),
+        });
+
+        install(sink.clone());
+        Ok(sink)
+    }
+
+    fn append<T: Serialize>(&self, event: Option<&'static str>, data: &T) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(&Record {
+            timestamp: Utc::now(),
+            event,
+            data,
+        })?;
+        line.push(b'\n');
+
+        // One write per line, so concurrent events never interleave
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+impl Analytics for JsonlSink {
+    fn track_query(&self, event: QueryEvent) {
+        if let Err(err) = self.append(Some("query"), &event) {
+            warn!(?err, "failed to write analytics event");
+        }
+    }
+
+    fn track_index(&self, event: &RepoEvent) {
+        if let Err(err) = self.append(None, event) {
+            warn!(?err, "failed to write analytics event");
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Analytics events sent to PostHog, either PostHog Cloud or a self-hosted instance.
+//!
+//! Events are queued and sent with the batch capture API, retried like RudderStack's.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::{
+    delivery::{self, Delivery},
+    install, Analytics, DeliveryStats, Permit, QueryEvent, RepoEvent,
+};
+
+/// PostHog Cloud, which is sent to unless another instance is configured
+pub const DEFAULT_POSTHOG_HOST: &str = "https://app.posthog.com";
+
+/// Events that don't belong to any user are attributed to the server
+const SERVER_ID: &str = "bleep";
+
+pub struct PosthogSink {
+    delivery: Delivery<Capture>,
+}
+
+/// A single event, as the capture API takes it
+#[derive(Serialize, Clone, Debug)]
+struct Capture {
+    event: &'static str,
+    distinct_id: String,
+    properties: Value,
+    timestamp: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct BatchRequest<'a> {
+    api_key: &'a str,
+    batch: &'a [Capture],
+}
+
+impl PosthogSink {
+    /// Send events to the project with the API key `key` on the PostHog instance at `host`
+    /// from now on
+    pub fn new(_: Permit, key: String, host: &str) -> anyhow::Result<Arc<Self>> {
+        let endpoint = format!("{}/batch/", host.trim_end_matches('/'));
+        let client = crate::proxy::client();
+
+        // Batches are sent from the delivery thread, which has no runtime of its own
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        let delivery = Delivery::start(delivery::Policy::default(), move |batch: &[Capture]| {
+            runtime.block_on(async {
+                client
+                    .post(&endpoint)
+                    .json(&BatchRequest {
+                        api_key: &key,
+                        batch,
+                    })
+                    .send()
+                    .await?
+                    .error_for_status()
+                    .map(drop)
+            })
+        });
+
+        let sink = Arc::new(Self { delivery });
+        install(sink.clone());
+        Ok(sink)
+    }
+}
+
+impl Analytics for PosthogSink {
+    fn track_query(&self, event: QueryEvent) {
+        let distinct_id = match event.user_id.is_empty() {
+            true => SERVER_ID.to_owned(),
+            false => event.user_id.clone(),
+        };
+
+        self.delivery.send(Capture {
+            event: "openai query",
+            distinct_id,
+            properties: json!({
+                "query_id": event.query_id,
+                "session_id": event.session_id,
+                "tenant": event.tenant,
+                "overlap_strategy": event.overlap_strategy,
+                "stages": event.stages,
+                "latency_ms": event.latency_ms,
+            }),
+            timestamp: Utc::now(),
+        });
+    }
+
+    fn track_index(&self, event: &RepoEvent) {
+        self.delivery.send(Capture {
+            event: "index lifecycle",
+            distinct_id: SERVER_ID.to_owned(),
+            properties: json!(event),
+            timestamp: Utc::now(),
+        });
+    }
+
+    fn delivery_stats(&self) -> Option<DeliveryStats> {
+        Some(self.delivery.stats())
+    }
+}
@@ -0,0 +1,576 @@
+//! A local record of every search and question, so operators can see what users ask and
+//! where retrieval comes up empty.
+//!
+//! Queries are appended to a file as lines of JSON, regardless of where analytics events are
+//! sent, and never leave the machine. The same file backs the daily usage totals.
+//!
+//! Entries record the tenant they were made in, and searching them takes a filter of the
+//! entries the caller can see, so that the administrators of a tenant only see its own.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use super::QueryEvent;
+
+/// The most entries returned by a single search
+const MAX_LIMIT: usize = 1000;
+
+/// Number of queries listed in each aggregate
+const TOP_QUERIES: usize = 20;
+
+/// Number of repositories listed for each day of usage
+const TOP_REPOS: usize = 10;
+
+/// Query language labels that narrow down a query, rather than say what to look for
+const FILTERS: &[&str] = &[
+    "repo:",
+    "org:",
+    "path:",
+    "lang:",
+    "group:",
+    "case:",
+    "open:",
+    "global_regex:",
+];
+
+const fn default_limit() -> usize {
+    100
+}
+
+pub struct QueryLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryKind {
+    /// A search with the query language
+    Search,
+    /// A question in natural language
+    Answer,
+}
+
+/// The snippet an answer was based on
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq, Eq)]
+pub struct SelectedSnippet {
+    pub repo_ref: String,
+    pub relative_path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct QueryLogEntry {
+    #[schema(value_type = String)]
+    pub timestamp: DateTime<Utc>,
+    pub kind: QueryKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    /// The tenant the query was made in, unless it was made by the operator
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub query: String,
+    /// Labels of the query that narrow it down, such as `repo:bloop`
+    pub filters: Vec<String>,
+    pub latency_ms: u64,
+    /// Number of results, or of snippets an answer was chosen from
+    pub result_count: usize,
+    /// Tokens sent to and received from the model
+    #[serde(default)]
+    pub tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selected: Option<SelectedSnippet>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl QueryLogEntry {
+    pub fn search(query: &str) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            kind: QueryKind::Search,
+            user_id: None,
+            tenant: None,
+            query: query.to_owned(),
+            filters: filters(query),
+            latency_ms: 0,
+            result_count: 0,
+            tokens: 0,
+            selected: None,
+            error: None,
+        }
+    }
+
+    /// The entry of a question, from the stages of its analytics event
+    pub fn answer(event: &QueryEvent) -> Self {
+        let stage = |name| event.stages.iter().find(|s| s.name == name);
+        let query = stage("raw_query")
+            .and_then(|s| s.data.as_str())
+            .unwrap_or_default();
+
+        Self {
+            timestamp: Utc::now(),
+            kind: QueryKind::Answer,
+            user_id: Some(event.user_id.clone()).filter(|id| !id.is_empty()),
+            tenant: event.tenant.clone(),
+            query: query.to_owned(),
+            filters: filters(query),
+            latency_ms: event
+                .stages
+                .iter()
+                .filter_map(|s| s.time_elapsed)
+                .sum::<u128>() as u64,
+            result_count: stage("search")
+                .and_then(|s| s.data.as_array())
+                .map_or(0, Vec::len),
+            tokens: event
+                .stages
+                .iter()
+                .filter(|s| s.name == "tokens")
+                .filter_map(|s| s.data.as_u64())
+                .sum::<u64>() as usize,
+            selected: stage("selected").and_then(|s| serde_json::from_value(s.data.clone()).ok()),
+            error: stage("error").map(|s| s.data.to_string()),
+        }
+    }
+
+    /// Repositories the query was about: those it was narrowed down to, and that of the
+    /// snippet an answer was based on
+    fn repos(&self) -> impl Iterator<Item = &str> {
+        self.filters
+            .iter()
+            .filter_map(|f| f.strip_prefix("repo:"))
+            .chain(self.selected.iter().map(|s| s.repo_ref.as_str()))
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct QueryLogParams {
+    /// Only return queries containing this text, ignoring case
+    q: Option<String>,
+    kind: Option<QueryKind>,
+    user_id: Option<String>,
+    /// Only return queries that found nothing
+    #[serde(default)]
+    no_results: bool,
+    /// Only return queries made at or after this time
+    #[param(value_type = Option<String>)]
+    since: Option<DateTime<Utc>>,
+    /// Only return queries made before this time
+    #[param(value_type = Option<String>)]
+    until: Option<DateTime<Utc>>,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+impl QueryLogParams {
+    fn matches(&self, entry: &QueryLogEntry) -> bool {
+        self.q.as_ref().map_or(true, |q| {
+            entry.query.to_lowercase().contains(&q.to_lowercase())
+        }) && self.kind.map_or(true, |k| k == entry.kind)
+            && self
+                .user_id
+                .as_ref()
+                .map_or(true, |u| Some(u) == entry.user_id.as_ref())
+            && (!self.no_results || entry.result_count == 0)
+            && self.since.map_or(true, |t| entry.timestamp >= t)
+            && self.until.map_or(true, |t| entry.timestamp < t)
+    }
+}
+
+/// How often a query was made
+#[derive(Serialize, ToSchema, Debug, PartialEq, Eq)]
+pub struct QueryCount {
+    query: String,
+    count: usize,
+}
+
+/// Totals over every matching query
+#[derive(Serialize, ToSchema, Default, Debug)]
+pub struct QueryStats {
+    total: usize,
+    /// Queries that found nothing
+    no_results: usize,
+    errors: usize,
+    average_latency_ms: u64,
+    /// The most frequent queries
+    top_queries: Vec<QueryCount>,
+    /// The most frequent queries that found nothing
+    top_no_results: Vec<QueryCount>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct UsageParams {
+    /// Only count queries made at or after this time
+    #[param(value_type = Option<String>)]
+    since: Option<DateTime<Utc>>,
+    /// Only count queries made before this time
+    #[param(value_type = Option<String>)]
+    until: Option<DateTime<Utc>>,
+}
+
+/// How often a repository was queried
+#[derive(Serialize, ToSchema, Debug, PartialEq, Eq)]
+pub struct RepoCount {
+    repo_ref: String,
+    count: usize,
+}
+
+/// Totals of one day, in UTC
+#[derive(Serialize, ToSchema, Debug)]
+pub struct DailyUsage {
+    #[schema(value_type = String)]
+    date: NaiveDate,
+    searches: usize,
+    answers: usize,
+    /// Distinct users who made a query
+    unique_users: usize,
+    /// Tokens sent to and received from the model
+    tokens: usize,
+    /// The most queried repositories
+    top_repos: Vec<RepoCount>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UsageResponse {
+    /// Days with any queries, oldest first
+    days: Vec<DailyUsage>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct QueryLogResponse {
+    /// Matching queries, most recent first
+    entries: Vec<QueryLogEntry>,
+    stats: QueryStats,
+}
+
+#[derive(Default)]
+struct Aggregate {
+    total: usize,
+    no_results: usize,
+    errors: usize,
+    latency_ms: u64,
+    counts: HashMap<String, usize>,
+    no_result_counts: HashMap<String, usize>,
+}
+
+impl Aggregate {
+    fn add(&mut self, entry: &QueryLogEntry) {
+        // Queries are counted regardless of case and spacing
+        let query = entry
+            .query
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+
+        self.total += 1;
+        self.latency_ms += entry.latency_ms;
+        self.errors += usize::from(entry.error.is_some());
+
+        if entry.result_count == 0 {
+            self.no_results += 1;
+            *self.no_result_counts.entry(query.clone()).or_default() += 1;
+        }
+
+        *self.counts.entry(query).or_default() += 1;
+    }
+
+    fn finish(self) -> QueryStats {
+        fn top(counts: HashMap<String, usize>) -> Vec<QueryCount> {
+            let mut counts = counts
+                .into_iter()
+                .map(|(query, count)| QueryCount { query, count })
+                .collect::<Vec<_>>();
+
+            counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.query.cmp(&b.query)));
+            counts.truncate(TOP_QUERIES);
+            counts
+        }
+
+        QueryStats {
+            total: self.total,
+            no_results: self.no_results,
+            errors: self.errors,
+            average_latency_ms: self.latency_ms / (self.total.max(1) as u64),
+            top_queries: top(self.counts),
+            top_no_results: top(self.no_result_counts),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Day {
+    searches: usize,
+    answers: usize,
+    users: HashSet<String>,
+    tokens: usize,
+    repos: HashMap<String, usize>,
+}
+
+impl Day {
+    fn add(&mut self, entry: &QueryLogEntry) {
+        match entry.kind {
+            QueryKind::Search => self.searches += 1,
+            QueryKind::Answer => self.answers += 1,
+        }
+
+        self.users.extend(entry.user_id.clone());
+        self.tokens += entry.tokens;
+
+        for repo in entry.repos() {
+            *self.repos.entry(repo.to_owned()).or_default() += 1;
+        }
+    }
+
+    fn finish(self, date: NaiveDate) -> DailyUsage {
+        let mut top_repos = self
+            .repos
+            .into_iter()
+            .map(|(repo_ref, count)| RepoCount { repo_ref, count })
+            .collect::<Vec<_>>();
+
+        top_repos.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.repo_ref.cmp(&b.repo_ref))
+        });
+        top_repos.truncate(TOP_REPOS);
+
+        DailyUsage {
+            date,
+            searches: self.searches,
+            answers: self.answers,
+            unique_users: self.users.len(),
+            tokens: self.tokens,
+            top_repos,
+        }
+    }
+}
+
+impl QueryLog {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open query log {}", path.display()))?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn append(&self, entry: &QueryLogEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// The most recent matching entries that are `visible`, and totals over all of them
+    pub fn search(
+        &self,
+        params: &QueryLogParams,
+        visible: impl Fn(&QueryLogEntry) -> bool,
+    ) -> anyhow::Result<QueryLogResponse> {
+        let limit = params.limit.min(MAX_LIMIT);
+
+        let mut entries = std::collections::VecDeque::with_capacity(limit);
+        let mut aggregate = Aggregate::default();
+        self.for_each(|entry| {
+            if !visible(&entry) || !params.matches(&entry) {
+                return;
+            }
+
+            aggregate.add(&entry);
+            if entries.len() == limit {
+                entries.pop_front();
+            }
+            entries.push_back(entry);
+        })?;
+
+        Ok(QueryLogResponse {
+            entries: entries.into_iter().rev().collect(),
+            stats: aggregate.finish(),
+        })
+    }
+
+    /// Totals of every day in the range, over the entries that are `visible`
+    pub fn usage(
+        &self,
+        params: &UsageParams,
+        visible: impl Fn(&QueryLogEntry) -> bool,
+    ) -> anyhow::Result<UsageResponse> {
+        let mut days = BTreeMap::<NaiveDate, Day>::new();
+        self.for_each(|entry| {
+            if visible(&entry)
+                && params.since.map_or(true, |t| entry.timestamp >= t)
+                && params.until.map_or(true, |t| entry.timestamp < t)
+            {
+                days.entry(entry.timestamp.date_naive())
+                    .or_default()
+                    .add(&entry);
+            }
+        })?;
+
+        Ok(UsageResponse {
+            days: days
+                .into_iter()
+                .map(|(date, day)| day.finish(date))
+                .collect(),
+        })
+    }
+
+    fn for_each(&self, mut f: impl FnMut(QueryLogEntry)) -> anyhow::Result<()> {
+        let file = File::open(&self.path)?;
+        for line in BufReader::new(file).lines() {
+            // A line may be partially written if the server stopped mid-write
+            if let Ok(entry) = serde_json::from_str::<QueryLogEntry>(&line?) {
+                f(entry);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn filters(query: &str) -> Vec<String> {
+    query
+        .split_whitespace()
+        .filter(|token| FILTERS.iter().any(|label| token.starts_with(label)))
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_and_aggregate() {
+        let dir = tempdir::TempDir::new("bleep").unwrap();
+        let log = QueryLog::open(&dir.path().join("queries.jsonl")).unwrap();
+
+        let entry = |query: &str, result_count| QueryLogEntry {
+            result_count,
+            latency_ms: 10,
+            ..QueryLogEntry::search(query)
+        };
+
+        log.append(&entry("repo:bloop parser", 3)).unwrap();
+        log.append(&entry("Where is  the parser?", 0)).unwrap();
+        log.append(&entry("where is the parser?", 0)).unwrap();
+
+        let params = |q: Option<&str>, no_results| QueryLogParams {
+            q: q.map(str::to_owned),
+            kind: None,
+            user_id: None,
+            no_results,
+            since: None,
+            until: None,
+            limit: 2,
+        };
+
+        let response = log.search(&params(None, false), |_| true).unwrap();
+        assert_eq!(response.entries.len(), 2);
+        assert_eq!(response.entries[0].query, "where is the parser?");
+        assert_eq!(response.stats.total, 3);
+        assert_eq!(response.stats.no_results, 2);
+        assert_eq!(response.stats.average_latency_ms, 10);
+        assert_eq!(
+            response.stats.top_no_results,
+            vec![QueryCount {
+                query: "where is the parser?".into(),
+                count: 2
+            }]
+        );
+
+        let response = log.search(&params(Some("REPO:"), false), |_| true).unwrap();
+        assert_eq!(response.stats.total, 1);
+        assert_eq!(response.entries[0].filters, vec!["repo:bloop".to_owned()]);
+
+        // Entries that can't be seen aren't listed or counted
+        let response = log
+            .search(&params(None, false), |e| e.result_count > 0)
+            .unwrap();
+        assert_eq!(response.stats.total, 1);
+        assert_eq!(response.entries.len(), 1);
+    }
+
+    #[test]
+    fn daily_usage() {
+        let dir = tempdir::TempDir::new("bleep").unwrap();
+        let log = QueryLog::open(&dir.path().join("queries.jsonl")).unwrap();
+
+        let at = |day| format!("2023-05-0{day}T12:00:00Z").parse().unwrap();
+        let answer = |day, user: &str, tokens| QueryLogEntry {
+            timestamp: at(day),
+            kind: QueryKind::Answer,
+            user_id: Some(user.to_owned()),
+            tokens,
+            selected: Some(SelectedSnippet {
+                repo_ref: "github.com/org/bleep".into(),
+                relative_path: "src/lib.rs".into(),
+                start_line: 1,
+                end_line: 10,
+            }),
+            ..QueryLogEntry::search("what does bleep do?")
+        };
+
+        log.append(&QueryLogEntry {
+            timestamp: at(1),
+            ..QueryLogEntry::search("repo:github.com/org/site parser")
+        })
+        .unwrap();
+        log.append(&answer(1, "alice", 100)).unwrap();
+        log.append(&answer(1, "alice", 50)).unwrap();
+        log.append(&answer(2, "bob", 10)).unwrap();
+
+        let usage = log
+            .usage(
+                &UsageParams {
+                    since: None,
+                    until: Some(at(2)),
+                },
+                |_| true,
+            )
+            .unwrap();
+
+        assert_eq!(usage.days.len(), 1);
+
+        let day = &usage.days[0];
+        assert_eq!(day.date, NaiveDate::from_ymd_opt(2023, 5, 1).unwrap());
+        assert_eq!((day.searches, day.answers), (1, 2));
+        assert_eq!(day.unique_users, 1);
+        assert_eq!(day.tokens, 150);
+        assert_eq!(
+            day.top_repos,
+            vec![
+                RepoCount {
+                    repo_ref: "github.com/org/bleep".into(),
+                    count: 2
+                },
+                RepoCount {
+                    repo_ref: "github.com/org/site".into(),
+                    count: 1
+                },
+            ]
+        );
+    }
+}
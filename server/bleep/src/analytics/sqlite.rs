@@ -0,0 +1,178 @@
+//! Analytics events stored in a local SQLite database.
+//!
+//! Every query is a row of the `query_events` table, and every step in the lifecycle of an
+//! index one of `index_events`. Stages, latencies and event details are stored as JSON, which
+//! SQLite's JSON functions can take apart.
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use tracing::warn;
+
+use super::{install, Analytics, QueryEvent, RepoEvent};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS query_events (
+    id INTEGER PRIMARY KEY,
+    timestamp TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    tenant TEXT,
+    session_id TEXT NOT NULL,
+    query_id TEXT NOT NULL,
+    overlap_strategy TEXT NOT NULL,
+    stages TEXT NOT NULL,
+    latency_ms TEXT
+);
+CREATE INDEX IF NOT EXISTS query_events_timestamp ON query_events (timestamp);
+CREATE TABLE IF NOT EXISTS index_events (
+    id INTEGER PRIMARY KEY,
+    timestamp TEXT NOT NULL,
+    event TEXT NOT NULL,
+    repo_ref TEXT NOT NULL,
+    data TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS index_events_repo_ref ON index_events (repo_ref, timestamp);
+";
+
+pub struct SqliteSink {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteSink {
+    /// Store events in the database at `path` from now on, creating it if it doesn't exist
+    pub fn new(path: &Path) -> anyhow::Result<Arc<Self>> {
+        let sink = Arc::new(Self::open(path)?);
+        install(sink.clone());
+        Ok(sink)
+    }
+
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("failed to open analytics database {}", path.display()))?;
+        conn.execute_batch(SCHEMA)?;
+
+        // Databases created before latencies were recorded lack their column
+        if conn.prepare("SELECT latency_ms FROM query_events").is_err() {
+            conn.execute("ALTER TABLE query_events ADD COLUMN latency_ms TEXT", [])?;
+        }
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn insert(&self, event: &QueryEvent) -> anyhow::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO query_events
+                (timestamp, user_id, tenant, session_id, query_id, overlap_strategy, stages,
+                 latency_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                Utc::now().to_rfc3339(),
+                event.user_id,
+                event.tenant,
+                event.session_id,
+                event.query_id.to_string(),
+                event.overlap_strategy.to_string(),
+                serde_json::to_string(&event.stages)?,
+                serde_json::to_string(&event.latency_ms)?,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn insert_index(&self, event: &RepoEvent) -> anyhow::Result<()> {
+        let data = serde_json::to_value(event)?;
+        let name = data["event"].as_str().unwrap_or_default().to_owned();
+
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO index_events (timestamp, event, repo_ref, data)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                Utc::now().to_rfc3339(),
+                name,
+                event.reporef().to_string(),
+                data.to_string(),
+            ],
+        )?;
+
+        Ok(())
+    }
+}
+
+impl Analytics for SqliteSink {
+    fn track_query(&self, event: QueryEvent) {
+        if let Err(err) = self.insert(&event) {
+            warn!(?err, "failed to store analytics event");
+        }
+    }
+
+    fn track_index(&self, event: &RepoEvent) {
+        if let Err(err) = self.insert_index(event) {
+            warn!(?err, "failed to store analytics event");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::RepoRef;
+
+    #[test]
+    fn stores_events() {
+        let dir = tempdir::TempDir::new("bleep").unwrap();
+        let sink = SqliteSink::open(&dir.path().join("analytics.db")).unwrap();
+
+        let event = QueryEvent {
+            user_id: "alice".into(),
+            session_id: "thread".into(),
+            ..Default::default()
+        };
+        sink.insert(&event).unwrap();
+        sink.insert(&event).unwrap();
+
+        let count: usize = sink
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM query_events WHERE user_id = 'alice'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        assert_eq!(count, 2);
+
+        let reporef = "github.com/org/repo".parse::<RepoRef>().unwrap();
+        sink.insert_index(&RepoEvent::FilesIndexed {
+            reporef,
+            files: 10,
+            chunks: 40,
+            duration_ms: 1200,
+        })
+        .unwrap();
+
+        let (event, chunks): (String, usize) = sink
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT event, json_extract(data, '$.chunks') FROM index_events
+                 WHERE repo_ref = 'github.com/org/repo'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+
+        assert_eq!(event, "files_indexed");
+        assert_eq!(chunks, 40);
+    }
+}
@@ -1,20 +1,32 @@
+mod events;
+mod jobs;
+mod syncs;
+
+pub(crate) use events::{is_milestone, subscribe, RepoEvent};
+pub(crate) use jobs::{Job, JobClass, JobId, JobKind, JobStatus, Jobs};
+
 use anyhow::bail;
+use dashmap::DashMap;
 use tracing::{debug, error, info};
 
 use crate::{
     indexes,
+    metrics::{self, IndexOperation},
     remotes::RemoteError,
-    repo::{RepoRef, Repository, SyncStatus},
+    repo::{GitRemote, RepoError, RepoRef, RepoRemote, Repository, SyncStatus},
     Application, Configuration,
 };
 
-use std::{future::Future, pin::Pin, sync::Arc, thread};
+use std::{future::Future, io::ErrorKind, path::Path, pin::Pin, sync::Arc, thread, time::Instant};
 
 type Task = Pin<Box<dyn Future<Output = ()> + Send + Sync>>;
 
 #[derive(Clone)]
 pub struct BackgroundExecutor {
     sender: flume::Sender<Task>,
+    /// When each removed repository was removed, to skip jobs queued for it before then
+    cancelled: Arc<DashMap<RepoRef, Instant>>,
+    syncs: Arc<syncs::Syncs>,
 }
 
 impl BackgroundExecutor {
@@ -51,7 +63,11 @@ impl BackgroundExecutor {
             }
         });
 
-        Self { sender }
+        Self {
+            sender,
+            cancelled: Arc::default(),
+            syncs: Arc::new(syncs::Syncs::new(syncs::DEBOUNCE)),
+        }
     }
 
     fn spawn<T>(&self, job: impl Future<Output = T> + Send + Sync + 'static) {
@@ -62,6 +78,22 @@ impl BackgroundExecutor {
             .unwrap();
     }
 
+    /// Number of jobs waiting to be scheduled
+    pub fn pending(&self) -> usize {
+        self.sender.len()
+    }
+
+    /// Cancel the jobs queued for a repository so far
+    fn cancel(&self, reporef: &RepoRef) {
+        self.cancelled.insert(reporef.clone(), Instant::now());
+    }
+
+    fn is_cancelled(&self, reporef: &RepoRef, queued_at: Instant) -> bool {
+        self.cancelled
+            .get(reporef)
+            .map_or(false, |cancelled_at| *cancelled_at >= queued_at)
+    }
+
     pub async fn wait_for<T: Send + Sync + 'static>(
         &self,
         job: impl Future<Output = T> + Send + Sync + 'static,
@@ -75,6 +107,8 @@ impl BackgroundExecutor {
 pub struct IndexWriter(pub(super) Application);
 impl IndexWriter {
     /// Pull or clone an existing, or new repo, respectively.
+    ///
+    /// Syncs of a repository requested while another waits to start are coalesced into it.
     pub(crate) async fn sync_and_index(self, repositories: Vec<RepoRef>) -> anyhow::Result<()> {
         let Self(app) = self;
         let background = app.background.clone();
@@ -83,7 +117,16 @@ impl IndexWriter {
             let mut set = tokio::task::JoinSet::new();
 
             for reporef in repositories {
-                set.spawn(IndexWriter(app.clone()).sync_and_index_call(reporef));
+                let writer = IndexWriter(app.clone());
+                let syncs = Arc::clone(&app.background.syncs);
+
+                set.spawn(async move {
+                    syncs
+                        .sync(reporef.clone(), move |queued_at| {
+                            writer.sync_and_index_call(reporef, queued_at)
+                        })
+                        .await
+                });
             }
 
             while let Some(job) = set.join_next().await {
@@ -96,24 +139,102 @@ impl IndexWriter {
         background.wait_for(job).await
     }
 
-    async fn sync_and_index_call(self, reporef: RepoRef) -> anyhow::Result<()> {
+    async fn sync_and_index_call(self, reporef: RepoRef, queued_at: Instant) -> anyhow::Result<()> {
+        if self.0.background.is_cancelled(&reporef, queued_at) {
+            debug!(?reporef, "repository was removed; skipping queued sync");
+            return Ok(());
+        }
+
         debug!(?reporef, "syncing repo");
+        RepoEvent::SyncStarted {
+            reporef: reporef.clone(),
+        }
+        .emit();
 
-        if let Err(err) = self.sync_repo(&reporef).await {
-            error!(?err, ?reporef, "failed to sync repository");
-            return Err(err);
+        // Repositories that were renamed on their remote are synced under their new name
+        let syncing = Instant::now();
+        let synced = self.sync_repo(&reporef).await;
+        metrics::observe_index(IndexOperation::Sync, syncing.elapsed());
+
+        let reporef = match synced {
+            Ok(synced) => synced,
+            Err(err) => {
+                error!(?err, ?reporef, "failed to sync repository");
+                RepoEvent::Error {
+                    reporef,
+                    message: err.to_string(),
+                }
+                .emit();
+                return Err(err);
+            }
+        };
+
+        RepoEvent::SyncFinished {
+            reporef: reporef.clone(),
+        }
+        .emit();
+
+        if self.0.background.is_cancelled(&reporef, queued_at) {
+            debug!(
+                ?reporef,
+                "repository was removed while syncing; skipping indexing"
+            );
+            return Ok(());
         }
 
         if let Err(err) = self.index_repo(&reporef).await {
             error!(?err, ?reporef, "failed to index repository");
+            RepoEvent::Error {
+                reporef,
+                message: err.to_string(),
+            }
+            .emit();
             return Err(err);
         }
 
         Ok(())
     }
 
+    /// Queue a job to sync and index each repository
     pub(crate) fn queue_sync_and_index(self, repositories: Vec<RepoRef>) {
-        tokio::task::spawn(self.sync_and_index(repositories));
+        let Self(app) = self;
+        for reporef in repositories {
+            app.jobs.queue(&app, JobKind::Index { reporef });
+        }
+    }
+
+    /// Remove a repository and everything derived from it: its clone, its documents in every
+    /// index, its embeddings, and its file cache. Jobs queued for it are cancelled.
+    ///
+    /// Removal waits for the index writers, so it never interleaves with indexing.
+    pub(crate) async fn delete_repo(self, reporef: RepoRef) -> anyhow::Result<()> {
+        let Self(app) = self;
+        let background = app.background.clone();
+        background.cancel(&reporef);
+        app.jobs.cancel_repo(&reporef);
+
+        let job = async move {
+            let writers = app.indexes.writers().await?;
+            let Some(repo) = app.repo_pool.get(&reporef).map(|r| r.value().clone()) else {
+                return Ok(());
+            };
+
+            let deleting = Instant::now();
+            IndexWriter(app.clone())
+                .delete_repo_indexes(&reporef, &repo, &writers)
+                .await?;
+
+            writers.commit().await?;
+            app.repo_pool.remove(&reporef);
+            app.config.source.save_pool(app.repo_pool.clone())?;
+
+            metrics::observe_index(IndexOperation::Delete, deleting.elapsed());
+            info!(?reporef, "repository removed");
+            RepoEvent::Removed { reporef }.emit();
+            Ok(())
+        };
+
+        background.wait_for(job).await
     }
 
     pub(crate) async fn startup_scan(self) -> anyhow::Result<()> {
@@ -135,7 +256,11 @@ impl IndexWriter {
 
         let writers = indexes.writers().await?;
         let (key, repo) = {
-            let ptr = repo_pool.get(reporef).unwrap();
+            // The repository may have been removed while it was syncing
+            let Some(ptr) = repo_pool.get(reporef) else {
+                return Ok(());
+            };
+
             let key = ptr.key().clone();
             let repo = ptr.value().clone();
             (key, repo)
@@ -149,6 +274,10 @@ impl IndexWriter {
                     writers.commit().await?;
                     repo_pool.remove(reporef);
                     config.source.save_pool(repo_pool.clone())?;
+                    RepoEvent::Removed {
+                        reporef: reporef.clone(),
+                    }
+                    .emit();
                 }
                 return deleted;
             }
@@ -163,7 +292,15 @@ impl IndexWriter {
             }
             _ => {
                 repo_pool.get_mut(reporef).unwrap().value_mut().sync_status = Indexing;
+                RepoEvent::IndexStarted {
+                    reporef: reporef.clone(),
+                }
+                .emit();
+
+                let indexing = Instant::now();
                 let indexed = repo.index(&key, &writers).await;
+                metrics::observe_index(IndexOperation::Index, indexing.elapsed());
+
                 let state = match &indexed {
                     Ok(state) => Some(state.clone()),
                     _ => None,
@@ -173,7 +310,9 @@ impl IndexWriter {
             }
         };
 
+        let committing = Instant::now();
         writers.commit().await?;
+        metrics::observe_index(IndexOperation::Commit, committing.elapsed());
         config.source.save_pool(repo_pool.clone())?;
 
         let mut repo = repo_pool.get_mut(reporef).unwrap();
@@ -181,12 +320,21 @@ impl IndexWriter {
             Ok(()) => {
                 repo.value_mut().sync_done_with(state.unwrap());
                 info!("commit complete; indexing done");
+                RepoEvent::IndexFinished {
+                    reporef: reporef.clone(),
+                }
+                .emit();
             }
             Err(err) => {
                 repo.value_mut().sync_status = Error {
                     message: err.to_string(),
                 };
                 error!(?err, ?reporef, "failed to index repository");
+                RepoEvent::Error {
+                    reporef: reporef.clone(),
+                    message: err.to_string(),
+                }
+                .emit();
             }
         }
 
@@ -198,10 +346,12 @@ impl IndexWriter {
     // Helper functions
     //
     //
-    async fn sync_repo(&self, repo: &RepoRef) -> anyhow::Result<()> {
+    /// Sync a repository, returning the name it was synced under, which changes if the
+    /// remote was renamed or transferred.
+    async fn sync_repo(&self, repo: &RepoRef) -> anyhow::Result<RepoRef> {
         let IndexWriter(app) = self;
 
-        let repo = repo.clone();
+        let mut repo = repo.clone();
         let backend = repo.backend();
         let creds = match app.credentials.for_repo(&repo) {
             Some(creds) => creds,
@@ -214,33 +364,139 @@ impl IndexWriter {
                     bail!("path not authorized {repo:?}")
                 }
 
-                self.0
-                    .repo_pool
-                    .entry(repo.to_owned())
-                    .or_insert_with(|| Repository::local_from(&repo));
+                self.0.repo_pool.entry(repo.to_owned()).or_insert_with(|| {
+                    RepoEvent::Added {
+                        reporef: repo.clone(),
+                    }
+                    .emit();
+                    Repository::local_from(&repo)
+                });
 
                 // we _never_ touch the git repositories of local repos
-                return Ok(());
+                return Ok(repo);
             }
         };
 
-        let synced = creds.sync(app.clone(), repo.clone()).await;
+        // Repositories marked for removal are cleaned up while indexing, fetching them again
+        // would overwrite the mark
+        let removed = app
+            .repo_pool
+            .get(&repo)
+            .map_or(false, |r| r.sync_status == SyncStatus::Removed);
+
+        if removed {
+            return Ok(repo);
+        }
+
+        let mut synced = creds.sync(app.clone(), repo.clone()).await;
+        if let Err(RemoteError::Renamed(ref name)) = synced {
+            let renamed = repo.renamed(name);
+            info!(?repo, ?renamed, "remote repository renamed");
+
+            self.rename_repo(&repo, &renamed).await?;
+            RepoEvent::Renamed {
+                from: repo.clone(),
+                to: renamed.clone(),
+            }
+            .emit();
+
+            repo = renamed;
+            synced = creds.sync(app.clone(), repo.clone()).await;
+        }
+
         if let Err(RemoteError::RemoteNotFound) = synced {
-            self.0
-                .repo_pool
-                .get_mut(&repo)
-                .unwrap()
-                .value_mut()
-                .sync_status = SyncStatus::RemoteRemoved;
+            if let Some(mut entry) = self.0.repo_pool.get_mut(&repo) {
+                entry.value_mut().sync_status = SyncStatus::RemoteRemoved;
+            }
 
             error!(?repo, "remote repository removed; disabling local syncing");
+            RepoEvent::RemoteRemoved {
+                reporef: repo.clone(),
+            }
+            .emit();
 
             // we want indexing to pick this up later and handle the new state
             // all local cleanups are done, so everything should be consistent
+            return Ok(repo);
+        }
+
+        synced?;
+        Ok(repo)
+    }
+
+    /// Move a repository that was renamed or transferred on its remote to its new name,
+    /// instead of keeping two copies of it.
+    ///
+    /// The clone and the embeddings are moved, but documents in the other indexes record
+    /// the old name and location, so those are dropped to be indexed again. If the new name
+    /// is already in use, the old copy is removed.
+    async fn rename_repo(&self, from: &RepoRef, to: &RepoRef) -> anyhow::Result<()> {
+        let IndexWriter(Application {
+            config,
+            repo_pool,
+            semantic,
+            indexes,
+            ..
+        }) = self;
+
+        let writers = indexes.writers().await?;
+        let Some(old) = repo_pool.get(from).map(|repo| repo.clone()) else {
+            return Ok(());
+        };
+
+        if repo_pool.contains_key(to) {
+            self.delete_repo_indexes(from, &old, &writers).await?;
+            repo_pool.remove(from);
+            writers.commit().await?;
+            config.source.save_pool(repo_pool.clone())?;
             return Ok(());
         }
 
-        Ok(synced?)
+        // The file cache is keyed by the location of the clone, which is about to change.
+        // Losing it only means the files are indexed again, so it can go before the move.
+        match old.delete_file_cache(&config.index_dir) {
+            Err(RepoError::IO { error }) if error.kind() == ErrorKind::NotFound => {}
+            result => result?,
+        }
+
+        let mut repo = old.clone();
+        repo.disk_path = config.source.repo_disk_path(to);
+        repo.sync_status = SyncStatus::Queued;
+        if let RepoRemote::Git(GitRemote {
+            ref mut address, ..
+        }) = repo.remote
+        {
+            *address = to.unpinned().name().to_owned();
+        }
+
+        // The pool still lists the repository under its old name until everything that can
+        // fail has been done, and the checkout is moved back if the embeddings can't follow.
+        let moved =
+            move_checkout(&old.disk_path, &repo.disk_path, &repo.remote.to_string()).await?;
+
+        if let Some(semantic) = semantic.wait().await {
+            if let Err(err) = semantic
+                .rename_repo(&from.to_string(), &to.to_string(), &to.indexed_name())
+                .await
+            {
+                if moved {
+                    move_checkout(&repo.disk_path, &old.disk_path, &old.remote.to_string()).await?;
+                }
+                return Err(err);
+            }
+        }
+
+        for handle in writers.iter() {
+            handle.delete(&old)?;
+        }
+
+        repo_pool.remove(from);
+        repo_pool.insert(to.clone(), repo);
+
+        writers.commit().await?;
+        config.source.save_pool(repo_pool.clone())?;
+
+        Ok(())
     }
 
     async fn delete_repo_indexes(
@@ -253,21 +509,102 @@ impl IndexWriter {
             config, semantic, ..
         }) = self;
 
-        if let Some(semantic) = semantic {
+        if let Some(semantic) = semantic.wait().await {
             semantic
                 .delete_points_by_path(&reporef.to_string(), std::iter::empty())
-                .await;
+                .await?;
+        }
+
+        // Repositories that were never indexed have no file cache, and ones that failed to
+        // clone have no checkout
+        match repo.delete_file_cache(&config.index_dir) {
+            Err(RepoError::IO { error }) if error.kind() == ErrorKind::NotFound => {}
+            result => result?,
         }
 
-        repo.delete_file_cache(&config.index_dir)?;
         if !reporef.is_local() {
-            tokio::fs::remove_dir_all(&repo.disk_path).await?;
+            match tokio::fs::remove_dir_all(&repo.disk_path).await {
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                result => result?,
+            }
         }
 
         for handle in writers {
-            handle.delete(repo);
+            handle.delete(repo)?;
         }
 
         Ok(())
     }
 }
+
+/// Move a checkout and point its `origin` at `remote`, moving it back if that fails.
+///
+/// Returns whether there was a checkout to move; repositories that failed to clone have none.
+async fn move_checkout(from: &Path, to: &Path, remote: &str) -> anyhow::Result<bool> {
+    match tokio::fs::rename(from, to).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        result => result?,
+    }
+
+    let repointed = git2::Repository::open(to).and_then(|git| git.remote_set_url("origin", remote));
+
+    if let Err(err) = repointed {
+        tokio::fs::rename(to, from).await?;
+        return Err(err.into());
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn checkout_is_moved_back_when_it_cant_be_repointed() {
+        let dir = tempdir::TempDir::new("move-checkout").unwrap();
+        let from = dir.path().join("old");
+        let to = dir.path().join("new");
+
+        // Not a git repository, so the remote can't be updated
+        std::fs::create_dir(&from).unwrap();
+        std::fs::write(from.join("README.md"), "hello").unwrap();
+
+        assert!(move_checkout(&from, &to, "github.com/org/new")
+            .await
+            .is_err());
+        assert!(from.join("README.md").exists());
+        assert!(!to.exists());
+    }
+
+    #[tokio::test]
+    async fn checkout_is_moved_and_repointed() {
+        let dir = tempdir::TempDir::new("move-checkout").unwrap();
+        let from = dir.path().join("old");
+        let to = dir.path().join("new");
+
+        git2::Repository::init(&from)
+            .unwrap()
+            .remote("origin", "https://github.com/org/old")
+            .unwrap();
+
+        let moved = move_checkout(&from, &to, "https://github.com/org/new").await;
+        assert!(moved.unwrap());
+        assert!(!from.exists());
+
+        let git = git2::Repository::open(&to).unwrap();
+        let origin = git.find_remote("origin").unwrap();
+        assert_eq!(origin.url(), Some("https://github.com/org/new"));
+    }
+
+    #[tokio::test]
+    async fn missing_checkouts_are_skipped() {
+        let dir = tempdir::TempDir::new("move-checkout").unwrap();
+        let from = dir.path().join("old");
+        let to = dir.path().join("new");
+
+        assert!(!move_checkout(&from, &to, "github.com/org/new")
+            .await
+            .unwrap());
+    }
+}
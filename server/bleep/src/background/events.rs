@@ -0,0 +1,117 @@
+//! Changes in the state of repositories, broadcast to whoever is listening.
+//!
+//! Events that nobody is subscribed to are dropped, and subscribers that fall too far behind
+//! miss the oldest ones. Every event but progress is also recorded as an analytics event.
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{analytics, repo::RepoRef};
+
+/// Events kept for subscribers that are behind
+const CAPACITY: usize = 1024;
+
+static EVENTS: Lazy<broadcast::Sender<RepoEvent>> = Lazy::new(|| broadcast::channel(CAPACITY).0);
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RepoEvent {
+    /// The repository was added to the index
+    Added {
+        reporef: RepoRef,
+    },
+    SyncStarted {
+        reporef: RepoRef,
+    },
+    SyncFinished {
+        reporef: RepoRef,
+    },
+    /// The repository was renamed or transferred on its remote
+    Renamed {
+        from: RepoRef,
+        to: RepoRef,
+    },
+    /// The repository no longer exists on its remote
+    RemoteRemoved {
+        reporef: RepoRef,
+    },
+    IndexStarted {
+        reporef: RepoRef,
+    },
+    /// Files of the repository indexed so far. Reported at most once for every percent.
+    IndexProgress {
+        reporef: RepoRef,
+        indexed: usize,
+        total: usize,
+    },
+    /// The files of the repository were indexed, and their chunks embedded
+    FilesIndexed {
+        reporef: RepoRef,
+        files: usize,
+        chunks: usize,
+        duration_ms: u64,
+    },
+    IndexFinished {
+        reporef: RepoRef,
+    },
+    Removed {
+        reporef: RepoRef,
+    },
+    Error {
+        reporef: RepoRef,
+        message: String,
+    },
+}
+
+impl RepoEvent {
+    pub(crate) fn emit(self) {
+        // Progress is only of interest while it's happening
+        if !matches!(self, Self::IndexProgress { .. }) {
+            analytics::track_index(&self);
+        }
+
+        // Sending only fails if there are no subscribers
+        _ = EVENTS.send(self);
+    }
+
+    pub fn reporef(&self) -> &RepoRef {
+        match self {
+            Self::Renamed { to, .. } => to,
+            Self::Added { reporef }
+            | Self::SyncStarted { reporef }
+            | Self::SyncFinished { reporef }
+            | Self::RemoteRemoved { reporef }
+            | Self::IndexStarted { reporef }
+            | Self::IndexProgress { reporef, .. }
+            | Self::FilesIndexed { reporef, .. }
+            | Self::IndexFinished { reporef }
+            | Self::Removed { reporef }
+            | Self::Error { reporef, .. } => reporef,
+        }
+    }
+}
+
+/// Receive the events emitted from now on
+pub(crate) fn subscribe() -> broadcast::Receiver<RepoEvent> {
+    EVENTS.subscribe()
+}
+
+/// Whether indexing `indexed` out of `total` files crossed into another percent
+pub(crate) fn is_milestone(indexed: usize, total: usize) -> bool {
+    indexed > 0 && total > 0 && (indexed * 100 / total) != ((indexed - 1) * 100 / total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_milestones() {
+        let milestones = (1..=1000).filter(|&n| is_milestone(n, 1000)).count();
+        assert_eq!(milestones, 100);
+
+        let milestones = (1..=3).filter(|&n| is_milestone(n, 3)).count();
+        assert_eq!(milestones, 3);
+    }
+}
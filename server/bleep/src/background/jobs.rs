@@ -0,0 +1,654 @@
+//! A queue of long-running work, such as indexing, that outlives the request that asked for it.
+//!
+//! Every job belongs to a class, which caps how many of its jobs run at once. Failed jobs are
+//! retried with backoff, and queued or running jobs can be cancelled. Jobs queued by kind are
+//! saved to disk, and the unfinished ones resumed after a restart. Jobs that run work a
+//! request waits on are only listed while the server runs, as nobody waits for them after a
+//! restart.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{Notify, Semaphore},
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    repo::RepoRef,
+    state::{pretty_write_file, read_file_or_default},
+    Application,
+};
+
+/// Attempts at a job before it fails
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Wait before the first retry, doubled for each one after
+const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Finished jobs kept for inspection
+const HISTORY: usize = 500;
+
+/// Jobs are told apart by random ids, so an id names the same job across restarts
+pub(crate) type JobId = uuid::Uuid;
+
+/// Jobs of a class share a limit on how many run at once
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobClass {
+    Index,
+    Maintenance,
+    Answer,
+}
+
+impl JobClass {
+    const ALL: &'static [Self] = &[Self::Index, Self::Maintenance, Self::Answer];
+
+    /// The most jobs of the class that run at once
+    fn concurrency(self) -> usize {
+        match self {
+            Self::Index => 4,
+            // Compaction holds the index writers, and can't run alongside anything else
+            Self::Maintenance => 1,
+            Self::Answer => 4,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobKind {
+    /// Sync a repository, and index what changed
+    Index {
+        #[schema(value_type = String)]
+        reporef: RepoRef,
+    },
+    /// Index a repository from scratch, embedding every file again
+    Reindex {
+        #[schema(value_type = String)]
+        reporef: RepoRef,
+    },
+    /// Merge index segments, and clean up unused index files
+    Compact,
+    /// Answer a batch of questions
+    BatchAnswer { questions: usize },
+    /// Review a pull request, and comment on it with the review
+    Review {
+        #[schema(value_type = String)]
+        reporef: RepoRef,
+        number: u64,
+    },
+}
+
+impl JobKind {
+    pub(crate) fn class(&self) -> JobClass {
+        match self {
+            Self::Index { .. } | Self::Reindex { .. } => JobClass::Index,
+            Self::Compact => JobClass::Maintenance,
+            Self::BatchAnswer { .. } | Self::Review { .. } => JobClass::Answer,
+        }
+    }
+
+    pub(crate) fn reporef(&self) -> Option<&RepoRef> {
+        match self {
+            Self::Index { reporef } | Self::Reindex { reporef } | Self::Review { reporef, .. } => {
+                Some(reporef)
+            }
+            Self::Compact | Self::BatchAnswer { .. } => None,
+        }
+    }
+
+    async fn run(&self, app: &Application) -> Result<()> {
+        match self {
+            Self::Index { reporef } => {
+                app.write_index()
+                    .sync_and_index(vec![reporef.clone()])
+                    .await
+            }
+            Self::Reindex { reporef } => {
+                if let Some(repo) = app.repo_pool.get(reporef) {
+                    repo.delete_file_cache(&app.config.index_dir)?;
+                }
+
+                app.write_index()
+                    .sync_and_index(vec![reporef.clone()])
+                    .await
+            }
+            Self::Compact => app.indexes.writers().await?.compact().await,
+            Self::BatchAnswer { .. } => Err(anyhow!("batch answers can't be resumed")),
+            Self::Review { .. } => Err(anyhow!("reviews can't be resumed")),
+        }
+    }
+
+    /// Stop the work of a cancelled job that doesn't stop with its future
+    fn stop(&self, app: &Application) {
+        // Syncs run on the background runtime, and are only waited on by the job
+        if let Self::Index { reporef } | Self::Reindex { reporef } = self {
+            app.background.cancel(reporef);
+        }
+    }
+}
+
+/// Cancels a job run by a request if the request's future is dropped before the job finishes,
+/// so it isn't left running
+struct RunGuard<'a> {
+    jobs: &'a Jobs,
+    id: JobId,
+}
+
+impl Drop for RunGuard<'_> {
+    fn drop(&mut self) {
+        // A no-op once the job has finished
+        self.jobs.cancel(self.id);
+    }
+}
+
+/// Drops the signal to cancel a job once it's no longer run, however it stopped
+struct CancellationGuard<'a> {
+    jobs: &'a Jobs,
+    id: JobId,
+}
+
+impl Drop for CancellationGuard<'_> {
+    fn drop(&mut self) {
+        self.jobs.cancellations.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed { error: String },
+    Cancelled,
+}
+
+impl JobStatus {
+    fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            Self::Succeeded | Self::Failed { .. } | Self::Cancelled
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub(crate) struct Job {
+    #[schema(value_type = String)]
+    pub id: JobId,
+    pub kind: JobKind,
+    pub class: JobClass,
+    pub status: JobStatus,
+    /// Attempts so far, including the one running
+    pub attempts: u32,
+    /// Whether the job is saved, and resumed after a restart
+    pub durable: bool,
+    #[schema(value_type = String)]
+    pub queued_at: DateTime<Utc>,
+    #[schema(value_type = Option<String>)]
+    pub started_at: Option<DateTime<Utc>>,
+    #[schema(value_type = Option<String>)]
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+pub(crate) struct Jobs {
+    path: PathBuf,
+    jobs: Mutex<HashMap<JobId, Job>>,
+    limits: HashMap<JobClass, Arc<Semaphore>>,
+    /// Signals to cancel each unfinished job
+    cancellations: Mutex<HashMap<JobId, Arc<Notify>>>,
+}
+
+impl Jobs {
+    /// Open the queue saved at `path`. Unfinished jobs stay queued until [`Jobs::resume`].
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let saved: Vec<Job> = read_file_or_default(path).unwrap_or_else(|err| {
+            // Such as a queue saved before jobs had random ids
+            warn!(
+                ?err,
+                "failed to read saved job queue; starting with an empty one"
+            );
+            Vec::new()
+        });
+        let jobs = saved
+            .into_iter()
+            .map(|mut job| {
+                // Jobs that were running when the server stopped start over
+                if job.status == JobStatus::Running {
+                    job.status = JobStatus::Queued;
+                }
+
+                (job.id, job)
+            })
+            .collect();
+
+        Ok(Self {
+            path: path.to_owned(),
+            jobs: Mutex::new(jobs),
+            limits: JobClass::ALL
+                .iter()
+                .map(|&class| (class, Arc::new(Semaphore::new(class.concurrency()))))
+                .collect(),
+            cancellations: Mutex::default(),
+        })
+    }
+
+    /// Run the jobs left unfinished by the last run of the server
+    pub(crate) fn resume(self: &Arc<Self>, app: &Application) {
+        let unfinished = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| job.status == JobStatus::Queued)
+            .map(|job| job.id)
+            .collect::<Vec<_>>();
+
+        if !unfinished.is_empty() {
+            info!(count = unfinished.len(), "resuming queued jobs");
+        }
+
+        for id in unfinished {
+            self.spawn(app.clone(), id);
+        }
+    }
+
+    /// Queue a job, to run in the background. A job that's already queued, and not yet
+    /// running, isn't queued twice.
+    pub(crate) fn queue(self: &Arc<Self>, app: &Application, kind: JobKind) -> JobId {
+        let queued = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .find(|job| job.kind == kind && job.status == JobStatus::Queued)
+            .map(|job| job.id);
+
+        if let Some(id) = queued {
+            return id;
+        }
+
+        let id = self.insert(kind, true);
+        self.spawn(app.clone(), id);
+        id
+    }
+
+    /// Run `work` as a job, waiting for it to finish. It isn't retried, or resumed after a
+    /// restart.
+    pub(crate) async fn run<T>(
+        &self,
+        kind: JobKind,
+        work: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let id = self.insert(kind.clone(), false);
+        self.run_inserted(id, kind, work).await
+    }
+
+    /// Run `work` as a job in the background, like `run`, returning its id at once along with
+    /// a handle to wait for it on
+    pub(crate) fn run_detached<T: Send + 'static>(
+        self: &Arc<Self>,
+        kind: JobKind,
+        work: impl Future<Output = Result<T>> + Send + 'static,
+    ) -> (JobId, JoinHandle<Result<T>>) {
+        let id = self.insert(kind.clone(), false);
+        let jobs = Arc::clone(self);
+
+        (
+            id,
+            tokio::spawn(async move { jobs.run_inserted(id, kind, work).await }),
+        )
+    }
+
+    async fn run_inserted<T>(
+        &self,
+        id: JobId,
+        kind: JobKind,
+        work: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let _guard = RunGuard { jobs: self, id };
+        let (cancelled, _cancellation) = self.cancellation(id);
+
+        let _permit = self.limits[&kind.class()].acquire().await?;
+        if !self.start(id) {
+            return Err(anyhow!("job {id} was cancelled"));
+        }
+
+        let result = tokio::select! {
+            result = work => result,
+            _ = cancelled.notified() => Err(anyhow!("job {id} was cancelled")),
+        };
+
+        self.finish(id, result.as_ref().err());
+        result
+    }
+
+    /// Cancel a job that hasn't finished, returning whether there was one. Running jobs stop
+    /// at their next await point, except for syncs and indexing, which finish the step they're
+    /// on, skipping the rest.
+    pub(crate) fn cancel(&self, id: JobId) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(&id).filter(|job| !job.status.is_finished()) else {
+            return false;
+        };
+
+        job.status = JobStatus::Cancelled;
+        job.finished_at = Some(Utc::now());
+
+        if let Some(cancelled) = self.cancellations.lock().unwrap().remove(&id) {
+            cancelled.notify_one();
+        }
+
+        self.save(&jobs);
+        true
+    }
+
+    /// Cancel the queued jobs of a repository
+    pub(crate) fn cancel_repo(&self, reporef: &RepoRef) {
+        let queued = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| job.status == JobStatus::Queued)
+            .filter(|job| job.kind.reporef() == Some(reporef))
+            .map(|job| job.id)
+            .collect::<Vec<_>>();
+
+        for id in queued {
+            self.cancel(id);
+        }
+    }
+
+    /// Every job that hasn't finished, and the most recent that have, oldest first
+    pub(crate) fn list(&self) -> Vec<Job> {
+        let mut jobs = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        jobs.sort_by_key(|job| job.queued_at);
+        jobs
+    }
+
+    pub(crate) fn get(&self, id: JobId) -> Option<Job> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    fn insert(&self, kind: JobKind, durable: bool) -> JobId {
+        let mut jobs = self.jobs.lock().unwrap();
+        let id = JobId::new_v4();
+
+        jobs.insert(
+            id,
+            Job {
+                id,
+                class: kind.class(),
+                kind,
+                status: JobStatus::Queued,
+                attempts: 0,
+                durable,
+                queued_at: Utc::now(),
+                started_at: None,
+                finished_at: None,
+            },
+        );
+
+        self.save(&jobs);
+        id
+    }
+
+    /// The signal to cancel a job, kept until the guard returned with it is dropped
+    fn cancellation(&self, id: JobId) -> (Arc<Notify>, CancellationGuard<'_>) {
+        let cancelled = Arc::clone(self.cancellations.lock().unwrap().entry(id).or_default());
+        (cancelled, CancellationGuard { jobs: self, id })
+    }
+
+    fn spawn(self: &Arc<Self>, app: Application, id: JobId) {
+        let jobs = Arc::clone(self);
+        tokio::spawn(async move { jobs.run_queued(app, id).await });
+    }
+
+    async fn run_queued(&self, app: Application, id: JobId) {
+        let Some(kind) = self.get(id).map(|job| job.kind) else {
+            return;
+        };
+
+        let (cancelled, _cancellation) = self.cancellation(id);
+        let mut backoff = RETRY_BACKOFF;
+
+        loop {
+            let Ok(permit) = self.limits[&kind.class()].acquire().await else {
+                return;
+            };
+
+            if !self.start(id) {
+                return;
+            }
+
+            let result = tokio::select! {
+                result = kind.run(&app) => result,
+                _ = cancelled.notified() => {
+                    kind.stop(&app);
+                    return;
+                }
+            };
+
+            let Err(err) = result else {
+                self.finish(id, None);
+                return;
+            };
+
+            if self
+                .get(id)
+                .map_or(true, |job| job.attempts >= MAX_ATTEMPTS)
+            {
+                error!(?err, %id, ?kind, "job failed");
+                self.finish(id, Some(&err));
+                return;
+            }
+
+            warn!(?err, %id, ?kind, "job failed; retrying");
+            self.requeue(id);
+            drop(permit);
+
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = cancelled.notified() => return,
+            }
+            backoff *= 2;
+        }
+    }
+
+    /// Mark a queued job as running, returning whether it should run
+    fn start(&self, id: JobId) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(job) = jobs
+            .get_mut(&id)
+            .filter(|job| job.status == JobStatus::Queued)
+        else {
+            return false;
+        };
+
+        job.status = JobStatus::Running;
+        job.attempts += 1;
+        job.started_at = Some(Utc::now());
+
+        self.save(&jobs);
+        true
+    }
+
+    fn requeue(&self, id: JobId) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs
+            .get_mut(&id)
+            .filter(|job| job.status == JobStatus::Running)
+        {
+            job.status = JobStatus::Queued;
+        }
+
+        self.save(&jobs);
+    }
+
+    fn finish(&self, id: JobId, error: Option<&anyhow::Error>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs
+            .get_mut(&id)
+            .filter(|job| job.status == JobStatus::Running)
+        {
+            job.status = match error {
+                None => JobStatus::Succeeded,
+                Some(err) => JobStatus::Failed {
+                    error: err.to_string(),
+                },
+            };
+            job.finished_at = Some(Utc::now());
+        }
+
+        // The oldest finished jobs make way for new ones
+        let mut finished = jobs
+            .values()
+            .filter(|job| job.status.is_finished())
+            .map(|job| (job.queued_at, job.id))
+            .collect::<Vec<_>>();
+        finished.sort();
+
+        for (_, id) in finished.iter().take(finished.len().saturating_sub(HISTORY)) {
+            jobs.remove(id);
+        }
+
+        self.save(&jobs);
+    }
+
+    fn save(&self, jobs: &HashMap<JobId, Job>) {
+        let mut durable = jobs.values().filter(|job| job.durable).collect::<Vec<_>>();
+        durable.sort_by_key(|job| job.queued_at);
+
+        if let Err(err) = pretty_write_file(&self.path, &durable) {
+            warn!(?err, "failed to save job queue");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn run_cancel_and_persist() {
+        let dir = tempdir::TempDir::new("bleep").unwrap();
+        let path = dir.path().join("jobs.json");
+        let jobs = Arc::new(Jobs::open(&path).unwrap());
+
+        let answered = jobs
+            .run(JobKind::BatchAnswer { questions: 2 }, async { Ok(42) })
+            .await
+            .unwrap();
+        assert_eq!(answered, 42);
+
+        let (detached, handle) =
+            jobs.run_detached(JobKind::BatchAnswer { questions: 1 }, async { Ok(7) });
+        assert_eq!(handle.await.unwrap().unwrap(), 7);
+        assert_eq!(jobs.get(detached).unwrap().status, JobStatus::Succeeded);
+
+        let failed = jobs
+            .run(JobKind::Compact, async {
+                Err::<(), _>(anyhow!("no space"))
+            })
+            .await;
+        assert!(failed.is_err());
+
+        let reporef = "github.com/org/repo".parse::<RepoRef>().unwrap();
+        let queued = jobs.insert(
+            JobKind::Index {
+                reporef: reporef.clone(),
+            },
+            true,
+        );
+        let other = jobs.insert(JobKind::Compact, true);
+        jobs.cancel_repo(&reporef);
+        assert_eq!(jobs.get(queued).unwrap().status, JobStatus::Cancelled);
+        assert!(!jobs.cancel(queued));
+
+        let statuses = jobs
+            .list()
+            .into_iter()
+            .map(|job| job.status)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![
+                JobStatus::Succeeded,
+                JobStatus::Succeeded,
+                JobStatus::Failed {
+                    error: "no space".into()
+                },
+                JobStatus::Cancelled,
+                JobStatus::Queued,
+            ]
+        );
+
+        // Only jobs queued by kind are saved, and the unfinished ones are queued again
+        let unsaved = jobs.insert(JobKind::BatchAnswer { questions: 1 }, false);
+        let reopened = Jobs::open(&path).unwrap();
+        let saved = reopened.list();
+        assert_eq!(saved.len(), 2);
+        assert_eq!(saved[1].id, other);
+        assert_eq!(saved[1].status, JobStatus::Queued);
+
+        // Jobs that weren't saved don't lend their ids to new ones
+        let new = reopened.insert(JobKind::Compact, false);
+        assert_ne!(new, unsaved);
+        assert!(jobs.get(new).is_none());
+        assert!(jobs.cancellations.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropped_runs_are_cancelled() {
+        let dir = tempdir::TempDir::new("bleep").unwrap();
+        let jobs = Jobs::open(&dir.path().join("jobs.json")).unwrap();
+
+        let mut run = Box::pin(jobs.run(
+            JobKind::BatchAnswer { questions: 1 },
+            std::future::pending::<Result<()>>(),
+        ));
+        assert!(futures::poll!(&mut run).is_pending());
+        assert_eq!(jobs.list()[0].status, JobStatus::Running);
+
+        // The request waiting on the job went away
+        drop(run);
+        let job = &jobs.list()[0];
+        assert_eq!(job.status, JobStatus::Cancelled);
+        assert!(job.finished_at.is_some());
+        assert!(jobs.cancellations.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelled_jobs_that_never_ran_are_forgotten() {
+        let dir = tempdir::TempDir::new("bleep").unwrap();
+        let jobs = Jobs::open(&dir.path().join("jobs.json")).unwrap();
+
+        // Cancelled before it got to run
+        let id = jobs.insert(JobKind::Compact, false);
+        assert!(jobs.cancel(id));
+
+        let run = jobs.run_inserted(id, JobKind::Compact, async { Ok(()) });
+        assert!(run.await.is_err());
+        assert!(jobs.cancellations.lock().unwrap().is_empty());
+    }
+}
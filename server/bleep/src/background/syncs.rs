@@ -0,0 +1,141 @@
+//! Syncs of a repository requested close together run once.
+//!
+//! Webhooks, the sync schedule and users often ask for the same repository to be synced at
+//! about the same time. A sync waits a moment before it starts, and every request for the
+//! repository made meanwhile joins it. Requests made once it's started wait for the next sync,
+//! which starts when the running one is done, as the repository may have changed since it was
+//! fetched.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use dashmap::DashMap;
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use tracing::debug;
+
+use crate::repo::RepoRef;
+
+/// How long a sync waits for more requests to join it before it starts
+pub(super) const DEBOUNCE: Duration = Duration::from_secs(2);
+
+type SharedSync = Shared<BoxFuture<'static, Result<(), Arc<anyhow::Error>>>>;
+
+/// The next sync of a repository, until it starts
+struct Next {
+    sync: SharedSync,
+    /// When the latest request that joined it was made
+    queued_at: Instant,
+}
+
+pub(super) struct Syncs {
+    debounce: Duration,
+    next: Mutex<HashMap<RepoRef, Next>>,
+    /// Held by the running sync of each repository
+    running: DashMap<RepoRef, Arc<tokio::sync::Mutex<()>>>,
+}
+
+impl Syncs {
+    pub(super) fn new(debounce: Duration) -> Self {
+        Self {
+            debounce,
+            next: Mutex::default(),
+            running: DashMap::default(),
+        }
+    }
+
+    /// Wait for the next sync of `reporef`, which runs `sync` if no other request is waiting
+    /// for it yet.
+    ///
+    /// `sync` is given when the latest request that joined it was made.
+    pub(super) async fn sync<F, Fut>(
+        self: &Arc<Self>,
+        reporef: RepoRef,
+        sync: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnOnce(Instant) -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let shared = match self.next.lock().unwrap().entry(reporef.clone()) {
+            Entry::Occupied(mut next) => {
+                debug!(?reporef, "joining queued sync");
+                next.get_mut().queued_at = Instant::now();
+                next.get().sync.clone()
+            }
+            Entry::Vacant(next) => {
+                let syncs = Arc::clone(self);
+                let shared = async move {
+                    tokio::time::sleep(syncs.debounce).await;
+
+                    let running = Arc::clone(&syncs.running.entry(reporef.clone()).or_default());
+                    let _running = running.lock().await;
+
+                    // Requests made from now on wait for the next sync
+                    let queued_at = syncs
+                        .next
+                        .lock()
+                        .unwrap()
+                        .remove(&reporef)
+                        .map_or_else(Instant::now, |next| next.queued_at);
+
+                    sync(queued_at).await.map_err(Arc::new)
+                }
+                .boxed()
+                .shared();
+
+                // The sync runs to the end, even if every request stops waiting for it
+                tokio::spawn(shared.clone());
+
+                next.insert(Next {
+                    sync: shared.clone(),
+                    queued_at: Instant::now(),
+                });
+                shared
+            }
+        };
+
+        shared.await.map_err(|err| anyhow!("{err:#}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn coalesces_requests() {
+        let syncs = Arc::new(Syncs::new(Duration::from_millis(50)));
+        let reporef = "github.com/org/repo".parse::<RepoRef>().unwrap();
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let request = || {
+            let runs = Arc::clone(&runs);
+            syncs.sync(reporef.clone(), move |_| async move {
+                runs.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+        };
+
+        let (a, b, c) = tokio::join!(request(), request(), request());
+        assert!(a.is_ok() && b.is_ok() && c.is_ok());
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        request().await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        let failed = syncs
+            .sync(reporef.clone(), |_| async { Err(anyhow!("no remote")) })
+            .await;
+        assert_eq!(failed.unwrap_err().to_string(), "no remote");
+    }
+}
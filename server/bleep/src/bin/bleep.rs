@@ -1,16 +1,48 @@
 use anyhow::Result;
-use bleep::{Application, Configuration, Environment};
+use bleep::{Application, Command, ConfigCommand, Configuration, Environment};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    Application::install_logging();
-    let app = Application::initialize(
-        Environment::server(),
-        Configuration::cli_overriding_config_file()?,
-    )
-    .await?;
+    let config = Configuration::load()?;
+    if config.command.as_ref().map_or(false, Command::serves_stdio) {
+        Application::install_stdio_logging();
+    } else {
+        Application::install_logging();
+    }
+
+    if let Some(Command::Config {
+        command: ConfigCommand::Check,
+    }) = config.command
+    {
+        println!("{}", serde_json::to_string_pretty(&config.redacted()?)?);
+        return Ok(());
+    }
+
+    if let Some(Command::Completions { shell, repos }) = config.command {
+        return bleep::print_completions(&config, shell, repos);
+    }
+
+    // Remote instances are asked without opening the local indexes
+    if let Some(command) = config.command.as_ref().filter(|c| c.is_remote()) {
+        return bleep::run_remote(command).await;
+    }
+
+    // Whoever runs `bleep index` or `bleep export` can read the path they give anyway, wherever
+    // it is
+    let env = match config.command {
+        Some(Command::Index { .. } | Command::Export { .. }) => Environment::insecure_local(),
+        _ => Environment::server(),
+    };
+
+    let app = Application::initialize(env, config).await?;
 
     app.initialize_sentry();
     app.initialize_analytics();
-    app.run().await
+
+    match app.config.command.clone() {
+        Some(Command::Lsp) => app.serve_lsp().await,
+        Some(Command::Mcp) => app.serve_mcp().await,
+        Some(command) => app.run_once(&command).await,
+        None => app.run().await,
+    }
 }
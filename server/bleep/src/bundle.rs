@@ -0,0 +1,647 @@
+//! Bundles of the indexes of a repository, so that they can be built on one machine, such as in
+//! CI, and served from others.
+//!
+//! A bundle is a gzipped tarball of:
+//!
+//! - `manifest.json`, the [`Manifest`]
+//! - `content/`, the repository's shard of the file index
+//! - `blobs/`, the contents of its large files, kept outside the shard
+//! - `embeddings.jsonl`, its embeddings, one [`crate::semantic::StoredEmbedding`] per line, if
+//!   semantic search is enabled
+//!
+//! The repository index keeps every repository in a single shard, so the repository's document
+//! is made again from the manifest when the bundle is imported, rather than bundled.
+//!
+//! Bundles are unpacked and checked against the instance importing them before anything is
+//! installed: the index schema and embedding model must be the same as the instance's, and
+//! every embedding must be readable. The embeddings are then stored next to the repository's
+//! previous ones, and only once they all are does the shard replace the repository's previous
+//! one, in a single step as far as searches can tell. Whichever embeddings are left over are
+//! deleted after. Repositories keep the checkout path they were indexed at, which the documents
+//! of the shard refer to.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use qdrant_client::qdrant::PointId;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    background::RepoEvent,
+    ctags::SymbolMap,
+    indexes::GlobalWriteHandle,
+    language::LanguageInfo,
+    repo::{get_repo_metadata, RepoError, RepoMetadata, RepoRef, Repository, SyncStatus},
+    semantic::{LoadState, Semantic, StoredEmbedding},
+    state::SCHEMA_VERSION,
+    Application, Configuration,
+};
+
+/// Version of the layout of bundles, bumped whenever it changes
+pub const FORMAT_VERSION: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+const CONTENT: &str = "content";
+const BLOBS: &str = "blobs";
+const EMBEDDINGS: &str = "embeddings.jsonl";
+
+/// Directory under the index directory that bundles are unpacked in
+const STAGING: &str = "staging";
+
+/// Embeddings queued to be stored at once when importing
+const IMPORT_BATCH_SIZE: usize = 256;
+
+/// What a bundle holds, and what it was made with
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub format: u32,
+    pub bleep_version: String,
+    /// Version of the index schema, which the importing instance must share
+    pub schema_version: String,
+    /// The embedding model, unless the bundle has no embeddings
+    pub model_id: Option<String>,
+    pub repo_ref: RepoRef,
+    pub repo: Repository,
+    /// Large files whose contents are bundled outside the shard
+    pub blobs: usize,
+    pub embeddings: usize,
+    /// Languages of the repository's files when it was exported
+    #[serde(default)]
+    pub langs: LanguageInfo,
+    /// Symbols ctags found in the repository's files when it was exported
+    #[serde(default)]
+    pub symbols: SymbolMap,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Write a bundle of the indexes of `reporef` to `output`, replacing whatever is there once it's
+/// written in full.
+///
+/// Indexing waits until the bundle is written, so that the shard, blobs and embeddings are of
+/// the same index.
+pub async fn export(app: &Application, reporef: &RepoRef, output: &Path) -> Result<Manifest> {
+    let _writers = app.indexes.writers().await?;
+
+    let Some(repo) = app.repo_pool.get(reporef).map(|repo| repo.clone()) else {
+        bail!("{reporef} was never added");
+    };
+
+    if repo.last_index_unix_secs == 0 {
+        bail!("{reporef} hasn't been indexed yet");
+    }
+
+    // A bundle without the embeddings of an instance that has them would import as though the
+    // repository had none
+    let semantic = app.semantic.wait().await;
+    if let LoadState::Failed(err) = app.semantic.state() {
+        bail!("semantic search failed to load, so embeddings can't be exported: {err}");
+    }
+
+    let embeddings_path = sibling(output, "embeddings.tmp");
+    let (model_id, embeddings) = match semantic {
+        Some(semantic) => {
+            let count = export_embeddings(&semantic, reporef, &embeddings_path)
+                .await
+                .map_err(|err| {
+                    _ = fs::remove_file(&embeddings_path);
+                    err
+                })?;
+
+            (Some(semantic.model_id().to_owned()), Some(count))
+        }
+        None => (None, None),
+    };
+
+    let file = &app.indexes.file;
+    let searcher = file
+        .searcher(Some(std::slice::from_ref(&repo.disk_path)))
+        .await;
+    let shard = file.shard_path(&repo.disk_path);
+    let metadata = get_repo_metadata(&repo.disk_path).await;
+
+    let written = tokio::task::block_in_place(|| -> Result<Manifest> {
+        let blobs = file
+            .source
+            .blob_keys(&searcher)?
+            .into_iter()
+            .map(|key| (file.source.blob_path(&key), key))
+            .collect::<Vec<_>>();
+
+        let manifest = Manifest {
+            format: FORMAT_VERSION,
+            bleep_version: env!("CARGO_PKG_VERSION").to_owned(),
+            schema_version: SCHEMA_VERSION.to_owned(),
+            model_id,
+            repo_ref: reporef.clone(),
+            repo,
+            blobs: blobs.len(),
+            embeddings: embeddings.unwrap_or_default(),
+            langs: metadata.langs.clone(),
+            symbols: metadata.symbols.clone(),
+            created_at: Utc::now(),
+        };
+
+        let embeddings = embeddings.map(|_| embeddings_path.as_path());
+        write_bundle(output, &manifest, &shard, &blobs, embeddings)?;
+        Ok(manifest)
+    });
+
+    _ = fs::remove_file(&embeddings_path);
+
+    let manifest = written?;
+    info!(
+        %reporef,
+        blobs = manifest.blobs,
+        embeddings = manifest.embeddings,
+        output = %output.display(),
+        "exported bundle"
+    );
+
+    Ok(manifest)
+}
+
+/// Write the embeddings of `reporef` to `path`, one per line, returning how many there are
+async fn export_embeddings(semantic: &Semantic, reporef: &RepoRef, path: &Path) -> Result<usize> {
+    let mut out = BufWriter::new(fs::File::create(path)?);
+    let mut count = 0;
+
+    semantic
+        .export_points(&reporef.to_string(), |embedding| {
+            serde_json::to_writer(&mut out, &embedding)?;
+            out.write_all(b"\n")?;
+            count += 1;
+            Ok(())
+        })
+        .await?;
+
+    out.flush()?;
+    Ok(count)
+}
+
+fn write_bundle(
+    output: &Path,
+    manifest: &Manifest,
+    shard: &Path,
+    blobs: &[(PathBuf, String)],
+    embeddings: Option<&Path>,
+) -> Result<()> {
+    // Written in full before it's moved into place, so that a failed export leaves no bundle
+    let tmp = sibling(output, "tmp");
+    let written = (|| -> Result<()> {
+        let out = BufWriter::new(fs::File::create(&tmp)?);
+        let mut tar = tar::Builder::new(GzEncoder::new(out, Compression::default()));
+
+        let manifest = serde_json::to_vec_pretty(manifest)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, MANIFEST, manifest.as_slice())?;
+
+        for entry in fs::read_dir(shard).context("failed to read the shard")? {
+            let entry = entry?;
+            let name = entry.file_name();
+
+            // Lock files are only meaningful to the writer that holds them
+            if !entry.file_type()?.is_file() || name.to_string_lossy().ends_with(".lock") {
+                continue;
+            }
+
+            tar.append_path_with_name(entry.path(), Path::new(CONTENT).join(name))?;
+        }
+
+        for (path, key) in blobs {
+            tar.append_path_with_name(path, Path::new(BLOBS).join(key))
+                .with_context(|| format!("failed to bundle blob {key}"))?;
+        }
+
+        if let Some(embeddings) = embeddings {
+            tar.append_path_with_name(embeddings, EMBEDDINGS)?;
+        }
+
+        let out = tar.into_inner()?.finish()?;
+        out.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        Ok(())
+    })();
+
+    match written {
+        Ok(()) => fs::rename(&tmp, output)
+            .with_context(|| format!("failed to write {}", output.display())),
+        Err(err) => {
+            _ = fs::remove_file(&tmp);
+            Err(err).with_context(|| format!("failed to write {}", output.display()))
+        }
+    }
+}
+
+/// Unpack the bundle at `path`, check it against this instance, and install it, replacing the
+/// indexes and embeddings of the repository if it was indexed already
+pub async fn import(app: &Application, path: &Path) -> Result<Manifest> {
+    let unpacked = Unpacked::open(app, path).await?;
+    unpacked.install(app).await
+}
+
+/// A directory of its own under the index directory, on the same file system as the indexes so
+/// that what's written there can be moved into place. It's removed once dropped.
+pub(crate) struct Staging(PathBuf);
+
+impl Staging {
+    pub(crate) fn new(config: &Configuration) -> Result<Self> {
+        let dir = config
+            .index_path(STAGING)
+            .as_ref()
+            .join(format!("{:016x}", rand::random::<u64>()));
+
+        fs::create_dir_all(&dir).context("failed to create staging dir")?;
+        Ok(Self(dir))
+    }
+}
+
+impl Deref for Staging {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.0) {
+            warn!(?err, dir = ?self.0, "failed to remove staging dir");
+        }
+    }
+}
+
+/// A bundle unpacked into a staging dir, that fits this instance
+pub(crate) struct Unpacked {
+    staging: Staging,
+    manifest: Manifest,
+    /// Where the bundled embeddings are stored, unless semantic search is disabled
+    embeddings: Option<Semantic>,
+}
+
+impl Unpacked {
+    /// Unpack the bundle at `path`, and check that it can be installed in this instance
+    pub(crate) async fn open(app: &Application, path: &Path) -> Result<Self> {
+        let staging = Staging::new(&app.config)?;
+
+        let manifest = tokio::task::block_in_place(|| {
+            let bundle = fs::File::open(path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+
+            tar::Archive::new(GzDecoder::new(BufReader::new(bundle)))
+                .unpack(&*staging)
+                .context("not a bundle")?;
+
+            let manifest = fs::read(staging.join(MANIFEST)).context("bundle has no manifest")?;
+            let manifest = serde_json::from_slice::<Manifest>(&manifest)
+                .context("bundle has an invalid manifest")?;
+
+            check(&manifest)?;
+            check_contents(&staging, &manifest)?;
+            Ok::<_, anyhow::Error>(manifest)
+        })?;
+
+        let semantic = app.semantic.wait().await;
+        if let LoadState::Failed(err) = app.semantic.state() {
+            bail!("semantic search failed to load, so embeddings can't be imported: {err}");
+        }
+
+        let embeddings = match (&manifest.model_id, semantic) {
+            (Some(bundled), Some(semantic)) if bundled != semantic.model_id() => bail!(
+                "the bundle's embeddings were made with model {bundled}, but this instance uses {}",
+                semantic.model_id()
+            ),
+            (Some(_), Some(semantic)) => Some(semantic),
+            (None, Some(_)) => {
+                bail!("the bundle has no embeddings, but this instance has semantic search")
+            }
+            (Some(_), None) => {
+                warn!("semantic search is disabled, so the bundled embeddings are left out");
+                None
+            }
+            (None, None) => None,
+        };
+
+        Ok(Self {
+            staging,
+            manifest,
+            embeddings,
+        })
+    }
+
+    pub(crate) fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Install the indexes and embeddings, replacing those of the repository if it's indexed
+    /// already
+    pub(crate) async fn install(self, app: &Application) -> Result<Manifest> {
+        let Self {
+            staging,
+            manifest,
+            embeddings,
+        } = self;
+
+        let reporef = &manifest.repo_ref;
+        let writers = app.indexes.writers().await?;
+
+        let imported = match &embeddings {
+            Some(semantic) => {
+                Some(import_embeddings(semantic, reporef, &staging.join(EMBEDDINGS)).await?)
+            }
+            None => None,
+        };
+
+        let installed = install_indexes(app, writers, &staging, &manifest).await;
+
+        // The repository's previous embeddings are left over once the bundle is installed, and
+        // the bundled ones otherwise
+        let cleaned = match (&embeddings, imported) {
+            (Some(semantic), Some(imported)) => {
+                let stale = match installed {
+                    Ok(_) => imported.previous,
+                    Err(_) => imported.stored,
+                };
+                semantic.delete_points(stale).await
+            }
+            _ => Ok(()),
+        };
+
+        let added = match installed {
+            Ok(added) => added,
+            Err(err) => {
+                if let Err(err) = cleaned {
+                    error!(?err, %reporef, "failed to delete the bundled embeddings");
+                }
+                return Err(err);
+            }
+        };
+
+        if added {
+            RepoEvent::Added {
+                reporef: reporef.clone(),
+            }
+            .emit();
+        }
+
+        RepoEvent::IndexFinished {
+            reporef: reporef.clone(),
+        }
+        .emit();
+
+        info!(
+            %reporef,
+            blobs = manifest.blobs,
+            embeddings = manifest.embeddings,
+            "imported bundle"
+        );
+
+        cleaned.context("the bundle was installed, but the previous embeddings weren't deleted")?;
+        Ok(manifest)
+    }
+}
+
+/// Install the indexes of an unpacked bundle, and add its repository, returning whether it's new
+async fn install_indexes(
+    app: &Application,
+    writers: GlobalWriteHandle<'_>,
+    staging: &Path,
+    manifest: &Manifest,
+) -> Result<bool> {
+    let reporef = &manifest.repo_ref;
+
+    // Files are skipped by the next sync if the cache says they're indexed already, which
+    // those of the previous index may not be in the bundled one
+    if let Some(previous) = app.repo_pool.get(reporef).map(|repo| repo.clone()) {
+        match previous.delete_file_cache(&app.config.index_dir) {
+            Err(RepoError::IO { error }) if error.kind() == ErrorKind::NotFound => {}
+            result => result?,
+        }
+    }
+
+    // Blobs go first, so that no document of the shard refers to one that's missing
+    let file = &app.indexes.file.source;
+    let blobs = staging.join(BLOBS);
+    if blobs.exists() {
+        for entry in fs::read_dir(&blobs)? {
+            let entry = entry?;
+            file.install_blob(&entry.file_name().to_string_lossy(), &entry.path())?;
+        }
+    }
+
+    let repo = Repository {
+        sync_status: SyncStatus::Done,
+        ..manifest.repo.clone()
+    };
+
+    let metadata = RepoMetadata {
+        last_commit_unix_secs: repo.last_commit_unix_secs,
+        last_commit_hash: repo.last_commit_hash.clone(),
+        disk_usage_bytes: repo.disk_usage_bytes,
+        symbols: manifest.symbols.clone(),
+        langs: manifest.langs.clone(),
+    };
+
+    writers
+        .install(reporef, &repo, &metadata, &staging.join(CONTENT))
+        .await?;
+
+    writers.commit().await?;
+    let added = app.repo_pool.insert(reporef.clone(), repo).is_none();
+    app.config.source.save_pool(app.repo_pool.clone())?;
+
+    Ok(added)
+}
+
+/// Check that a bundle was made by a version of bleep whose indexes this one can read
+fn check(manifest: &Manifest) -> Result<()> {
+    if manifest.format != FORMAT_VERSION {
+        bail!(
+            "bundles of format {} can't be imported, only those of format {FORMAT_VERSION}",
+            manifest.format
+        );
+    }
+
+    if manifest.schema_version != SCHEMA_VERSION {
+        bail!(
+            "the bundle's index schema is {}, but this instance's is {SCHEMA_VERSION}; export it \
+             again with bleep {}",
+            manifest.schema_version,
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+
+    Ok(())
+}
+
+/// Check that an unpacked bundle has everything its manifest says it has
+fn check_contents(dir: &Path, manifest: &Manifest) -> Result<()> {
+    if !dir.join(CONTENT).join("meta.json").exists() {
+        bail!("the bundle has no index");
+    }
+
+    if manifest.model_id.is_some() {
+        let embeddings = fs::File::open(dir.join(EMBEDDINGS))
+            .context("the bundle has no embeddings, though its manifest says it does")?;
+
+        let mut count = 0;
+        for line in BufReader::new(embeddings).lines() {
+            count += 1;
+            serde_json::from_str::<StoredEmbedding>(&line?)
+                .with_context(|| format!("embedding {count} of the bundle is invalid"))?;
+        }
+
+        if count != manifest.embeddings {
+            bail!(
+                "the bundle has {count} embeddings, though its manifest says it has {}",
+                manifest.embeddings
+            );
+        }
+    }
+
+    let blobs = dir.join(BLOBS);
+    if !blobs.exists() {
+        return Ok(());
+    }
+
+    // Blobs are named by the hash of their contents, and would be shared with other
+    // repositories
+    for entry in fs::read_dir(&blobs)? {
+        let entry = entry?;
+        let key = entry.file_name().to_string_lossy().into_owned();
+        let hash = blake3::hash(&fs::read(entry.path())?);
+
+        if hash.to_hex().as_str() != key {
+            bail!("blob {key} of the bundle is corrupt");
+        }
+    }
+
+    Ok(())
+}
+
+/// The ids of the embeddings of a repository, as a bundle's are imported
+struct Imported {
+    /// Those of the bundle
+    stored: Vec<PointId>,
+    /// Those the repository had before
+    previous: Vec<PointId>,
+}
+
+/// Store the embeddings in `path` next to those `reporef` has already, waiting until they're
+/// stored. Those that were stored are deleted again if the rest can't be.
+async fn import_embeddings(
+    semantic: &Semantic,
+    reporef: &RepoRef,
+    path: &Path,
+) -> Result<Imported> {
+    let previous = semantic
+        .point_ids(&reporef.to_string())
+        .await
+        .context("failed to read the repository's embeddings")?;
+
+    let mut stored = vec![];
+    if let Err(err) = store_embeddings(semantic, path, &mut stored).await {
+        if let Err(err) = semantic.delete_points(stored).await {
+            error!(?err, %reporef, "failed to delete the bundled embeddings");
+        }
+
+        return Err(err.context("failed to store the bundle's embeddings"));
+    }
+
+    Ok(Imported { stored, previous })
+}
+
+async fn store_embeddings(semantic: &Semantic, path: &Path, ids: &mut Vec<PointId>) -> Result<()> {
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    for line in BufReader::new(fs::File::open(path)?).lines() {
+        batch.push(serde_json::from_str::<StoredEmbedding>(&line?)?);
+
+        if batch.len() == IMPORT_BATCH_SIZE {
+            semantic
+                .import_points(std::mem::take(&mut batch), ids)
+                .await?;
+        }
+    }
+
+    if !batch.is_empty() {
+        semantic.import_points(batch, ids).await?;
+    }
+
+    Ok(())
+}
+
+/// A path next to `path`, with `suffix` appended to its name
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".{suffix}"));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn siblings_keep_the_name() {
+        assert_eq!(
+            sibling(Path::new("/tmp/repo.bloop"), "tmp"),
+            Path::new("/tmp/repo.bloop.tmp")
+        );
+        assert_eq!(
+            sibling(Path::new("repo.bloop"), "embeddings.tmp"),
+            Path::new("repo.bloop.embeddings.tmp")
+        );
+    }
+
+    #[test]
+    fn bundles_of_other_versions_are_rejected() {
+        let reporef = RepoRef::from(&PathBuf::from("/tmp/repo"));
+        let manifest = Manifest {
+            format: FORMAT_VERSION,
+            bleep_version: env!("CARGO_PKG_VERSION").to_owned(),
+            schema_version: SCHEMA_VERSION.to_owned(),
+            model_id: None,
+            repo: Repository::local_from(&reporef),
+            repo_ref: reporef,
+            blobs: 0,
+            embeddings: 0,
+            langs: LanguageInfo {
+                path_map: [(PathBuf::from("/tmp/repo/main.rs"), Some("Rust".to_owned()))].into(),
+                most_common_lang: Some("Rust".to_owned()),
+            },
+            symbols: Default::default(),
+            created_at: Utc::now(),
+        };
+
+        assert!(check(&manifest).is_ok());
+
+        let roundtrip = serde_json::to_vec(&manifest).unwrap();
+        let roundtrip = serde_json::from_slice::<Manifest>(&roundtrip).unwrap();
+        assert!(check(&roundtrip).is_ok());
+        assert_eq!(roundtrip.langs.path_map, manifest.langs.path_map);
+
+        assert!(check(&Manifest {
+            format: FORMAT_VERSION + 1,
+            ..manifest.clone()
+        })
+        .is_err());
+
+        assert!(check(&Manifest {
+            schema_version: "0".to_owned(),
+            ..manifest
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,295 @@
+//! A library API, for applications that embed code search rather than run the server.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! let client = bleep::Client::open("/path/to/index").await?;
+//! client.add_repo("https://github.com/bloopai/bloop").await?;
+//!
+//! for file in client.search("symbol:Indexer lang:rust", 10).await? {
+//!     println!("{} {}", file.repo_ref, file.relative_path);
+//! }
+//!
+//! let answer = client.answer("where are files indexed?").await?;
+//! println!("{}", answer.text);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use clap::Parser;
+
+use crate::{
+    background::{self, RepoEvent},
+    repo::{Backend, GitRemote, RepoRef, RepoRemote, SyncStatus},
+    webserver::{
+        answer::{self, AnswerState, Params},
+        query::{ApiQuery, QueryResult},
+    },
+    Application, Configuration, Environment,
+};
+
+/// Indexes, searches and answers questions about repositories, in process.
+///
+/// Every method needs a multi-threaded Tokio runtime.
+#[derive(Clone)]
+pub struct Client {
+    app: Application,
+    answers: Arc<AnswerState>,
+}
+
+/// An answer to a question about the indexed code
+#[derive(Debug, Clone)]
+pub struct Answer {
+    pub text: String,
+    /// The snippets the answer is based on
+    pub sources: Vec<Source>,
+}
+
+/// A file that matched a search
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub repo_ref: String,
+    pub relative_path: String,
+    pub lang: Option<String>,
+    /// The parts of the file that matched, if its contents did
+    pub snippets: Vec<SearchSnippet>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchSnippet {
+    pub text: String,
+    /// Lines of the snippet, counting from 0
+    pub lines: Range<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub repo_ref: String,
+    pub relative_path: String,
+    pub text: String,
+    /// Lines of the snippet, counting from 0
+    pub lines: Range<usize>,
+}
+
+impl Client {
+    /// Open the indexes in `index_dir`, creating them if there are none, with every other
+    /// option left at its default
+    pub async fn open(index_dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let mut config = Configuration::try_parse_from(["bleep"])?;
+        config.index_dir = index_dir.into();
+
+        Self::new(config).await
+    }
+
+    /// Open the indexes `config` points at
+    pub async fn new(config: Configuration) -> anyhow::Result<Self> {
+        // Whoever embeds bleep can read the paths they give it anyway, wherever they are
+        let app = Application::initialize(Environment::insecure_local(), config).await?;
+
+        Ok(Self {
+            answers: Arc::new(AnswerState::new(&app.config)?),
+            app,
+        })
+    }
+
+    /// Add the repository `target` names, sync and index it, and return the repo ref it's
+    /// indexed under.
+    ///
+    /// `target` is the path of a local repository, the URL of a GitHub repository, or a repo
+    /// ref such as `github.com/org/repo`.
+    pub async fn add_repo(&self, target: &str) -> anyhow::Result<String> {
+        let reporef = sync_and_index(&self.app, resolve(target)?, |_| {}).await?;
+        Ok(reporef.to_string())
+    }
+
+    /// Sync a repository that was added, and index what changed since it was last indexed
+    pub async fn index(&self, repo_ref: &str) -> anyhow::Result<()> {
+        let reporef = repo_ref.parse::<RepoRef>()?;
+        if !self.app.repo_pool.contains_key(&reporef) {
+            bail!("{repo_ref} was never added");
+        }
+
+        sync_and_index(&self.app, reporef, |_| {}).await?;
+        Ok(())
+    }
+
+    /// Repo refs of the repositories that were added
+    pub fn repos(&self) -> Vec<String> {
+        self.app
+            .repo_pool
+            .iter()
+            .map(|repo| repo.key().to_string())
+            .collect()
+    }
+
+    /// Search with a query in the bloop query language, returning at most `limit` files.
+    ///
+    /// Files that match by path alone have no snippets.
+    pub async fn search(&self, query: &str, limit: usize) -> anyhow::Result<Vec<SearchResult>> {
+        let results = ApiQuery::new(query.to_owned(), 0, limit)
+            .run(&self.app)
+            .await?;
+
+        Ok(results
+            .into_iter()
+            .filter_map(|result| match result {
+                QueryResult::Snippets(file) => Some(SearchResult {
+                    repo_ref: file.repo_ref,
+                    relative_path: file.relative_path,
+                    lang: file.lang,
+                    snippets: file
+                        .snippets
+                        .into_iter()
+                        .map(|snippet| SearchSnippet {
+                            text: snippet.data,
+                            lines: snippet.line_range,
+                        })
+                        .collect(),
+                }),
+                QueryResult::FileResult(file) => Some(SearchResult {
+                    repo_ref: file.repo_ref,
+                    relative_path: file.relative_path.text,
+                    lang: file.lang,
+                    snippets: vec![],
+                }),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Answer a question about the indexed code, in natural language.
+    ///
+    /// Answers need semantic search, which is only enabled with a `qdrant_url`.
+    pub async fn answer(&self, question: &str) -> anyhow::Result<Answer> {
+        if self.app.semantic.wait().await.is_none() {
+            bail!("answers need semantic search, which is disabled or failed to load");
+        }
+
+        let params = Params {
+            q: question.to_owned(),
+            thread_id: uuid::Uuid::new_v4().to_string(),
+            limit: answer::default_limit(),
+            user_id: answer::default_user_id(),
+            fields: Default::default(),
+            answer_lang: None,
+            access: Default::default(),
+            llm_permits: None,
+        };
+
+        let answer = answer::answer_one(&self.answers, params, &self.app, None).await;
+        if let Some(error) = answer.error {
+            bail!(error);
+        }
+
+        let sources = answer.snippets.map(|s| s.matches).unwrap_or_default();
+        Ok(Answer {
+            text: answer.answer.unwrap_or_default(),
+            sources: sources
+                .into_iter()
+                .map(|snippet| Source {
+                    repo_ref: snippet.repo_ref,
+                    relative_path: snippet.relative_path,
+                    text: snippet.text,
+                    lines: snippet.start_line..snippet.end_line + 1,
+                })
+                .collect(),
+        })
+    }
+}
+
+/// The repository `target` names: a local path, a GitHub URL, or a repo ref such as
+/// `github.com/org/repo`
+pub(crate) fn resolve(target: &str) -> anyhow::Result<RepoRef> {
+    let path = Path::new(target);
+    if path.exists() {
+        return Ok(RepoRef::from(&crate::canonicalize(path)?));
+    }
+
+    if let Ok(RepoRemote::Git(GitRemote { address, .. })) = target.parse::<RepoRemote>() {
+        return Ok(RepoRef::new(Backend::Github, &address)?);
+    }
+
+    target
+        .parse::<RepoRef>()
+        .with_context(|| format!("`{target}` is neither a local path nor a repository"))
+}
+
+/// Sync and index a repository, adding it if it's new, and return the repo ref it's indexed
+/// under, which changes if it was renamed on its remote. Events about the repository are
+/// passed to `on_event` as they happen.
+pub(crate) async fn sync_and_index(
+    app: &Application,
+    reporef: RepoRef,
+    mut on_event: impl FnMut(&RepoEvent),
+) -> anyhow::Result<RepoRef> {
+    let mut events = background::subscribe();
+    let mut current = reporef.clone();
+
+    let indexing = app.write_index().sync_and_index(vec![reporef]);
+    tokio::pin!(indexing);
+
+    let synced = loop {
+        tokio::select! {
+            synced = &mut indexing => break synced,
+            Ok(event) = events.recv() => {
+                match event {
+                    RepoEvent::Renamed { ref from, ref to } if *from == current => {
+                        on_event(&event);
+                        current = to.clone();
+                    }
+                    RepoEvent::Renamed { .. } => {}
+                    _ if *event.reporef() == current => on_event(&event),
+                    _ => {}
+                }
+            }
+        }
+    };
+
+    synced?;
+
+    // Indexing failures are recorded on the repository, rather than returned
+    let status = app
+        .repo_pool
+        .get(&current)
+        .map(|repo| repo.sync_status.clone());
+
+    // Indexing saves the pool before recording its outcome
+    app.config.source.save_pool(app.repo_pool.clone())?;
+
+    match status {
+        Some(SyncStatus::Done) => Ok(current),
+        Some(SyncStatus::Error { message }) => bail!("failed to index {current}: {message}"),
+        Some(SyncStatus::RemoteRemoved) => bail!("{current} no longer exists on its remote"),
+        Some(status) => bail!("{current} was left {status:?}"),
+        None => bail!("{current} was removed while indexing"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_resolve() {
+        let github = RepoRef::new(Backend::Github, "bloopai/bloop").unwrap();
+
+        assert_eq!(resolve("https://github.com/bloopai/bloop").unwrap(), github);
+        assert_eq!(resolve("git@github.com:bloopai/bloop.git").unwrap(), github);
+        assert_eq!(resolve("github.com/bloopai/bloop").unwrap(), github);
+
+        let here = std::env::current_dir().unwrap();
+        assert_eq!(
+            resolve(".").unwrap(),
+            RepoRef::from(&crate::canonicalize(&here).unwrap())
+        );
+
+        assert!(resolve("not/a/repository").is_err());
+    }
+}
@@ -0,0 +1,95 @@
+//! Shell completions of the command line, generated from its definition.
+//!
+//! Scripts for bash and fish also complete repo refs, where `bleep export --repo` and
+//! `bleep index` take them, with the repos of the state file, which the scripts list by running
+//! `bleep completions --repos` as they complete.
+
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use clap::CommandFactory;
+use clap_complete::Shell;
+
+use crate::Configuration;
+
+const BIN_NAME: &str = "bleep";
+
+/// Completes the repos of the state file, falling back to the generated completions
+const BASH_REPOS: &str = r#"
+_bleep_repos() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD-1]}"
+    if [[ "$prev" == "--repo" || ( "$prev" == "index" && "$cur" != -* ) ]]; then
+        COMPREPLY=($(compgen -W "$(bleep completions --repos 2>/dev/null)" -- "$cur"))
+        if [[ ${#COMPREPLY[@]} -gt 0 ]]; then
+            return 0
+        fi
+    fi
+
+    _bleep "$@"
+}
+
+complete -F _bleep_repos -o bashdefault -o default bleep
+"#;
+
+const FISH_REPOS: &str = r#"
+complete -c bleep -n "__fish_seen_subcommand_from export" -l repo -x -a "(bleep completions --repos 2>/dev/null)"
+complete -c bleep -n "__fish_seen_subcommand_from index" -a "(bleep completions --repos 2>/dev/null)"
+"#;
+
+/// Print the completion script for `shell`, or the repos of the state file, one per line, if
+/// `repos` is set
+pub fn print_completions(config: &Configuration, shell: Option<Shell>, repos: bool) -> Result<()> {
+    let mut out = std::io::stdout().lock();
+
+    if repos {
+        for reporef in config.source.saved_repos(&config.index_dir)? {
+            writeln!(out, "{reporef}")?;
+        }
+
+        return Ok(());
+    }
+
+    write_script(shell.context("no shell given")?, &mut out)
+}
+
+fn write_script(shell: Shell, out: &mut impl Write) -> Result<()> {
+    clap_complete::generate(shell, &mut Configuration::command(), BIN_NAME, out);
+
+    match shell {
+        Shell::Bash => out.write_all(BASH_REPOS.as_bytes())?,
+        Shell::Fish => out.write_all(FISH_REPOS.as_bytes())?,
+        _ => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(shell: Shell) -> String {
+        let mut out = vec![];
+        write_script(shell, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn scripts_cover_subcommands_and_flags() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let script = script(shell);
+            for word in ["completions", "export", "--index-dir", "--remote"] {
+                assert!(script.contains(word), "{shell} script lacks {word}");
+            }
+        }
+    }
+
+    #[test]
+    fn repos_are_completed_dynamically() {
+        let bash = script(Shell::Bash);
+        assert!(bash.contains("complete -F _bleep_repos"));
+
+        assert!(script(Shell::Fish).contains("bleep completions --repos"));
+        assert!(!script(Shell::Zsh).contains("--repos"));
+    }
+}
@@ -1,10 +1,19 @@
-use crate::{semantic::chunk::OverlapStrategy, state::StateSource};
-use anyhow::{Context, Result};
-use clap::Parser;
+use crate::{
+    analytics::AnalyticsSink,
+    remotes::CloneOptions,
+    semantic::chunk::OverlapStrategy,
+    state::StateSource,
+    webserver::{keys::ApiKey, slack::SlackTeam, tenant::Tenant},
+};
+use anyhow::{ensure, Context, Result};
+use clap::{Args, Parser, Subcommand};
 
 use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize, Serializer};
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 #[derive(Serialize, Deserialize, Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -14,9 +23,13 @@ pub struct Configuration {
     //
     #[clap(short, long)]
     #[serde(skip)]
-    /// If a config file is given, it will override _all_ command line parameters!
+    /// JSON file of options, which environment variables and command line flags override
     pub config_file: Option<PathBuf>,
 
+    #[clap(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+
     #[clap(flatten)]
     #[serde(default)]
     pub source: StateSource,
@@ -31,15 +44,15 @@ pub struct Configuration {
     /// Quit after indexing the specified repos
     pub index_only: bool,
 
-    #[clap(long, default_value_t = false)]
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
     #[serde(default)]
     /// Disable periodic reindexing, and `git pull` on remote repositories.
-    pub disable_background: bool,
+    pub disable_background: Option<bool>,
 
-    #[clap(long, default_value_t = false)]
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
     #[serde(default)]
     /// Disable system-native notification backends to detect new git commits immediately.
-    pub disable_fsevents: bool,
+    pub disable_fsevents: Option<bool>,
 
     #[clap(short, long, default_value_t = default_buffer_size())]
     #[serde(default = "default_buffer_size")]
@@ -66,6 +79,12 @@ pub struct Configuration {
     /// Bind the webserver to `<host>`
     pub port: u16,
 
+    #[clap(long)]
+    #[serde(default)]
+    /// Addresses to serve the API on, either `<host>:<port>` or `unix:<path>` for a unix
+    /// socket. Replaces `host` and `port` if given.
+    pub listen: Vec<String>,
+
     //
     // External dependencies
     //
@@ -78,6 +97,17 @@ pub struct Configuration {
     /// URL for the answer-api
     pub answer_api_url: String,
 
+    #[clap(long, value_enum, default_value_t)]
+    #[serde(default)]
+    /// Where to send analytics events. The `jsonl` and `sqlite` sinks keep them on this
+    /// machine, even with telemetry disabled.
+    pub analytics_sink: AnalyticsSink,
+
+    #[clap(long)]
+    /// File the `jsonl` and `sqlite` analytics sinks write to. Defaults to `analytics.jsonl`
+    /// or `analytics.db` in the index directory.
+    pub analytics_file: Option<PathBuf>,
+
     #[clap(long)]
     /// Key for analytics backend
     pub analytics_key: Option<String>,
@@ -86,10 +116,26 @@ pub struct Configuration {
     /// Analytics data plane identifier
     pub analytics_data_plane: Option<String>,
 
+    #[clap(long)]
+    /// PostHog instance the `posthog` analytics sink sends to, for self-hosted installations.
+    /// Defaults to PostHog Cloud.
+    pub posthog_host: Option<String>,
+
     #[clap(long)]
     /// Sentry Data Source Name
     pub sentry_dsn: Option<String>,
 
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    #[serde(default)]
+    /// Never send analytics events or error reports, even if they're configured
+    pub disable_telemetry: Option<bool>,
+
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    #[serde(default)]
+    /// Replace user ids in analytics events with a keyed hash of them, which is the same for
+    /// every event of a user but can't be traced back to them without the instance's key
+    pub anonymize_user_ids: Option<bool>,
+
     //
     // Semantic values
     //
@@ -111,6 +157,17 @@ pub struct Configuration {
     /// Chunking strategy
     pub overlap: Option<OverlapStrategy>,
 
+    #[clap(long, default_value_t = default_embedding_cache_size())]
+    #[serde(default = "default_embedding_cache_size")]
+    /// Number of recently computed embeddings kept in memory, so that unchanged chunks and
+    /// repeated queries aren't embedded again. 0 disables the cache.
+    pub embedding_cache_size: usize,
+
+    #[clap(long, default_value_t = default_embedding_threads())]
+    #[serde(default = "default_embedding_threads")]
+    /// Threads each embedding is computed on
+    pub embedding_threads: usize,
+
     //
     // Installation-specific values
     //
@@ -129,7 +186,7 @@ pub struct Configuration {
     pub github_app_id: Option<u64>,
 
     #[clap(long)]
-    /// GitHub App installation ID
+    /// GitHub App installation ID. Can be left out if the app is only installed once.
     pub github_app_install_id: Option<u64>,
 
     #[clap(long)]
@@ -141,6 +198,283 @@ pub struct Configuration {
     /// Bot secret token
     pub bot_secret: Option<SecretString>,
 
+    #[clap(long)]
+    #[serde(serialize_with = "serialize_secret_opt_str", default)]
+    /// Secret used to sign GitHub webhook deliveries
+    pub github_webhook_secret: Option<SecretString>,
+
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    #[serde(default)]
+    /// Review pull requests of indexed GitHub repositories as they're opened, when GitHub
+    /// webhook deliveries of `pull_request` events arrive, and comment on them with the review
+    pub review_pull_requests: Option<bool>,
+
+    #[clap(long)]
+    /// Base URL of a self-hosted GitLab instance. Defaults to `https://gitlab.com`.
+    pub gitlab_url: Option<String>,
+
+    #[clap(long)]
+    #[serde(serialize_with = "serialize_secret_opt_str", default)]
+    /// GitLab access token with the `read_api` and `read_repository` scopes
+    pub gitlab_token: Option<SecretString>,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// GitLab groups to discover projects in, including their subgroups. Every project the
+    /// token is a member of is available if none are given.
+    pub gitlab_groups: Vec<String>,
+
+    #[clap(long)]
+    #[serde(serialize_with = "serialize_secret_opt_str", default)]
+    /// Secret token GitLab sends with webhook deliveries
+    pub gitlab_webhook_secret: Option<SecretString>,
+
+    #[clap(long)]
+    #[serde(serialize_with = "serialize_secret_opt_str", default)]
+    /// Signing secret of the Slack app whose slash command asks questions. The command is
+    /// served at `/api/slack/command` if set.
+    pub slack_signing_secret: Option<SecretString>,
+
+    #[clap(skip)]
+    #[serde(default)]
+    /// The repositories and tenant of each Slack workspace, by team id. Commands from
+    /// workspaces that aren't listed are rejected.
+    pub slack_teams: HashMap<String, SlackTeam>,
+
+    #[clap(long)]
+    #[serde(serialize_with = "serialize_secret_opt_str", default)]
+    /// Secret used to sign the answers posted to the callback URLs of batches. Batches can only
+    /// be asked with a `callback_url` if set.
+    pub answer_callback_secret: Option<SecretString>,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// Hosts answers can be posted back to, as the `callback_url` of batches. If none are given,
+    /// any host is allowed, unless it resolves to a private, loopback or link-local address.
+    pub answer_callback_hosts: Vec<String>,
+
+    #[clap(long)]
+    /// Base URL of a Bitbucket Server instance. Bitbucket Cloud is used if unset.
+    pub bitbucket_url: Option<String>,
+
+    #[clap(long)]
+    /// User that `bitbucket_token` is an app password of. It is used as an access token if
+    /// unset.
+    pub bitbucket_username: Option<String>,
+
+    #[clap(long)]
+    #[serde(serialize_with = "serialize_secret_opt_str", default)]
+    /// Bitbucket app password or access token, with read access to repositories
+    pub bitbucket_token: Option<SecretString>,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// Bitbucket Cloud workspaces, or Bitbucket Server project keys, to list repositories in.
+    /// Every repository the credentials can access is available if none are given.
+    pub bitbucket_workspaces: Vec<String>,
+
+    #[clap(long)]
+    /// Azure DevOps organization URL, e.g. `https://dev.azure.com/org`, or the collection URL
+    /// of an Azure DevOps Server
+    pub azure_devops_url: Option<String>,
+
+    #[clap(long)]
+    #[serde(serialize_with = "serialize_secret_opt_str", default)]
+    /// Azure DevOps personal access token with the `Code (Read)` scope
+    pub azure_devops_token: Option<SecretString>,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// Azure DevOps projects to list repositories in. Every repository in the organization is
+    /// available if none are given.
+    pub azure_devops_projects: Vec<String>,
+
+    #[clap(long)]
+    /// Private key to clone and fetch repositories with over SSH, such as a deploy key. New
+    /// repositories are cloned over SSH when this is set.
+    pub ssh_key: Option<PathBuf>,
+
+    #[clap(long)]
+    #[serde(serialize_with = "serialize_secret_opt_str", default)]
+    /// Passphrase of `ssh_key`, if it is encrypted
+    pub ssh_key_passphrase: Option<SecretString>,
+
+    #[clap(long)]
+    /// `known_hosts` file to check the keys of SSH hosts against. Defaults to
+    /// `~/.ssh/known_hosts`. Hosts that aren't listed are rejected.
+    pub ssh_known_hosts: Option<PathBuf>,
+
+    #[clap(long)]
+    /// Cron expression to sync every repository on, e.g. `0 3 * * *` for nightly. Days of the
+    /// week are numbered as in crontab, from Sunday as 0 (or 7). Repositories are polled for
+    /// changes if unset.
+    pub sync_schedule: Option<String>,
+
+    #[clap(skip)]
+    #[serde(default)]
+    /// Cron expressions to sync individual repositories on, by repository ref. These take
+    /// precedence over `sync_schedule`.
+    pub repo_sync_schedules: HashMap<String, String>,
+
+    #[clap(skip)]
+    #[serde(default)]
+    /// Clone depth, single branch fetches, sparse checkouts, and mirrors of individual
+    /// repositories, by repository ref
+    pub repo_clone_options: HashMap<String, CloneOptions>,
+
+    #[clap(long)]
+    /// Maximum number of repositories cloned or fetched at once. Further syncs wait their turn.
+    pub max_git_transfers: Option<usize>,
+
+    #[clap(long)]
+    /// Combined download rate of every clone and fetch, in KiB per second
+    pub git_bandwidth_limit: Option<u64>,
+
+    #[clap(long)]
+    /// Proxy for outbound traffic, e.g. `http://proxy:3128` or `socks5://proxy:1080`. Git only
+    /// supports HTTP(S) proxies.
+    pub proxy: Option<String>,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// Hosts to connect to directly, bypassing `proxy`: domain names, which include their
+    /// subdomains, IP addresses, CIDR ranges, or `*` for every host
+    pub no_proxy: Vec<String>,
+
+    #[clap(skip)]
+    #[serde(default)]
+    /// Hashed API keys and their scopes. If any keys are configured, either here or in the
+    /// local key store, every API request must present one of them.
+    pub api_keys: Vec<ApiKey>,
+
+    #[clap(skip)]
+    #[serde(default)]
+    /// Patterns of the repositories each GitHub user can access. Once any user is listed here,
+    /// users without an entry can't access any repository.
+    pub user_repos: HashMap<String, Vec<String>>,
+
+    #[clap(skip)]
+    #[serde(default)]
+    /// Tenants sharing this instance. Once any tenant is configured, requests can only access
+    /// the repositories of the tenant they are attributed to.
+    pub tenants: Vec<Tenant>,
+
+    #[clap(long)]
+    /// Maximum number of API requests per minute for each client
+    pub rate_limit: Option<u32>,
+
+    #[clap(long)]
+    /// Maximum number of answer requests per minute for each client
+    pub answer_rate_limit: Option<u32>,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// Origins allowed to make cross-origin requests. Any origin is allowed if none are given.
+    pub cors_allowed_origins: Vec<String>,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// Methods allowed in cross-origin requests. Defaults to the method being requested.
+    pub cors_allowed_methods: Vec<String>,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// Headers allowed in cross-origin requests. Defaults to the headers being requested.
+    pub cors_allowed_headers: Vec<String>,
+
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    #[serde(default)]
+    /// Allow cross-origin requests to include credentials, such as cookies
+    pub cors_allow_credentials: Option<bool>,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// Compression algorithms offered for responses, `gzip` and `br`. Responses are sent
+    /// uncompressed if none are given.
+    pub compression: Vec<String>,
+
+    #[clap(long)]
+    /// Maximum size of request bodies, in bytes
+    pub max_body_size: Option<usize>,
+
+    #[clap(long)]
+    /// Seconds allowed for API requests to start responding, except for answers
+    pub request_timeout: Option<u64>,
+
+    #[clap(long)]
+    /// Seconds allowed for answer requests to start responding
+    pub answer_timeout: Option<u64>,
+
+    #[clap(long)]
+    /// Seconds allowed to connect to the answer API. Defaults to 10.
+    pub answer_api_connect_timeout: Option<u64>,
+
+    #[clap(long)]
+    /// Seconds the answer API can go without sending anything. Defaults to 30.
+    pub answer_api_read_timeout: Option<u64>,
+
+    #[clap(long)]
+    /// Seconds allowed for a completion of the answer API, retries included. Defaults to 120.
+    pub answer_api_timeout: Option<u64>,
+
+    #[clap(long)]
+    /// Failed attempts in a row after which requests to the answer API fail right away, until
+    /// a probe succeeds. Defaults to 5, and 0 never stops sending requests.
+    pub answer_api_max_failures: Option<u32>,
+
+    #[clap(long)]
+    /// Seconds between probes of the answer API while requests to it fail right away.
+    /// Defaults to 30.
+    pub answer_api_probe_interval: Option<u64>,
+
+    #[clap(long)]
+    /// Idle connections to the answer API kept open for reuse. Defaults to 32.
+    pub answer_api_pool_size: Option<usize>,
+
+    #[clap(long)]
+    /// Maximum number of requests handled at once. Further requests fail with `503`.
+    pub max_concurrent_requests: Option<usize>,
+
+    #[clap(long)]
+    /// Maximum number of searches, file and tree requests handled at once. Further ones fail
+    /// with `503`.
+    pub max_concurrent_searches: Option<usize>,
+
+    #[clap(long)]
+    /// Maximum number of answer requests handled at once, without taking from the searches'
+    /// budget. Further ones wait in a queue of `max_queued_answers`.
+    pub max_concurrent_answers: Option<usize>,
+
+    #[clap(long, default_value_t = default_max_queued_answers())]
+    #[serde(default = "default_max_queued_answers")]
+    /// Maximum number of answer requests waiting for one of `max_concurrent_answers` to finish.
+    /// Further ones fail right away with `503` and a `Retry-After`, rather than waiting to time
+    /// out.
+    pub max_queued_answers: usize,
+
+    #[clap(long, requires = "tls_key")]
+    /// PEM certificate chain to serve the API over HTTPS. Reloaded on `SIGHUP`. Unix sockets
+    /// are always served over plain HTTP.
+    pub tls_cert: Option<PathBuf>,
+
+    #[clap(long, requires = "tls_cert")]
+    /// PEM private key matching `tls_cert`
+    pub tls_key: Option<PathBuf>,
+
+    #[clap(long)]
+    /// File to record every authenticated API call to. Calls are not recorded if unset.
+    pub audit_log: Option<PathBuf>,
+
+    #[clap(long)]
+    /// File to record every search and question to, for administrators to search. Queries
+    /// are not recorded if unset.
+    pub query_log: Option<PathBuf>,
+
+    #[clap(long)]
+    /// File created once the server is ready for traffic, after semantic search has loaded, for
+    /// readiness probes that check for a file. Removed on startup.
+    pub readiness_file: Option<PathBuf>,
+
     //
     // Cloud deployment values
     //
@@ -163,6 +497,140 @@ macro_rules! right_if_default {
     };
 }
 
+/// Prefix of the environment variables that set options
+const ENV_PREFIX: &str = "BLEEP_";
+
+/// Options that are left out when the configuration is printed
+const SECRET_OPTIONS: &[&str] = &[
+    "github_client_id",
+    "github_client_secret",
+    "bot_secret",
+    "github_webhook_secret",
+    "gitlab_token",
+    "gitlab_webhook_secret",
+    "slack_signing_secret",
+    "answer_callback_secret",
+    "bitbucket_token",
+    "azure_devops_token",
+    "ssh_key_passphrase",
+    "analytics_key",
+    "sentry_dsn",
+];
+
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Serve the Language Server Protocol over stdin and stdout, rather than the HTTP API, so
+    /// that editors can navigate and ask about indexed code
+    Lsp,
+    /// Serve the Model Context Protocol over stdin and stdout, rather than the HTTP API, so that
+    /// agents can search, read and ask about indexed code as tools
+    Mcp,
+    /// Search the indexed code, print the results, and exit
+    Query {
+        /// A query written in the bloop query language
+        q: String,
+
+        #[clap(flatten)]
+        options: OneShot,
+    },
+    /// Answer a question about the indexed code, print the answer, and exit
+    Answer {
+        /// The question, in natural language
+        q: String,
+
+        #[clap(flatten)]
+        options: OneShot,
+    },
+    /// Add a repository, sync and index it, and exit once it's indexed, reporting progress.
+    /// Exits with an error if the repository couldn't be synced or indexed.
+    Index {
+        /// Path of a local repository, or the URL or name of a remote one, such as
+        /// `https://github.com/org/repo` or `github.com/org/repo`
+        target: String,
+    },
+    /// Write a bundle of the indexes and embeddings of an indexed repository, which another
+    /// instance can import without indexing it again
+    Export {
+        /// The repository, such as `github.com/org/repo`, or the path of a local one
+        #[clap(long)]
+        repo: String,
+
+        /// Path to write the bundle to
+        #[clap(short, long)]
+        output: PathBuf,
+    },
+    /// Install a bundle written by `bleep export`, replacing the indexes and embeddings of its
+    /// repository if it's indexed already
+    Import {
+        /// Path of the bundle
+        bundle: PathBuf,
+    },
+    /// Inspect the configuration
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Print a script that completes the subcommands and flags of `bleep` in a shell, such as
+    /// `bleep completions bash > /etc/bash_completion.d/bleep`
+    Completions {
+        /// The shell the script is for
+        #[clap(required_unless_present = "repos")]
+        shell: Option<clap_complete::Shell>,
+
+        /// Print the indexed repos, one per line, which the scripts complete repo refs with
+        #[clap(long, hide = true)]
+        repos: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigCommand {
+    /// Check the configuration, and print it as merged from defaults, the config file,
+    /// environment variables and command line flags, with secrets left out
+    Check,
+}
+
+impl Command {
+    /// Whether the command runs against a remote instance, rather than the local indexes
+    pub fn is_remote(&self) -> bool {
+        match self {
+            Self::Query { options, .. } | Self::Answer { options, .. } => options.remote.is_some(),
+            Self::Lsp
+            | Self::Mcp
+            | Self::Index { .. }
+            | Self::Export { .. }
+            | Self::Import { .. }
+            | Self::Config { .. }
+            | Self::Completions { .. } => false,
+        }
+    }
+
+    /// Whether the command serves a protocol over stdin and stdout, which leaves stdout to it
+    pub fn serves_stdio(&self) -> bool {
+        matches!(self, Self::Lsp | Self::Mcp)
+    }
+}
+
+/// Options of commands that print a response and exit
+#[derive(Args, Debug, Clone, PartialEq, Eq)]
+pub struct OneShot {
+    #[clap(long)]
+    /// URL of a running instance to ask, rather than the local indexes
+    pub remote: Option<reqwest::Url>,
+
+    #[clap(long, requires = "remote")]
+    /// API key presented to the remote instance
+    pub api_key: Option<String>,
+
+    #[clap(long, default_value_t = false)]
+    /// Print the response as JSON, as the API returns it, rather than as text
+    pub json: bool,
+
+    #[clap(long, default_value_t = 10)]
+    /// Results printed by a query
+    pub limit: usize,
+}
+
 impl Configuration {
     pub fn read(file: impl AsRef<Path>) -> Result<Self> {
         let file = std::fs::File::open(file)?;
@@ -177,19 +645,148 @@ impl Configuration {
         self.index_dir.join(name)
     }
 
+    /// Whether periodic reindexing, and `git pull` on remote repositories, are disabled
+    pub fn disable_background(&self) -> bool {
+        self.disable_background.unwrap_or_default()
+    }
+
+    /// Whether system-native notifications of new commits are disabled
+    pub fn disable_fsevents(&self) -> bool {
+        self.disable_fsevents.unwrap_or_default()
+    }
+
+    /// Whether analytics events and error reports are never sent
+    pub fn disable_telemetry(&self) -> bool {
+        self.disable_telemetry.unwrap_or_default()
+    }
+
+    /// Whether user ids in analytics events are replaced with a keyed hash of them
+    pub fn anonymize_user_ids(&self) -> bool {
+        self.anonymize_user_ids.unwrap_or_default()
+    }
+
+    /// Whether pull requests are reviewed as they're opened
+    pub fn review_pull_requests(&self) -> bool {
+        self.review_pull_requests.unwrap_or_default()
+    }
+
+    /// Whether cross-origin requests can include credentials
+    pub fn cors_allow_credentials(&self) -> bool {
+        self.cors_allow_credentials.unwrap_or_default()
+    }
+
     pub fn github_client_id_and_secret(&self) -> Option<(&str, &str)> {
         let id = self.github_client_id.as_ref()?.expose_secret();
         let secret = self.github_client_secret.as_ref()?.expose_secret();
         Some((id, secret))
     }
 
-    pub fn cli_overriding_config_file() -> Result<Self> {
+    /// The configuration, layered from the lowest precedence to the highest: defaults, the config
+    /// file, environment variables, and command line flags
+    pub fn load() -> Result<Self> {
         let cli = Self::from_cli()?;
-        let Ok(file) = cli.config_file.as_ref().context("no config file specified").and_then(Self::read) else {
-	    return Ok(cli);
-	};
+        let env = Self::from_env(std::env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        }))?;
+
+        let config = match cli.config_file {
+            Some(ref path) => {
+                let file = Self::read(path)
+                    .with_context(|| format!("failed to read config file {}", path.display()))?;
+                Self::merge(Self::merge(file, env), cli)
+            }
+            None => Self::merge(env, cli),
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
 
-        Ok(Self::merge(file, cli))
+    /// Options set by environment variables named after them, as in `BLEEP_MAX_THREADS=4`.
+    ///
+    /// Values are read as JSON, so lists are written as `["a", "b"]`, unless the option is a
+    /// string. Variables that don't name an option are ignored.
+    pub fn from_env(vars: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        let mut options = serde_json::Map::new();
+
+        for (name, value) in vars {
+            let Some(option) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+
+            let option = option.to_ascii_lowercase();
+            let accepts = |value: &serde_json::Value| {
+                let mut options = serde_json::Map::new();
+                options.insert(option.clone(), value.clone());
+                serde_json::from_value::<Self>(options.into()).map(drop)
+            };
+
+            // Strings that happen to be valid JSON, like tokens made of digits, are still strings
+            let value = match serde_json::from_str(&value) {
+                Ok(parsed) if accepts(&parsed).is_ok() => parsed,
+                _ => serde_json::Value::String(value),
+            };
+
+            accepts(&value).with_context(|| format!("invalid value for {name}"))?;
+            options.insert(option, value);
+        }
+
+        Ok(serde_json::from_value(options.into())?)
+    }
+
+    /// Check that options are consistent with each other, before anything is started
+    pub fn validate(&self) -> Result<()> {
+        let urls = [
+            ("answer-api-url", Some(&self.answer_api_url)),
+            ("qdrant-url", self.qdrant_url.as_ref()),
+            ("gitlab-url", self.gitlab_url.as_ref()),
+            ("bitbucket-url", self.bitbucket_url.as_ref()),
+            ("azure-devops-url", self.azure_devops_url.as_ref()),
+        ];
+
+        for (option, url) in urls {
+            if let Some(url) = url {
+                reqwest::Url::parse(url).with_context(|| format!("--{option} is not a URL"))?;
+            }
+        }
+
+        ensure!(
+            self.tls_cert.is_some() == self.tls_key.is_some(),
+            "--tls-cert and --tls-key must be given together"
+        );
+        ensure!(
+            self.github_client_id.is_some() == self.github_client_secret.is_some(),
+            "--github-client-id and --github-client-secret must be given together"
+        );
+        ensure!(
+            self.github_app_id.is_none() || self.github_app_private_key.is_some(),
+            "--github-app-id needs --github-app-private-key"
+        );
+        ensure!(
+            self.max_chunk_tokens > 0,
+            "--max-chunk-tokens must be positive"
+        );
+        ensure!(
+            self.embedding_threads > 0,
+            "--embedding-threads must be positive"
+        );
+
+        Ok(())
+    }
+
+    /// The configuration as JSON, as a config file would set it, with secrets left out
+    pub fn redacted(&self) -> Result<serde_json::Value> {
+        let mut value = serde_json::to_value(self)?;
+
+        if let Some(options) = value.as_object_mut() {
+            for option in SECRET_OPTIONS {
+                if let Some(secret) = options.get_mut(*option).filter(|v| !v.is_null()) {
+                    *secret = "<redacted>".into();
+                }
+            }
+        }
+
+        Ok(value)
     }
 
     /// Merge 2 configurations with values from `b` taking precedence
@@ -203,17 +800,19 @@ impl Configuration {
         Self {
             config_file: b.config_file.or(a.config_file),
 
+            command: b.command.or(a.command),
+
             ctags_path: b.ctags_path.or(a.ctags_path),
 
             source: right_if_default!(b.source, a.source, Default::default()),
 
-            index_dir: b.index_dir,
+            index_dir: right_if_default!(b.index_dir, a.index_dir, default_index_path()),
 
             index_only: b.index_only | a.index_only,
 
-            disable_background: b.disable_background | a.disable_background,
+            disable_background: b.disable_background.or(a.disable_background),
 
-            disable_fsevents: b.disable_fsevents | a.disable_fsevents,
+            disable_fsevents: b.disable_fsevents.or(a.disable_fsevents),
 
             buffer_size: right_if_default!(b.buffer_size, a.buffer_size, default_buffer_size()),
 
@@ -229,6 +828,8 @@ impl Configuration {
 
             port: right_if_default!(b.port, a.port, default_port()),
 
+            listen: right_if_default!(b.listen, a.listen, vec![]),
+
             model_dir: right_if_default!(b.model_dir, a.model_dir, default_model_dir()),
 
             max_chunk_tokens: right_if_default!(
@@ -239,8 +840,22 @@ impl Configuration {
 
             overlap: b.overlap.or(a.overlap),
 
+            embedding_cache_size: right_if_default!(
+                b.embedding_cache_size,
+                a.embedding_cache_size,
+                default_embedding_cache_size()
+            ),
+
+            embedding_threads: right_if_default!(
+                b.embedding_threads,
+                a.embedding_threads,
+                default_embedding_threads()
+            ),
+
             frontend_dist: b.frontend_dist.or(a.frontend_dist),
 
+            readiness_file: b.readiness_file.or(a.readiness_file),
+
             qdrant_url: b.qdrant_url.or(a.qdrant_url),
 
             answer_api_url: right_if_default!(
@@ -263,11 +878,171 @@ impl Configuration {
 
             bot_secret: b.bot_secret.or(a.bot_secret),
 
+            github_webhook_secret: b.github_webhook_secret.or(a.github_webhook_secret),
+
+            review_pull_requests: b.review_pull_requests.or(a.review_pull_requests),
+
+            gitlab_url: b.gitlab_url.or(a.gitlab_url),
+
+            gitlab_token: b.gitlab_token.or(a.gitlab_token),
+
+            gitlab_groups: right_if_default!(b.gitlab_groups, a.gitlab_groups, vec![]),
+
+            gitlab_webhook_secret: b.gitlab_webhook_secret.or(a.gitlab_webhook_secret),
+
+            slack_signing_secret: b.slack_signing_secret.or(a.slack_signing_secret),
+
+            slack_teams: right_if_default!(b.slack_teams, a.slack_teams, HashMap::new()),
+
+            answer_callback_secret: b.answer_callback_secret.or(a.answer_callback_secret),
+
+            answer_callback_hosts: right_if_default!(
+                b.answer_callback_hosts,
+                a.answer_callback_hosts,
+                vec![]
+            ),
+
+            bitbucket_url: b.bitbucket_url.or(a.bitbucket_url),
+
+            bitbucket_username: b.bitbucket_username.or(a.bitbucket_username),
+
+            bitbucket_token: b.bitbucket_token.or(a.bitbucket_token),
+
+            bitbucket_workspaces: right_if_default!(
+                b.bitbucket_workspaces,
+                a.bitbucket_workspaces,
+                vec![]
+            ),
+
+            azure_devops_url: b.azure_devops_url.or(a.azure_devops_url),
+
+            azure_devops_token: b.azure_devops_token.or(a.azure_devops_token),
+
+            azure_devops_projects: right_if_default!(
+                b.azure_devops_projects,
+                a.azure_devops_projects,
+                vec![]
+            ),
+
+            ssh_key: b.ssh_key.or(a.ssh_key),
+
+            ssh_key_passphrase: b.ssh_key_passphrase.or(a.ssh_key_passphrase),
+
+            ssh_known_hosts: b.ssh_known_hosts.or(a.ssh_known_hosts),
+
+            sync_schedule: b.sync_schedule.or(a.sync_schedule),
+
+            repo_sync_schedules: right_if_default!(
+                b.repo_sync_schedules,
+                a.repo_sync_schedules,
+                HashMap::new()
+            ),
+
+            repo_clone_options: right_if_default!(
+                b.repo_clone_options,
+                a.repo_clone_options,
+                HashMap::new()
+            ),
+
+            max_git_transfers: b.max_git_transfers.or(a.max_git_transfers),
+
+            git_bandwidth_limit: b.git_bandwidth_limit.or(a.git_bandwidth_limit),
+
+            proxy: b.proxy.or(a.proxy),
+
+            no_proxy: right_if_default!(b.no_proxy, a.no_proxy, vec![]),
+
+            api_keys: right_if_default!(b.api_keys, a.api_keys, vec![]),
+
+            user_repos: right_if_default!(b.user_repos, a.user_repos, HashMap::new()),
+
+            tenants: right_if_default!(b.tenants, a.tenants, vec![]),
+
+            rate_limit: b.rate_limit.or(a.rate_limit),
+
+            answer_rate_limit: b.answer_rate_limit.or(a.answer_rate_limit),
+
+            cors_allowed_origins: right_if_default!(
+                b.cors_allowed_origins,
+                a.cors_allowed_origins,
+                vec![]
+            ),
+
+            cors_allowed_methods: right_if_default!(
+                b.cors_allowed_methods,
+                a.cors_allowed_methods,
+                vec![]
+            ),
+
+            cors_allowed_headers: right_if_default!(
+                b.cors_allowed_headers,
+                a.cors_allowed_headers,
+                vec![]
+            ),
+
+            cors_allow_credentials: b.cors_allow_credentials.or(a.cors_allow_credentials),
+
+            compression: right_if_default!(b.compression, a.compression, vec![]),
+
+            max_body_size: b.max_body_size.or(a.max_body_size),
+
+            request_timeout: b.request_timeout.or(a.request_timeout),
+
+            answer_timeout: b.answer_timeout.or(a.answer_timeout),
+
+            answer_api_connect_timeout: b
+                .answer_api_connect_timeout
+                .or(a.answer_api_connect_timeout),
+
+            answer_api_read_timeout: b.answer_api_read_timeout.or(a.answer_api_read_timeout),
+
+            answer_api_timeout: b.answer_api_timeout.or(a.answer_api_timeout),
+
+            answer_api_max_failures: b.answer_api_max_failures.or(a.answer_api_max_failures),
+
+            answer_api_probe_interval: b.answer_api_probe_interval.or(a.answer_api_probe_interval),
+
+            answer_api_pool_size: b.answer_api_pool_size.or(a.answer_api_pool_size),
+
+            max_concurrent_requests: b.max_concurrent_requests.or(a.max_concurrent_requests),
+
+            max_concurrent_searches: b.max_concurrent_searches.or(a.max_concurrent_searches),
+
+            max_concurrent_answers: b.max_concurrent_answers.or(a.max_concurrent_answers),
+
+            max_queued_answers: right_if_default!(
+                b.max_queued_answers,
+                a.max_queued_answers,
+                default_max_queued_answers()
+            ),
+
+            tls_cert: b.tls_cert.or(a.tls_cert),
+
+            tls_key: b.tls_key.or(a.tls_key),
+
+            audit_log: b.audit_log.or(a.audit_log),
+
+            query_log: b.query_log.or(a.query_log),
+
+            analytics_sink: right_if_default!(
+                b.analytics_sink,
+                a.analytics_sink,
+                AnalyticsSink::default()
+            ),
+
+            analytics_file: b.analytics_file.or(a.analytics_file),
+
             analytics_key: b.analytics_key.or(a.analytics_key),
 
             analytics_data_plane: b.analytics_data_plane.or(a.analytics_data_plane),
 
+            posthog_host: b.posthog_host.or(a.posthog_host),
+
             sentry_dsn: b.sentry_dsn.or(a.sentry_dsn),
+
+            disable_telemetry: b.disable_telemetry.or(a.disable_telemetry),
+
+            anonymize_user_ids: b.anonymize_user_ids.or(a.anonymize_user_ids),
         }
     }
 }
@@ -337,3 +1112,98 @@ fn default_answer_api_url() -> String {
 fn default_max_chunk_tokens() -> usize {
     256
 }
+
+const fn default_embedding_cache_size() -> usize {
+    8192
+}
+
+const fn default_embedding_threads() -> usize {
+    1
+}
+
+const fn default_max_queued_answers() -> usize {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn options_are_read_from_env() {
+        let config = Configuration::from_env(vars(&[
+            ("BLEEP_PORT", "8080"),
+            ("BLEEP_DISABLE_BACKGROUND", "true"),
+            ("BLEEP_GITLAB_GROUPS", r#"["infra", "platform"]"#),
+            ("BLEEP_HOST", "0.0.0.0"),
+            ("BLEEP_BITBUCKET_USERNAME", "1234"),
+            ("BLEEP_NOT_AN_OPTION", "1"),
+            ("PORT", "9090"),
+        ]))
+        .unwrap();
+
+        assert_eq!(config.port, 8080);
+        assert!(config.disable_background());
+        assert_eq!(config.gitlab_groups, ["infra", "platform"]);
+        assert_eq!(config.host, "0.0.0.0");
+        assert_eq!(config.bitbucket_username.as_deref(), Some("1234"));
+    }
+
+    #[test]
+    fn env_overrides_file_and_cli_overrides_env() {
+        let file = serde_json::from_str::<Configuration>(
+            r#"{ "port": 8000, "host": "10.0.0.1", "max_chunk_tokens": 128 }"#,
+        )
+        .unwrap();
+        let env =
+            Configuration::from_env(vars(&[("BLEEP_PORT", "8080"), ("BLEEP_HOST", "10.0.0.2")]))
+                .unwrap();
+        let cli = Configuration::try_parse_from(["bleep", "--host", "0.0.0.0"]).unwrap();
+
+        let config = Configuration::merge(Configuration::merge(file, env), cli);
+        assert_eq!(config.max_chunk_tokens, 128);
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.host, "0.0.0.0");
+    }
+
+    #[test]
+    fn flags_are_overridden_by_higher_layers() {
+        let file = serde_json::from_str::<Configuration>(
+            r#"{ "disable_background": true, "disable_telemetry": true }"#,
+        )
+        .unwrap();
+        let env = Configuration::from_env(vars(&[
+            ("BLEEP_DISABLE_BACKGROUND", "false"),
+            ("BLEEP_ANONYMIZE_USER_IDS", "true"),
+        ]))
+        .unwrap();
+        let cli = Configuration::try_parse_from([
+            "bleep",
+            "--disable-telemetry=false",
+            "--anonymize-user-ids",
+            "--disable-fsevents",
+            "lsp",
+        ])
+        .unwrap();
+
+        let config = Configuration::merge(Configuration::merge(file, env), cli);
+        assert!(!config.disable_background());
+        assert!(!config.disable_telemetry());
+        assert!(config.anonymize_user_ids());
+        assert!(config.disable_fsevents());
+        assert!(!config.review_pull_requests());
+        assert_eq!(config.command, Some(Command::Lsp));
+    }
+
+    #[test]
+    fn invalid_env_values_are_rejected() {
+        let err = Configuration::from_env(vars(&[("BLEEP_PORT", "not a port")])).unwrap_err();
+        assert!(err.to_string().contains("BLEEP_PORT"));
+    }
+}
@@ -46,7 +46,7 @@ enum EnvironmentInner {
     /// - `--github-client-secret`
     /// - `--github-app-id`
     /// - `--github-app-private-key`
-    /// - `--github-app-install-id`
+    /// - `--github-app-install-id`, if the app has more than one installation
     /// - `--instance-domain`
     ///
     /// Users are authenticated by checking whether they belong to the organization which installed
@@ -56,6 +56,21 @@ enum EnvironmentInner {
 	GithubInstallation as u64
 	| AuthorizationRequired as u64,
 
+    /// Use a GitHub App installation to list and fetch repositories, without logging users in
+    /// through GitHub.
+    ///
+    /// This is the private server environment for instances that aren't configured with a
+    /// GitHub client ID, and only need the following flags:
+    ///
+    /// - `--github-app-id`
+    /// - `--github-app-private-key`
+    /// - `--github-app-install-id`, if the app has more than one installation
+    ///
+    /// API access can still be restricted with API keys.
+    InstallationServer =
+	GithubInstallation as u64
+	| SafePathScan as u64,
+
     /// Enables scanning arbitrary user-specified locations through a Web-endpoint.
     InsecureLocal =
 	AnyPathScan as u64
@@ -74,6 +89,10 @@ impl Environment {
         Self(EnvironmentInner::PrivateServer)
     }
 
+    pub fn installation_server() -> Self {
+        Self(EnvironmentInner::InstallationServer)
+    }
+
     pub fn insecure_local() -> Self {
         Self(EnvironmentInner::InsecureLocal)
     }
@@ -81,4 +100,18 @@ impl Environment {
     pub(crate) fn allow(&self, f: Feature) -> bool {
         0 < self.0 as u64 & f as u64
     }
+
+    /// Names of the features allowed in this environment
+    pub(crate) fn features(&self) -> Vec<&'static str> {
+        [
+            (AnyPathScan, "any_path_scan"),
+            (SafePathScan, "safe_path_scan"),
+            (AuthorizationRequired, "authorization_required"),
+            (GithubDeviceFlow, "github_device_flow"),
+            (GithubInstallation, "github_installation"),
+        ]
+        .into_iter()
+        .filter_map(|(f, name)| self.allow(f).then_some(name))
+        .collect()
+    }
 }
@@ -1,31 +1,50 @@
-use std::{fs, ops::Deref, path::Path, sync::Arc};
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 use async_trait::async_trait;
 use smallvec::SmallVec;
 use tantivy::{
     collector::{Collector, MultiFruit},
-    schema::Schema,
-    tokenizer::NgramTokenizer,
-    DocAddress, Document, IndexReader, IndexWriter, Score,
+    query::{BooleanQuery, TermQuery},
+    schema::{IndexRecordOption, Schema},
+    DocAddress, Document, IndexWriter, Score, Term,
 };
-use tokio::sync::RwLock;
 
+pub mod blobs;
 pub mod file;
 pub mod reader;
 pub mod repo;
+pub mod shard;
 
+pub use blobs::Content;
 pub use file::File;
 pub use repo::Repo;
-use tracing::debug;
+pub use shard::MultiSearcher;
+use tokio::{
+    runtime::{Handle, RuntimeFlavor},
+    sync::{oneshot, OwnedSemaphorePermit, Semaphore},
+};
+use tracing::{debug, warn};
 
 use crate::{
     query::parser::Query,
     repo::{RepoMetadata, RepoRef, Repository},
-    semantic::Semantic,
+    semantic::SemanticLoader,
     Configuration,
 };
 
+/// Reads of an index that run at once. Others wait for one of them to finish, rather than
+/// taking up more threads of the blocking pool.
+const MAX_CONCURRENT_READS: usize = 16;
+
+/// Documents of a query read ahead of the ones its results have been iterated up to.
+const READ_AHEAD_DOCS: usize = 16;
+
 pub type GlobalWriteHandleRef<'a> = [IndexWriteHandle<'a>];
 
 pub struct GlobalWriteHandle<'a> {
@@ -56,6 +75,33 @@ impl<'a> GlobalWriteHandle<'a> {
 
         Ok(())
     }
+
+    pub async fn compact(self) -> Result<()> {
+        for mut handle in self.handles {
+            handle.compact().await?
+        }
+
+        Ok(())
+    }
+
+    /// Install `shard`, a shard of the file index written elsewhere, as the files of `repo`,
+    /// moving it into place, and index the repository itself.
+    ///
+    /// The files are searchable right away, and the repository once this is committed.
+    pub async fn install(
+        &self,
+        reporef: &RepoRef,
+        repo: &Repository,
+        metadata: &RepoMetadata,
+        shard: &Path,
+    ) -> Result<()> {
+        let [repo_index, file_index] = &self.handles[..] else {
+            unreachable!("writers are opened for the repo and file indexes");
+        };
+
+        file_index.install(&repo.disk_path, shard).await?;
+        repo_index.index(reporef, repo, metadata)
+    }
 }
 
 pub struct Indexes {
@@ -65,26 +111,37 @@ pub struct Indexes {
 }
 
 impl Indexes {
-    pub fn new(config: Arc<Configuration>, semantic: Option<Semantic>) -> Result<Self> {
+    pub fn new(config: Arc<Configuration>, semantic: SemanticLoader) -> Result<Self> {
         if config.source.index_version_mismatch() {
             std::fs::remove_dir_all(config.index_path("repo"))?;
             std::fs::remove_dir_all(config.index_path("content"))?;
+            // Blobs of the old index are never referenced again, and may not exist
+            _ = std::fs::remove_dir_all(config.index_path("blobs"));
             config.source.save_index_version()?;
         }
 
+        let (repo, file) = rayon::join(
+            || {
+                Indexer::create(
+                    Repo::new(),
+                    config.index_path("repo").as_ref(),
+                    config.repo_buffer_size,
+                    config.max_threads,
+                )
+            },
+            || {
+                Indexer::create(
+                    File::new(config.clone(), semantic),
+                    config.index_path("content").as_ref(),
+                    config.buffer_size,
+                    config.max_threads,
+                )
+            },
+        );
+
         Ok(Self {
-            repo: Indexer::create(
-                Repo::new(),
-                config.index_path("repo").as_ref(),
-                config.repo_buffer_size,
-                config.max_threads,
-            )?,
-            file: Indexer::create(
-                File::new(config.clone(), semantic),
-                config.index_path("content").as_ref(),
-                config.buffer_size,
-                config.max_threads,
-            )?,
+            repo: repo?,
+            file: file?,
             write_mutex: Default::default(),
         })
     }
@@ -100,6 +157,17 @@ impl Indexes {
             _write_lock,
         })
     }
+
+    /// Load the term dictionaries of both indexes, which searches otherwise load on first use
+    pub async fn warm_up(&self) -> Result<()> {
+        self.repo.warm_up().await?;
+        self.file.warm_up().await
+    }
+
+    /// Whether a writer is currently held, e.g. because a repository is being indexed
+    pub fn is_writing(&self) -> bool {
+        self.write_mutex.try_lock().is_err()
+    }
 }
 
 pub trait Indexable: Send + Sync {
@@ -114,8 +182,23 @@ pub trait Indexable: Send + Sync {
 
     fn delete_by_repo(&self, writer: &IndexWriter, repo: &Repository);
 
+    /// Return a term matching every document of the repository stored at `disk_path`
+    fn repo_term(&self, disk_path: &Path) -> Term;
+
     /// Return the tantivy `Schema` of the current index
     fn schema(&self) -> Schema;
+
+    /// Return the shard the documents of the repository stored at `disk_path` are kept in, if
+    /// this index keeps each repository in a shard of its own
+    fn shard(&self, _disk_path: &Path) -> Option<String> {
+        None
+    }
+
+    /// Remove anything stored outside the index that no document visible to `searcher` refers
+    /// to anymore
+    fn collect_garbage(&self, _searcher: &MultiSearcher) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -142,19 +225,45 @@ pub trait DocumentRead: Send + Sync {
 
 pub struct IndexWriteHandle<'a> {
     source: &'a dyn Indexable,
-    index: &'a tantivy::Index,
-    reader: &'a RwLock<IndexReader>,
-    writer: IndexWriter,
+    shards: &'a shard::Shards,
+    buffer_size: usize,
+    threads: usize,
+    /// Writers of the shards written to so far, each opened as it's first written to
+    writers: Mutex<BTreeMap<String, IndexWriter>>,
+    /// Shards of deleted repositories, removed once the deletion is committed
+    removed: Mutex<BTreeSet<String>>,
 }
 
 impl<'a> IndexWriteHandle<'a> {
-    pub async fn refresh_reader(&self) -> Result<()> {
-        *self.reader.write().await = self.index.reader()?;
-        Ok(())
+    /// Run `f` with the writer of the shard `key`, opening it if it isn't yet
+    fn with_writer<R>(&self, key: String, f: impl FnOnce(&IndexWriter) -> Result<R>) -> Result<R> {
+        let mut writers = self.writers.lock().unwrap();
+        let writer = match writers.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let writer = self
+                    .shards
+                    .writer(entry.key(), self.threads, self.buffer_size)?;
+                entry.insert(writer)
+            }
+        };
+
+        f(writer)
     }
 
-    pub fn delete(&self, repo: &Repository) {
-        self.source.delete_by_repo(&self.writer, repo)
+    pub fn delete(&self, repo: &Repository) -> Result<()> {
+        match self.source.shard(&repo.disk_path) {
+            // The whole shard goes, along with anything written to it so far
+            Some(key) => {
+                self.writers.lock().unwrap().remove(&key);
+                self.removed.lock().unwrap().insert(key);
+                Ok(())
+            }
+            None => self.with_writer(shard::key(self.source, &repo.disk_path), |writer| {
+                self.source.delete_by_repo(writer, repo);
+                Ok(())
+            }),
+        }
     }
 
     pub fn index(
@@ -163,30 +272,81 @@ impl<'a> IndexWriteHandle<'a> {
         repo: &Repository,
         metadata: &RepoMetadata,
     ) -> Result<()> {
-        self.source
-            .index_repository(reporef, repo, metadata, &self.writer)
+        let key = shard::key(self.source, &repo.disk_path);
+
+        // A repository that was deleted earlier on starts over with an empty shard
+        let emptied = self.removed.lock().unwrap().remove(&key);
+
+        self.with_writer(key, |writer| {
+            if emptied {
+                writer.delete_all_documents()?;
+            }
+
+            self.source
+                .index_repository(reporef, repo, metadata, writer)
+        })
+    }
+
+    /// Replace the shard of the repository stored at `disk_path` with the index in `dir`,
+    /// dropping anything written to it so far
+    async fn install(&self, disk_path: &Path, dir: &Path) -> Result<()> {
+        let key = shard::key(self.source, disk_path);
+        self.writers.lock().unwrap().remove(&key);
+        self.removed.lock().unwrap().remove(&key);
+
+        self.shards.install(key, dir).await
     }
 
     pub async fn commit(&mut self) -> Result<()> {
-        self.writer.commit()?;
-        self.refresh_reader().await?;
+        let writers = std::mem::take(self.writers.get_mut().unwrap());
+        for (key, mut writer) in writers {
+            writer.commit()?;
+            self.shards.insert(key, writer.index()).await?;
+        }
+
+        let removed = std::mem::take(self.removed.get_mut().unwrap());
+        for key in removed {
+            self.shards.remove(&key).await?;
+        }
 
         Ok(())
     }
 
     pub fn rollback(&mut self) -> Result<()> {
-        self.writer.rollback()?;
+        for writer in self.writers.get_mut().unwrap().values_mut() {
+            writer.rollback()?;
+        }
+
+        self.removed.get_mut().unwrap().clear();
         Ok(())
     }
+
+    /// Merge the searchable segments of each shard into one, and remove index files that are no
+    /// longer used.
+    pub async fn compact(&mut self) -> Result<()> {
+        for key in self.shards.keys().await {
+            let mut writer = self.shards.writer(&key, self.threads, self.buffer_size)?;
+            let segments = writer.index().searchable_segment_ids()?;
+            if segments.len() > 1 {
+                writer.merge(&segments).await?;
+            }
+
+            writer.garbage_collect_files().await?;
+            self.shards.insert(key, writer.index()).await?;
+        }
+
+        let searcher = self.shards.searcher(None).await;
+        self.source.collect_garbage(&searcher)
+    }
 }
 
-/// A wrapper around `tantivy::IndexReader`.
+/// A wrapper around the shards of an index.
 ///
 /// This contains the schema, and also additional fields used to enable re-indexing.
 pub struct Indexer<T> {
-    pub source: T,
-    pub index: tantivy::Index,
-    pub reader: RwLock<IndexReader>,
+    pub source: Arc<T>,
+    shards: shard::Shards,
+    reads: Arc<Semaphore>,
     pub reindex_buffer_size: usize,
     pub reindex_threads: usize,
 }
@@ -194,38 +354,21 @@ pub struct Indexer<T> {
 impl<T: Indexable> Indexer<T> {
     fn write_handle(&self) -> Result<IndexWriteHandle<'_>> {
         Ok(IndexWriteHandle {
-            source: &self.source,
-            index: &self.index,
-            reader: &self.reader,
-            writer: self
-                .index
-                .writer_with_num_threads(self.reindex_threads, self.reindex_buffer_size)?,
+            source: &*self.source,
+            shards: &self.shards,
+            buffer_size: self.reindex_buffer_size,
+            threads: self.reindex_threads,
+            writers: Default::default(),
+            removed: Default::default(),
         })
     }
 
-    fn init_index(schema: Schema, path: &Path, threads: usize) -> Result<tantivy::Index> {
-        fs::create_dir_all(path).context("failed to create index dir")?;
-
-        let mut index =
-            tantivy::Index::open_or_create(tantivy::directory::MmapDirectory::open(path)?, schema)?;
-
-        index.set_default_multithread_executor()?;
-        index.set_multithread_executor(threads)?;
-        index
-            .tokenizers()
-            .register("default", NgramTokenizer::new(1, 3, false));
-
-        Ok(index)
-    }
-
     /// Create an index using `source` at the specified path.
     pub fn create(source: T, path: &Path, buffer_size: usize, threads: usize) -> Result<Self> {
-        let index = Self::init_index(source.schema(), path, threads)?;
-        let reader = index.reader()?.into();
         let instance = Self {
-            reader,
-            index,
-            source,
+            shards: shard::Shards::open(path, source.schema())?,
+            source: Arc::new(source),
+            reads: Arc::new(Semaphore::new(MAX_CONCURRENT_READS)),
             reindex_threads: threads,
             reindex_buffer_size: buffer_size,
         };
@@ -233,40 +376,207 @@ impl<T: Indexable> Indexer<T> {
         Ok(instance)
     }
 
+    /// A searcher of every shard, or, if `repos` is set, only of the shards of the repositories
+    /// stored at the given disk paths.
+    pub async fn searcher(&self, repos: Option<&[PathBuf]>) -> MultiSearcher {
+        let keys = repos.map(|repos| {
+            repos
+                .iter()
+                .map(|path| shard::key(&*self.source, path))
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>()
+        });
+
+        self.shards.searcher(keys.as_deref()).await
+    }
+
+    /// The directory of the shard the documents of the repository stored at `disk_path` are
+    /// kept in
+    pub fn shard_path(&self, disk_path: &Path) -> PathBuf {
+        self.shards.path(&shard::key(&*self.source, disk_path))
+    }
+
+    /// An identifier of the documents currently visible to searches.
+    ///
+    /// This changes whenever documents are added or deleted, and is stable across restarts.
+    pub async fn generation(&self) -> String {
+        self.searcher(None).await.generation()
+    }
+
+    pub async fn num_docs(&self) -> u64 {
+        self.searcher(None).await.num_docs()
+    }
+
+    /// Open the inverted index of every indexed field in every segment.
+    pub async fn warm_up(&self) -> Result<()> {
+        let searcher = self.searcher(None).await;
+        let schema = self.source.schema();
+
+        tokio::task::spawn_blocking(move || {
+            for segment in searcher.segment_readers() {
+                for (field, entry) in schema.fields() {
+                    if entry.is_indexed() {
+                        segment.inverted_index(field)?;
+                    }
+                }
+            }
+
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Run `read` with `searcher` on the blocking pool, so that reading from disk doesn't hold
+    /// up a runtime thread. At most [`MAX_CONCURRENT_READS`] reads of this index run at once.
+    pub async fn read<F, R>(&self, searcher: MultiSearcher, read: F) -> Result<R>
+    where
+        F: FnOnce(&T, &MultiSearcher) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+        T: 'static,
+    {
+        let permit = self.read_permit().await;
+        let source = Arc::clone(&self.source);
+
+        tokio::task::spawn_blocking(move || {
+            let result = read(&source, &searcher);
+            drop(permit);
+            result
+        })
+        .await?
+    }
+
+    async fn read_permit(&self) -> OwnedSemaphorePermit {
+        Arc::clone(&self.reads)
+            .acquire_owned()
+            .await
+            .expect("index read semaphore is never closed")
+    }
+
+    /// Execute a query against this index.
+    ///
+    /// If `repos` is set, only documents belonging to the repositories stored at the given disk
+    /// paths are matched, and only their shards are searched. Queries are compiled right away,
+    /// and then searched on the blocking pool, which counts towards [`MAX_CONCURRENT_READS`].
+    ///
+    /// Documents are read as the results are iterated, at most [`READ_AHEAD_DOCS`] ahead, and the
+    /// read ends once they all have been, or the results are dropped. Documents that fail to
+    /// be read end the results early.
     pub async fn query<'a, R, I, C>(
         &'a self,
         queries: I,
         doc_reader: &'a R,
         collector: C,
-    ) -> Result<SearchResults<'_, R::Document>>
+        repos: Option<&[PathBuf]>,
+    ) -> Result<SearchResults<'static, R::Document>>
     where
         I: Iterator<Item = &'a Query<'a>> + Send,
-        C: Collector<Fruit = (Vec<(Score, DocAddress)>, MultiFruit)>,
-        R: DocumentRead<Schema = T>,
+        C: Collector<Fruit = (Vec<(Score, DocAddress)>, MultiFruit)> + 'static,
+        R: DocumentRead<Schema = T> + Clone + 'static,
+        R::Document: Send + Sync + 'static,
+        T: 'static,
     {
-        let searcher = self.reader.read().await.searcher();
+        let searcher = self.searcher(repos).await;
         let queries = queries
             .filter(|q| doc_reader.query_matches(q))
             .collect::<SmallVec<[_; 2]>>();
-        let compiled_query =
-            doc_reader.compile(&self.source, queries.iter().copied(), &self.index)?;
 
-        let (top_k, metadata) = searcher
-            .search(&compiled_query, &collector)
-            .context("failed to execute search query")?;
+        let compile = |index: &tantivy::Index| -> Result<Box<dyn tantivy::query::Query>> {
+            let compiled_query =
+                doc_reader.compile(&self.source, queries.iter().copied(), index)?;
+
+            let Some(repos) = repos else {
+                return Ok(compiled_query);
+            };
+
+            let allowed = repos
+                .iter()
+                .map(|path| {
+                    Box::new(TermQuery::new(
+                        self.source.repo_term(path),
+                        IndexRecordOption::Basic,
+                    )) as Box<dyn tantivy::query::Query>
+                })
+                .collect();
+
+            Ok(Box::new(BooleanQuery::intersection(vec![
+                compiled_query,
+                Box::new(BooleanQuery::union(allowed)),
+            ])))
+        };
 
-        let iter = top_k.into_iter().map(move |(_score, addr)| {
-            let doc = searcher.doc(addr).unwrap();
-            doc_reader.read_document(&self.source, doc)
+        let compiled = searcher.compile(compile)?;
+        let doc_reader = doc_reader.clone();
+
+        let permit = self.read_permit().await;
+        let source = Arc::clone(&self.source);
+        let (metadata_tx, metadata_rx) = oneshot::channel();
+        let (docs_tx, docs_rx) = flume::bounded(READ_AHEAD_DOCS);
+
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+
+            let top_k = match searcher.search_compiled(&compiled, &collector) {
+                Ok((top_k, metadata)) => {
+                    if metadata_tx.send(Ok(metadata)).is_err() {
+                        return;
+                    }
+                    top_k
+                }
+                Err(err) => {
+                    _ = metadata_tx.send(Err(err));
+                    return;
+                }
+            };
+
+            for (_score, addr) in top_k {
+                let doc = match searcher.doc(addr) {
+                    Ok(doc) => doc_reader.read_document(&source, doc),
+                    Err(err) => {
+                        warn!(?err, "failed to read document");
+                        return;
+                    }
+                };
+
+                if docs_tx.send(doc).is_err() {
+                    return;
+                }
+            }
         });
 
+        let metadata = metadata_rx.await??;
+
         Ok(SearchResults {
-            docs: Box::new(iter),
+            docs: Box::new(Docs(docs_rx)),
             metadata,
         })
     }
 }
 
+/// Documents sent by a read of the index, as it reads them.
+struct Docs<T>(flume::Receiver<T>);
+
+impl<T> Iterator for Docs<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match self.0.try_recv() {
+            Ok(doc) => return Some(doc),
+            Err(flume::TryRecvError::Disconnected) => return None,
+            Err(flume::TryRecvError::Empty) => {}
+        }
+
+        // Results are mostly iterated on the runtime, so let it move other tasks off this
+        // thread while it waits.
+        match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(|| self.0.recv().ok())
+            }
+            _ => self.0.recv().ok(),
+        }
+    }
+}
+
 pub struct SearchResults<'a, T> {
     pub docs: Box<dyn Iterator<Item = T> + Sync + Send + 'a>,
     pub metadata: MultiFruit,
@@ -0,0 +1,176 @@
+//! Contents of large files, stored outside the index.
+//!
+//! Stored tantivy documents are decompressed and copied whenever they're read, which is costly
+//! for large files that are read again to answer every question about them. Their contents are
+//! instead written to a file of their own, named by a hash of the contents, and referenced by
+//! the document. Reading one maps that file into memory, and clones share the mapping.
+
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    io::Write,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use memmap2::Mmap;
+use tracing::{debug, warn};
+
+/// The contents of a file, either read from its document or mapped from the blob store
+#[derive(Clone)]
+pub struct Content(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Owned(Arc<str>),
+    /// Only ever made from a mapping checked to be valid UTF-8
+    Mapped(Arc<Mmap>),
+}
+
+impl Deref for Content {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match &self.0 {
+            Repr::Owned(s) => s,
+            // SAFETY: the mapping was checked to be UTF-8 when it was made, and blobs are never
+            // written to once they're in place
+            Repr::Mapped(map) => unsafe { std::str::from_utf8_unchecked(map) },
+        }
+    }
+}
+
+impl Default for Content {
+    fn default() -> Self {
+        Self::from("")
+    }
+}
+
+impl From<String> for Content {
+    fn from(s: String) -> Self {
+        Self(Repr::Owned(s.into()))
+    }
+}
+
+impl From<&str> for Content {
+    fn from(s: &str) -> Self {
+        Self(Repr::Owned(s.into()))
+    }
+}
+
+impl fmt::Debug for Content {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Content").field("len", &self.len()).finish()
+    }
+}
+
+/// Files of large contents, each named by the hash of its contents
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Store `content`, returning the key it can be read back with
+    pub fn put(&self, content: &str) -> Result<String> {
+        let key = blake3::hash(content.as_bytes()).to_hex().to_string();
+        let path = self.dir.join(&key);
+
+        // Blobs with the same key have the same contents, and are never written to again
+        if path.exists() {
+            return Ok(key);
+        }
+
+        fs::create_dir_all(&self.dir).context("failed to create blob dir")?;
+
+        // Written in full before it's moved into place, so it's never read half-written
+        let tmp = self
+            .dir
+            .join(format!(".{key}.{}.tmp", rand::random::<u32>()));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+
+        Ok(key)
+    }
+
+    pub fn get(&self, key: &str) -> Result<Content> {
+        let file =
+            fs::File::open(self.path(key)).with_context(|| format!("failed to open blob {key}"))?;
+
+        // SAFETY: blobs are moved into place once written, and never written to again
+        let map = unsafe { Mmap::map(&file)? };
+        std::str::from_utf8(&map).with_context(|| format!("blob {key} is not UTF-8"))?;
+
+        Ok(Content(Repr::Mapped(Arc::new(map))))
+    }
+
+    /// Move the blob in `path`, written elsewhere, into place as `key`, unless it's there already
+    pub fn install(&self, key: &str, path: &Path) -> Result<()> {
+        let dest = self.path(key);
+        if dest.exists() {
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.dir).context("failed to create blob dir")?;
+        fs::rename(path, dest).with_context(|| format!("failed to install blob {key}"))
+    }
+
+    /// The file the blob `key` is stored in
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    /// Remove every blob whose key isn't in `live`
+    pub fn retain(&self, live: &HashSet<String>) -> Result<()> {
+        if !self.dir.exists() {
+            return Ok(());
+        }
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if live.contains(name.to_string_lossy().as_ref()) {
+                continue;
+            }
+
+            // Blobs that are mapped can't be removed on some platforms, and are tried again
+            // next time
+            match fs::remove_file(entry.path()) {
+                Ok(()) => debug!(?name, "removed unused blob"),
+                Err(err) => warn!(?err, ?name, "failed to remove unused blob"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn put_get_retain() {
+        let dir = tempdir::TempDir::new("bleep").unwrap();
+        let blobs = BlobStore::new(dir.path().join("blobs"));
+
+        let key = blobs.put("fn main() {}\n").unwrap();
+        assert_eq!(blobs.put("fn main() {}\n").unwrap(), key);
+        let other = blobs.put("fn other() {}\n").unwrap();
+
+        let content = blobs.get(&key).unwrap();
+        assert_eq!(&*content, "fn main() {}\n");
+        assert_eq!(&*content.clone(), "fn main() {}\n");
+
+        blobs.retain(&HashSet::from([key.clone()])).unwrap();
+        assert!(blobs.get(&other).is_err());
+        assert_eq!(&*blobs.get(&key).unwrap(), "fn main() {}\n");
+    }
+}
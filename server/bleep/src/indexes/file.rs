@@ -2,19 +2,23 @@ use std::{
     collections::{HashMap, HashSet},
     ops::Not,
     path::{Path, PathBuf, MAIN_SEPARATOR},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use dashmap::mapref::entry::Entry;
+use lru::LruCache;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use smallvec::SmallVec;
 use tantivy::{
-    collector::TopDocs,
+    collector::{Count, DocSetCollector, TopDocs},
     doc,
-    query::{BooleanQuery, QueryParser, TermQuery},
+    query::{BooleanQuery, QueryParser, RegexQuery, TermQuery},
     schema::{
         BytesOptions, Field, IndexRecordOption, Schema, Term, TextFieldIndexing, TextOptions, FAST,
         STORED, STRING,
@@ -32,17 +36,45 @@ use {
 };
 
 use super::{
-    reader::{ContentDocument, ContentReader},
-    DocumentRead, Indexable, Indexer,
+    blobs::{BlobStore, Content},
+    reader::{self, ContentDocument, ContentReader},
+    DocumentRead, Indexable, Indexer, MultiSearcher,
 };
 use crate::{
+    background::{self, RepoEvent},
     intelligence::TreeSitterFile,
     repo::{FileCache, RepoMetadata, RepoRef, Repository},
-    semantic::Semantic,
+    semantic::{Semantic, SemanticLoader},
     symbol::SymbolLocations,
     Configuration,
 };
 
+/// Documents looked up by path that are kept, as the same few files are read again to answer
+/// every question about them
+const DOCUMENT_CACHE_SIZE: usize = 256;
+
+/// A document looked up by repo, path, and the index generation it was read at. Documents of
+/// previous generations are never looked up again, and make way for new ones.
+type DocumentKey = (String, String, String);
+
+/// Files at least this large are stored in the blob store rather than in their document
+const LARGE_FILE_LEN: usize = 64 * 1024;
+
+/// An entry of a directory listing
+pub struct DirectoryEntry {
+    /// Path relative to the repo root. Directories end with a path separator.
+    pub relative_path: String,
+    pub lang: Option<String>,
+    /// Size of the indexed content, in bytes. Unset for directories.
+    pub size: Option<usize>,
+}
+
+impl DirectoryEntry {
+    pub fn is_dir(&self) -> bool {
+        self.relative_path.ends_with(MAIN_SEPARATOR)
+    }
+}
+
 struct Workload<'a> {
     entry_disk_path: PathBuf,
     repo_disk_path: &'a Path,
@@ -50,13 +82,18 @@ struct Workload<'a> {
     repo_name: &'a str,
     repo_metadata: &'a RepoMetadata,
     cache: &'a FileCache,
+    semantic: Option<&'a Semantic>,
+    /// Chunks of the repository embedded so far
+    chunks: &'a AtomicUsize,
 }
 
 #[derive(Clone)]
 pub struct File {
     config: Arc<Configuration>,
     schema: Schema,
-    semantic: Option<Semantic>,
+    semantic: SemanticLoader,
+    documents: Arc<Mutex<LruCache<DocumentKey, ContentDocument>>>,
+    blobs: Arc<BlobStore>,
 
     #[cfg(feature = "debug")]
     histogram: Arc<RwLock<Histogram>>,
@@ -78,7 +115,11 @@ pub struct File {
     // github: github.com/org/repo
     pub repo_name: Field,
 
+    // Searchable, but not stored. The contents of small files are stored in `stored_content`,
+    // and large files are stored in the blob store, under the key in `content_blob`.
     pub content: Field,
+    pub stored_content: Field,
+    pub content_blob: Field,
     pub line_end_indices: Field,
 
     // a flat list of every symbol's text, for searching, e.g.: ["File", "Repo", "worker"]
@@ -97,13 +138,14 @@ pub struct File {
 }
 
 impl File {
-    pub fn new(config: Arc<Configuration>, semantic: Option<Semantic>) -> Self {
+    pub fn new(config: Arc<Configuration>, semantic: SemanticLoader) -> Self {
         let mut builder = tantivy::schema::SchemaBuilder::new();
-        let trigram = TextOptions::default().set_stored().set_indexing_options(
-            TextFieldIndexing::default()
-                .set_tokenizer("default")
-                .set_index_option(IndexRecordOption::WithFreqsAndPositions),
-        );
+        let indexing = TextFieldIndexing::default()
+            .set_tokenizer("default")
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+        let trigram = TextOptions::default()
+            .set_stored()
+            .set_indexing_options(indexing.clone());
 
         let entry_disk_path = builder.add_text_field("entry_disk_path", STRING);
         let repo_disk_path = builder.add_text_field("repo_disk_path", STRING);
@@ -111,7 +153,12 @@ impl File {
         let repo_name = builder.add_text_field("repo_name", trigram.clone());
         let relative_path = builder.add_text_field("relative_path", trigram.clone());
 
-        let content = builder.add_text_field("content", trigram.clone());
+        let content = builder.add_text_field(
+            "content",
+            TextOptions::default().set_indexing_options(indexing),
+        );
+        let stored_content = builder.add_text_field("stored_content", STORED);
+        let content_blob = builder.add_text_field("content_blob", STRING | STORED);
         let line_end_indices =
             builder.add_bytes_field("line_end_indices", BytesOptions::default().set_stored());
 
@@ -130,6 +177,10 @@ impl File {
         let raw_repo_name = builder.add_bytes_field("raw_repo_name", FAST);
         let raw_relative_path = builder.add_bytes_field("raw_relative_path", FAST);
 
+        let blobs = Arc::new(BlobStore::new(
+            config.index_path("blobs").as_ref().to_owned(),
+        ));
+
         Self {
             entry_disk_path,
             repo_disk_path,
@@ -137,6 +188,8 @@ impl File {
             repo_ref,
             repo_name,
             content,
+            stored_content,
+            content_blob,
             line_end_indices,
             symbols,
             symbol_locations,
@@ -146,6 +199,10 @@ impl File {
             schema: builder.build(),
             semantic,
             config,
+            documents: Arc::new(Mutex::new(LruCache::new(
+                DOCUMENT_CACHE_SIZE.try_into().unwrap(),
+            ))),
+            blobs,
             raw_content,
             raw_repo_name,
             raw_relative_path,
@@ -245,6 +302,10 @@ impl Indexable for File {
         let file_cache = repo.open_file_cache(&self.config.index_dir)?;
         let repo_name = reporef.indexed_name();
 
+        // Files indexed without their embeddings are never embedded later, as they're cached,
+        // so indexing fails, to be retried, while semantic search can't be loaded
+        let semantic = self.semantic.wait_blocking()?;
+
         // note: this WILL observe .gitignore files for the respective repos.
         let walker = ignore::Walk::new(&repo.disk_path)
             .filter_map(|de| match de {
@@ -266,6 +327,9 @@ impl Indexable for File {
             .collect::<Vec<PathBuf>>();
 
         let start = std::time::Instant::now();
+        let total = walker.len();
+        let progress = AtomicUsize::new(0);
+        let chunks = AtomicUsize::new(0);
 
         use rayon::prelude::*;
         walker.into_par_iter().for_each(|entry_disk_path| {
@@ -276,15 +340,43 @@ impl Indexable for File {
                 repo_name: &repo_name,
                 cache: &file_cache,
                 repo_metadata,
+                semantic: semantic.as_ref(),
+                chunks: &chunks,
             };
 
             debug!(?entry_disk_path, "queueing entry");
             if let Err(err) = self.worker(workload, writer) {
                 warn!(%err, ?entry_disk_path, "indexing failed; skipping");
             }
+
+            let indexed = progress.fetch_add(1, Ordering::Relaxed) + 1;
+            if background::is_milestone(indexed, total) {
+                RepoEvent::IndexProgress {
+                    reporef: reporef.clone(),
+                    indexed,
+                    total,
+                }
+                .emit();
+            }
         });
 
+        // Files whose embeddings weren't stored mustn't be cached as indexed, so the run fails,
+        // and they're embedded again when it's retried
+        if let Some(semantic) = &semantic {
+            let reporef = reporef.to_string();
+            tokio::task::block_in_place(|| {
+                Handle::current().block_on(semantic.flush_points(&reporef))
+            })?;
+        }
+
         info!(?repo.disk_path, "repo file indexing finished, took {:?}", start.elapsed());
+        RepoEvent::FilesIndexed {
+            reporef: reporef.clone(),
+            files: total,
+            chunks: chunks.into_inner(),
+            duration_ms: start.elapsed().as_millis() as u64,
+        }
+        .emit();
 
         // files that are no longer tracked by the git index are to be removed
         // from the tantivy & qdrant indices
@@ -308,16 +400,18 @@ impl Indexable for File {
 
         // batch-delete points from qdrant index
         if !qdrant_remove_list.is_empty() {
-            if let Some(semantic) = &self.semantic {
-                let semantic = semantic.clone();
+            if let Some(semantic) = semantic {
                 let reporef = reporef.to_string();
                 tokio::spawn(async move {
-                    semantic
+                    if let Err(err) = semantic
                         .delete_points_by_path(
                             reporef.as_str(),
                             qdrant_remove_list.iter().map(|t| t.as_str()),
                         )
-                        .await;
+                        .await
+                    {
+                        warn!(?err, %reporef, "failed to delete embeddings of removed files");
+                    }
                 });
             }
         }
@@ -327,52 +421,78 @@ impl Indexable for File {
     }
 
     fn delete_by_repo(&self, writer: &IndexWriter, repo: &Repository) {
-        writer.delete_term(Term::from_field_text(
-            self.repo_disk_path,
-            &repo.disk_path.to_string_lossy(),
-        ));
+        writer.delete_term(self.repo_term(&repo.disk_path));
+    }
+
+    fn repo_term(&self, disk_path: &Path) -> Term {
+        Term::from_field_text(self.repo_disk_path, &disk_path.to_string_lossy())
     }
 
     fn schema(&self) -> Schema {
         self.schema.clone()
     }
+
+    fn shard(&self, disk_path: &Path) -> Option<String> {
+        let hash = blake3::hash(disk_path.to_string_lossy().as_bytes());
+        Some(hash.to_hex()[..16].to_owned())
+    }
+
+    fn collect_garbage(&self, searcher: &MultiSearcher) -> Result<()> {
+        let live = self.blob_keys(searcher)?;
+        debug!(live = live.len(), "removing unused blobs");
+        self.blobs.retain(&live)
+    }
 }
 
 impl Indexer<File> {
+    /// A searcher of the shard of the repository `repo_ref`
+    async fn repo_searcher(&self, repo_ref: &RepoRef) -> MultiSearcher {
+        let disk_path = self.source.config.source.repo_disk_path(repo_ref);
+        let searcher = self.searcher(Some(&[disk_path])).await;
+
+        // Repositories kept somewhere else, such as before the repository directory was moved,
+        // are only found by searching every shard
+        if searcher.is_empty() {
+            return self.searcher(None).await;
+        }
+
+        searcher
+    }
+
     pub async fn file_body(&self, file_disk_path: &str) -> Result<String> {
         // Mostly taken from `by_path`, below.
         //
         // TODO: This can be unified with `by_path` below, but we first need to decide on a unified
         // path referencing API throughout the webserver.
 
-        let reader = self.reader.read().await;
-        let searcher = reader.searcher();
+        let searcher = self.searcher(None).await;
 
         let query = TermQuery::new(
             Term::from_field_text(self.source.entry_disk_path, file_disk_path),
             IndexRecordOption::Basic,
         );
 
-        let collector = TopDocs::with_limit(1);
-        let search_results = searcher
-            .search(&query, &collector)
-            .context("failed to search index")?;
-
-        match search_results.as_slice() {
-            [] => Err(anyhow::Error::msg("no path found")),
-            [(_, doc_addr)] => Ok(searcher
-                .doc(*doc_addr)
-                .context("failed to get document by address")?
-                .get_first(self.source.content)
-                .context("content field was missing")?
-                .as_text()
-                .context("content field did not contain text")?
-                .to_owned()),
-            _ => {
-                warn!("TopDocs is not limited to 1 and index contains duplicates");
-                Err(anyhow::Error::msg("multiple paths returned"))
+        self.read(searcher, move |source, searcher| {
+            let collector = TopDocs::with_limit(1);
+            let search_results = searcher
+                .search(|_| Ok(Box::new(query.clone())), &collector)
+                .context("failed to search index")?;
+
+            match search_results.as_slice() {
+                [] => Err(anyhow::Error::msg("no path found")),
+                [(_, doc_addr)] => {
+                    let doc = searcher
+                        .doc(*doc_addr)
+                        .context("failed to get document by address")?;
+                    Ok(source.read_content(&doc).to_string())
+                }
+                _ => {
+                    warn!("TopDocs is not limited to 1 and index contains duplicates");
+                    Err(anyhow::Error::msg("multiple paths returned"))
+                }
             }
-        }
+        })
+        .await
     }
 
     pub async fn by_path(
@@ -380,49 +500,132 @@ impl Indexer<File> {
         repo_ref: &RepoRef,
         relative_path: &str,
     ) -> Result<ContentDocument> {
-        let reader = self.reader.read().await;
-        let searcher = reader.searcher();
+        let searcher = self.repo_searcher(repo_ref).await;
 
-        let file_index = searcher.index();
-        let file_source = &self.source;
+        let key = (
+            repo_ref.to_string(),
+            relative_path.to_owned(),
+            searcher.generation(),
+        );
+
+        if let Some(doc) = self.source.documents.lock().unwrap().get(&key) {
+            return Ok(doc.clone());
+        }
 
         // query the `relative_path` field of the `File` index, using tantivy's query language
         //
         // XXX: can we use the bloop query language here instead?
-        let query_parser = QueryParser::for_index(
-            file_index,
-            vec![self.source.repo_disk_path, self.source.relative_path],
+        let query_str = format!("repo_ref:\"{repo_ref}\" AND relative_path:\"{relative_path}\"");
+
+        let doc = self
+            .read(searcher, move |file_source, searcher| {
+                let query = |file_index: &tantivy::Index| {
+                    let query_parser = QueryParser::for_index(
+                        file_index,
+                        vec![file_source.repo_disk_path, file_source.relative_path],
+                    );
+
+                    Ok(query_parser
+                        .parse_query(&query_str)
+                        .expect("failed to parse tantivy query"))
+                };
+
+                let collector = TopDocs::with_limit(1);
+                let search_results = searcher
+                    .search(query, &collector)
+                    .expect("failed to search index");
+
+                match search_results.as_slice() {
+                    // no paths matched, the input path was not well formed
+                    [] => Err(anyhow::Error::msg("no path found")),
+
+                    // exactly one path, good
+                    [(_, doc_addr)] => {
+                        let retrieved_doc = searcher
+                            .doc(*doc_addr)
+                            .expect("failed to get document by address");
+                        Ok(ContentReader.read_document(file_source, retrieved_doc))
+                    }
+
+                    // more than one path matched, this can occur when top docs is no
+                    // longer limited to 1 and the index contains dupes
+                    _ => {
+                        warn!("TopDocs is not limited to 1 and index contains duplicates");
+                        Err(anyhow::Error::msg("multiple paths returned"))
+                    }
+                }
+            })
+            .await?;
+
+        self.source.documents.lock().unwrap().put(key, doc.clone());
+        Ok(doc)
+    }
+
+    /// Count the files and directories indexed for a repo
+    pub async fn count_by_repo(&self, repo_ref: &RepoRef) -> Result<usize> {
+        let searcher = self.repo_searcher(repo_ref).await;
+
+        let query = TermQuery::new(
+            Term::from_field_text(self.source.repo_ref, &repo_ref.to_string()),
+            IndexRecordOption::Basic,
         );
-        let query = query_parser
-            .parse_query(&format!(
-                "repo_ref:\"{repo_ref}\" AND relative_path:\"{relative_path}\""
-            ))
-            .expect("failed to parse tantivy query");
-
-        let collector = TopDocs::with_limit(1);
-        let search_results = searcher
-            .search(&query, &collector)
-            .expect("failed to search index");
-
-        match search_results.as_slice() {
-            // no paths matched, the input path was not well formed
-            [] => Err(anyhow::Error::msg("no path found")),
-
-            // exactly one path, good
-            [(_, doc_addr)] => {
-                let retrieved_doc = searcher
-                    .doc(*doc_addr)
-                    .expect("failed to get document by address");
-                Ok(ContentReader.read_document(file_source, retrieved_doc))
-            }
 
-            // more than one path matched, this can occur when top docs is no
-            // longer limited to 1 and the index contains dupes
-            _ => {
-                warn!("TopDocs is not limited to 1 and index contains duplicates");
-                Err(anyhow::Error::msg("multiple paths returned"))
-            }
+        self.read(searcher, move |_, searcher| {
+            searcher
+                .search(|_| Ok(Box::new(query.clone())), &Count)
+                .context("failed to search index")
+        })
+        .await
+    }
+
+    /// List the files and directories directly under a directory of a repo
+    ///
+    /// `relative_dir` is relative to the repo root, and an empty string lists the root itself.
+    pub async fn list_directory(
+        &self,
+        repo_disk_path: &Path,
+        relative_dir: &str,
+    ) -> Result<Vec<DirectoryEntry>> {
+        let searcher = self.searcher(Some(&[repo_disk_path.to_owned()])).await;
+
+        let mut prefix = repo_disk_path.to_string_lossy().into_owned();
+        prefix.push(MAIN_SEPARATOR);
+        let relative_dir = relative_dir.trim_matches(MAIN_SEPARATOR);
+        if !relative_dir.is_empty() {
+            prefix.push_str(relative_dir);
+            prefix.push(MAIN_SEPARATOR);
         }
+
+        // Direct children only: a non-empty name without any further separators
+        let sep = regex::escape(&MAIN_SEPARATOR.to_string());
+        let pattern = format!("{}[^{sep}]+", regex::escape(&prefix));
+        let query = RegexQuery::from_pattern(&pattern, self.source.entry_disk_path)
+            .context("failed to build directory query")?;
+
+        self.read(searcher, move |source, searcher| {
+            let doc_addrs = searcher
+                .search(|_| Ok(Box::new(query.clone())), &DocSetCollector)
+                .context("failed to search index")?;
+
+            doc_addrs
+                .into_iter()
+                .map(|doc_addr| {
+                    let doc = searcher
+                        .doc(doc_addr)
+                        .context("failed to get document by address")?;
+                    let relative_path = reader::read_text_field(&doc, source.relative_path);
+                    let size = (!relative_path.ends_with(MAIN_SEPARATOR))
+                        .then(|| source.read_content(&doc).len());
+
+                    Ok(DirectoryEntry {
+                        lang: reader::read_lang_field(&doc, source.lang),
+                        relative_path,
+                        size,
+                    })
+                })
+                .collect()
+        })
+        .await
     }
 
     // Produce all files in a repo
@@ -431,8 +634,7 @@ impl Indexer<File> {
     //  - directory retrieval is ready
     //  - unified referencing is ready
     pub async fn by_repo(&self, repo_ref: &RepoRef, lang: Option<&str>) -> Vec<ContentDocument> {
-        let reader = self.reader.read().await;
-        let searcher = reader.searcher();
+        let searcher = self.repo_searcher(repo_ref).await;
 
         // repo query
         let path_query = Box::new(TermQuery::new(
@@ -453,22 +655,66 @@ impl Indexer<File> {
             None => BooleanQuery::intersection(vec![path_query]),
         };
 
-        let collector = TopDocs::with_limit(100);
-        searcher
-            .search(&query, &collector)
-            .expect("failed to search index")
-            .into_iter()
-            .map(|(_, doc_addr)| {
-                let retrieved_doc = searcher
-                    .doc(doc_addr)
-                    .expect("failed to get document by address");
-                ContentReader.read_document(&self.source, retrieved_doc)
-            })
-            .collect()
+        self.read(searcher, move |source, searcher| {
+            let collector = TopDocs::with_limit(100);
+            Ok(searcher
+                .search(|_| Ok(Box::new(query.clone())), &collector)
+                .expect("failed to search index")
+                .into_iter()
+                .map(|(_, doc_addr)| {
+                    let retrieved_doc = searcher
+                        .doc(doc_addr)
+                        .expect("failed to get document by address");
+                    ContentReader.read_document(source, retrieved_doc)
+                })
+                .collect())
+        })
+        .await
+        .expect("failed to read index")
     }
 }
 
 impl File {
+    /// Read the contents of a file from its document, or from the blob store if it's large
+    pub fn read_content(&self, doc: &tantivy::Document) -> Content {
+        if let Some(content) = doc.get_first(self.stored_content).and_then(|v| v.as_text()) {
+            return content.into();
+        }
+
+        let Some(key) = doc.get_first(self.content_blob).and_then(|v| v.as_text()) else {
+            return Content::default();
+        };
+
+        self.blobs.get(key).unwrap_or_else(|err| {
+            warn!(?err, key, "failed to read content blob");
+            Content::default()
+        })
+    }
+
+    /// Keys of the blobs referenced by the documents `searcher` sees
+    pub fn blob_keys(&self, searcher: &MultiSearcher) -> Result<HashSet<String>> {
+        let mut keys = HashSet::new();
+        for segment in searcher.segment_readers() {
+            let index = segment.inverted_index(self.content_blob)?;
+            let mut terms = index.terms().stream()?;
+            while let Some((key, _)) = terms.next() {
+                keys.insert(String::from_utf8_lossy(key).into_owned());
+            }
+        }
+
+        Ok(keys)
+    }
+
+    /// The file the blob `key` is stored in
+    pub fn blob_path(&self, key: &str) -> PathBuf {
+        self.blobs.path(key)
+    }
+
+    /// Move the blob in `path`, such as one unpacked from a bundle, into the blob store as `key`
+    pub fn install_blob(&self, key: &str, path: &Path) -> Result<()> {
+        self.blobs.install(key, path)
+    }
+
     #[tracing::instrument(fields(repo=%workload.repo_ref, entry_disk_path=?workload.entry_disk_path), skip_all)]
     fn worker(&self, workload: Workload<'_>, writer: &IndexWriter) -> Result<()> {
         let Workload {
@@ -478,6 +724,8 @@ impl File {
             repo_name,
             repo_metadata,
             cache,
+            semantic,
+            chunks,
         } = workload;
 
         #[cfg(feature = "debug")]
@@ -533,9 +781,10 @@ impl File {
                 .langs
                 .path_map
                 .get(&entry_disk_path)
+                .map(Option::as_deref)
                 .unwrap_or_else(|| {
                     warn!("Path not found in language map");
-                    &Some("")
+                    None
                 })
                 .unwrap_or("")
         } else {
@@ -599,8 +848,8 @@ impl File {
 
         // produce vectors for this document if it is a file
         if entry_disk_path.is_file() {
-            if let Some(semantic) = &self.semantic {
-                tokio::task::block_in_place(|| {
+            if let Some(semantic) = semantic {
+                let embedded = tokio::task::block_in_place(|| {
                     Handle::current().block_on(semantic.insert_points_for_buffer(
                         repo_name,
                         &repo_ref,
@@ -609,13 +858,15 @@ impl File {
                         lang_str,
                     ))
                 });
+
+                chunks.fetch_add(embedded, Ordering::Relaxed);
             }
         }
 
         trace!("writing document");
         #[cfg(feature = "debug")]
         let buf_size = buffer.len();
-        writer.add_document(doc!(
+        let mut doc = doc!(
             self.repo_disk_path => repo_disk_path.to_string_lossy().as_ref(),
             self.entry_disk_path => entry_disk_path.to_string_lossy().as_ref(),
             self.relative_path => relative_path_str.as_ref(),
@@ -631,7 +882,15 @@ impl File {
             self.raw_content => buffer.as_bytes(),
             self.raw_repo_name => repo_name.as_bytes(),
             self.raw_relative_path => relative_path_str.as_ref().as_bytes(),
-        ))?;
+        );
+
+        if buffer.len() >= LARGE_FILE_LEN {
+            doc.add_text(self.content_blob, self.blobs.put(&buffer)?);
+        } else {
+            doc.add_text(self.stored_content, &buffer);
+        }
+
+        writer.add_document(doc)?;
 
         trace!("document written");
 
@@ -7,7 +7,7 @@ use tantivy::{
     Index,
 };
 
-use super::{file::File, repo::Repo, DocumentRead};
+use super::{blobs::Content, file::File, repo::Repo, DocumentRead};
 use crate::{
     query::{
         compiler::Compiler,
@@ -18,7 +18,7 @@ use crate::{
 
 #[derive(Default, Debug, Clone)]
 pub struct ContentDocument {
-    pub content: String,
+    pub content: Content,
     pub lang: Option<String>,
     pub relative_path: String,
     pub repo_name: String,
@@ -40,6 +40,7 @@ pub struct RepoDocument {
     pub repo_ref: String,
 }
 
+#[derive(Clone)]
 pub struct ContentReader;
 
 #[async_trait]
@@ -85,7 +86,7 @@ impl DocumentRead for ContentReader {
         let relative_path = read_text_field(&doc, schema.relative_path);
         let repo_ref = read_text_field(&doc, schema.repo_ref);
         let repo_name = read_text_field(&doc, schema.repo_name);
-        let content = read_text_field(&doc, schema.content);
+        let content = schema.read_content(&doc);
         let lang = read_lang_field(&doc, schema.lang);
 
         let line_end_indices = doc
@@ -117,6 +118,7 @@ impl DocumentRead for ContentReader {
     }
 }
 
+#[derive(Clone)]
 pub struct FileReader;
 
 #[async_trait]
@@ -176,6 +178,7 @@ impl DocumentRead for FileReader {
     }
 }
 
+#[derive(Clone)]
 pub struct RepoReader;
 
 #[async_trait]
@@ -192,6 +195,13 @@ impl DocumentRead for RepoReader {
                 path: None,
                 target: None,
                 ..
+            } | Query {
+                open: Some(false) | None,
+                group: Some(..),
+                path: None,
+                lang: None,
+                target: None,
+                ..
             }
         )
     }
@@ -223,6 +233,7 @@ impl DocumentRead for RepoReader {
     }
 }
 
+#[derive(Clone)]
 pub struct OpenReader;
 
 #[derive(Debug)]
@@ -289,7 +300,7 @@ impl DocumentRead for OpenReader {
         let repo_name = read_text_field(&doc, schema.repo_name);
         let repo_ref = read_text_field(&doc, schema.repo_ref);
         let lang = read_lang_field(&doc, schema.lang);
-        let content = read_text_field(&doc, schema.content);
+        let content = schema.read_content(&doc).to_string();
 
         Self::Document {
             relative_path,
@@ -314,11 +325,11 @@ pub fn base_name(path: &str) -> &str {
         .unwrap_or("")
 }
 
-fn read_text_field(doc: &tantivy::Document, field: Field) -> String {
+pub(super) fn read_text_field(doc: &tantivy::Document, field: Field) -> String {
     doc.get_first(field).unwrap().as_text().unwrap().to_owned()
 }
 
-fn read_lang_field(doc: &tantivy::Document, lang: Field) -> Option<String> {
+pub(super) fn read_lang_field(doc: &tantivy::Document, lang: Field) -> Option<String> {
     let lang_str = crate::query::languages::proper_case(
         doc.get_first(lang)
             .and_then(Value::as_bytes)
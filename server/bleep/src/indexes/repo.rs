@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use anyhow::Result;
 use async_trait::async_trait;
 use tantivy::{
@@ -94,10 +96,11 @@ impl Indexable for Repo {
     }
 
     fn delete_by_repo(&self, writer: &IndexWriter, repo: &Repository) {
-        writer.delete_term(Term::from_field_text(
-            self.disk_path,
-            &repo.disk_path.to_string_lossy(),
-        ));
+        writer.delete_term(self.repo_term(&repo.disk_path));
+    }
+
+    fn repo_term(&self, disk_path: &Path) -> Term {
+        Term::from_field_text(self.disk_path, &disk_path.to_string_lossy())
     }
 
     fn schema(&self) -> Schema {
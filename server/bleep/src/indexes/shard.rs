@@ -0,0 +1,432 @@
+//! Indexes split into shards, each a tantivy index of its own.
+//!
+//! The file index keeps every repository in a shard of its own, so that adding or removing a
+//! repository never touches the documents of any other, and searches scoped to some
+//! repositories only read their shards. Other indexes keep every document in a single shard.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use anyhow::{Context, Result};
+use tantivy::{
+    collector::{Collector, SegmentCollector},
+    schema::Schema,
+    tokenizer::NgramTokenizer,
+    DocAddress, Document, IndexReader, IndexWriter, ReloadPolicy, Searcher, SegmentReader,
+};
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+use super::Indexable;
+
+/// The key of the only shard of indexes that aren't sharded
+const UNSHARDED: &str = "all";
+
+/// The key of the shard documents of the repository at `disk_path` are kept in
+pub(super) fn key(source: &dyn Indexable, disk_path: &Path) -> String {
+    source
+        .shard(disk_path)
+        .unwrap_or_else(|| UNSHARDED.to_owned())
+}
+
+/// Shards opened between logs of the progress of opening an index
+const PROGRESS_INTERVAL: usize = 50;
+
+/// The shards of an index, each kept in a directory named by its key
+pub struct Shards {
+    path: PathBuf,
+    schema: Schema,
+    readers: RwLock<BTreeMap<String, IndexReader>>,
+}
+
+impl Shards {
+    /// Open every shard under `path`, in parallel
+    pub fn open(path: &Path, schema: Schema) -> Result<Self> {
+        use rayon::prelude::*;
+
+        std::fs::create_dir_all(path).context("failed to create index dir")?;
+
+        let mut dirs = vec![];
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry);
+            }
+        }
+
+        let start = Instant::now();
+        let total = dirs.len();
+        let opened = AtomicUsize::new(0);
+
+        let readers = dirs
+            .par_iter()
+            .map(|entry| {
+                let index = init_index(schema.clone(), &entry.path())?;
+                let reader = reader(&index)?;
+
+                let opened = opened.fetch_add(1, Ordering::Relaxed) + 1;
+                if opened % PROGRESS_INTERVAL == 0 {
+                    info!(?path, opened, total, "opening index shards");
+                }
+
+                Ok((entry.file_name().to_string_lossy().into_owned(), reader))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?;
+
+        info!(?path, shards = total, elapsed = ?start.elapsed(), "opened index shards");
+        Ok(Self {
+            path: path.to_owned(),
+            schema,
+            readers: RwLock::new(readers),
+        })
+    }
+
+    /// The directory the shard `key` is kept in
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.path.join(key)
+    }
+
+    pub async fn keys(&self) -> Vec<String> {
+        self.readers.read().await.keys().cloned().collect()
+    }
+
+    /// A searcher of every shard, or only of those with the given keys
+    pub async fn searcher(&self, keys: Option<&[String]>) -> MultiSearcher {
+        let readers = self.readers.read().await;
+        let searchers = match keys {
+            None => readers.values().map(IndexReader::searcher).collect(),
+            Some(keys) => keys
+                .iter()
+                .filter_map(|key| readers.get(key))
+                .map(IndexReader::searcher)
+                .collect(),
+        };
+
+        MultiSearcher::new(searchers)
+    }
+
+    /// Open a writer of the shard `key`, creating the shard if it doesn't exist yet.
+    ///
+    /// Documents it writes are only searchable once it's committed, and the shard is
+    /// [`Shards::insert`]ed.
+    pub fn writer(&self, key: &str, threads: usize, buffer_size: usize) -> Result<IndexWriter> {
+        let index = init_index(self.schema.clone(), &self.path.join(key))?;
+        Ok(index.writer_with_num_threads(threads, buffer_size)?)
+    }
+
+    /// Search the documents last committed to the shard `key` from now on
+    pub async fn insert(&self, key: String, index: &tantivy::Index) -> Result<()> {
+        let reader = reader(index)?;
+        self.readers.write().await.insert(key, reader);
+        Ok(())
+    }
+
+    /// Replace the shard `key` with the index in `dir`, which is moved into place. Searches wait
+    /// until it's in place, rather than seeing neither shard.
+    ///
+    /// The shard it replaces is moved next to `dir` first, and then removed.
+    pub async fn install(&self, key: String, dir: &Path) -> Result<()> {
+        let path = self.path.join(&key);
+        let old = dir.with_extension("old");
+
+        let mut readers = self.readers.write().await;
+        readers.remove(&key);
+
+        if path.exists() {
+            std::fs::rename(&path, &old).context("failed to move the shard aside")?;
+        }
+
+        if let Err(err) = std::fs::rename(dir, &path) {
+            if old.exists() {
+                std::fs::rename(&old, &path)?;
+                let index = init_index(self.schema.clone(), &path)?;
+                readers.insert(key, reader(&index)?);
+            }
+
+            return Err(err).context("failed to move the shard into place");
+        }
+
+        let index = init_index(self.schema.clone(), &path)?;
+        readers.insert(key.clone(), reader(&index)?);
+        drop(readers);
+
+        match tokio::fs::remove_dir_all(&old).await {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => warn!(?err, key, "failed to remove replaced index shard"),
+            Ok(()) => debug!(key, "removed replaced index shard"),
+        }
+
+        Ok(())
+    }
+
+    /// Remove the shard `key`, and every document in it
+    pub async fn remove(&self, key: &str) -> Result<()> {
+        if self.readers.write().await.remove(key).is_none() {
+            return Ok(());
+        }
+
+        match tokio::fs::remove_dir_all(self.path.join(key)).await {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => warn!(?err, key, "failed to remove index shard"),
+            Ok(()) => debug!(key, "removed index shard"),
+        }
+
+        Ok(())
+    }
+}
+
+fn init_index(schema: Schema, path: &Path) -> Result<tantivy::Index> {
+    std::fs::create_dir_all(path).context("failed to create index dir")?;
+
+    let index =
+        tantivy::Index::open_or_create(tantivy::directory::MmapDirectory::open(path)?, schema)?;
+
+    index
+        .tokenizers()
+        .register("default", NgramTokenizer::new(1, 3, false));
+
+    Ok(index)
+}
+
+/// Readers are replaced whenever their shard is committed to, rather than watching for commits,
+/// which takes a thread for every shard
+fn reader(index: &tantivy::Index) -> Result<IndexReader> {
+    Ok(index
+        .reader_builder()
+        .reload_policy(ReloadPolicy::Manual)
+        .try_into()?)
+}
+
+/// A searcher of several shards at once.
+///
+/// The segments of each shard are numbered after those of the shards before it, so the fruits a
+/// collector gathers from every shard can be merged as if they came from a single index.
+pub struct MultiSearcher {
+    searchers: Vec<Searcher>,
+    first_segments: Vec<u32>,
+}
+
+impl MultiSearcher {
+    fn new(searchers: Vec<Searcher>) -> Self {
+        let first_segments = searchers
+            .iter()
+            .scan(0, |next, searcher| {
+                let first = *next;
+                *next += searcher.segment_readers().len() as u32;
+                Some(first)
+            })
+            .collect();
+
+        Self {
+            searchers,
+            first_segments,
+        }
+    }
+
+    /// Search every shard in parallel, with the query `query` compiles for it
+    pub fn search<Q, C>(&self, query: Q, collector: &C) -> Result<C::Fruit>
+    where
+        Q: Fn(&tantivy::Index) -> Result<Box<dyn tantivy::query::Query>>,
+        C: Collector,
+    {
+        self.search_compiled(&self.compile(query)?, collector)
+    }
+
+    /// The query `query` compiles for every shard, in order, to search later with
+    /// [`MultiSearcher::search_compiled`]
+    pub fn compile<Q>(&self, query: Q) -> Result<Vec<Box<dyn tantivy::query::Query>>>
+    where
+        Q: Fn(&tantivy::Index) -> Result<Box<dyn tantivy::query::Query>>,
+    {
+        self.searchers
+            .iter()
+            .map(|searcher| query(searcher.index()))
+            .collect()
+    }
+
+    /// Search every shard in parallel, with the query compiled for it by
+    /// [`MultiSearcher::compile`]
+    pub fn search_compiled<C: Collector>(
+        &self,
+        queries: &[Box<dyn tantivy::query::Query>],
+        collector: &C,
+    ) -> Result<C::Fruit> {
+        use rayon::prelude::*;
+
+        let fruits = self
+            .searchers
+            .par_iter()
+            .zip(&self.first_segments)
+            .zip(queries)
+            .map(|((searcher, &first_segment), query)| {
+                let collector = ShardCollector {
+                    inner: collector,
+                    first_segment,
+                };
+
+                searcher
+                    .search(query.as_ref(), &collector)
+                    .context("failed to execute search query")
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(collector.merge_fruits(fruits.into_iter().flatten().collect())?)
+    }
+
+    /// Read the document at an address returned by [`MultiSearcher::search`]
+    pub fn doc(&self, addr: DocAddress) -> Result<Document> {
+        let shard = self
+            .first_segments
+            .partition_point(|&first| first <= addr.segment_ord)
+            - 1;
+
+        let addr = DocAddress::new(addr.segment_ord - self.first_segments[shard], addr.doc_id);
+        Ok(self.searchers[shard].doc(addr)?)
+    }
+
+    pub fn num_docs(&self) -> u64 {
+        self.searchers.iter().map(Searcher::num_docs).sum()
+    }
+
+    /// Whether there are no shards to search
+    pub fn is_empty(&self) -> bool {
+        self.searchers.is_empty()
+    }
+
+    pub fn segment_readers(&self) -> impl Iterator<Item = &SegmentReader> {
+        self.searchers.iter().flat_map(Searcher::segment_readers)
+    }
+
+    /// The generation of the documents visible to this searcher. See
+    /// [`super::Indexer::generation`].
+    pub fn generation(&self) -> String {
+        let mut hasher = blake3::Hasher::new();
+        for segment in self.segment_readers() {
+            hasher.update(segment.segment_id().uuid_string().as_bytes());
+            hasher.update(&segment.delete_opstamp().unwrap_or_default().to_le_bytes());
+        }
+
+        hasher.finalize().to_hex()[..16].to_owned()
+    }
+}
+
+/// Collects the segments of a single shard, numbered from `first_segment`, leaving them for
+/// `inner` to merge with those of every other shard
+struct ShardCollector<'a, C> {
+    inner: &'a C,
+    first_segment: u32,
+}
+
+impl<C: Collector> Collector for ShardCollector<'_, C> {
+    type Fruit = Vec<<C::Child as SegmentCollector>::Fruit>;
+
+    type Child = C::Child;
+
+    fn for_segment(
+        &self,
+        segment_local_id: u32,
+        segment_reader: &SegmentReader,
+    ) -> tantivy::Result<C::Child> {
+        self.inner
+            .for_segment(self.first_segment + segment_local_id, segment_reader)
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.inner.requires_scoring()
+    }
+
+    fn merge_fruits(
+        &self,
+        segments: Vec<<C::Child as SegmentCollector>::Fruit>,
+    ) -> tantivy::Result<Self::Fruit> {
+        Ok(segments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::{
+        collector::{Count, TopDocs},
+        doc,
+        query::{AllQuery, TermQuery},
+        schema::{IndexRecordOption, STORED, STRING},
+        Term,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn searches_span_shards() {
+        let dir = tempdir::TempDir::new("bleep").unwrap();
+        let mut builder = Schema::builder();
+        let name = builder.add_text_field("name", STRING | STORED);
+        let shards = Shards::open(dir.path(), builder.build()).unwrap();
+
+        // Shard `a` has two segments, so the segment of `b` is numbered after both
+        let batches = [
+            ("a", vec![vec!["a1", "a2"], vec!["a3"]]),
+            ("b", vec![vec!["b1"]]),
+        ];
+        for (key, batches) in batches {
+            let mut writer = shards.writer(key, 1, 15_000_000).unwrap();
+            for batch in batches {
+                for n in batch {
+                    writer.add_document(doc!(name => n)).unwrap();
+                }
+                writer.commit().unwrap();
+            }
+            shards.insert(key.to_owned(), writer.index()).await.unwrap();
+        }
+
+        let searcher = shards.searcher(None).await;
+        assert_eq!(searcher.num_docs(), 4);
+        assert_eq!(searcher.segment_readers().count(), 3);
+
+        let read = |addr| {
+            let doc = searcher.doc(addr).unwrap();
+            doc.get_first(name).unwrap().as_text().unwrap().to_owned()
+        };
+
+        // Fruits of every shard are merged
+        let all = searcher
+            .search(|_| Ok(Box::new(AllQuery)), &TopDocs::with_limit(10))
+            .unwrap();
+        let mut names = all
+            .into_iter()
+            .map(|(_, addr)| read(addr))
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["a1", "a2", "a3", "b1"]);
+        assert_eq!(
+            searcher.search(|_| Ok(Box::new(AllQuery)), &Count).unwrap(),
+            4
+        );
+
+        // Addresses are mapped back to the shard they came from
+        let term = Term::from_field_text(name, "b1");
+        let b1 = searcher
+            .search(
+                |_| {
+                    Ok(Box::new(TermQuery::new(
+                        term.clone(),
+                        IndexRecordOption::Basic,
+                    )))
+                },
+                &TopDocs::with_limit(1),
+            )
+            .unwrap();
+        assert_eq!(b1[0].1.segment_ord, 2);
+        assert_eq!(read(b1[0].1), "b1");
+
+        let only_b = shards.searcher(Some(&["b".to_owned()])).await;
+        assert_eq!(
+            only_b.search(|_| Ok(Box::new(AllQuery)), &Count).unwrap(),
+            1
+        );
+        assert!(shards.searcher(Some(&["c".to_owned()])).await.is_empty());
+    }
+}
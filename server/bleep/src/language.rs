@@ -1,14 +1,15 @@
 use hyperpolyglot::detect;
 use ignore::WalkBuilder;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
 };
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct LanguageInfo {
-    pub path_map: HashMap<PathBuf, Option<&'static str>>,
-    pub most_common_lang: Option<&'static str>,
+    pub path_map: HashMap<PathBuf, Option<String>>,
+    pub most_common_lang: Option<String>,
 }
 
 pub fn get_language_info<P: AsRef<Path>>(path: P) -> LanguageInfo {
@@ -53,7 +54,7 @@ pub fn get_language_info<P: AsRef<Path>>(path: P) -> LanguageInfo {
     let mut counts = HashMap::<&'static str, usize>::new();
     for (path, detection) in rx {
         let lang = detection.map(|d| d.language());
-        path_map.insert(path, lang);
+        path_map.insert(path, lang.map(str::to_owned));
 
         // count recognized langs
         if let Some(l) = lang {
@@ -61,7 +62,10 @@ pub fn get_language_info<P: AsRef<Path>>(path: P) -> LanguageInfo {
         }
     }
 
-    let most_common_lang = counts.into_iter().max_by_key(|(_, v)| *v).map(|(k, _)| k);
+    let most_common_lang = counts
+        .into_iter()
+        .max_by_key(|(_, v)| *v)
+        .map(|(k, _)| k.to_owned());
 
     LanguageInfo {
         path_map,
@@ -25,9 +25,10 @@ use dunce::canonicalize;
 use std::fs::canonicalize;
 
 use crate::{
-    background::BackgroundExecutor, indexes::Indexes, semantic::Semantic, state::RepositoryPool,
+    background::BackgroundExecutor, indexes::Indexes, semantic::SemanticLoader,
+    state::RepositoryPool,
 };
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, Result};
 use axum::extract::FromRef;
 
 use dashmap::{mapref::entry::Entry, DashMap};
@@ -38,27 +39,51 @@ use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
 mod background;
+mod bundle;
+mod client;
 mod collector;
+mod completions;
 mod config;
 mod env;
 mod language;
+mod proxy;
+mod readiness;
 mod remotes;
 mod repo;
 mod webserver;
 
+// Public for the desktop app and the benchmarks, but not supported for other uses, which
+// go through `Client`
+#[doc(hidden)]
 pub mod analytics;
+#[doc(hidden)]
 pub mod ctags;
+#[doc(hidden)]
 pub mod indexes;
+#[doc(hidden)]
 pub mod intelligence;
+#[doc(hidden)]
+pub mod metrics;
+#[doc(hidden)]
 pub mod query;
+#[doc(hidden)]
 pub mod semantic;
+#[doc(hidden)]
 pub mod snippet;
+#[doc(hidden)]
 pub mod state;
+#[doc(hidden)]
 pub mod symbol;
+#[doc(hidden)]
 pub mod text_range;
 
-pub use config::{default_parallelism, minimum_parallelism, Configuration};
+pub use client::{Answer, Client, SearchResult, SearchSnippet, Source};
+pub use completions::print_completions;
+pub use config::{
+    default_parallelism, minimum_parallelism, Command, ConfigCommand, Configuration, OneShot,
+};
 pub use env::Environment;
+pub use webserver::cli::run_remote;
 
 const LOG_ENV_VAR: &str = "BLOOP_LOG";
 static LOGGER_INSTALLED: OnceCell<bool> = OnceCell::new();
@@ -71,19 +96,22 @@ pub struct Application {
     pub config: Arc<Configuration>,
     repo_pool: RepositoryPool,
     background: BackgroundExecutor,
-    semantic: Option<Semantic>,
+    jobs: Arc<background::Jobs>,
+    semantic: SemanticLoader,
     indexes: Arc<Indexes>,
     credentials: remotes::Backends,
     cookie_key: axum_extra::extract::cookie::Key,
+    api_keys: Arc<Vec<webserver::keys::ApiKey>>,
+    sync_schedules: Arc<remotes::Schedules>,
+    secrets: Arc<remotes::SecretStore>,
+    feedback: Arc<semantic::Feedback>,
+    query_log: Option<Arc<analytics::QueryLog>>,
     prior_conversational_store: Arc<DashMap<String, Vec<(String, String)>>>,
 }
 
 impl Application {
-    pub async fn initialize(env: Environment, config: Configuration) -> Result<Application> {
-        let mut config = match config.config_file {
-            None => config,
-            Some(ref path) => Configuration::read(path)?,
-        };
+    pub async fn initialize(env: Environment, mut config: Configuration) -> Result<Application> {
+        config.validate()?;
 
         config.max_threads = config.max_threads.max(minimum_parallelism());
         let threads = config.max_threads;
@@ -94,6 +122,8 @@ impl Application {
         config.source.set_default_dir(&config.index_dir);
 
         let config = Arc::new(config);
+        remotes::Throttle::install(&config);
+        proxy::install(&config)?;
 
         // Set path to Ctags binary
         if let Some(ref executable) = config.ctags_path {
@@ -102,37 +132,102 @@ impl Application {
                 .map_err(|existing| anyhow!("ctags binary already set: {existing:?}"))?;
         }
 
-        // Initialise Semantic index if `qdrant_url` set in config
+        // Load the semantic subsystem in the background if `qdrant_url` set in config, so that
+        // lexical search is served in the meantime
         let semantic = match config.qdrant_url {
             Some(ref url) => {
-                match Semantic::initialize(&config.model_dir, url, Arc::clone(&config)).await {
-                    Ok(semantic) => Some(semantic),
-                    Err(e) => {
-                        bail!("Qdrant initialization failed: {}", e);
-                    }
-                }
+                SemanticLoader::load(config.model_dir.clone(), url.clone(), Arc::clone(&config))
             }
             None => {
                 warn!("Semantic search disabled because `qdrant_url` is not provided. Starting without.");
-                None
+                SemanticLoader::disabled()
             }
         };
 
-        let env = if config.github_app_id.is_some() {
-            info!("Starting bleep in private server mode");
-            Environment::private_server()
-        } else {
-            env
+        let env = match (config.github_app_id, &config.github_client_id) {
+            (Some(_), Some(_)) => {
+                info!("Starting bleep in private server mode");
+                Environment::private_server()
+            }
+            (Some(_), None) => {
+                info!("Starting bleep in GitHub App installation mode");
+                Environment::installation_server()
+            }
+            (None, _) => env,
         };
 
         let prior_conversational_store = Arc::new(DashMap::new());
 
+        let mut api_keys = config.source.initialize_api_keys()?;
+        api_keys.extend(config.api_keys.iter().cloned());
+        if !api_keys.is_empty() {
+            info!(count = api_keys.len(), "API key authentication enabled");
+        }
+
+        let credentials: remotes::Backends = config.source.initialize_credentials()?.into();
+
+        // GitLab, Bitbucket, and Azure DevOps are configured rather than connected to, so the
+        // configuration always wins over stored credentials
+        match remotes::gitlab::State::from_config(&config) {
+            Some(gitlab) => {
+                info!(url = %gitlab.url, "GitLab repositories enabled");
+                credentials.set_gitlab(gitlab);
+            }
+            None => {
+                credentials.remove(repo::Backend::Gitlab);
+            }
+        }
+
+        match remotes::bitbucket::State::from_config(&config) {
+            Some(bitbucket) => {
+                info!(
+                    url = bitbucket.url.as_deref().unwrap_or("bitbucket.org"),
+                    "Bitbucket repositories enabled"
+                );
+                credentials.set_bitbucket(bitbucket);
+            }
+            None => {
+                credentials.remove(repo::Backend::Bitbucket);
+            }
+        }
+
+        match remotes::azure::State::from_config(&config) {
+            Some(azure) => {
+                info!(url = %azure.url, "Azure DevOps repositories enabled");
+                credentials.set_azure(azure);
+            }
+            None => {
+                credentials.remove(repo::Backend::AzureDevops);
+            }
+        }
+
+        let sync_schedules = remotes::Schedules::from_config(&config)?;
+
+        if config.anonymize_user_ids() {
+            analytics::pseudonymize_user_ids(config.source.initialize_user_id_key()?);
+        }
+
+        let query_log = match config.query_log {
+            Some(ref path) => Some(Arc::new(analytics::QueryLog::open(path)?)),
+            None => None,
+        };
+
+        // Indexes are opened on the rayon pool, which the background executor sets up
+        let background = BackgroundExecutor::start(config.clone());
+        let indexes = Arc::new(Indexes::new(config.clone(), semantic.clone())?);
+
         Ok(Self {
-            indexes: Arc::new(Indexes::new(config.clone(), semantic.clone())?),
-            background: BackgroundExecutor::start(config.clone()),
+            indexes,
+            background,
+            jobs: Arc::new(config.source.initialize_jobs()?),
             repo_pool: config.source.initialize_pool()?,
             cookie_key: config.source.initialize_cookie_key()?,
-            credentials: config.source.initialize_credentials()?.into(),
+            api_keys: Arc::new(api_keys),
+            credentials,
+            sync_schedules: Arc::new(sync_schedules),
+            secrets: Arc::new(config.source.initialize_secrets()?),
+            feedback: Arc::new(config.source.initialize_feedback()?),
+            query_log,
             semantic,
             config,
             env,
@@ -141,6 +236,11 @@ impl Application {
     }
 
     pub fn initialize_sentry(&self) {
+        if analytics::Permit::from_config(&self.config).is_none() {
+            info!("telemetry disabled, skipping Sentry initialization");
+            return;
+        }
+
         let Some(ref dsn) = self.config.sentry_dsn else {
             info!("Sentry DSN missing, skipping initialization");
             return;
@@ -164,6 +264,34 @@ impl Application {
     }
 
     pub fn initialize_analytics(&self) {
+        use analytics::{AnalyticsSink, JsonlSink, SqliteSink};
+
+        let path = |name| {
+            self.config
+                .analytics_file
+                .clone()
+                .unwrap_or_else(|| self.config.index_dir.join(name))
+        };
+
+        let installed = match self.config.analytics_sink {
+            AnalyticsSink::Rudder => return self.initialize_rudder(),
+            AnalyticsSink::Posthog => return self.initialize_posthog(),
+            AnalyticsSink::Jsonl => JsonlSink::new(&path("analytics.jsonl")).map(drop),
+            AnalyticsSink::Sqlite => SqliteSink::new(&path("analytics.db")).map(drop),
+        };
+
+        match installed {
+            Ok(()) => info!(sink = ?self.config.analytics_sink, "local analytics enabled"),
+            Err(err) => warn!(?err, "failed to initialize analytics"),
+        }
+    }
+
+    fn initialize_rudder(&self) {
+        let Some(permit) = analytics::Permit::from_config(&self.config) else {
+            info!("telemetry disabled, skipping analytics initialization");
+            return;
+        };
+
         let Some(key) = &self.config.analytics_key else {
             warn!("analytics key missing; skipping initialization");
             return;
@@ -175,15 +303,47 @@ impl Application {
         };
 
         info!("initializing analytics ...");
-        analytics::RudderHub::new(key.to_owned(), data_plane.to_owned());
+        analytics::RudderHub::new(permit, key.to_owned(), data_plane.to_owned());
+    }
+
+    fn initialize_posthog(&self) {
+        let Some(permit) = analytics::Permit::from_config(&self.config) else {
+            info!("telemetry disabled, skipping analytics initialization");
+            return;
+        };
+
+        let Some(key) = &self.config.analytics_key else {
+            warn!("analytics key missing; skipping initialization");
+            return;
+        };
+
+        let host = self
+            .config
+            .posthog_host
+            .as_deref()
+            .unwrap_or(analytics::DEFAULT_POSTHOG_HOST);
+
+        info!(host, "initializing PostHog analytics ...");
+        if let Err(err) = analytics::PosthogSink::new(permit, key.to_owned(), host) {
+            warn!(?err, "failed to initialize analytics");
+        }
     }
 
     pub fn install_logging() {
+        Self::install_logging_to_stderr(false);
+    }
+
+    /// Install logging to stderr, for commands that serve a protocol over stdout
+    pub fn install_stdio_logging() {
+        Self::install_logging_to_stderr(true);
+    }
+
+    fn install_logging_to_stderr(stderr: bool) {
         if let Some(true) = LOGGER_INSTALLED.get() {
             return;
         }
 
-        if !tracing_subscribe() {
+        if !tracing_subscribe(stderr) {
             warn!("Failed to install tracing_subscriber. There's probably one already...");
         };
 
@@ -195,23 +355,39 @@ impl Application {
     }
 
     pub fn track_query(&self, event: &analytics::QueryEvent) {
-        tokio::task::block_in_place(|| analytics::RudderHub::track_query(event.clone()))
+        self.log_query(&analytics::QueryLogEntry::answer(event));
+        tokio::task::block_in_place(|| analytics::track_query(event.clone()))
+    }
+
+    /// Record a query in the query log, if there is one
+    pub(crate) fn log_query(&self, entry: &analytics::QueryLogEntry) {
+        let Some(ref query_log) = self.query_log else {
+            return;
+        };
+
+        if let Err(err) = tokio::task::block_in_place(|| query_log.append(entry)) {
+            error!(?err, "failed to write to the query log");
+        }
     }
 
     pub async fn run(self) -> Result<()> {
         Self::install_logging();
+        readiness::clear(&self.config)?;
 
         let mut joins = tokio::task::JoinSet::new();
 
         if self.config.index_only {
             joins.spawn(self.write_index().startup_scan());
         } else {
-            if !self.config.disable_background {
-                tokio::spawn(remotes::sync_repositories(self.clone()));
+            if !self.config.disable_background() {
+                tokio::spawn(remotes::sync_repo_lists(self.clone()));
                 tokio::spawn(remotes::check_credentials(self.clone()));
                 tokio::spawn(remotes::check_repo_updates(self.clone()));
+                tokio::spawn(remotes::run_sync_schedules(self.clone()));
             }
 
+            self.jobs.resume(&self);
+            self.warm_up().await;
             joins.spawn(webserver::start(self));
         }
 
@@ -225,6 +401,41 @@ impl Application {
         Ok(())
     }
 
+    /// Serve the Language Server Protocol over stdin and stdout, rather than the HTTP API
+    pub async fn serve_lsp(self) -> Result<()> {
+        Self::install_stdio_logging();
+
+        self.warm_up().await;
+        webserver::lsp::serve(self).await
+    }
+
+    /// Run a one-shot `query`, `answer`, `index`, `export` or `import` command against the local indexes
+    pub async fn run_once(self, command: &Command) -> Result<()> {
+        Self::install_logging();
+        webserver::cli::run_local(self, command).await
+    }
+
+    /// Serve the Model Context Protocol over stdin and stdout, rather than the HTTP API. It's
+    /// also served over HTTP at `/api/mcp`.
+    pub async fn serve_mcp(self) -> Result<()> {
+        Self::install_stdio_logging();
+
+        self.warm_up().await;
+        webserver::mcp::serve_stdio(self).await
+    }
+
+    /// Load the indexes ahead of the first requests that need them, which would otherwise take
+    /// seconds longer to answer. The embedding model is warmed up as it's loaded.
+    async fn warm_up(&self) {
+        let start = std::time::Instant::now();
+
+        if let Err(err) = self.indexes.warm_up().await {
+            warn!(?err, "failed to warm up indexes");
+        }
+
+        info!(elapsed = ?start.elapsed(), "warm-up finished");
+    }
+
     pub(crate) fn allow_path(&self, path: impl AsRef<Path>) -> bool {
         if self.env.allow(env::Feature::AnyPathScan) {
             return true;
@@ -297,9 +508,11 @@ impl FromRef<Application> for axum_extra::extract::cookie::Key {
 }
 
 #[cfg(all(tokio_unstable, feature = "debug"))]
-fn tracing_subscribe() -> bool {
+fn tracing_subscribe(stderr: bool) -> bool {
     use tracing_subscriber::{fmt, prelude::*};
-    let env_filter = fmt::layer().with_filter(EnvFilter::from_env(LOG_ENV_VAR));
+    let env_filter = fmt::layer()
+        .with_writer(log_writer(stderr))
+        .with_filter(EnvFilter::from_env(LOG_ENV_VAR));
     tracing_subscriber::registry()
         .with(env_filter)
         .with(console_subscriber::spawn())
@@ -308,11 +521,22 @@ fn tracing_subscribe() -> bool {
 }
 
 #[cfg(not(all(tokio_unstable, feature = "debug")))]
-fn tracing_subscribe() -> bool {
+fn tracing_subscribe(stderr: bool) -> bool {
     use tracing_subscriber::{fmt, prelude::*};
-    let env_filter = fmt::layer().with_filter(EnvFilter::from_env(LOG_ENV_VAR));
+    let env_filter = fmt::layer()
+        .with_writer(log_writer(stderr))
+        .with_filter(EnvFilter::from_env(LOG_ENV_VAR));
     tracing_subscriber::registry()
         .with(env_filter)
         .try_init()
         .is_ok()
 }
+
+fn log_writer(stderr: bool) -> tracing_subscriber::fmt::writer::BoxMakeWriter {
+    use tracing_subscriber::fmt::writer::BoxMakeWriter;
+    if stderr {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    }
+}
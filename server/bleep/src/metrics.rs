@@ -0,0 +1,225 @@
+//! Latency histograms of each stage of answering a question, and of index operations.
+//!
+//! Histograms are kept in memory since startup, and exposed in the Prometheus text format, so
+//! a regression can be narrowed down to the stage it's in.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+
+/// Upper bounds of the buckets, in seconds. Answers wait on the LLM for seconds, and indexing
+/// a large repository takes minutes.
+const BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0, 1800.0,
+];
+
+/// A stage of answering a question
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnswerStage {
+    /// Parsing the question
+    Parse,
+    /// Asking the LLM to rephrase the question as a search
+    Rephrase,
+    /// Searching for snippets
+    Retrieve,
+    /// Asking the LLM which snippet answers the question
+    Select,
+    /// Growing the selected snippet with the code around it
+    Grow,
+    /// Streaming the answer from the LLM
+    Explain,
+}
+
+impl AnswerStage {
+    const ALL: &'static [Self] = &[
+        Self::Parse,
+        Self::Rephrase,
+        Self::Retrieve,
+        Self::Select,
+        Self::Grow,
+        Self::Explain,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Parse => "parse",
+            Self::Rephrase => "rephrase",
+            Self::Retrieve => "retrieve",
+            Self::Select => "select",
+            Self::Grow => "grow",
+            Self::Explain => "explain",
+        }
+    }
+}
+
+/// An operation on a repository's index
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexOperation {
+    /// Cloning or fetching the repository
+    Sync,
+    /// Indexing its files
+    Index,
+    /// Committing the index writers
+    Commit,
+    /// Removing the repository and its indexes
+    Delete,
+}
+
+impl IndexOperation {
+    const ALL: &'static [Self] = &[Self::Sync, Self::Index, Self::Commit, Self::Delete];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sync => "sync",
+            Self::Index => "index",
+            Self::Commit => "commit",
+            Self::Delete => "delete",
+        }
+    }
+}
+
+static ANSWER_STAGES: Lazy<Family> = Lazy::new(|| {
+    Family::new(
+        "bleep_answer_stage_duration_seconds",
+        "Time taken by each stage of answering a question",
+        "stage",
+        AnswerStage::ALL.iter().map(|s| s.name()),
+    )
+});
+
+static INDEX_OPERATIONS: Lazy<Family> = Lazy::new(|| {
+    Family::new(
+        "bleep_index_operation_duration_seconds",
+        "Time taken by each operation on a repository's index",
+        "operation",
+        IndexOperation::ALL.iter().map(|o| o.name()),
+    )
+});
+
+/// Record how long a stage of answering a question took
+pub fn observe_answer(stage: AnswerStage, elapsed: Duration) {
+    ANSWER_STAGES.observe(stage as usize, elapsed);
+}
+
+/// Record how long an operation on an index took
+pub fn observe_index(operation: IndexOperation, elapsed: Duration) {
+    INDEX_OPERATIONS.observe(operation as usize, elapsed);
+}
+
+/// Every histogram, in the Prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+    ANSWER_STAGES.render(&mut out);
+    INDEX_OPERATIONS.render(&mut out);
+    out
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations in each bucket, but not in the ones before it
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Histograms of one metric, one for each value of its label
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    label: &'static str,
+    histograms: Vec<(&'static str, Histogram)>,
+}
+
+impl Family {
+    fn new(
+        name: &'static str,
+        help: &'static str,
+        label: &'static str,
+        values: impl Iterator<Item = &'static str>,
+    ) -> Self {
+        Self {
+            name,
+            help,
+            label,
+            histograms: values.map(|v| (v, Histogram::default())).collect(),
+        }
+    }
+
+    fn observe(&self, index: usize, elapsed: Duration) {
+        self.histograms[index].1.observe(elapsed);
+    }
+
+    fn render(&self, out: &mut String) {
+        let Self { name, label, .. } = self;
+
+        _ = writeln!(out, "# HELP {name} {}", self.help);
+        _ = writeln!(out, "# TYPE {name} histogram");
+
+        for (value, histogram) in &self.histograms {
+            // Buckets are cumulative in the exposition format
+            let mut cumulative = 0;
+            for (bound, bucket) in BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                _ = writeln!(
+                    out,
+                    "{name}_bucket{{{label}=\"{value}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+
+            let count = histogram.count.load(Ordering::Relaxed);
+            let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+            _ = writeln!(
+                out,
+                "{name}_bucket{{{label}=\"{value}\",le=\"+Inf\"}} {count}"
+            );
+            _ = writeln!(out, "{name}_sum{{{label}=\"{value}\"}} {sum}");
+            _ = writeln!(out, "{name}_count{{{label}=\"{value}\"}} {count}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_exposition() {
+        let family = Family::new("latency", "Latency", "stage", ["parse", "grow"].into_iter());
+        family.observe(0, Duration::from_millis(3));
+        family.observe(0, Duration::from_millis(200));
+        family.observe(0, Duration::from_secs(3600));
+
+        let mut out = String::new();
+        family.render(&mut out);
+
+        for line in [
+            "# TYPE latency histogram",
+            "latency_bucket{stage=\"parse\",le=\"0.005\"} 1",
+            "latency_bucket{stage=\"parse\",le=\"0.25\"} 2",
+            "latency_bucket{stage=\"parse\",le=\"1800\"} 2",
+            "latency_bucket{stage=\"parse\",le=\"+Inf\"} 3",
+            "latency_sum{stage=\"parse\"} 3600.203",
+            "latency_count{stage=\"parse\"} 3",
+            "latency_count{stage=\"grow\"} 0",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {line:?} in\n{out}");
+        }
+    }
+}
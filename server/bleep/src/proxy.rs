@@ -0,0 +1,177 @@
+//! Outbound traffic through an HTTP(S) or SOCKS proxy.
+//!
+//! Hosts matching a `no_proxy` rule are connected to directly. Rules are domain names, which
+//! also cover their subdomains, IP addresses, CIDR ranges, or `*` for every host.
+//!
+//! libgit2 can only tunnel through HTTP(S) proxies, and never proxies SSH, so git operations
+//! connect directly when the proxy is a SOCKS one.
+//!
+//! Clients built inside other crates, like octocrab's GitHub clients, can't be handed the proxy,
+//! so it's also exported to the environment reqwest reads its system proxy from.
+
+use std::net::IpAddr;
+
+use once_cell::sync::OnceCell;
+use tracing::warn;
+
+use crate::Configuration;
+
+static PROXY: OnceCell<Proxy> = OnceCell::new();
+
+struct Proxy {
+    url: String,
+    no_proxy: Vec<String>,
+}
+
+/// Install the proxy in `config`. Only the first configuration installed takes effect.
+pub(crate) fn install(config: &Configuration) -> anyhow::Result<()> {
+    let Some(ref url) = config.proxy else {
+        return Ok(());
+    };
+
+    // Catch malformed addresses at startup, rather than on every request
+    reqwest::Proxy::all(url)?;
+
+    if !is_http(url) {
+        warn!(
+            url,
+            "git doesn't support SOCKS proxies, and will connect directly"
+        );
+    }
+
+    if PROXY
+        .set(Proxy {
+            url: url.clone(),
+            no_proxy: config.no_proxy.clone(),
+        })
+        .is_ok()
+    {
+        export(url, &config.no_proxy);
+    }
+
+    Ok(())
+}
+
+/// Set the environment variables reqwest builds its system proxy from. reqwest reads them once,
+/// when the first client is built, so this must run before any client is.
+fn export(url: &str, no_proxy: &[String]) {
+    std::env::set_var("HTTP_PROXY", url);
+    std::env::set_var("HTTPS_PROXY", url);
+    std::env::set_var("NO_PROXY", no_proxy.join(","));
+}
+
+/// An HTTP client builder that sends requests through the proxy
+pub(crate) fn client_builder() -> reqwest::ClientBuilder {
+    let builder = reqwest::Client::builder();
+    let Some(proxy) = PROXY.get() else {
+        return builder;
+    };
+
+    match reqwest::Proxy::all(&proxy.url) {
+        Ok(all) => {
+            let no_proxy = reqwest::NoProxy::from_string(&proxy.no_proxy.join(","));
+            builder.proxy(all.no_proxy(no_proxy))
+        }
+        // The address was checked when the proxy was installed
+        Err(_) => builder,
+    }
+}
+
+/// An HTTP client that sends requests through the proxy
+pub(crate) fn client() -> reqwest::Client {
+    client_builder()
+        .build()
+        .expect("failed to initialize HTTP client")
+}
+
+/// Proxy settings to connect to the git remote at `url` with
+pub(crate) fn git(url: &str) -> git2::ProxyOptions<'static> {
+    let mut options = git2::ProxyOptions::new();
+
+    if let Some(proxy) = PROXY.get() {
+        if is_http(&proxy.url) && !bypassed(&proxy.no_proxy, url) {
+            options.url(&proxy.url);
+        }
+    }
+
+    options
+}
+
+fn is_http(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// Whether `url` is exempt from the proxy by any of `rules`
+fn bypassed(rules: &[String], url: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(url) else {
+        return false;
+    };
+
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+
+    rules.iter().any(|rule| matches(rule.trim(), host))
+}
+
+fn matches(rule: &str, host: &str) -> bool {
+    if rule == "*" {
+        return true;
+    }
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return match rule.split_once('/') {
+            Some((network, bits)) => in_network(ip, network, bits),
+            None => rule.parse::<IpAddr>() == Ok(ip),
+        };
+    }
+
+    let host = host.to_ascii_lowercase();
+    let domain = rule
+        .trim_start_matches('*')
+        .trim_start_matches('.')
+        .to_ascii_lowercase();
+
+    !domain.is_empty() && (host == domain || host.ends_with(&format!(".{domain}")))
+}
+
+fn in_network(ip: IpAddr, network: &str, bits: &str) -> bool {
+    let (Ok(network), Ok(bits)) = (network.parse::<IpAddr>(), bits.parse::<u32>()) else {
+        return false;
+    };
+
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) if bits <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) if bits <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_proxy_rules() {
+        let rules = ["git.corp.example".to_owned(), "10.0.0.0/8".to_owned()];
+
+        assert!(bypassed(&rules, "https://git.corp.example/org/repo.git"));
+        assert!(bypassed(&rules, "https://eu.git.corp.example/org/repo.git"));
+        assert!(bypassed(&rules, "http://10.1.2.3:8080/repo.git"));
+        assert!(!bypassed(&rules, "https://github.com/org/repo.git"));
+        assert!(!bypassed(&rules, "https://notgit.corp.example/repo.git"));
+        assert!(!bypassed(&rules, "http://11.1.2.3/repo.git"));
+
+        assert!(matches("*", "github.com"));
+        assert!(matches(".example.com", "example.com"));
+        assert!(matches("*.example.com", "api.example.com"));
+        assert!(matches("::1", "[::1]"));
+    }
+}
@@ -145,7 +145,12 @@ impl Compiler {
                 intersection.push(field_query);
             }
 
-            sub_queries.push(Box::new(BooleanQuery::intersection(intersection)));
+            // Queries may only be scoped by terms that aren't in the index, such as `group:`
+            if intersection.is_empty() {
+                sub_queries.push(Box::new(AllQuery));
+            } else {
+                sub_queries.push(Box::new(BooleanQuery::intersection(intersection)));
+            }
         }
 
         Ok(if sub_queries.len() == 1 {
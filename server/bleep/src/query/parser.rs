@@ -13,6 +13,8 @@ pub struct Query<'a> {
     pub repo: Option<Literal<'a>>,
     pub path: Option<Literal<'a>>,
     pub lang: Option<Cow<'a, str>>,
+    /// A repository group, as assigned through the API
+    pub group: Option<Cow<'a, str>>,
     pub target: Option<Target<'a>>,
 }
 
@@ -56,6 +58,7 @@ impl<'a> Query<'a> {
             repo: rhs.repo.or(self.repo),
             path: rhs.path.or(self.path),
             lang: rhs.lang.or(self.lang),
+            group: rhs.group.or(self.group),
 
             target: match (self.target, rhs.target) {
                 (Some(Target::Content(lhs)), Some(Target::Content(rhs))) => {
@@ -284,6 +287,7 @@ enum Expr<'a> {
     Symbol(Literal<'a>),
     Path(Literal<'a>),
     Lang(Cow<'a, str>),
+    Group(Cow<'a, str>),
     Content(Literal<'a>),
 
     CaseSensitive(bool),
@@ -307,6 +311,7 @@ impl<'a> Expr<'a> {
             Rule::symbol => Symbol(Literal::from(pair.into_inner().next().unwrap())),
             Rule::org => Org(Literal::from(pair.into_inner().next().unwrap())),
             Rule::lang => Lang(pair.into_inner().as_str().into()),
+            Rule::repo_group => Group(pair.into_inner().as_str().into()),
 
             Rule::open => {
                 let inner = pair.into_inner().next().unwrap();
@@ -456,6 +461,10 @@ fn flatten(root: Expr<'_>) -> SmallVec<[Query<'_>; 1]> {
             lang: Some(super::languages::parse_alias(lang)),
             ..Default::default()
         }],
+        Expr::Group(group) => smallvec![Query {
+            group: Some(group),
+            ..Default::default()
+        }],
         Expr::Content(lit) => smallvec![Query {
             target: Some(Target::Content(lit)),
             ..Default::default()
@@ -988,6 +997,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn group_parse() {
+        assert_eq!(
+            parse("group:backend ParseError or group:frontend").unwrap(),
+            vec![
+                Query {
+                    group: Some("backend".into()),
+                    target: Some(Target::Content(Literal::Plain("ParseError".into()))),
+                    ..Query::default()
+                },
+                Query {
+                    group: Some("frontend".into()),
+                    ..Query::default()
+                },
+            ],
+        );
+    }
+
     #[test]
     fn escape_characters() {
         assert_eq!(
@@ -8,7 +8,7 @@ use tantivy::{
 
 use crate::indexes::file::File;
 
-pub struct DocumentTweaker(pub File);
+pub struct DocumentTweaker(pub Arc<File>);
 pub struct SegmentScorer {
     line_length: Arc<dyn Column<f64>>,
     lang: BytesFastFieldReader,
@@ -0,0 +1,69 @@
+//! Signalling that the server is ready for traffic.
+//!
+//! Lexical search is served as soon as the webserver is up, but questions can't be answered
+//! until semantic search has loaded, so orchestrators shouldn't route traffic to the instance
+//! before then. Once the listeners are bound, the indexes open and semantic search loaded,
+//! systemd is notified if it started `bleep`, the readiness file is created if one is
+//! configured, and `serving` is logged.
+
+use std::io::ErrorKind;
+
+use anyhow::Context;
+use tracing::{error, info};
+
+use crate::{semantic::LoadState, Application, Configuration};
+
+/// Remove the readiness file a previous run left behind, so that the server isn't taken to be
+/// ready before it is
+pub(crate) fn clear(config: &Configuration) -> anyhow::Result<()> {
+    let Some(ref path) = config.readiness_file else {
+        return Ok(());
+    };
+
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("failed to remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Signal readiness once semantic search has loaded, or right away if it's disabled.
+///
+/// An instance whose semantic search failed to load never becomes ready.
+pub(crate) async fn signal(app: Application, listeners: Vec<String>) {
+    app.semantic.wait().await;
+
+    if let LoadState::Failed(err) = app.semantic.state() {
+        error!(%err, "semantic search failed to load; not signalling readiness");
+        notify(false, &format!("semantic search failed to load: {err}"));
+        return;
+    }
+
+    if let Some(ref path) = app.config.readiness_file {
+        if let Err(err) = std::fs::write(path, format!("{}\n", std::process::id())) {
+            error!(?err, path = %path.display(), "failed to create readiness file");
+        }
+    }
+
+    notify(true, "serving");
+    info!(?listeners, "serving");
+}
+
+/// Tell systemd how startup is going, if it's waiting to be told
+#[cfg(unix)]
+fn notify(ready: bool, status: &str) {
+    use sd_notify::NotifyState;
+
+    let states = match ready {
+        true => vec![NotifyState::Ready, NotifyState::Status(status)],
+        false => vec![NotifyState::Status(status)],
+    };
+
+    if let Err(err) = sd_notify::notify(false, &states) {
+        error!(?err, "failed to notify systemd");
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_ready: bool, _status: &str) {}
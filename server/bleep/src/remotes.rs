@@ -4,25 +4,41 @@ use std::{
     borrow::Borrow,
     path::{Path, PathBuf},
     process::Command,
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
 use dashmap::{mapref::one::Ref, DashMap};
 use git2::{Cred, CredentialType, RemoteCallbacks};
 use ignore::WalkBuilder;
+use secrecy::SecretString;
 use serde::{Deserialize, Serialize};
-use tracing::{error, warn};
+use tracing::{error, info, warn};
 
 use crate::{
-    remotes,
-    repo::{Backend, RepoRef, Repository, SyncStatus},
+    background::RepoEvent,
+    proxy, remotes,
+    repo::{Backend, GitProtocol, GitRemote, RepoRef, RepoRemote, Repository, SyncStatus},
     Application,
 };
 
+pub mod azure;
+pub mod bitbucket;
+mod checkout;
 pub mod github;
+pub mod gitlab;
+pub(crate) mod ssh;
 
+mod import;
 mod poll;
+mod schedule;
+mod secrets;
+mod throttle;
+pub use checkout::CloneOptions;
+pub(crate) use import::{dedup_forks, list_org, ForkPolicy, ImportFilter, OrgRepo};
 pub(crate) use poll::*;
+pub(crate) use schedule::*;
+pub(crate) use secrets::{RepoSecrets, SecretStore};
+pub(crate) use throttle::Throttle;
 
 type GitCreds = Box<
     dyn FnMut(&str, Option<&str>, CredentialType) -> std::result::Result<Cred, git2::Error>
@@ -60,10 +76,35 @@ pub(crate) enum RemoteError {
     #[error("github access error: {0}")]
     GitHub(#[from] octocrab::Error),
 
+    #[error("github authorization error: {0}")]
+    OAuth(String),
+
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("revision not found: {0}")]
+    RevisionNotFound(String),
+
+    #[error("remote repository was renamed to {0}")]
+    Renamed(String),
+
     #[error("low-level code: {0:?}")]
     UnspecifiedGit(git2::Error),
 }
 
+impl RemoteError {
+    /// Whether the credentials were rejected, and may work again once refreshed
+    fn is_unauthorized(&self) -> bool {
+        match self {
+            RemoteError::PermissionDenied => true,
+            RemoteError::GitHub(octocrab::Error::GitHub { source, .. }) => {
+                source.message == "Bad credentials"
+            }
+            _ => false,
+        }
+    }
+}
+
 impl From<git2::Error> for RemoteError {
     fn from(value: git2::Error) -> Self {
         use git2::ErrorCode::*;
@@ -76,32 +117,153 @@ impl From<git2::Error> for RemoteError {
     }
 }
 
-async fn git_clone(auth: GitCreds, url: &str, target: &Path) -> Result<()> {
+/// Authenticate with the backend's credentials, or an SSH key when connecting over SSH, and
+/// keep transfers within the bandwidth limit
+fn callbacks(mut auth: GitCreds, ssh: Option<ssh::Ssh>) -> RemoteCallbacks<'static> {
+    let mut callbacks = RemoteCallbacks::new();
+
+    let mut received = 0;
+    callbacks.transfer_progress(move |progress| {
+        Throttle::received(&mut received, progress.received_bytes());
+        true
+    });
+
+    match ssh {
+        Some(ssh) => {
+            let key = ssh.clone();
+            callbacks.credentials(move |url, username, allowed| {
+                if allowed.contains(CredentialType::SSH_KEY) {
+                    key.credentials(username)
+                } else {
+                    auth(url, username, allowed)
+                }
+            });
+            callbacks.certificate_check(move |cert, host| ssh.check_host(cert, host));
+        }
+        None => {
+            callbacks.credentials(auth);
+        }
+    }
+
+    callbacks
+}
+
+/// Share credentials between the connections made by one operation
+fn shared(auth: GitCreds) -> impl Fn() -> GitCreds {
+    let auth = Arc::new(Mutex::new(auth));
+    move || {
+        let auth = Arc::clone(&auth);
+        Box::new(move |url, username, allowed| {
+            let mut auth = auth.lock().unwrap();
+            (*auth)(url, username, allowed)
+        })
+    }
+}
+
+/// Run `f` with each of `urls` in turn until it succeeds, returning the last error if none does
+fn failover(urls: &[String], mut f: impl FnMut(&str) -> Result<()>) -> Result<()> {
+    let mut result = Err(RemoteError::Configuration("remote url"));
+
+    for url in urls {
+        result = f(url);
+        match result {
+            Ok(()) => break,
+            Err(ref err) => warn!(?err, url, "failed to fetch, trying the next remote"),
+        }
+    }
+
+    result
+}
+
+/// The branch the remote's `HEAD` points to
+fn default_branch(auth: GitCreds, ssh: Option<ssh::Ssh>, url: &str) -> Result<String> {
+    let mut remote = git2::Remote::create_detached(url)?;
+    let connection = remote.connect_auth(
+        git2::Direction::Fetch,
+        Some(callbacks(auth, ssh)),
+        Some(proxy::git(url)),
+    )?;
+    let head = connection.default_branch()?;
+
+    head.as_str()
+        .and_then(|head| head.strip_prefix("refs/heads/"))
+        .map(str::to_owned)
+        .ok_or(RemoteError::InvalidLocalState)
+}
+
+async fn git_clone(
+    auth: GitCreds,
+    ssh: Option<ssh::Ssh>,
+    url: &str,
+    target: &Path,
+    clone_options: &CloneOptions,
+) -> Result<()> {
     let url = url.to_owned();
     let target = target.to_owned();
+    let clone_options = clone_options.clone();
 
     tokio::task::spawn_blocking(move || {
-        let options = {
-            let mut callbacks = RemoteCallbacks::new();
-            callbacks.credentials(auth);
+        let auth = shared(auth);
+        let existed = target.exists();
+
+        failover(&clone_options.urls(&url), |from| {
+            // Only the branch that's checked out is fetched, so it must be known up front
+            let branch = match clone_options.single_branch {
+                true => Some(default_branch(auth(), ssh.clone(), from)?),
+                false => None,
+            };
+
+            let mut builder = git2::build::RepoBuilder::new();
+            if let Some(ref branch) = branch {
+                let fetched = branch.clone();
+                builder.branch(branch);
+                builder.remote_create(move |repo, name, url| {
+                    let refspec = format!("+refs/heads/{fetched}:refs/remotes/{name}/{fetched}");
+                    repo.remote_with_fetch(name, url, &refspec)
+                });
+            }
 
-            let mut fo = git2::FetchOptions::new();
-            fo.remote_callbacks(callbacks);
-            fo
-        };
+            let options = {
+                let mut fo = git2::FetchOptions::new();
+                fo.remote_callbacks(callbacks(auth(), ssh.clone()));
+                fo.proxy_options(proxy::git(from));
+                clone_options.fetch_options(&mut fo);
+                fo
+            };
+
+            builder.fetch_options(options);
+            if !clone_options.sparse.is_empty() {
+                builder.with_checkout(clone_options.checkout());
+            }
+
+            let git = builder.clone(from, &target).map_err(|err| {
+                // Leave nothing behind for the next remote to trip over
+                if !existed {
+                    _ = std::fs::remove_dir_all(&target);
+                }
+                err
+            })?;
 
-        let mut builder = git2::build::RepoBuilder::new();
-        builder.fetch_options(options);
-        builder.clone(&url, &target)
+            // Mirrors are only fetched from, `origin` is always the repository's own remote
+            if from != url {
+                git.remote_set_url("origin", &url)?;
+            }
+
+            Ok(())
+        })
     })
     .await
-    .expect("git failed")?;
-
-    Ok(())
+    .expect("git failed")
 }
 
-async fn git_pull(auth: GitCreds, repo: &Repository) -> Result<()> {
+async fn git_pull(
+    auth: GitCreds,
+    ssh: Option<ssh::Ssh>,
+    repo: &Repository,
+    clone_options: &CloneOptions,
+) -> Result<()> {
     let disk_path = repo.disk_path.to_owned();
+    let clone_options = clone_options.clone();
 
     tokio::task::spawn_blocking(move || {
         let git = git2::Repository::open(&disk_path)?;
@@ -113,17 +275,29 @@ async fn git_pull(auth: GitCreds, repo: &Repository) -> Result<()> {
             .last()
             .ok_or(RemoteError::InvalidLocalState)?;
 
-        let mut options = {
-            let mut callbacks = RemoteCallbacks::new();
-            callbacks.credentials(auth);
+        let auth = shared(auth);
+        let origin = git
+            .find_remote("origin")?
+            .url()
+            .ok_or(RemoteError::InvalidLocalState)?
+            .to_owned();
 
-            let mut fo = git2::FetchOptions::new();
-            fo.remote_callbacks(callbacks);
-            fo
-        };
+        failover(&clone_options.urls(&origin), |from| {
+            let mut options = {
+                let mut fo = git2::FetchOptions::new();
+                fo.remote_callbacks(callbacks(auth(), ssh.clone()));
+                fo.proxy_options(proxy::git(from));
+                clone_options.fetch_options(&mut fo);
+                fo
+            };
+
+            let mut remote = match from == origin {
+                true => git.find_remote("origin")?,
+                false => git.remote_anonymous(from)?,
+            };
 
-        let mut remote = git.find_remote("origin")?;
-        remote.fetch(&[&branch], Some(&mut options), None)?;
+            Ok(remote.fetch(&[&branch], Some(&mut options), None)?)
+        })?;
 
         let fetch_head = git.find_reference("FETCH_HEAD")?;
         let new_head = fetch_head.peel(git2::ObjectType::Commit)?;
@@ -131,7 +305,7 @@ async fn git_pull(auth: GitCreds, repo: &Repository) -> Result<()> {
         Ok::<_, RemoteError>(git.reset(
             &new_head,
             git2::ResetType::Hard,
-            Some(git2::build::CheckoutBuilder::new().force()),
+            Some(clone_options.checkout().force()),
         )?)
     })
     .await
@@ -248,24 +422,69 @@ impl Backends {
 	    return None;
 	};
 
-        let BackendCredential::Github(ref github) = handle.value().inner;
+        let BackendCredential::Github(ref github) = handle.value().inner else {
+            return None;
+        };
+
         Some(github.clone())
     }
 
     pub(crate) fn set_github(&self, gh: github::State) {
+        self.set(Backend::Github, BackendCredential::Github(gh));
+    }
+
+    pub(crate) fn gitlab(&self) -> Option<gitlab::State> {
+        let handle = self.backends.get(&Backend::Gitlab)?;
+        let BackendCredential::Gitlab(ref gitlab) = handle.value().inner else {
+            return None;
+        };
+
+        Some(gitlab.clone())
+    }
+
+    pub(crate) fn set_gitlab(&self, gl: gitlab::State) {
+        self.set(Backend::Gitlab, BackendCredential::Gitlab(gl));
+    }
+
+    pub(crate) fn bitbucket(&self) -> Option<bitbucket::State> {
+        let handle = self.backends.get(&Backend::Bitbucket)?;
+        let BackendCredential::Bitbucket(ref bitbucket) = handle.value().inner else {
+            return None;
+        };
+
+        Some(bitbucket.clone())
+    }
+
+    pub(crate) fn set_bitbucket(&self, bb: bitbucket::State) {
+        self.set(Backend::Bitbucket, BackendCredential::Bitbucket(bb));
+    }
+
+    pub(crate) fn azure(&self) -> Option<azure::State> {
+        let handle = self.backends.get(&Backend::AzureDevops)?;
+        let BackendCredential::AzureDevops(ref azure) = handle.value().inner else {
+            return None;
+        };
+
+        Some(azure.clone())
+    }
+
+    pub(crate) fn set_azure(&self, az: azure::State) {
+        self.set(Backend::AzureDevops, BackendCredential::AzureDevops(az));
+    }
+
+    fn set(&self, backend: Backend, cred: BackendCredential) {
         self.backends
-            .entry(Backend::Github)
+            .entry(backend)
             .and_modify(|existing| {
-                existing.inner = BackendCredential::Github(gh.clone());
+                existing.inner = cred.clone();
                 existing.updated_tx.send(()).unwrap();
             })
-            .or_insert_with(|| BackendCredential::Github(gh).into());
+            .or_insert_with(|| cred.into());
     }
 
-    pub(crate) fn github_updated(&self) -> Option<flume::Receiver<()>> {
-        self.backends
-            .get(&Backend::Github)
-            .map(|v| v.updated.clone())
+    /// Notified when the credentials of `backend` change
+    pub(crate) fn updated(&self, backend: &Backend) -> Option<flume::Receiver<()>> {
+        self.backends.get(backend).map(|v| v.updated.clone())
     }
 
     pub(crate) fn serialize(&self) -> DashMap<Backend, BackendCredential> {
@@ -276,37 +495,108 @@ impl Backends {
     }
 }
 
+/// Fail with [`RemoteError::Renamed`] if a remote, looked up by `address`, now goes by a
+/// different name. Hosts redirect requests for renamed and transferred repositories, and
+/// treat names case-insensitively.
+fn check_renamed(address: &str, canonical: &str) -> Result<()> {
+    match address.eq_ignore_ascii_case(canonical) {
+        true => Ok(()),
+        false => Err(RemoteError::Renamed(canonical.to_owned())),
+    }
+}
+
+/// Check out a tag or commit, detaching `HEAD` from the default branch
+async fn git_checkout(
+    disk_path: &Path,
+    revision: &str,
+    clone_options: &CloneOptions,
+) -> Result<()> {
+    let disk_path = disk_path.to_owned();
+    let revision = revision.to_owned();
+    let clone_options = clone_options.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let git = git2::Repository::open(&disk_path)?;
+        let commit = git
+            .revparse_single(&revision)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|_| RemoteError::RevisionNotFound(revision))?;
+
+        git.checkout_tree(commit.as_object(), Some(clone_options.checkout().force()))?;
+
+        Ok::<_, RemoteError>(git.set_head_detached(commit.id())?)
+    })
+    .await
+    .expect("git failed")
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) enum BackendCredential {
     Github(github::State),
+    Gitlab(gitlab::State),
+    Bitbucket(bitbucket::State),
+    AzureDevops(azure::State),
 }
 
 impl BackendCredential {
     pub(crate) async fn sync(self, app: Application, repo_ref: RepoRef) -> Result<()> {
-        use BackendCredential::*;
+        let secrets = app.secrets.for_repo(&repo_ref);
+        // Secrets set for the repository take precedence over the backend's
+        let ssh = ssh::Ssh::from_config(&app.config).map(|ssh| match secrets.ssh_key_passphrase {
+            Some(passphrase) => ssh.with_passphrase(passphrase),
+            None => ssh,
+        });
+
+        let creds = match secrets.token {
+            Some(token) => self.with_token(token),
+            None => self,
+        };
+
+        let options = CloneOptions::for_repo(&app.config.repo_clone_options, &repo_ref);
 
+        // Held until the fetch is over, including any retry. This is waited for before
+        // taking the pool entry, which would otherwise lock out the syncs holding a slot.
+        let transfer = Throttle::transfer().await;
         let existing = app.repo_pool.get_mut(&repo_ref);
-        let synced = match existing {
+        let (repo, clone) = match existing {
             // if there's a parallel process already syncing, just return
             Some(repo) if repo.sync_status == SyncStatus::Syncing => {
                 return Err(RemoteError::SyncInProgress)
             }
             Some(mut repo) => {
                 repo.value_mut().sync_status = SyncStatus::Syncing;
-                let repo = repo.downgrade();
-
-                match self {
-                    Github(gh) => gh.auth.pull_repo(&repo).await,
-                }
+                (repo.downgrade(), false)
             }
             None => {
-                let repo = create_repository(&app, &repo_ref);
+                let remote = creds.remote(&repo_ref.unpinned(), ssh.is_some());
+                (create_repository(&app, &repo_ref, remote), true)
+            }
+        };
+
+        let mut synced = match (clone, repo_ref.revision()) {
+            // Pinned repositories don't change once they're checked out
+            (false, Some(_)) => Ok(()),
+            _ => creds.fetch(&repo, clone, ssh.clone(), &options).await,
+        };
 
-                match self {
-                    Github(gh) => gh.auth.clone_repo(&repo, &repo.disk_path.clone()).await,
+        // Tokens may have expired since they were last refreshed, so retry once with fresh ones
+        if let Err(ref err) = synced {
+            if err.is_unauthorized() {
+                if let Some(refreshed) = creds.refreshed(&app).await {
+                    info!(%repo_ref, "retrying sync with refreshed credentials");
+                    synced = refreshed.fetch(&repo, clone, ssh, &options).await;
                 }
             }
-        };
+        }
+
+        drop(transfer);
+
+        if let (true, Some(revision)) = (clone && synced.is_ok(), repo_ref.revision()) {
+            synced = git_checkout(&repo.disk_path, revision, &options).await;
+        }
+
+        // release the pool entry before updating it below
+        drop(repo);
 
         let new_status = match synced {
             Ok(_) => SyncStatus::Queued,
@@ -315,34 +605,123 @@ impl BackendCredential {
             },
         };
 
-        app.repo_pool
-            .get_mut(&repo_ref)
-            .unwrap()
-            .value_mut()
-            .sync_status = new_status;
+        // The repository may have been removed while it was syncing
+        if let Some(mut repo) = app.repo_pool.get_mut(&repo_ref) {
+            repo.value_mut().sync_status = new_status;
+        }
 
         synced
     }
-}
 
-fn create_repository<'a>(app: &'a Application, reporef: &RepoRef) -> Ref<'a, RepoRef, Repository> {
-    let name = reporef.to_string();
-    let disk_path = app
-        .config
-        .source
-        .repo_path_for_name(&name.replace('/', "_"));
+    /// The same backend, authenticating with a token set for one repository
+    fn with_token(self, token: SecretString) -> Self {
+        use BackendCredential::*;
+
+        match self {
+            Github(gh) => Github(gh.with_token(token)),
+            Gitlab(gl) => Gitlab(gl.with_token(token)),
+            Bitbucket(bb) => Bitbucket(bb.with_token(token)),
+            AzureDevops(az) => AzureDevops(az.with_token(token)),
+        }
+    }
+
+    /// Clone a new repository, or pull into an existing one
+    async fn fetch(
+        &self,
+        repo: &Repository,
+        clone: bool,
+        ssh: Option<ssh::Ssh>,
+        options: &CloneOptions,
+    ) -> Result<()> {
+        use BackendCredential::*;
+
+        if clone {
+            let target = &repo.disk_path;
+            match self {
+                Github(gh) => gh.auth.clone_repo(repo, target, ssh, options).await,
+                Gitlab(gl) => gl.clone_repo(repo, target, ssh, options).await,
+                Bitbucket(bb) => bb.clone_repo(repo, target, ssh, options).await,
+                AzureDevops(az) => az.clone_repo(repo, target, ssh, options).await,
+            }
+        } else {
+            match self {
+                Github(gh) => gh.auth.pull_repo(repo, ssh, options).await,
+                Gitlab(gl) => gl.pull_repo(repo, ssh, options).await,
+                Bitbucket(bb) => bb.pull_repo(repo, ssh, options).await,
+                AzureDevops(az) => az.pull_repo(repo, ssh, options).await,
+            }
+        }
+    }
+
+    /// Refresh credentials that expire, returning the new ones.
+    ///
+    /// Tokens that are configured, or granted through OAuth without a refresh token, don't
+    /// expire, and are never refreshed.
+    async fn refreshed(&self, app: &Application) -> Option<Self> {
+        let BackendCredential::Github(github::State { auth, .. }) = self else {
+            return None;
+        };
+
+        let refreshed = match auth {
+            github::Auth::App { .. } => github::refresh_github_installation_token(app).await,
+            github::Auth::OAuth {
+                refresh_token: Some(_),
+                ..
+            } => github::refresh_oauth_token(app).await,
+            github::Auth::OAuth { .. } => return None,
+        };
+
+        if let Err(err) = refreshed {
+            error!(?err, "failed to refresh GitHub token");
+            return None;
+        }
 
-    let remote = reporef.as_ref().into();
+        app.credentials.github().map(BackendCredential::Github)
+    }
+
+    /// The remote to clone a new repository from, over SSH if `ssh` is set and the backend
+    /// supports it
+    fn remote(&self, reporef: &RepoRef, ssh: bool) -> RepoRemote {
+        match self {
+            BackendCredential::Github(_) if ssh => RepoRemote::Git(GitRemote {
+                protocol: GitProtocol::Ssh,
+                host: "github.com".to_owned(),
+                address: reporef.name().to_owned(),
+            }),
+            BackendCredential::Github(_) => reporef.into(),
+            BackendCredential::Gitlab(gl) => gl.remote(reporef, ssh),
+            BackendCredential::Bitbucket(bb) => bb.remote(reporef, ssh),
+            BackendCredential::AzureDevops(az) => az.remote(reporef),
+        }
+    }
+}
+
+fn create_repository<'a>(
+    app: &'a Application,
+    reporef: &RepoRef,
+    remote: RepoRemote,
+) -> Ref<'a, RepoRef, Repository> {
+    let disk_path = app.config.source.repo_disk_path(reporef);
 
     app.repo_pool
         .entry(reporef.clone())
-        .or_insert_with(|| Repository {
-            disk_path,
-            remote,
-            sync_status: SyncStatus::Syncing,
-            last_index_unix_secs: 0,
-            last_commit_unix_secs: 0,
-            most_common_lang: None,
+        .or_insert_with(|| {
+            RepoEvent::Added {
+                reporef: reporef.clone(),
+            }
+            .emit();
+
+            Repository {
+                disk_path,
+                remote,
+                sync_status: SyncStatus::Syncing,
+                last_index_unix_secs: 0,
+                last_commit_unix_secs: 0,
+                last_commit_hash: None,
+                disk_usage_bytes: 0,
+                most_common_lang: None,
+                groups: Default::default(),
+            }
         })
         .downgrade()
 }
@@ -0,0 +1,198 @@
+use reqwest::StatusCode;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    repo::{GitProtocol, GitRemote, RepoRemote},
+    Configuration,
+};
+
+use super::*;
+
+const API_VERSION: &str = "7.0";
+
+/// Azure DevOps ignores the user name when authenticating with a personal access token
+const PAT_USER: &str = "pat";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct State {
+    /// Organization URL, e.g. `https://dev.azure.com/org`, or collection URL on Azure DevOps
+    /// Server
+    pub url: String,
+    /// Personal access token
+    #[serde(serialize_with = "crate::config::serialize_secret_str")]
+    token: SecretString,
+    /// Projects to list repositories in. If empty, every project in the organization is used.
+    #[serde(default)]
+    projects: Vec<String>,
+    #[serde(skip)]
+    pub repositories: Arc<Vec<Repo>>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Repo {
+    pub name: String,
+    pub project: Project,
+    /// HTTPS clone URL
+    pub remote_url: String,
+    pub ssh_url: Option<String>,
+    /// Disabled repositories can't be cloned
+    #[serde(default)]
+    pub is_disabled: bool,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct Project {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+struct List<T> {
+    value: Vec<T>,
+}
+
+impl Repo {
+    /// `project/repo`, which names the repository within its organization
+    pub(crate) fn full_name(&self) -> String {
+        format!("{}/{}", self.project.name, self.name)
+    }
+}
+
+impl State {
+    /// The same organization, authenticating with `token` instead
+    pub(super) fn with_token(self, token: SecretString) -> Self {
+        Self { token, ..self }
+    }
+
+    pub(crate) fn from_config(config: &Configuration) -> Option<Self> {
+        Some(Self {
+            url: config
+                .azure_devops_url
+                .as_deref()?
+                .trim_end_matches('/')
+                .to_owned(),
+            token: config.azure_devops_token.clone()?,
+            projects: config.azure_devops_projects.clone(),
+            repositories: Arc::default(),
+        })
+    }
+
+    pub(crate) fn remote(&self, reporef: &RepoRef) -> RepoRemote {
+        let (project, repo) = reporef.name().split_once('/').unwrap_or_default();
+
+        RepoRemote::Git(GitRemote {
+            protocol: GitProtocol::Https,
+            host: self
+                .url
+                .trim_start_matches("https://")
+                .trim_start_matches("http://")
+                .to_owned(),
+            address: format!("{project}/_git/{repo}"),
+        })
+    }
+
+    /// Get a representative list of repositories currently accessible
+    pub(crate) async fn current_repo_list(&self) -> Result<Vec<Repo>> {
+        let mut repos: Vec<Repo> = vec![];
+
+        if self.projects.is_empty() {
+            repos = self.list("_apis/git/repositories").await?;
+        } else {
+            for project in &self.projects {
+                repos.extend(
+                    self.list(&format!("{project}/_apis/git/repositories"))
+                        .await?,
+                );
+            }
+        }
+
+        repos.retain(|repo| !repo.is_disabled);
+        Ok(repos)
+    }
+
+    /// Create a new object with the updated repository list
+    pub(crate) fn update_repositories(self, repos: Vec<Repo>) -> Self {
+        Self {
+            repositories: repos.into(),
+            ..self
+        }
+    }
+
+    pub(crate) async fn clone_repo(
+        &self,
+        repo: &Repository,
+        target: &Path,
+        ssh: Option<ssh::Ssh>,
+        options: &CloneOptions,
+    ) -> Result<()> {
+        // Azure DevOps doesn't accept the `.git` suffix our remotes are displayed with, so
+        // clone from the URLs it gives us instead
+        let origin = self.check_repo(repo).await?;
+        let url = match (&ssh, origin.ssh_url) {
+            (Some(_), Some(ssh_url)) => ssh_url,
+            _ => origin.remote_url,
+        };
+
+        git_clone(self.git_cred(), ssh, &url, target, options).await
+    }
+
+    pub(crate) async fn pull_repo(
+        &self,
+        repo: &Repository,
+        ssh: Option<ssh::Ssh>,
+        options: &CloneOptions,
+    ) -> Result<()> {
+        self.check_repo(repo).await?;
+        git_pull(self.git_cred(), ssh, repo, options).await
+    }
+
+    async fn check_repo(&self, repo: &Repository) -> Result<Repo> {
+        let RepoRemote::Git(GitRemote { ref address, .. }) = repo.remote else {
+            return Err(RemoteError::NotSupported(
+                "azure devops without git backend",
+            ));
+        };
+
+        let Some((project, name)) = address.split_once("/_git/") else {
+            return Err(RemoteError::RemoteNotFound);
+        };
+
+        let origin: Repo = self
+            .get(&format!("{project}/_apis/git/repositories/{name}"))
+            .await?;
+
+        if origin.is_disabled {
+            return Err(RemoteError::PermissionDenied);
+        }
+
+        Ok(origin)
+    }
+
+    fn git_cred(&self) -> GitCreds {
+        let token = self.token.clone();
+        Box::new(move |_, _, _| Cred::userpass_plaintext(PAT_USER, token.expose_secret()))
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let response = crate::proxy::client()
+            .get(format!("{}/{path}", self.url))
+            .basic_auth("", Some(self.token.expose_secret()))
+            .query(&[("api-version", API_VERSION)])
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Err(RemoteError::RemoteNotFound),
+            // Invalid tokens get a sign in page, rather than a 401
+            StatusCode::UNAUTHORIZED
+            | StatusCode::FORBIDDEN
+            | StatusCode::NON_AUTHORITATIVE_INFORMATION => Err(RemoteError::PermissionDenied),
+            _ => Ok(response.error_for_status()?.json().await?),
+        }
+    }
+
+    async fn list<T: DeserializeOwned>(&self, path: &str) -> Result<Vec<T>> {
+        Ok(self.get::<List<T>>(path).await?.value)
+    }
+}
@@ -0,0 +1,355 @@
+use chrono::{DateTime, Utc};
+use reqwest::{RequestBuilder, StatusCode};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    repo::{GitProtocol, GitRemote, RepoRemote},
+    Configuration,
+};
+
+use super::*;
+
+const CLOUD_API: &str = "https://api.bitbucket.org/2.0";
+const CLOUD_HOST: &str = "bitbucket.org";
+const PAGE_SIZE: &str = "100";
+
+/// Git username for access tokens, which aren't tied to a user
+const TOKEN_USER: &str = "x-token-auth";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct State {
+    /// Base URL of a Bitbucket Server instance. Bitbucket Cloud is used if unset.
+    pub url: Option<String>,
+    /// User the app password belongs to. Access tokens are used if unset.
+    username: Option<String>,
+    /// App password, or repository, project, or workspace access token
+    #[serde(serialize_with = "crate::config::serialize_secret_str")]
+    token: SecretString,
+    /// Workspaces on Cloud, or project keys on Server, to list repositories in. If empty,
+    /// every repository the credentials can access is used.
+    #[serde(default)]
+    workspaces: Vec<String>,
+    #[serde(skip)]
+    pub repositories: Arc<Vec<Repo>>,
+}
+
+/// A repository on either Bitbucket Cloud or Server
+#[derive(Clone, Debug)]
+pub(crate) struct Repo {
+    /// `workspace/slug` on Cloud, `PROJECT/slug` on Server
+    pub full_name: String,
+    /// Clone URLs, without any user names
+    pub clone_urls: Vec<String>,
+    /// Only known on Cloud
+    pub updated_on: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct Links {
+    clone: Vec<CloneLink>,
+}
+
+#[derive(Deserialize)]
+struct CloneLink {
+    href: String,
+}
+
+impl Links {
+    fn clone_urls(self) -> Vec<String> {
+        self.clone
+            .into_iter()
+            .map(|link| without_user(&link.href))
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+struct CloudPage<T> {
+    values: Vec<T>,
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CloudRepo {
+    full_name: String,
+    updated_on: DateTime<Utc>,
+    links: Links,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerPage<T> {
+    values: Vec<T>,
+    is_last_page: bool,
+    next_page_start: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ServerRepo {
+    slug: String,
+    project: ServerProject,
+    links: Links,
+}
+
+#[derive(Deserialize)]
+struct ServerProject {
+    key: String,
+}
+
+impl From<CloudRepo> for Repo {
+    fn from(repo: CloudRepo) -> Self {
+        Repo {
+            full_name: repo.full_name,
+            clone_urls: repo.links.clone_urls(),
+            updated_on: Some(repo.updated_on),
+        }
+    }
+}
+
+impl From<ServerRepo> for Repo {
+    fn from(repo: ServerRepo) -> Self {
+        Repo {
+            full_name: format!("{}/{}", repo.project.key, repo.slug),
+            clone_urls: repo.links.clone_urls(),
+            updated_on: None,
+        }
+    }
+}
+
+/// Bitbucket includes the user name in HTTPS clone links, which our remotes don't have
+fn without_user(url: &str) -> String {
+    match url.strip_prefix("https://").and_then(|u| u.split_once('@')) {
+        Some((user, rest)) if !user.contains('/') => format!("https://{rest}"),
+        _ => url.to_owned(),
+    }
+}
+
+impl State {
+    /// The same workspace, authenticating with an access `token` instead
+    pub(super) fn with_token(self, token: SecretString) -> Self {
+        Self {
+            username: None,
+            token,
+            ..self
+        }
+    }
+
+    pub(crate) fn from_config(config: &Configuration) -> Option<Self> {
+        Some(Self {
+            url: config
+                .bitbucket_url
+                .as_deref()
+                .map(|url| url.trim_end_matches('/').to_owned()),
+            username: config.bitbucket_username.clone(),
+            token: config.bitbucket_token.clone()?,
+            workspaces: config.bitbucket_workspaces.clone(),
+            repositories: Arc::default(),
+        })
+    }
+
+    /// Bitbucket Server serves SSH on its own port, which remotes can't express, so it's
+    /// always cloned from over HTTPS
+    pub(crate) fn remote(&self, reporef: &RepoRef, ssh: bool) -> RepoRemote {
+        let (protocol, host) = match self.url {
+            Some(ref url) => (
+                GitProtocol::Https,
+                format!(
+                    "{}/scm",
+                    url.trim_start_matches("https://")
+                        .trim_start_matches("http://")
+                ),
+            ),
+            None if ssh => (GitProtocol::Ssh, CLOUD_HOST.to_owned()),
+            None => (GitProtocol::Https, CLOUD_HOST.to_owned()),
+        };
+
+        RepoRemote::Git(GitRemote {
+            protocol,
+            host,
+            address: reporef.name().to_owned(),
+        })
+    }
+
+    /// Get a representative list of repositories currently accessible
+    pub(crate) async fn current_repo_list(&self) -> Result<Vec<Repo>> {
+        let mut repos = vec![];
+
+        match (&self.url, self.workspaces.is_empty()) {
+            (None, true) => {
+                repos = self
+                    .list_cloud(format!("{CLOUD_API}/repositories?role=member"))
+                    .await?
+            }
+            (None, false) => {
+                for workspace in &self.workspaces {
+                    repos.extend(
+                        self.list_cloud(format!("{CLOUD_API}/repositories/{workspace}"))
+                            .await?,
+                    );
+                }
+            }
+            (Some(url), true) => {
+                repos = self
+                    .list_server(format!("{url}/rest/api/1.0/repos"))
+                    .await?
+            }
+            (Some(url), false) => {
+                for project in &self.workspaces {
+                    repos.extend(
+                        self.list_server(format!("{url}/rest/api/1.0/projects/{project}/repos"))
+                            .await?,
+                    );
+                }
+            }
+        }
+
+        Ok(repos)
+    }
+
+    /// Create a new object with the updated repository list
+    pub(crate) fn update_repositories(self, repos: Vec<Repo>) -> Self {
+        Self {
+            repositories: repos.into(),
+            ..self
+        }
+    }
+
+    pub(crate) async fn clone_repo(
+        &self,
+        repo: &Repository,
+        target: &Path,
+        ssh: Option<ssh::Ssh>,
+        options: &CloneOptions,
+    ) -> Result<()> {
+        self.check_repo(repo).await?;
+        git_clone(
+            self.git_cred(),
+            ssh,
+            &repo.remote.to_string(),
+            target,
+            options,
+        )
+        .await
+    }
+
+    pub(crate) async fn pull_repo(
+        &self,
+        repo: &Repository,
+        ssh: Option<ssh::Ssh>,
+        options: &CloneOptions,
+    ) -> Result<()> {
+        self.check_repo(repo).await?;
+        git_pull(self.git_cred(), ssh, repo, options).await
+    }
+
+    async fn check_repo(&self, repo: &Repository) -> Result<()> {
+        let RepoRemote::Git(GitRemote { ref address, .. }) = repo.remote else {
+            return Err(RemoteError::NotSupported("bitbucket without git backend"));
+        };
+
+        let origin: Repo = match self.url {
+            Some(ref url) => {
+                let Some((project, slug)) = address.split_once('/') else {
+                    return Err(RemoteError::RemoteNotFound);
+                };
+
+                let url = format!("{url}/rest/api/1.0/projects/{project}/repos/{slug}");
+                self.json::<ServerRepo>(self.request(&url)).await?.into()
+            }
+            None => {
+                let url = format!("{CLOUD_API}/repositories/{address}");
+                self.json::<CloudRepo>(self.request(&url)).await?.into()
+            }
+        };
+
+        check_renamed(address, &origin.full_name)
+    }
+
+    fn git_cred(&self) -> GitCreds {
+        let username = self.username.as_deref().unwrap_or(TOKEN_USER).to_owned();
+        let token = self.token.clone();
+        Box::new(move |_, _, _| Cred::userpass_plaintext(&username, token.expose_secret()))
+    }
+
+    fn request(&self, url: &str) -> RequestBuilder {
+        let request = crate::proxy::client().get(url);
+        match self.username {
+            Some(ref username) => request.basic_auth(username, Some(self.token.expose_secret())),
+            None => request.bearer_auth(self.token.expose_secret()),
+        }
+    }
+
+    async fn get(&self, request: RequestBuilder) -> Result<reqwest::Response> {
+        let response = request.send().await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Err(RemoteError::RemoteNotFound),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(RemoteError::PermissionDenied),
+            _ => Ok(response.error_for_status()?),
+        }
+    }
+
+    async fn json<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T> {
+        Ok(self.get(request).await?.json().await?)
+    }
+
+    /// Follow the `next` links of Bitbucket Cloud pages
+    async fn list_cloud(&self, url: String) -> Result<Vec<Repo>> {
+        let mut repos = vec![];
+        let mut next = Some(url);
+
+        // Only the first page takes a size, later links already carry it
+        let mut query = vec![("pagelen", PAGE_SIZE)];
+        while let Some(url) = next {
+            let page: CloudPage<CloudRepo> = self.json(self.request(&url).query(&query)).await?;
+
+            repos.extend(page.values.into_iter().map(Repo::from));
+            next = page.next;
+            query.clear();
+        }
+
+        Ok(repos)
+    }
+
+    /// Follow the `nextPageStart` offsets of Bitbucket Server pages
+    async fn list_server(&self, url: String) -> Result<Vec<Repo>> {
+        let mut repos = vec![];
+        let mut start = 0;
+
+        loop {
+            let start_param = start.to_string();
+            let query = [("limit", PAGE_SIZE), ("start", &start_param)];
+            let page: ServerPage<ServerRepo> = self.json(self.request(&url).query(&query)).await?;
+
+            repos.extend(page.values.into_iter().map(Repo::from));
+            match page.next_page_start {
+                Some(next) if !page.is_last_page => start = next,
+                _ => break,
+            }
+        }
+
+        Ok(repos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clone_urls_without_user() {
+        assert_eq!(
+            without_user("https://someone@bitbucket.org/workspace/repo.git"),
+            "https://bitbucket.org/workspace/repo.git"
+        );
+        assert_eq!(
+            without_user("https://bitbucket.example.com/scm/proj/repo.git"),
+            "https://bitbucket.example.com/scm/proj/repo.git"
+        );
+        assert_eq!(
+            without_user("ssh://git@bitbucket.example.com:7999/proj/repo.git"),
+            "ssh://git@bitbucket.example.com:7999/proj/repo.git"
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Per-repository settings for cloning and fetching: limiting what's cloned and checked out,
+//! for repositories where only part of the history or the tree matters, and mirrors to fetch
+//! from.
+
+use std::collections::HashMap;
+
+use git2::{build::CheckoutBuilder, FetchOptions};
+use serde::{Deserialize, Serialize};
+
+use crate::repo::RepoRef;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct CloneOptions {
+    /// Fetch only this many commits of history. Revisions that repositories are pinned to must
+    /// be within this depth.
+    pub depth: Option<u32>,
+    /// Fetch only the default branch
+    pub single_branch: bool,
+    /// Check out only the paths matching these pathspecs, e.g. `src/` or `*.rs`. Everything is
+    /// checked out if empty.
+    pub sparse: Vec<String>,
+    /// Mirrors to fetch from before the repository's own remote, in order. Each is tried in
+    /// turn until one succeeds, with the same credentials.
+    pub mirrors: Vec<String>,
+}
+
+impl CloneOptions {
+    /// Options for a repository, falling back to those of the repository it's pinned from
+    pub(crate) fn for_repo(options: &HashMap<String, Self>, reporef: &RepoRef) -> Self {
+        options
+            .get(&reporef.to_string())
+            .or_else(|| options.get(&reporef.unpinned().to_string()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// URLs to fetch from, in order of preference
+    pub(super) fn urls(&self, remote: &str) -> Vec<String> {
+        self.mirrors
+            .iter()
+            .cloned()
+            .chain(Some(remote.to_owned()))
+            .collect()
+    }
+
+    pub(super) fn fetch_options(&self, fo: &mut FetchOptions<'_>) {
+        if let Some(depth) = self.depth {
+            fo.depth(depth.try_into().unwrap_or(i32::MAX));
+        }
+    }
+
+    pub(super) fn checkout(&self) -> CheckoutBuilder<'static> {
+        let mut checkout = CheckoutBuilder::new();
+        for path in &self.sparse {
+            checkout.path(path.as_str());
+        }
+
+        checkout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::Backend;
+
+    #[test]
+    fn pinned_repos_share_options() {
+        let options = CloneOptions {
+            depth: Some(1),
+            ..Default::default()
+        };
+        let config = [("github.com/org/repo".to_owned(), options.clone())].into();
+
+        let pinned = RepoRef::new(Backend::Github, "org/repo@v1.0").unwrap();
+        let other = RepoRef::new(Backend::Github, "org/other").unwrap();
+        assert_eq!(CloneOptions::for_repo(&config, &pinned), options);
+        assert_eq!(
+            CloneOptions::for_repo(&config, &other),
+            CloneOptions::default()
+        );
+    }
+
+    #[test]
+    fn mirrors_before_remote() {
+        let options = CloneOptions {
+            mirrors: vec!["https://mirror.internal/org/repo.git".into()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            options.urls("https://github.com/org/repo.git"),
+            [
+                "https://mirror.internal/org/repo.git",
+                "https://github.com/org/repo.git"
+            ]
+        );
+    }
+}
@@ -12,6 +12,91 @@ use crate::repo::{GitRemote, RepoRemote, Repository};
 
 use super::*;
 
+/// Repositories the installation was granted access to
+#[derive(Deserialize)]
+struct InstallationRepositories {
+    repositories: Vec<octocrab::models::Repository>,
+}
+
+/// How far apart two branches are
+#[derive(Deserialize)]
+struct Comparison {
+    ahead_by: u64,
+}
+
+#[derive(Deserialize)]
+struct InstallationSummary {
+    id: u64,
+}
+
+const ACCESS_TOKEN_URL: &str = "https://github.com/login/oauth/access_token";
+
+/// GitHub's answer to a request for a user access token
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum TokenResponse {
+    Token {
+        access_token: SecretString,
+        token_type: String,
+        /// Comma-separated list of the scopes granted
+        #[serde(default)]
+        scope: String,
+        /// Seconds until the access token expires, if the app expires its tokens
+        expires_in: Option<i64>,
+        refresh_token: Option<SecretString>,
+    },
+    Error {
+        error: String,
+        error_description: Option<String>,
+    },
+}
+
+impl TokenResponse {
+    /// The credentials granted at `now`, or the error GitHub answered with
+    pub(crate) fn into_auth(self, now: DateTime<Utc>) -> Result<Auth> {
+        match self {
+            TokenResponse::Token {
+                access_token,
+                token_type,
+                scope,
+                expires_in,
+                refresh_token,
+            } => Ok(Auth::OAuth {
+                access_token,
+                token_type,
+                scope: scope
+                    .split(',')
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+                refresh_token,
+                expiry: expires_in.map(|secs| now + chrono::Duration::seconds(secs)),
+            }),
+            TokenResponse::Error {
+                error,
+                error_description,
+            } => Err(RemoteError::OAuth(match error_description {
+                Some(description) => format!("{error}: {description}"),
+                None => error,
+            })),
+        }
+    }
+}
+
+/// Ask GitHub for a user access token in exchange for `grant`: the device code of a login, or
+/// the refresh token of an expiring access token
+pub(crate) async fn request_token(grant: &[(&str, &str)]) -> Result<TokenResponse> {
+    Ok(crate::proxy::client()
+        .post(ACCESS_TOKEN_URL)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .form(grant)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct State {
     pub auth: Auth,
@@ -20,13 +105,27 @@ pub(crate) struct State {
 }
 
 impl State {
-    fn with_auth(auth: Auth) -> Self {
+    pub(crate) fn with_auth(auth: Auth) -> Self {
         Self {
             auth,
             repositories: Arc::default(),
         }
     }
 
+    /// The same installation, authenticating with a personal access `token` instead
+    pub(super) fn with_token(self, token: SecretString) -> Self {
+        Self {
+            auth: Auth::OAuth {
+                access_token: token,
+                token_type: "bearer".to_owned(),
+                scope: vec![],
+                refresh_token: None,
+                expiry: None,
+            },
+            ..self
+        }
+    }
+
     pub fn client(&self) -> octocrab::Result<Octocrab> {
         self.auth.client()
     }
@@ -53,7 +152,7 @@ impl State {
     pub(crate) fn expiry(&self) -> Option<DateTime<Utc>> {
         match self.auth {
             Auth::App { expiry, .. } => Some(expiry),
-            _ => None,
+            Auth::OAuth { expiry, .. } => expiry,
         }
     }
 
@@ -82,6 +181,11 @@ pub(crate) enum Auth {
         access_token: SecretString,
         token_type: String,
         scope: Vec<String>,
+        /// Renews the access token, if it expires
+        #[serde(default, serialize_with = "crate::config::serialize_secret_opt_str")]
+        refresh_token: Option<SecretString>,
+        #[serde(default)]
+        expiry: Option<DateTime<Utc>>,
     },
     /// Github App installation token.
     App {
@@ -95,16 +199,6 @@ pub(crate) enum Auth {
     },
 }
 
-impl From<octocrab::auth::OAuth> for State {
-    fn from(auth: octocrab::auth::OAuth) -> Self {
-        Self::with_auth(Auth::OAuth {
-            access_token: auth.access_token,
-            token_type: auth.token_type,
-            scope: auth.scope,
-        })
-    }
-}
-
 impl Auth {
     pub async fn from_installation(
         install: Installation,
@@ -127,20 +221,36 @@ impl Auth {
 }
 
 impl Auth {
-    pub(crate) async fn clone_repo(&self, repo: &Repository, target: &Path) -> Result<()> {
+    pub(crate) async fn clone_repo(
+        &self,
+        repo: &Repository,
+        target: &Path,
+        ssh: Option<ssh::Ssh>,
+        options: &CloneOptions,
+    ) -> Result<()> {
         self.check_repo(repo).await?;
-        git_clone(self.git_cred(), &repo.remote.to_string(), target).await
+        git_clone(
+            self.git_cred(),
+            ssh,
+            &repo.remote.to_string(),
+            target,
+            options,
+        )
+        .await
     }
 
-    pub(crate) async fn pull_repo(&self, repo: &Repository) -> Result<()> {
+    pub(crate) async fn pull_repo(
+        &self,
+        repo: &Repository,
+        ssh: Option<ssh::Ssh>,
+        options: &CloneOptions,
+    ) -> Result<()> {
         self.check_repo(repo).await?;
-        git_pull(self.git_cred(), repo).await
+        git_pull(self.git_cred(), ssh, repo, options).await
     }
 
     pub async fn check_repo(&self, repo: &Repository) -> Result<()> {
-        let RepoRemote::Git(GitRemote {
-            ref address, ..
-        }) = repo.remote else {
+        let RepoRemote::Git(GitRemote { ref address, .. }) = repo.remote else {
             return Err(RemoteError::NotSupported("github without git backend"));
         };
 
@@ -163,7 +273,13 @@ impl Auth {
                 Some(StatusCode::FORBIDDEN) => Err(RemoteError::PermissionDenied),
                 _ => Ok(response.map(|_| ())?),
             },
-            _ => Ok(response.map(|_| ())?),
+            // Requests for renamed and transferred repositories are redirected to their new
+            // name
+            Ok(origin) => match origin.full_name {
+                Some(ref full_name) => check_renamed(address, full_name),
+                None => Ok(()),
+            },
+            Err(err) => Err(err.into()),
         }
     }
 
@@ -186,6 +302,7 @@ impl Auth {
                 access_token,
                 token_type,
                 scope,
+                ..
             } => {
                 let token = octocrab::auth::OAuth {
                     access_token,
@@ -215,14 +332,21 @@ impl Auth {
                         .send()
                         .await
                 }
-                remotes::github::Auth::App { ref org, .. } => {
-                    gh_client
-                        .orgs(org)
-                        .list_repos()
-                        .per_page(100)
-                        .page(page)
-                        .send()
-                        .await
+                // Installations can be limited to some of the organization's repositories
+                remotes::github::Auth::App { .. } => {
+                    let resp: InstallationRepositories = gh_client
+                        .get(
+                            "installation/repositories",
+                            Some(&[("per_page", 100), ("page", page)]),
+                        )
+                        .await?;
+
+                    if resp.repositories.is_empty() {
+                        break;
+                    }
+
+                    results.extend(resp.repositories);
+                    continue;
                 }
             }?;
 
@@ -235,6 +359,94 @@ impl Auth {
 
         Ok(results)
     }
+
+    /// Every repository of an organization the credentials can see.
+    ///
+    /// Listings don't say what forks were forked from, so each fork is looked up.
+    pub(crate) async fn org_repos(&self, org: &str) -> Result<Vec<OrgRepo>> {
+        let gh_client = self.client()?;
+        let mut results = vec![];
+        for page in 1.. {
+            let repos: Vec<octocrab::models::Repository> = gh_client
+                .get(
+                    format!("orgs/{org}/repos"),
+                    Some(&[("per_page", 100), ("page", page)]),
+                )
+                .await
+                .map_err(|err| match err {
+                    octocrab::Error::GitHub { ref source, .. } if source.message == "Not Found" => {
+                        RemoteError::RemoteNotFound
+                    }
+                    err => err.into(),
+                })?;
+
+            if repos.is_empty() {
+                break;
+            }
+
+            for repo in repos {
+                let Some(Ok(reporef)) = repo
+                    .full_name
+                    .as_deref()
+                    .map(|name| RepoRef::new(Backend::Github, name))
+                else {
+                    continue;
+                };
+
+                let fork_of = match repo.fork {
+                    Some(true) => gh_client
+                        .repos(org, &repo.name)
+                        .get()
+                        .await?
+                        .parent
+                        .and_then(|parent| parent.full_name)
+                        .and_then(|name| RepoRef::new(Backend::Github, &name).ok()),
+                    _ => None,
+                };
+
+                results.push(OrgRepo {
+                    reporef,
+                    archived: repo.archived.unwrap_or_default(),
+                    language: repo
+                        .language
+                        .as_ref()
+                        .and_then(|l| l.as_str())
+                        .map(str::to_lowercase),
+                    fork_of,
+                });
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Whether a fork has commits on its default branch that its parent's doesn't
+    pub(crate) async fn diverged_from_parent(&self, fork: &str) -> Result<bool> {
+        let (owner, name) = fork
+            .split_once('/')
+            .ok_or(RemoteError::NotSupported("invalid repo address"))?;
+
+        let gh_client = self.client()?;
+        let repo = gh_client.repos(owner, name).get().await?;
+
+        let (Some(branch), Some(parent)) = (repo.default_branch, repo.parent) else {
+            return Ok(true);
+        };
+
+        let (Some(parent_name), Some(parent_branch)) = (parent.full_name, parent.default_branch)
+        else {
+            return Ok(true);
+        };
+
+        let comparison: Comparison = gh_client
+            .get(
+                format!("repos/{parent_name}/compare/{parent_branch}...{owner}:{branch}"),
+                None::<&()>,
+            )
+            .await?;
+
+        Ok(comparison.ahead_by > 0)
+    }
 }
 
 pub(crate) async fn refresh_github_installation_token(app: &Application) -> Result<()> {
@@ -245,11 +457,6 @@ pub(crate) async fn refresh_github_installation_token(app: &Application) -> Resu
             .ok_or(RemoteError::Configuration("github_app_private_key"))?,
     )?;
 
-    let install_id = app
-        .config
-        .github_app_install_id
-        .ok_or(RemoteError::Configuration("github_app_install_id"))?;
-
     let octocrab = Octocrab::builder()
         .app(
             app.config
@@ -260,6 +467,22 @@ pub(crate) async fn refresh_github_installation_token(app: &Application) -> Resu
         )
         .build()?;
 
+    let install_id = match app.config.github_app_install_id {
+        Some(install_id) => install_id,
+        // Without an ID, the app must only be installed once, or we can't tell which
+        // installation is meant
+        None => {
+            let installations: Vec<InstallationSummary> =
+                octocrab.get("app/installations", None::<&()>).await?;
+
+            let [ref only] = installations[..] else {
+                return Err(RemoteError::Configuration("github_app_install_id"));
+            };
+
+            only.id
+        }
+    };
+
     let installation: Installation = octocrab
         .get(format!("app/installations/{install_id}"), None::<&()>)
         .await?;
@@ -272,6 +495,118 @@ pub(crate) async fn refresh_github_installation_token(app: &Application) -> Resu
 
     let auth = remotes::github::Auth::from_installation(installation, install_id, octocrab).await?;
 
-    app.credentials.set_github(State::with_auth(auth));
+    // Keep the repositories listed with the previous token until they are listed again
+    let repositories = app
+        .credentials
+        .github()
+        .map(|gh| gh.repositories)
+        .unwrap_or_default();
+
+    app.credentials.set_github(State { auth, repositories });
     Ok(())
 }
+
+/// Renew the user access token granted through the device flow with its refresh token
+pub(crate) async fn refresh_oauth_token(app: &Application) -> Result<()> {
+    let Some(State {
+        auth: Auth::OAuth {
+            refresh_token: Some(refresh_token),
+            ..
+        },
+        repositories,
+    }) = app.credentials.github()
+    else {
+        return Err(RemoteError::NotSupported("github token can't be refreshed"));
+    };
+
+    let (client_id, client_secret) = app
+        .config
+        .github_client_id_and_secret()
+        .ok_or(RemoteError::Configuration("github_client_secret"))?;
+
+    let auth = request_token(&[
+        ("client_id", client_id),
+        ("client_secret", client_secret),
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token.expose_secret()),
+    ])
+    .await?
+    .into_auth(Utc::now())?;
+
+    app.credentials.set_github(State { auth, repositories });
+
+    // Refresh tokens are single use, so the new one must outlive a restart
+    if let Err(err) = app
+        .config
+        .source
+        .save_credentials(&app.credentials.serialize())
+    {
+        error!(?err, "failed to save refreshed GitHub token");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiring_tokens_are_refreshable() {
+        let now = Utc::now();
+        let response: TokenResponse = serde_json::from_str(
+            r#"{
+                "access_token": "ghu_access",
+                "token_type": "bearer",
+                "scope": "repo,read:org",
+                "expires_in": 28800,
+                "refresh_token": "ghr_refresh",
+                "refresh_token_expires_in": 15811200
+            }"#,
+        )
+        .unwrap();
+
+        let state = State::with_auth(response.into_auth(now).unwrap());
+        assert_eq!(state.expiry(), Some(now + chrono::Duration::hours(8)));
+
+        let Auth::OAuth {
+            access_token,
+            scope,
+            refresh_token,
+            ..
+        } = state.auth
+        else {
+            panic!("device flow grants OAuth tokens");
+        };
+        assert_eq!(access_token.expose_secret(), "ghu_access");
+        assert_eq!(scope, ["repo", "read:org"]);
+        assert_eq!(refresh_token.unwrap().expose_secret(), "ghr_refresh");
+    }
+
+    #[test]
+    fn tokens_without_expiry_are_kept() {
+        let response: TokenResponse = serde_json::from_str(
+            r#"{"access_token": "gho_access", "token_type": "bearer", "scope": "repo"}"#,
+        )
+        .unwrap();
+
+        let state = State::with_auth(response.into_auth(Utc::now()).unwrap());
+        assert_eq!(state.expiry(), None);
+    }
+
+    #[test]
+    fn rejected_refresh_tokens_are_errors() {
+        let response: TokenResponse = serde_json::from_str(
+            r#"{
+                "error": "bad_refresh_token",
+                "error_description": "The refresh token passed is incorrect or expired."
+            }"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            response.into_auth(Utc::now()),
+            Err(RemoteError::OAuth(err)) if err.starts_with("bad_refresh_token")
+        ));
+    }
+}
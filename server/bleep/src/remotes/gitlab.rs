@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    repo::{GitProtocol, GitRemote, RepoRemote},
+    Configuration,
+};
+
+use super::*;
+
+const DEFAULT_URL: &str = "https://gitlab.com";
+const PAGE_SIZE: &str = "100";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct State {
+    /// Base URL of the GitLab instance, e.g. `https://gitlab.com`
+    pub url: String,
+    /// Personal, group, or project access token
+    #[serde(serialize_with = "crate::config::serialize_secret_str")]
+    token: SecretString,
+    /// Groups to discover projects in. If empty, every project the token is a member of is used.
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(skip)]
+    pub repositories: Arc<Vec<Project>>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct Project {
+    pub path_with_namespace: String,
+    #[serde(default)]
+    pub archived: bool,
+    pub http_url_to_repo: String,
+    pub ssh_url_to_repo: String,
+    pub last_activity_at: DateTime<Utc>,
+    #[serde(default)]
+    pub default_branch: Option<String>,
+    /// The project this one is a fork of
+    #[serde(default)]
+    pub forked_from_project: Option<ForkParent>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct ForkParent {
+    pub id: u64,
+    pub path_with_namespace: String,
+    #[serde(default)]
+    pub default_branch: Option<String>,
+}
+
+/// Encode a project or group path for use in API URLs
+fn encode(path: &str) -> String {
+    path.replace('/', "%2F")
+}
+
+impl State {
+    /// The same instance, authenticating with `token` instead
+    pub(super) fn with_token(self, token: SecretString) -> Self {
+        Self { token, ..self }
+    }
+
+    pub(crate) fn from_config(config: &Configuration) -> Option<Self> {
+        Some(Self {
+            url: config
+                .gitlab_url
+                .as_deref()
+                .unwrap_or(DEFAULT_URL)
+                .trim_end_matches('/')
+                .to_owned(),
+            token: config.gitlab_token.clone()?,
+            groups: config.gitlab_groups.clone(),
+            repositories: Arc::default(),
+        })
+    }
+
+    /// Hostname and path prefix of the instance, as used in clone URLs
+    fn host(&self) -> &str {
+        self.url
+            .trim_start_matches("https://")
+            .trim_start_matches("http://")
+    }
+
+    pub(crate) fn remote(&self, reporef: &RepoRef, ssh: bool) -> RepoRemote {
+        let (protocol, host) = match ssh {
+            // SSH is served from the root of the host, even if the instance isn't
+            true => (
+                GitProtocol::Ssh,
+                self.host().split('/').next().unwrap_or_default(),
+            ),
+            false => (GitProtocol::Https, self.host()),
+        };
+
+        RepoRemote::Git(GitRemote {
+            protocol,
+            host: host.to_owned(),
+            address: reporef.name().to_owned(),
+        })
+    }
+
+    /// Get a representative list of projects currently accessible
+    pub(crate) async fn current_repo_list(&self) -> Result<Vec<Project>> {
+        let mut projects = vec![];
+
+        if self.groups.is_empty() {
+            projects = self.paginate("projects", &[("membership", "true")]).await?;
+        } else {
+            for group in &self.groups {
+                let path = format!("groups/{}/projects", encode(group));
+                projects.extend(
+                    self.paginate::<Project>(&path, &[("include_subgroups", "true")])
+                        .await?,
+                );
+            }
+        }
+
+        // Groups may overlap through their subgroups
+        projects.sort_by(|a, b| a.path_with_namespace.cmp(&b.path_with_namespace));
+        projects.dedup_by(|a, b| a.path_with_namespace == b.path_with_namespace);
+
+        Ok(projects)
+    }
+
+    /// Every project of a group and its subgroups, with their primary language if
+    /// `languages` is set
+    pub(crate) async fn group_repos(&self, group: &str, languages: bool) -> Result<Vec<OrgRepo>> {
+        let path = format!("groups/{}/projects", encode(group));
+        let projects: Vec<Project> = self
+            .paginate(&path, &[("include_subgroups", "true")])
+            .await?;
+
+        let mut results = vec![];
+        for project in projects {
+            let Ok(reporef) = RepoRef::new(Backend::Gitlab, &project.path_with_namespace) else {
+                continue;
+            };
+
+            let language = match languages {
+                true => self.primary_language(&project.path_with_namespace).await?,
+                false => None,
+            };
+
+            let fork_of = project
+                .forked_from_project
+                .and_then(|parent| RepoRef::new(Backend::Gitlab, &parent.path_with_namespace).ok());
+
+            results.push(OrgRepo {
+                reporef,
+                archived: project.archived,
+                language,
+                fork_of,
+            });
+        }
+
+        Ok(results)
+    }
+
+    /// Whether a fork has commits on its default branch that its parent's doesn't
+    pub(crate) async fn diverged_from_parent(&self, fork: &str) -> Result<bool> {
+        let path = format!("projects/{}", encode(fork));
+        let project: Project = self.get(&path, &[]).await?.json().await?;
+
+        let (Some(branch), Some(parent)) = (project.default_branch, project.forked_from_project)
+        else {
+            return Ok(true);
+        };
+
+        let Some(parent_branch) = parent.default_branch else {
+            return Ok(true);
+        };
+
+        #[derive(Deserialize)]
+        struct Comparison {
+            commits: Vec<serde_json::Value>,
+        }
+
+        let comparison: Comparison = self
+            .get(
+                &format!("{path}/repository/compare"),
+                &[
+                    ("from", &parent_branch),
+                    ("to", &branch),
+                    ("from_project_id", &parent.id.to_string()),
+                ],
+            )
+            .await?
+            .json()
+            .await?;
+
+        Ok(!comparison.commits.is_empty())
+    }
+
+    /// The language most of a project is written in
+    async fn primary_language(&self, project: &str) -> Result<Option<String>> {
+        let path = format!("projects/{}/languages", encode(project));
+        let shares: HashMap<String, f64> = self.get(&path, &[]).await?.json().await?;
+
+        Ok(shares
+            .into_iter()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(language, _)| language.to_lowercase()))
+    }
+
+    /// Create a new object with the updated project list
+    pub(crate) fn update_repositories(self, repos: Vec<Project>) -> Self {
+        Self {
+            repositories: repos.into(),
+            ..self
+        }
+    }
+
+    pub(crate) async fn clone_repo(
+        &self,
+        repo: &Repository,
+        target: &Path,
+        ssh: Option<ssh::Ssh>,
+        options: &CloneOptions,
+    ) -> Result<()> {
+        self.check_repo(repo).await?;
+        git_clone(
+            self.git_cred(),
+            ssh,
+            &repo.remote.to_string(),
+            target,
+            options,
+        )
+        .await
+    }
+
+    pub(crate) async fn pull_repo(
+        &self,
+        repo: &Repository,
+        ssh: Option<ssh::Ssh>,
+        options: &CloneOptions,
+    ) -> Result<()> {
+        self.check_repo(repo).await?;
+        git_pull(self.git_cred(), ssh, repo, options).await
+    }
+
+    async fn check_repo(&self, repo: &Repository) -> Result<()> {
+        let RepoRemote::Git(GitRemote { ref address, .. }) = repo.remote else {
+            return Err(RemoteError::NotSupported("gitlab without git backend"));
+        };
+
+        let project: Project = self
+            .get(&format!("projects/{}", encode(address)), &[])
+            .await?
+            .json()
+            .await?;
+
+        check_renamed(address, &project.path_with_namespace)
+    }
+
+    fn git_cred(&self) -> GitCreds {
+        let token = self.token.clone();
+        Box::new(move |_, _, _| Cred::userpass_plaintext("oauth2", token.expose_secret()))
+    }
+
+    async fn get(&self, path: &str, query: &[(&str, &str)]) -> Result<reqwest::Response> {
+        let response = crate::proxy::client()
+            .get(format!("{}/api/v4/{path}", self.url))
+            .header("PRIVATE-TOKEN", self.token.expose_secret())
+            .query(query)
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Err(RemoteError::RemoteNotFound),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(RemoteError::PermissionDenied),
+            _ => Ok(response.error_for_status()?),
+        }
+    }
+
+    async fn paginate<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Vec<T>> {
+        let mut results = vec![];
+        for page in 1.. {
+            let page = page.to_string();
+            let query = [query, &[("per_page", PAGE_SIZE), ("page", &page)]].concat();
+            let items: Vec<T> = self.get(path, &query).await?.json().await?;
+
+            if items.is_empty() {
+                break;
+            }
+
+            results.extend(items);
+        }
+
+        Ok(results)
+    }
+}
@@ -0,0 +1,229 @@
+//! Importing every repository of a GitHub organization, or a GitLab group, at once.
+
+use std::collections::HashSet;
+
+use tracing::debug;
+use utoipa::ToSchema;
+
+use super::*;
+
+/// A repository of an organization, as listed by its backend
+#[derive(Debug)]
+pub(crate) struct OrgRepo {
+    pub reporef: RepoRef,
+    pub archived: bool,
+    /// Primary language, in lowercase, if known
+    pub language: Option<String>,
+    /// The repository this one is a fork of
+    pub fork_of: Option<RepoRef>,
+}
+
+/// What to do with forks of repositories that are indexed, or imported along with them
+#[derive(Deserialize, ToSchema, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ForkPolicy {
+    /// Leave them out
+    #[default]
+    Skip,
+    /// Import only the forks whose default branch has commits their parent's doesn't
+    Diverged,
+    /// Import them like any other repository
+    Include,
+}
+
+/// Which of an organization's repositories to import
+#[derive(Default, Debug)]
+pub(crate) struct ImportFilter {
+    /// Repository names to import, where `*` matches anything. Every repository is imported
+    /// if empty.
+    pub include: Vec<String>,
+    /// Repository names to leave out, where `*` matches anything
+    pub exclude: Vec<String>,
+    /// Primary languages of the repositories to import. Any language is imported if empty.
+    pub languages: Vec<String>,
+    /// Import archived repositories too
+    pub archived: bool,
+    pub forks: ForkPolicy,
+}
+
+impl ImportFilter {
+    pub(crate) fn matches(&self, repo: &OrgRepo) -> bool {
+        let name = repo.reporef.name();
+
+        (self.archived || !repo.archived)
+            && (self.include.is_empty() || self.include.iter().any(|p| wildcard(p, name)))
+            && !self.exclude.iter().any(|p| wildcard(p, name))
+            && (self.languages.is_empty()
+                || repo.language.as_ref().map_or(false, |language| {
+                    self.languages
+                        .iter()
+                        .any(|l| l.eq_ignore_ascii_case(language))
+                }))
+    }
+}
+
+/// List the repositories of an organization on GitHub, or a group and its subgroups on GitLab.
+///
+/// GitLab doesn't list the languages of projects, so they're looked up one by one when
+/// `filter` selects by language.
+pub(crate) async fn list_org(
+    app: &Application,
+    backend: Backend,
+    org: &str,
+    filter: &ImportFilter,
+) -> Result<Vec<OrgRepo>> {
+    match backend {
+        Backend::Github => {
+            let gh = app
+                .credentials
+                .github()
+                .ok_or(RemoteError::Configuration("github credentials"))?;
+
+            gh.auth.org_repos(org).await
+        }
+        Backend::Gitlab => {
+            let gl = app
+                .credentials
+                .gitlab()
+                .ok_or(RemoteError::Configuration("gitlab credentials"))?;
+
+            gl.group_repos(org, !filter.languages.is_empty()).await
+        }
+        _ => Err(RemoteError::NotSupported(
+            "importing organizations from this backend",
+        )),
+    }
+}
+
+/// Leave out the forks `policy` doesn't import, of repositories that are either indexed
+/// already, or among `repos`.
+///
+/// Forks are compared with their parent only when `policy` imports diverged ones, which takes
+/// a request or two for each fork.
+pub(crate) async fn dedup_forks(
+    app: &Application,
+    repos: Vec<OrgRepo>,
+    policy: ForkPolicy,
+) -> Result<Vec<OrgRepo>> {
+    if policy == ForkPolicy::Include {
+        return Ok(repos);
+    }
+
+    let importing = repos
+        .iter()
+        .map(|repo| repo.reporef.clone())
+        .collect::<HashSet<_>>();
+
+    let mut kept = vec![];
+    for repo in repos {
+        let Some(ref parent) = repo.fork_of else {
+            kept.push(repo);
+            continue;
+        };
+
+        if !importing.contains(parent) && !app.repo_pool.contains_key(parent) {
+            kept.push(repo);
+            continue;
+        }
+
+        if policy == ForkPolicy::Diverged && diverged(app, &repo.reporef).await? {
+            kept.push(repo);
+            continue;
+        }
+
+        debug!(reporef = %repo.reporef, %parent, "skipping fork");
+    }
+
+    Ok(kept)
+}
+
+/// Whether a fork has commits on its default branch that its parent's doesn't
+async fn diverged(app: &Application, fork: &RepoRef) -> Result<bool> {
+    match fork.backend() {
+        Backend::Github => {
+            let gh = app
+                .credentials
+                .github()
+                .ok_or(RemoteError::Configuration("github credentials"))?;
+
+            gh.auth.diverged_from_parent(fork.name()).await
+        }
+        Backend::Gitlab => {
+            let gl = app
+                .credentials
+                .gitlab()
+                .ok_or(RemoteError::Configuration("gitlab credentials"))?;
+
+            gl.diverged_from_parent(fork.name()).await
+        }
+        _ => Ok(true),
+    }
+}
+
+/// Match `name` against `pattern`, where `*` matches any run of characters
+fn wildcard(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        // The last part has to match at the end, the others anywhere in between
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo(name: &str, archived: bool, language: Option<&str>) -> OrgRepo {
+        OrgRepo {
+            reporef: RepoRef::new(Backend::Github, name).unwrap(),
+            archived,
+            language: language.map(str::to_owned),
+            fork_of: None,
+        }
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard("org/*", "org/repo"));
+        assert!(wildcard("org/*-service", "org/billing-service"));
+        assert!(wildcard("*", "org/repo"));
+        assert!(wildcard("org/repo", "org/repo"));
+        assert!(!wildcard("org/repo", "org/repository"));
+        assert!(!wildcard("org/*-service", "org/service-mesh"));
+    }
+
+    #[test]
+    fn import_filter() {
+        let filter = ImportFilter {
+            include: vec!["org/*".into()],
+            exclude: vec!["org/legacy-*".into()],
+            languages: vec!["Rust".into()],
+            archived: false,
+            forks: ForkPolicy::Skip,
+        };
+
+        assert!(filter.matches(&repo("org/bleep", false, Some("rust"))));
+        assert!(!filter.matches(&repo("org/bleep", true, Some("rust"))));
+        assert!(!filter.matches(&repo("org/legacy-api", false, Some("rust"))));
+        assert!(!filter.matches(&repo("org/site", false, Some("typescript"))));
+        assert!(!filter.matches(&repo("org/docs", false, None)));
+
+        assert!(ImportFilter::default().matches(&repo("org/docs", false, None)));
+    }
+}
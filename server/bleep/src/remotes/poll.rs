@@ -4,6 +4,7 @@ use std::{
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use async_trait::async_trait;
 use chrono::Utc;
 use notify_debouncer_mini::{
     new_debouncer_opt,
@@ -14,10 +15,11 @@ use rand::{distributions, thread_rng, Rng};
 use tokio::time::sleep;
 use tracing::{debug, error, info, warn};
 
+use super::{azure, bitbucket, github, gitlab, BackendCredential, Backends};
 use crate::{
     env::Feature,
     remotes,
-    repo::{Backend, RepoRef, SyncStatus},
+    repo::{get_last_modified, Backend, RepoRef, SyncStatus},
     Application,
 };
 
@@ -29,7 +31,107 @@ const POLL_INTERVAL_MINUTE: &[Duration] = &[
     Duration::from_secs(30 * 60),
 ];
 
-pub(crate) async fn sync_repositories(app: Application) {
+/// A backend whose repositories are listed periodically, so they can be offered for indexing
+#[async_trait]
+pub(crate) trait RepoListing: Sized + Send + Sync + 'static {
+    type Repo: Send;
+
+    const BACKEND: Backend;
+
+    /// The backend's current credentials, if it's configured
+    fn current(credentials: &Backends) -> Option<Self>;
+
+    async fn list(&self) -> remotes::Result<Vec<Self::Repo>>;
+
+    /// The same credentials, listing `repos`
+    fn with_repos(self, repos: Vec<Self::Repo>) -> BackendCredential;
+}
+
+#[async_trait]
+impl RepoListing for github::State {
+    type Repo = octocrab::models::Repository;
+    const BACKEND: Backend = Backend::Github;
+
+    fn current(credentials: &Backends) -> Option<Self> {
+        credentials.github()
+    }
+
+    async fn list(&self) -> remotes::Result<Vec<Self::Repo>> {
+        self.current_repo_list().await
+    }
+
+    fn with_repos(self, repos: Vec<Self::Repo>) -> BackendCredential {
+        BackendCredential::Github(self.update_repositories(repos))
+    }
+}
+
+#[async_trait]
+impl RepoListing for gitlab::State {
+    type Repo = gitlab::Project;
+    const BACKEND: Backend = Backend::Gitlab;
+
+    fn current(credentials: &Backends) -> Option<Self> {
+        credentials.gitlab()
+    }
+
+    async fn list(&self) -> remotes::Result<Vec<Self::Repo>> {
+        self.current_repo_list().await
+    }
+
+    fn with_repos(self, repos: Vec<Self::Repo>) -> BackendCredential {
+        BackendCredential::Gitlab(self.update_repositories(repos))
+    }
+}
+
+#[async_trait]
+impl RepoListing for bitbucket::State {
+    type Repo = bitbucket::Repo;
+    const BACKEND: Backend = Backend::Bitbucket;
+
+    fn current(credentials: &Backends) -> Option<Self> {
+        credentials.bitbucket()
+    }
+
+    async fn list(&self) -> remotes::Result<Vec<Self::Repo>> {
+        self.current_repo_list().await
+    }
+
+    fn with_repos(self, repos: Vec<Self::Repo>) -> BackendCredential {
+        BackendCredential::Bitbucket(self.update_repositories(repos))
+    }
+}
+
+#[async_trait]
+impl RepoListing for azure::State {
+    type Repo = azure::Repo;
+    const BACKEND: Backend = Backend::AzureDevops;
+
+    fn current(credentials: &Backends) -> Option<Self> {
+        credentials.azure()
+    }
+
+    async fn list(&self) -> remotes::Result<Vec<Self::Repo>> {
+        self.current_repo_list().await
+    }
+
+    fn with_repos(self, repos: Vec<Self::Repo>) -> BackendCredential {
+        BackendCredential::AzureDevops(self.update_repositories(repos))
+    }
+}
+
+/// Keep the lists of repositories of every backend up to date
+pub(crate) async fn sync_repo_lists(app: Application) {
+    tokio::join!(
+        sync_repo_list::<github::State>(app.clone()),
+        sync_repo_list::<gitlab::State>(app.clone()),
+        sync_repo_list::<bitbucket::State>(app.clone()),
+        sync_repo_list::<azure::State>(app),
+    );
+}
+
+/// Keep the list of repositories of a backend up to date. Backends are listed again
+/// periodically, and as soon as their credentials change, such as when a user logs in.
+async fn sync_repo_list<B: RepoListing>(app: Application) {
     const POLL_PERIOD: Duration = POLL_INTERVAL_MINUTE[1];
     const LIVENESS: Duration = Duration::from_secs(3);
 
@@ -41,13 +143,13 @@ pub(crate) async fn sync_repositories(app: Application) {
         loop {
             tokio::select! {
                 _ = sleep(POLL_PERIOD) => {
-                    debug!("timeout expired; refreshing repositories");
+                    debug!(backend = ?B::BACKEND, "timeout expired; refreshing repositories");
                     return SystemTime::now();
                 },
                 result = handle.recv_async() => {
                     let now = SystemTime::now();
                     if result.is_ok() && now.duration_since(last_poll).unwrap() > POLL_PERIOD {
-                        debug!("github credentials changed; refreshing repositories");
+                        debug!(backend = ?B::BACKEND, "credentials changed; refreshing repositories");
                         return now;
                     }
                 },
@@ -57,23 +159,22 @@ pub(crate) async fn sync_repositories(app: Application) {
 
     let mut last_poll = UNIX_EPOCH;
     loop {
-        let Some(github) = app.credentials.github() else {
-            timeout().await;
-            continue;
-	};
-
-        let Ok(repos) = github.current_repo_list().await else {
+        let Some(backend) = B::current(&app.credentials) else {
             timeout().await;
             continue;
-	};
+        };
 
-        let updated = app.credentials.github_updated().unwrap();
-        let new = github.update_repositories(repos);
+        let updated = app.credentials.updated(&B::BACKEND).unwrap();
+        match backend.list().await {
+            Ok(repos) => {
+                app.credentials.set(B::BACKEND, backend.with_repos(repos));
 
-        app.credentials.set_github(new);
+                // swallow the event that's generated from this update
+                _ = updated.recv_async().await;
+            }
+            Err(err) => error!(?err, backend = ?B::BACKEND, "failed to list repositories"),
+        }
 
-        // swallow the event that's generated from this update
-        _ = updated.recv_async().await;
         last_poll = timeout_or_update(last_poll, updated).await;
     }
 }
@@ -94,6 +195,17 @@ pub(crate) async fn check_credentials(app: Application) {
         }
 
         if app.env.allow(Feature::GithubDeviceFlow) {
+            // Tokens of apps that expire them are renewed ahead of time; a failed refresh
+            // leaves them to be removed once they're no longer valid
+            match app.credentials.github().and_then(|c| c.expiry()) {
+                Some(expiry) if expiry <= Utc::now() + chrono::Duration::minutes(10) => {
+                    if let Err(e) = remotes::github::refresh_oauth_token(&app).await {
+                        error!(?e, "failed to refresh GitHub OAuth token");
+                    }
+                }
+                _ => {}
+            }
+
             let expired = if let Some(github) = app.credentials.github() {
                 github.validate().await.is_err()
             } else {
@@ -123,6 +235,11 @@ pub(crate) async fn check_repo_updates(app: Application) {
             .collect::<Vec<_>>();
 
         for repo in repos {
+            // Scheduled repositories are synced by the scheduler instead, and pinned ones never
+            if repo.revision().is_some() || app.sync_schedules.for_repo(&repo).is_some() {
+                continue;
+            }
+
             let app = app.clone();
             let reporef = repo.clone();
 
@@ -163,13 +280,17 @@ async fn periodic_repo_poll(app: Application, reporef: RepoRef) -> Option<()> {
             return None;
         }
 
-        if let Err(err) = app
-            .write_index()
-            .sync_and_index(vec![reporef.clone()])
-            .await
-        {
-            error!(?err, ?reporef, "failed to sync & index repo");
-            return None;
+        if dir_changed(&app, &reporef).await {
+            if let Err(err) = app
+                .write_index()
+                .sync_and_index(vec![reporef.clone()])
+                .await
+            {
+                error!(?err, ?reporef, "failed to sync & index repo");
+                return None;
+            }
+        } else {
+            debug!(?reporef, "directory unchanged since it was indexed");
         }
 
         let (updated, status) = check_repo(&app, &reporef)?;
@@ -212,6 +333,25 @@ async fn periodic_repo_poll(app: Application, reporef: RepoRef) -> Option<()> {
     }
 }
 
+/// Plain directories have no commits to compare, so they're only reindexed once something in
+/// them is modified after they were indexed. Everything else is left to the sync to check.
+async fn dir_changed(app: &Application, reporef: &RepoRef) -> bool {
+    let (disk_path, indexed) = match app.repo_pool.get(reporef) {
+        Some(repo)
+            if reporef.is_local()
+                && repo.sync_status == SyncStatus::Done
+                && repo.is_plain_dir() =>
+        {
+            (repo.disk_path.clone(), repo.last_commit_unix_secs)
+        }
+        _ => return true,
+    };
+
+    tokio::task::spawn_blocking(move || get_last_modified(&disk_path) > indexed)
+        .await
+        .unwrap_or(true)
+}
+
 struct Poller {
     poll_interval_index: usize,
     minimum_interval_index: usize,
@@ -227,23 +367,26 @@ impl Poller {
         let (tx, rx) = flume::bounded(10);
 
         let mut _debouncer = None;
-        if app.config.disable_fsevents.not() && reporef.backend() == Backend::Local {
+        if app.config.disable_fsevents().not() && reporef.backend() == Backend::Local {
             let git_path = app
                 .repo_pool
                 .get(reporef)
                 .map(|repo| repo.value().disk_path.join(".git"))?;
 
-            let mut debouncer = debounced_events(tx);
-            debouncer
-                .watcher()
-                .watch(&git_path, RecursiveMode::Recursive)
-                .unwrap();
-            _debouncer = Some(debouncer);
+            // Plain directories are polled for modified files instead
+            if git_path.exists() {
+                let mut debouncer = debounced_events(tx);
+                debouncer
+                    .watcher()
+                    .watch(&git_path, RecursiveMode::Recursive)
+                    .unwrap();
+                _debouncer = Some(debouncer);
 
-            info!(?reporef, ?git_path, "will reindex repo on git changes");
+                info!(?reporef, ?git_path, "will reindex repo on git changes");
 
-            poll_interval_index = POLL_INTERVAL_MINUTE.len() - 1;
-            minimum_interval_index = POLL_INTERVAL_MINUTE.len() - 1;
+                poll_interval_index = POLL_INTERVAL_MINUTE.len() - 1;
+                minimum_interval_index = POLL_INTERVAL_MINUTE.len() - 1;
+            }
         }
 
         Some(Self {
@@ -0,0 +1,229 @@
+//! Syncing repositories on cron schedules, instead of polling them for changes.
+//!
+//! A schedule can be set for every repository, and overridden for individual repositories.
+//! Repositories without a schedule keep being polled.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use tokio::time::sleep;
+use tracing::{debug, info};
+
+use crate::{repo::RepoRef, Application, Configuration};
+
+/// The longest the scheduler sleeps, so newly added repositories are picked up
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub(crate) struct Schedules {
+    global: Option<Schedule>,
+    repos: HashMap<RepoRef, Schedule>,
+}
+
+/// Parse a cron expression, with or without a seconds field
+fn parse(expr: &str) -> anyhow::Result<Schedule> {
+    let mut fields = expr
+        .split_whitespace()
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    if fields.len() == 5 {
+        fields.insert(0, "0".to_owned());
+    }
+
+    if let Some(weekdays) = fields.get_mut(5) {
+        *weekdays = crontab_weekdays(weekdays)
+            .with_context(|| format!("invalid sync schedule `{expr}`"))?;
+    }
+
+    let expr = fields.join(" ");
+    Schedule::from_str(&expr).with_context(|| format!("invalid sync schedule `{expr}`"))
+}
+
+/// Renumber the days of the week from crontab's numbering, where Sunday is 0 or 7, to the
+/// `cron` crate's, where Sunday is 1 and Saturday 7. Named days are kept as they are.
+fn crontab_weekdays(field: &str) -> anyhow::Result<String> {
+    if field == "*" || field == "?" {
+        return Ok(field.to_owned());
+    }
+
+    let mut names = vec![];
+    let mut days = BTreeSet::new();
+    for item in field.split(',') {
+        if item.chars().any(|c| c.is_ascii_alphabetic()) {
+            names.push(item.to_owned());
+            continue;
+        }
+
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>()?),
+            None => (item, 1),
+        };
+
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start.parse::<u8>()?, end.parse::<u8>()?),
+            None if range == "*" => (0, 7),
+            None if step > 1 => (range.parse()?, 7),
+            None => (range.parse()?, range.parse()?),
+        };
+
+        if step == 0 || start > end || end > 7 {
+            bail!("invalid day of the week `{item}`");
+        }
+
+        days.extend((start..=end).step_by(step).map(|day| day % 7 + 1));
+    }
+
+    names.extend(days.into_iter().map(|day| day.to_string()));
+    Ok(names.join(","))
+}
+
+impl Schedules {
+    pub(crate) fn from_config(config: &Configuration) -> anyhow::Result<Self> {
+        let global = config.sync_schedule.as_deref().map(parse).transpose()?;
+        let repos = config
+            .repo_sync_schedules
+            .iter()
+            .map(|(reporef, expr)| {
+                let reporef = reporef
+                    .parse::<RepoRef>()
+                    .with_context(|| format!("invalid repository `{reporef}`"))?;
+                Ok((reporef, parse(expr)?))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { global, repos })
+    }
+
+    pub(crate) fn for_repo(&self, reporef: &RepoRef) -> Option<&Schedule> {
+        self.repos.get(reporef).or(self.global.as_ref())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.global.is_none() && self.repos.is_empty()
+    }
+}
+
+pub(crate) async fn run_sync_schedules(app: Application) {
+    if app.sync_schedules.is_empty() {
+        return;
+    }
+
+    let mut next_runs: HashMap<RepoRef, DateTime<Utc>> = HashMap::new();
+    loop {
+        let now = Utc::now();
+        let mut due = vec![];
+
+        for elem in app.repo_pool.iter() {
+            let reporef = elem.key();
+            if !elem.sync_status.indexable() || reporef.revision().is_some() {
+                continue;
+            }
+
+            let Some(schedule) = app.sync_schedules.for_repo(reporef) else {
+                continue;
+            };
+
+            let next = next_runs.entry(reporef.clone()).or_insert_with(|| {
+                schedule
+                    .after(&now)
+                    .next()
+                    .unwrap_or(DateTime::<Utc>::MAX_UTC)
+            });
+
+            if *next <= now {
+                due.push(reporef.clone());
+                *next = schedule
+                    .after(&now)
+                    .next()
+                    .unwrap_or(DateTime::<Utc>::MAX_UTC);
+            }
+        }
+
+        next_runs.retain(|reporef, _| app.repo_pool.contains_key(reporef));
+
+        if !due.is_empty() {
+            info!(repos = ?due, "syncing repositories on schedule");
+            app.write_index().queue_sync_and_index(due);
+        }
+
+        let until_next = next_runs
+            .values()
+            .min()
+            .and_then(|next| (*next - Utc::now()).to_std().ok())
+            .unwrap_or(MAX_SLEEP)
+            .min(MAX_SLEEP);
+
+        debug!(?until_next, "waiting for the next scheduled sync");
+        sleep(until_next).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::Backend;
+
+    #[test]
+    fn parse_schedules() {
+        assert!(parse("*/5 * * * *").is_ok());
+        assert!(parse("0 0 3 * * *").is_ok());
+        assert!(parse("every day").is_err());
+        assert!(parse("0 3 * * 8").is_err());
+
+        let nightly = parse("0 3 * * *").unwrap();
+        let after = "2023-01-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(
+            nightly.after(&after).next().unwrap(),
+            "2023-01-02T03:00:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+    }
+
+    #[test]
+    fn weekdays_are_numbered_as_in_crontab() {
+        // A Sunday
+        let after = "2023-01-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let next = |expr| parse(expr).unwrap().after(&after).next().unwrap();
+
+        let monday = "2023-01-02T09:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let saturday = "2023-01-07T09:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let sunday = "2023-01-08T09:00:00Z".parse::<DateTime<Utc>>().unwrap();
+
+        assert_eq!(next("0 9 * * 1-5"), monday);
+        assert_eq!(next("0 9 * * 6"), saturday);
+        assert_eq!(next("0 9 * * 0"), sunday);
+        assert_eq!(next("0 9 * * 7"), sunday);
+        assert_eq!(next("0 0 9 * * 1"), monday);
+        assert_eq!(next("0 9 * * Mon"), monday);
+
+        assert_eq!(crontab_weekdays("1-5").unwrap(), "2,3,4,5,6");
+        assert_eq!(crontab_weekdays("0,6").unwrap(), "1,7");
+        assert_eq!(crontab_weekdays("*/2").unwrap(), "1,3,5,7");
+        assert_eq!(crontab_weekdays("5-7").unwrap(), "1,6,7");
+    }
+
+    #[test]
+    fn repo_overrides_global() {
+        let busy = RepoRef::new(Backend::Github, "org/busy").unwrap();
+        let other = RepoRef::new(Backend::Github, "org/other").unwrap();
+        let schedules = Schedules {
+            global: Some(parse("0 3 * * *").unwrap()),
+            repos: [(busy.clone(), parse("*/5 * * * *").unwrap())].into(),
+        };
+
+        assert_eq!(
+            schedules.for_repo(&busy).unwrap().to_string(),
+            "0 */5 * * * *"
+        );
+        assert_eq!(
+            schedules.for_repo(&other).unwrap().to_string(),
+            "0 0 3 * * *"
+        );
+        assert!(Schedules::default().for_repo(&other).is_none());
+    }
+}
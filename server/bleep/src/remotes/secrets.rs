@@ -0,0 +1,197 @@
+//! Credentials for individual repositories, encrypted at rest.
+//!
+//! The store is sealed with ChaCha20-Poly1305 under a key kept in a separate file, which only
+//! the server's user can read. Losing the key loses the secrets, which have to be set again.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    repo::RepoRef,
+    state::{pretty_write_file, read_file_or_default, write_private},
+};
+
+/// Credentials to sync one repository with, in place of the backend's
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub(crate) struct RepoSecrets {
+    /// Access token to clone and fetch with
+    #[serde(serialize_with = "crate::config::serialize_secret_opt_str", default)]
+    pub token: Option<SecretString>,
+    /// Passphrase of the SSH key
+    #[serde(serialize_with = "crate::config::serialize_secret_opt_str", default)]
+    pub ssh_key_passphrase: Option<SecretString>,
+}
+
+/// The store as written to disk
+#[derive(Serialize, Deserialize, Default)]
+struct Sealed {
+    nonce: String,
+    ciphertext: String,
+}
+
+pub(crate) struct SecretStore {
+    path: PathBuf,
+    key: LessSafeKey,
+    secrets: Mutex<HashMap<String, RepoSecrets>>,
+}
+
+impl SecretStore {
+    /// Open the store at `path`, creating the key at `key_path` if there isn't one yet
+    pub(crate) fn open(path: &Path, key_path: &Path) -> Result<Self> {
+        let key = read_or_create_key(key_path)?;
+        let sealed: Sealed = read_file_or_default(path)?;
+
+        let secrets = match sealed.ciphertext.is_empty() {
+            true => HashMap::new(),
+            false => {
+                let plaintext = unseal(&key, &sealed).context("failed to decrypt secret store")?;
+                serde_json::from_slice(&plaintext)?
+            }
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            key,
+            secrets: Mutex::new(secrets),
+        })
+    }
+
+    /// The secrets of a repository. Pinned repositories use those of the repository they're
+    /// pinned from, unless they have their own.
+    pub(crate) fn for_repo(&self, reporef: &RepoRef) -> RepoSecrets {
+        let secrets = self.secrets.lock().unwrap();
+        secrets
+            .get(&reporef.to_string())
+            .or_else(|| secrets.get(&reporef.unpinned().to_string()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Set or replace the secrets of a repository
+    pub(crate) fn set(&self, reporef: &RepoRef, repo_secrets: RepoSecrets) -> Result<()> {
+        let mut secrets = self.secrets.lock().unwrap();
+        secrets.insert(reporef.to_string(), repo_secrets);
+        self.save(&secrets)
+    }
+
+    /// Remove the secrets of a repository, returning whether it had any
+    pub(crate) fn remove(&self, reporef: &RepoRef) -> Result<bool> {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.remove(&reporef.to_string()).is_none() {
+            return Ok(false);
+        }
+
+        self.save(&secrets)?;
+        Ok(true)
+    }
+
+    fn save(&self, secrets: &HashMap<String, RepoSecrets>) -> Result<()> {
+        let plaintext = serde_json::to_vec(secrets)?;
+        Ok(pretty_write_file(&self.path, &seal(&self.key, plaintext)?)?)
+    }
+}
+
+fn seal(key: &LessSafeKey, mut plaintext: Vec<u8>) -> Result<Sealed> {
+    // Every save uses a fresh nonce, so one is never reused with the same key
+    let mut nonce = [0; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow::anyhow!("failed to generate nonce"))?;
+
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::empty(),
+        &mut plaintext,
+    )
+    .map_err(|_| anyhow::anyhow!("failed to encrypt secret store"))?;
+
+    Ok(Sealed {
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(plaintext),
+    })
+}
+
+fn unseal(key: &LessSafeKey, sealed: &Sealed) -> Result<Vec<u8>> {
+    let Ok(nonce) = <[u8; NONCE_LEN]>::try_from(STANDARD.decode(&sealed.nonce)?) else {
+        bail!("invalid nonce");
+    };
+
+    let mut ciphertext = STANDARD.decode(&sealed.ciphertext)?;
+    let plaintext = key
+        .open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            &mut ciphertext,
+        )
+        .map_err(|_| anyhow::anyhow!("wrong key, or the store was tampered with"))?;
+
+    Ok(plaintext.to_vec())
+}
+
+fn read_or_create_key(path: &Path) -> Result<LessSafeKey> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let mut bytes = vec![0; CHACHA20_POLY1305.key_len()];
+            SystemRandom::new()
+                .fill(&mut bytes)
+                .map_err(|_| anyhow::anyhow!("failed to generate key"))?;
+
+            write_private(path, &bytes)?;
+            bytes
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &bytes)
+        .map_err(|_| anyhow::anyhow!("invalid secret store key: {path:?}"))?;
+
+    Ok(LessSafeKey::new(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::ExposeSecret;
+
+    use super::*;
+
+    #[test]
+    fn secrets_roundtrip() {
+        let dir = tempdir::TempDir::new("bleep").unwrap();
+        let (path, key) = (dir.path().join("secrets.json"), dir.path().join("key.bin"));
+        let reporef = "github.com/org/repo".parse::<RepoRef>().unwrap();
+
+        let store = SecretStore::open(&path, &key).unwrap();
+        store
+            .set(
+                &reporef,
+                RepoSecrets {
+                    token: Some("hunter2".to_owned().into()),
+                    ssh_key_passphrase: None,
+                },
+            )
+            .unwrap();
+
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("hunter2"));
+
+        let store = SecretStore::open(&path, &key).unwrap();
+        let pinned = "github.com/org/repo@v1".parse::<RepoRef>().unwrap();
+        let token = store.for_repo(&pinned).token.unwrap();
+        assert_eq!(token.expose_secret(), "hunter2");
+
+        assert!(store.remove(&reporef).unwrap());
+        assert!(store.for_repo(&reporef).token.is_none());
+    }
+}
@@ -0,0 +1,222 @@
+//! Cloning and fetching over SSH, with a private key such as a deploy key.
+//!
+//! Host keys are checked against a `known_hosts` file, which is read on every connection so
+//! that hosts can be added without a restart. Hosts that aren't listed are rejected.
+
+use std::path::PathBuf;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use git2::{CertificateCheckStatus, Cred};
+use ring::hmac;
+use secrecy::{ExposeSecret, SecretString};
+
+use crate::Configuration;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Ssh {
+    key: PathBuf,
+    passphrase: Option<SecretString>,
+    known_hosts: PathBuf,
+}
+
+impl Ssh {
+    pub(crate) fn from_config(config: &Configuration) -> Option<Self> {
+        let known_hosts = config.ssh_known_hosts.clone().or_else(|| {
+            directories::BaseDirs::new().map(|dirs| dirs.home_dir().join(".ssh/known_hosts"))
+        })?;
+
+        Some(Self {
+            key: config.ssh_key.clone()?,
+            passphrase: config.ssh_key_passphrase.clone(),
+            known_hosts,
+        })
+    }
+
+    pub(super) fn with_passphrase(self, passphrase: SecretString) -> Self {
+        Self {
+            passphrase: Some(passphrase),
+            ..self
+        }
+    }
+
+    pub(super) fn credentials(&self, username: Option<&str>) -> Result<Cred, git2::Error> {
+        Cred::ssh_key(
+            username.unwrap_or("git"),
+            None,
+            &self.key,
+            self.passphrase.as_ref().map(|p| p.expose_secret().as_str()),
+        )
+    }
+
+    /// Verify the key presented by `host` is listed in the `known_hosts` file.
+    pub(super) fn check_host(
+        &self,
+        cert: &git2::Cert<'_>,
+        host: &str,
+    ) -> Result<CertificateCheckStatus, git2::Error> {
+        // TLS certificates are left to the default checks
+        let Some(hostkey) = cert.as_hostkey() else {
+            return Ok(CertificateCheckStatus::CertificatePassthrough);
+        };
+
+        let Some(key) = hostkey.hostkey() else {
+            return Err(git2::Error::from_str("host didn't present a host key"));
+        };
+
+        let known_hosts = std::fs::read_to_string(&self.known_hosts).map_err(|err| {
+            git2::Error::from_str(&format!(
+                "failed to read {}: {err}",
+                self.known_hosts.display()
+            ))
+        })?;
+
+        if known(&known_hosts, host, key) {
+            Ok(CertificateCheckStatus::CertificateOk)
+        } else {
+            Err(git2::Error::from_str(&format!(
+                "host key for {host} is not listed in {}",
+                self.known_hosts.display()
+            )))
+        }
+    }
+}
+
+/// Whether `key` is listed for `host`, and not revoked.
+fn known(known_hosts: &str, host: &str, key: &[u8]) -> bool {
+    let mut found = false;
+
+    for line in known_hosts.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace().peekable();
+        let marker = fields.next_if(|field| field.starts_with('@'));
+
+        let (Some(patterns), Some(_key_type), Some(encoded)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+
+        if !matches_host(patterns, host) || STANDARD.decode(encoded).ok().as_deref() != Some(key) {
+            continue;
+        }
+
+        match marker {
+            None => found = true,
+            Some("@revoked") => return false,
+            // Certificate authorities sign host keys, they aren't host keys themselves
+            Some(_) => {}
+        }
+    }
+
+    found
+}
+
+/// Match `host` against a comma-separated list of patterns, which may be hashed, contain
+/// wildcards, or be negated.
+fn matches_host(patterns: &str, host: &str) -> bool {
+    let mut matched = false;
+
+    for pattern in patterns.split(',') {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+
+        if matches_pattern(pattern, host) {
+            if negated {
+                return false;
+            }
+
+            matched = true;
+        }
+    }
+
+    matched
+}
+
+fn matches_pattern(pattern: &str, host: &str) -> bool {
+    if let Some(hashed) = pattern.strip_prefix("|1|") {
+        return matches_hashed(hashed, host);
+    }
+
+    // The port isn't known here, so hosts on non-standard ports match on any port
+    let pattern = match pattern.strip_prefix('[').and_then(|p| p.split_once("]:")) {
+        Some((pattern, _port)) => pattern,
+        None => pattern,
+    };
+
+    wildcard(pattern.as_bytes(), host.as_bytes())
+}
+
+/// `|1|salt|hash`, where the hash is an HMAC-SHA1 of the host name keyed by the salt
+fn matches_hashed(hashed: &str, host: &str) -> bool {
+    let Some((salt, hash)) = hashed.split_once('|') else {
+        return false;
+    };
+
+    let (Ok(salt), Ok(hash)) = (STANDARD.decode(salt), STANDARD.decode(hash)) else {
+        return false;
+    };
+
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &salt);
+    hmac::verify(&key, host.as_bytes(), &hash).is_ok()
+}
+
+/// Glob matching with `*` and `?`, case insensitive like host names
+fn wildcard(pattern: &[u8], host: &[u8]) -> bool {
+    match (pattern.split_first(), host.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            wildcard(rest, host) || (!host.is_empty() && wildcard(pattern, &host[1..]))
+        }
+        (Some((b'?', rest)), Some((_, host))) => wildcard(rest, host),
+        (Some((p, rest)), Some((h, host))) if p.eq_ignore_ascii_case(h) => wildcard(rest, host),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+
+    #[test]
+    fn known_hosts() {
+        let key = STANDARD.decode(KEY).unwrap();
+        let file = format!(
+            "# comment\n\
+             github.com,140.82.121.4 ssh-ed25519 {KEY}\n\
+             *.example.com,!evil.example.com ssh-ed25519 {KEY}\n\
+             [git.internal]:2222 ssh-ed25519 {KEY}\n\
+             @revoked revoked.example.com ssh-ed25519 {KEY}\n"
+        );
+
+        assert!(known(&file, "github.com", &key));
+        assert!(known(&file, "GitHub.com", &key));
+        assert!(known(&file, "gitlab.example.com", &key));
+        assert!(known(&file, "git.internal", &key));
+        assert!(!known(&file, "evil.example.com", &key));
+        assert!(!known(&file, "revoked.example.com", &key));
+        assert!(!known(&file, "gitlab.com", &key));
+        assert!(!known(&file, "github.com", b"another key"));
+    }
+
+    #[test]
+    fn hashed_host() {
+        let salt = b"0123456789abcdefghij";
+        let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, salt);
+        let hash = hmac::sign(&key, b"github.com");
+        let pattern = format!(
+            "|1|{}|{}",
+            STANDARD.encode(salt),
+            STANDARD.encode(hash.as_ref())
+        );
+
+        assert!(matches_host(&pattern, "github.com"));
+        assert!(!matches_host(&pattern, "gitlab.com"));
+    }
+}
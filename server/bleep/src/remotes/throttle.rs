@@ -0,0 +1,112 @@
+//! Caps on the network traffic of clones and fetches, so that syncing many repositories at
+//! once doesn't saturate the uplink.
+//!
+//! The limits are process-wide, and installed once at startup.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::OnceCell;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::Configuration;
+
+static THROTTLE: OnceCell<Throttle> = OnceCell::new();
+
+pub(crate) struct Throttle {
+    transfers: Option<Semaphore>,
+    bandwidth: Option<Mutex<Bucket>>,
+}
+
+impl Throttle {
+    /// Install the limits in `config`. Only the first configuration installed takes effect.
+    pub(crate) fn install(config: &Configuration) {
+        _ = THROTTLE.set(Self {
+            transfers: config.max_git_transfers.map(|n| Semaphore::new(n.max(1))),
+            bandwidth: config
+                .git_bandwidth_limit
+                .map(|kib| Mutex::new(Bucket::new(kib.max(1) * 1024))),
+        });
+    }
+
+    /// Wait for a transfer slot, which is held until the permit is dropped
+    pub(super) async fn transfer() -> Option<SemaphorePermit<'static>> {
+        let transfers = THROTTLE.get()?.transfers.as_ref()?;
+        transfers.acquire().await.ok()
+    }
+
+    /// Account for the `bytes` received by a transfer so far, blocking until they fit in the
+    /// bandwidth limit. This is called from libgit2's progress callbacks, which run on the
+    /// thread doing the transfer, so blocking it holds off the download.
+    pub(super) fn received(last: &mut usize, bytes: usize) {
+        let Some(bandwidth) = THROTTLE.get().and_then(|t| t.bandwidth.as_ref()) else {
+            return;
+        };
+
+        let delta = bytes.saturating_sub(*last);
+        *last = bytes;
+
+        let wait = bandwidth.lock().unwrap().take(delta as u64, Instant::now());
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// Bytes received since `start`, allowing `rate` bytes per second
+struct Bucket {
+    rate: u64,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Take `bytes` out of the allowance, returning how long to wait before they're due
+    fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        // Time spent idle doesn't build up credit for a later burst
+        if self.due() < now {
+            self.start = now;
+            self.bytes = 0;
+        }
+
+        self.bytes += bytes;
+        self.due().saturating_duration_since(now)
+    }
+
+    fn due(&self) -> Instant {
+        self.start + Duration::from_secs_f64(self.bytes as f64 / self.rate as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_waits_for_allowance() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            rate: 1024,
+            start,
+            bytes: 0,
+        };
+
+        assert_eq!(bucket.take(512, start), Duration::from_millis(500));
+        assert_eq!(bucket.take(512, start), Duration::from_secs(1));
+
+        // Once the allowance catches up, transfers go through straight away
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take(0, later), Duration::ZERO);
+        assert_eq!(bucket.take(1024, later), Duration::from_secs(1));
+    }
+}
@@ -1,6 +1,8 @@
 use dashmap::DashMap;
+use ignore::WalkBuilder;
 use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
+    collections::BTreeSet,
     fmt::{self, Display},
     path::{Path, PathBuf},
     str::FromStr,
@@ -38,6 +40,9 @@ impl<T> From<T> for FreshValue<T> {
 pub enum Backend {
     Local,
     Github,
+    Gitlab,
+    Bitbucket,
+    AzureDevops,
 }
 
 // Repository identifier
@@ -52,10 +57,16 @@ impl RepoRef {
         use Backend::*;
 
         match backend {
-            Github => Ok(RepoRef {
-                backend,
-                name: name.as_ref().to_owned(),
-            }),
+            Github | Gitlab | Bitbucket | AzureDevops => {
+                if name.as_ref().ends_with('@') {
+                    return Err(RepoError::InvalidRevision);
+                }
+
+                Ok(RepoRef {
+                    backend,
+                    name: name.as_ref().to_owned(),
+                })
+            }
             Local => {
                 let path = Path::new(name.as_ref());
 
@@ -83,6 +94,9 @@ impl RepoRef {
         let refstr = components.join("/");
         let pathstr = match refstr.trim_start_matches('/').split_once('/') {
             Some(("github.com", name)) => return RepoRef::new(Backend::Github, name),
+            Some(("gitlab", name)) => return RepoRef::new(Backend::Gitlab, name),
+            Some(("bitbucket", name)) => return RepoRef::new(Backend::Bitbucket, name),
+            Some(("azure", name)) => return RepoRef::new(Backend::AzureDevops, name),
             Some(("local", name)) => name,
             _ => &refstr,
         };
@@ -103,23 +117,65 @@ impl RepoRef {
         self.backend == Backend::Local
     }
 
+    /// The tag or commit a remote repository is pinned to, as in `org/repo@v2.3`.
+    ///
+    /// Pinned repositories are checked out once, and never synced again.
+    pub fn revision(&self) -> Option<&str> {
+        match self.backend {
+            Backend::Local => None,
+            _ => self.name.rsplit_once('@').map(|(_, revision)| revision),
+        }
+    }
+
+    /// The repository a pinned repository is checked out from
+    pub fn unpinned(&self) -> RepoRef {
+        match self.name.rsplit_once('@') {
+            Some((name, _)) if !self.is_local() => RepoRef {
+                backend: self.backend(),
+                name: name.to_owned(),
+            },
+            _ => self.clone(),
+        }
+    }
+
+    /// The same repository under the name it was renamed or transferred to, pinned to the
+    /// same revision
+    pub fn renamed(&self, name: &str) -> RepoRef {
+        let name = match self.revision() {
+            Some(revision) => format!("{name}@{revision}"),
+            None => name.to_owned(),
+        };
+
+        RepoRef {
+            backend: self.backend(),
+            name,
+        }
+    }
+
     pub fn indexed_name(&self) -> String {
         // Local repos indexed as: dirname
         // Github repos indexed as: github.com/org/repo
+        // Gitlab repos indexed as: gitlab/group/project
+        // Bitbucket repos indexed as: bitbucket/workspace/repo
+        // Azure DevOps repos indexed as: azure/project/repo
         match self.backend {
             Backend::Local => Path::new(&self.name)
                 .file_name()
                 .expect("last component is `..`")
                 .to_string_lossy()
                 .into(),
-            Backend::Github => format!("{}", self),
+            Backend::Github | Backend::Gitlab | Backend::Bitbucket | Backend::AzureDevops => {
+                format!("{}", self)
+            }
         }
     }
 
     pub fn display_name(&self) -> String {
         match self.backend {
             // org_name/repo_name
-            Backend::Github => self.name.to_owned(),
+            Backend::Github | Backend::Gitlab | Backend::Bitbucket | Backend::AzureDevops => {
+                self.name.to_owned()
+            }
             // repo_name
             Backend::Local => self.indexed_name(),
         }
@@ -162,6 +218,12 @@ impl FromStr for RepoRef {
         match refstr.trim_start_matches('/').split_once('/') {
             // github.com/...
             Some(("github.com", name)) => RepoRef::new(Backend::Github, name),
+            // gitlab/...
+            Some(("gitlab", name)) => RepoRef::new(Backend::Gitlab, name),
+            // bitbucket/...
+            Some(("bitbucket", name)) => RepoRef::new(Backend::Bitbucket, name),
+            // azure/...
+            Some(("azure", name)) => RepoRef::new(Backend::AzureDevops, name),
             // local/...
             Some(("local", name)) => RepoRef::new(Backend::Local, name),
             _ => Err(RepoError::InvalidBackend),
@@ -173,6 +235,9 @@ impl Display for RepoRef {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.backend() {
             Backend::Github => write!(f, "github.com/{}", self.name()),
+            Backend::Gitlab => write!(f, "gitlab/{}", self.name()),
+            Backend::Bitbucket => write!(f, "bitbucket/{}", self.name()),
+            Backend::AzureDevops => write!(f, "azure/{}", self.name()),
             Backend::Local => write!(f, "local/{}", self.name()),
         }
     }
@@ -205,7 +270,16 @@ pub struct Repository {
     pub sync_status: SyncStatus,
     pub last_commit_unix_secs: u64,
     pub last_index_unix_secs: u64,
+    /// The commit that was checked out when the repository was last indexed
+    #[serde(default)]
+    pub last_commit_hash: Option<String>,
+    /// Size of the checkout when the repository was last indexed
+    #[serde(default)]
+    pub disk_usage_bytes: u64,
     pub most_common_lang: Option<String>,
+    /// Groups the repository belongs to, which searches can be scoped to
+    #[serde(default)]
+    pub groups: BTreeSet<String>,
 }
 
 impl Repository {
@@ -233,9 +307,12 @@ impl Repository {
             sync_status: SyncStatus::Queued,
             last_index_unix_secs: 0,
             last_commit_unix_secs: 0,
+            last_commit_hash: None,
+            disk_usage_bytes: 0,
             disk_path,
             remote,
             most_common_lang: None,
+            groups: BTreeSet::new(),
         }
     }
 
@@ -257,6 +334,11 @@ impl Repository {
         Ok(metadata)
     }
 
+    /// Whether this is a plain directory, rather than a git checkout
+    pub(crate) fn is_plain_dir(&self) -> bool {
+        !self.disk_path.join(".git").exists()
+    }
+
     /// Marks the repository for removal on the next sync
     /// Does not initiate a new sync.
     pub(crate) fn mark_removed(&mut self) {
@@ -272,8 +354,10 @@ impl Repository {
     pub(crate) fn sync_done_with(&mut self, metadata: Arc<RepoMetadata>) {
         self.last_index_unix_secs = get_unix_time(SystemTime::now());
         self.last_commit_unix_secs = metadata.last_commit_unix_secs;
+        self.last_commit_hash = metadata.last_commit_hash.clone();
+        self.disk_usage_bytes = metadata.disk_usage_bytes;
         self.sync_status = SyncStatus::Done;
-        self.most_common_lang = metadata.langs.most_common_lang.map(|l| l.to_string());
+        self.most_common_lang = metadata.langs.most_common_lang.clone();
     }
 
     fn file_cache_path(&self, index_dir: &Path) -> PathBuf {
@@ -312,14 +396,19 @@ fn get_unix_time(time: SystemTime) -> u64 {
 #[derive(Debug)]
 pub struct RepoMetadata {
     pub last_commit_unix_secs: u64,
+    pub last_commit_hash: Option<String>,
+    pub disk_usage_bytes: u64,
     pub symbols: ctags::SymbolMap,
     pub langs: LanguageInfo,
 }
 
-async fn get_repo_metadata(repo_disk_path: &PathBuf) -> Arc<RepoMetadata> {
-    let repo = git2::Repository::open(repo_disk_path)
-        .and_then(|repo| Ok(repo.head()?.peel_to_commit()?.time().seconds() as u64))
-        .unwrap_or(0);
+pub(crate) async fn get_repo_metadata(repo_disk_path: &PathBuf) -> Arc<RepoMetadata> {
+    let head = git2::Repository::open(repo_disk_path)
+        .and_then(|repo| {
+            let commit = repo.head()?.peel_to_commit()?;
+            Ok((commit.time().seconds() as u64, commit.id().to_string()))
+        })
+        .ok();
 
     // Extract symbols using Ctags for all languages which are not covered by a more
     // precise form of symbol extraction.
@@ -346,13 +435,46 @@ async fn get_repo_metadata(repo_disk_path: &PathBuf) -> Arc<RepoMetadata> {
     ];
 
     RepoMetadata {
-        last_commit_unix_secs: repo,
+        // Plain directories have no commits, so they're dated by their latest change instead
+        last_commit_unix_secs: match head {
+            Some((time, _)) => time,
+            None => get_last_modified(repo_disk_path),
+        },
+        last_commit_hash: head.map(|(_, id)| id),
+        disk_usage_bytes: get_disk_usage(repo_disk_path),
         symbols: ctags::get_symbols(repo_disk_path, exclude_langs).await,
         langs: get_language_info(repo_disk_path),
     }
     .into()
 }
 
+/// Modification time of the most recently changed file or directory in a directory.
+///
+/// Directories are included so removing a file counts as a change.
+pub(crate) fn get_last_modified(path: &Path) -> u64 {
+    WalkBuilder::new(path)
+        .build()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter_map(|metadata| metadata.modified().ok())
+        .filter_map(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|time| time.as_secs())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Total size of the files in a directory, including ignored and hidden files
+fn get_disk_usage(path: &Path) -> u64 {
+    WalkBuilder::new(path)
+        .standard_filters(false)
+        .build()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
 #[derive(Serialize, Deserialize, ToSchema, PartialEq, Eq, Clone, Debug)]
 #[serde(rename_all = "snake_case")]
 pub enum SyncStatus {
@@ -409,6 +531,28 @@ impl<T: AsRef<RepoRef>> From<T> for RepoRemote {
                 host: "github.com".to_owned(),
                 address: name.to_owned(),
             }),
+            // Self-hosted instances have their own hosts, which only the backend knows about
+            RepoRef {
+                backend: Backend::Gitlab,
+                name,
+            } => RepoRemote::Git(GitRemote {
+                protocol: GitProtocol::Https,
+                host: "gitlab.com".to_owned(),
+                address: name.to_owned(),
+            }),
+            RepoRef {
+                backend: Backend::Bitbucket,
+                name,
+            } => RepoRemote::Git(GitRemote {
+                protocol: GitProtocol::Https,
+                host: "bitbucket.org".to_owned(),
+                address: name.to_owned(),
+            }),
+            // Azure DevOps repositories can only be found within an organization
+            RepoRef {
+                backend: Backend::AzureDevops,
+                name: _name,
+            } => RepoRemote::None,
             RepoRef {
                 backend: Backend::Local,
                 name: _name,
@@ -474,6 +618,8 @@ pub enum RepoError {
     InvalidPath,
     #[error("backend not recognized")]
     InvalidBackend,
+    #[error("pinned revision can't be empty")]
+    InvalidRevision,
     #[error("IO error: {error}")]
     IO {
         #[from]
@@ -501,6 +647,18 @@ mod test {
             "github.com/bloopai/bloop".parse::<RepoRef>().unwrap(),
             RepoRef::new(Backend::Github, "bloopai/bloop").unwrap()
         );
+        assert_eq!(
+            "gitlab/group/subgroup/project".parse::<RepoRef>().unwrap(),
+            RepoRef::new(Backend::Gitlab, "group/subgroup/project").unwrap()
+        );
+        assert_eq!(
+            "bitbucket/workspace/repo".parse::<RepoRef>().unwrap(),
+            RepoRef::new(Backend::Bitbucket, "workspace/repo").unwrap()
+        );
+        assert_eq!(
+            "azure/My Project/repo".parse::<RepoRef>().unwrap(),
+            RepoRef::new(Backend::AzureDevops, "My Project/repo").unwrap()
+        );
         assert_eq!(
             "local//tmp/repository".parse::<RepoRef>().unwrap(),
             RepoRef::new(Backend::Local, "/tmp/repository").unwrap()
@@ -514,12 +672,61 @@ mod test {
         }
     }
 
+    #[test]
+    fn pinned_reporef() {
+        let pinned = "github.com/org/repo@v2.3".parse::<RepoRef>().unwrap();
+        assert_eq!(pinned.revision(), Some("v2.3"));
+        assert_eq!(
+            pinned.unpinned(),
+            RepoRef::new(Backend::Github, "org/repo").unwrap()
+        );
+        assert_eq!(pinned.to_string(), "github.com/org/repo@v2.3");
+
+        let local = RepoRef::new(Backend::Local, "/tmp/me@host").unwrap();
+        assert_eq!(local.revision(), None);
+        assert_eq!(local.unpinned(), local);
+
+        assert!("github.com/org/repo@".parse::<RepoRef>().is_err());
+    }
+
+    #[test]
+    fn renamed_reporef() {
+        let pinned = "github.com/org/repo@v2.3".parse::<RepoRef>().unwrap();
+        assert_eq!(
+            pinned.renamed("neworg/repo").to_string(),
+            "github.com/neworg/repo@v2.3"
+        );
+
+        let unpinned = "gitlab/group/repo".parse::<RepoRef>().unwrap();
+        assert_eq!(
+            unpinned.renamed("group/renamed").to_string(),
+            "gitlab/group/renamed"
+        );
+    }
+
+    #[test]
+    fn plain_dir_last_modified() {
+        let dir = tempdir::TempDir::new("bleep").unwrap();
+        assert_eq!(get_last_modified(&dir.path().join("missing")), 0);
+
+        std::fs::write(dir.path().join("main.rs"), "fn main() {}").unwrap();
+        assert!(get_last_modified(dir.path()) > 0);
+
+        let repo = Repository::local_from(&RepoRef::from(&dir.path()));
+        assert!(repo.is_plain_dir());
+    }
+
     #[test]
     fn serialize_reporef() {
         assert_eq!(
             r#""github.com/org/repo""#,
             &serde_json::to_string(&RepoRef::new(Backend::Github, "org/repo").unwrap()).unwrap()
         );
+        assert_eq!(
+            r#""gitlab/group/project""#,
+            &serde_json::to_string(&RepoRef::new(Backend::Gitlab, "group/project").unwrap())
+                .unwrap()
+        );
         assert_eq!(
             r#""local//org/repo""#,
             &serde_json::to_string(&RepoRef::new(Backend::Local, "/org/repo").unwrap()).unwrap()
@@ -1,29 +1,77 @@
-use std::{collections::HashMap, ops::Not, path::Path, sync::Arc};
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    ops::Not,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
 
 use crate::{query::parser::NLQuery, Configuration};
 
+use lru::LruCache;
 use ndarray::Axis;
 use ort::{
     tensor::{FromArray, InputTensor, OrtOwnedTensor},
     Environment, ExecutionProvider, GraphOptimizationLevel, LoggingLevel, SessionBuilder,
 };
 use qdrant_client::{
-    prelude::{QdrantClient, QdrantClientConfig},
+    prelude::{Payload, QdrantClient, QdrantClientConfig},
     qdrant::{
-        r#match::MatchValue, vectors_config, with_payload_selector::SelectorOptions,
-        CollectionOperationResponse, CreateCollection, Distance, FieldCondition, Filter, Match,
-        PointId, PointStruct, ScoredPoint, SearchPoints, VectorParams, VectorsConfig,
-        WithPayloadSelector,
+        r#match::MatchValue, value::Kind, vectors::VectorsOptions, vectors_config,
+        with_payload_selector::SelectorOptions, with_vectors_selector, CollectionOperationResponse,
+        CountPoints, CreateCollection, Distance, FieldCondition, Filter, Match, PointId,
+        PointStruct, ScoredPoint, ScrollPoints, SearchPoints, VectorParams, VectorsConfig,
+        WithPayloadSelector, WithVectorsSelector,
     },
 };
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tracing::{debug, info, trace, warn};
+use tracing::{debug, trace, warn};
 
 pub mod chunk;
+mod feedback;
+mod loader;
+mod upsert;
+
+pub(crate) use feedback::Feedback;
+pub use loader::{LoadState, SemanticLoader};
 
 const COLLECTION_NAME: &str = "documents";
 
+/// Points read at once when exporting the embeddings of a repo
+const EXPORT_PAGE_SIZE: u32 = 256;
+
+/// Points deleted at once by their ids
+const DELETE_BATCH_SIZE: usize = 1024;
+
+/// Results of recent searches that are kept, as the same question is often asked again
+const SEARCH_CACHE_SIZE: usize = 128;
+
+/// An embedding computed recently, by the hash of the text it embeds
+type EmbeddingCache = Mutex<LruCache<blake3::Hash, Vec<f32>>>;
+
+/// A search by its normalized query, repo and language filters, the repos it's restricted to,
+/// and limit, at a generation of the embeddings
+type SearchKey = (
+    String,
+    Option<String>,
+    Option<String>,
+    Option<Vec<String>>,
+    u64,
+    u64,
+);
+
+/// An embedding as it's exported, with the payload it's stored with
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoredEmbedding {
+    pub vector: Vec<f32>,
+    pub payload: HashMap<String, String>,
+}
+
 #[derive(Error, Debug)]
 pub enum SemanticError {
     /// Represents failure to initialize Qdrant client
@@ -49,7 +97,16 @@ pub struct Semantic {
     tokenizer: Arc<tokenizers::Tokenizer>,
     gpt2_tokenizer: Arc<tokenizers::Tokenizer>,
     session: Arc<ort::Session>,
+    /// Hash of the embedding model, to tell models apart
+    model_id: Arc<str>,
     config: Arc<Configuration>,
+    upserts: Arc<upsert::Upserts>,
+    searches: Arc<Mutex<LruCache<SearchKey, Vec<ScoredPoint>>>>,
+    /// Unset if the cache is disabled
+    embeddings: Option<Arc<EmbeddingCache>>,
+    /// Incremented whenever embeddings are stored, moved or removed. Searches made at previous
+    /// generations are never looked up again, and make way for new ones.
+    generation: Arc<AtomicU64>,
 }
 
 fn collection_config() -> CreateCollection {
@@ -65,36 +122,58 @@ fn collection_config() -> CreateCollection {
     }
 }
 
+/// Hash the model file, so the model in use can be identified.
+fn model_id(path: &Path) -> anyhow::Result<String> {
+    use anyhow::Context;
+
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("failed to open model {}", path.display()))?;
+
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Create the collection embeddings are stored in, unless it exists
+async fn ensure_collection(qdrant: Arc<QdrantClient>) -> Result<(), SemanticError> {
+    match qdrant.has_collection(COLLECTION_NAME).await {
+        Ok(has_collection) => {
+            if has_collection.not() {
+                let CollectionOperationResponse { result, time } = qdrant
+                    .create_collection(&collection_config())
+                    .await
+                    .unwrap();
+
+                debug!(
+                    time,
+                    created = result,
+                    name = COLLECTION_NAME,
+                    "created qdrant collection"
+                );
+
+                assert!(result);
+            }
+        }
+        Err(_) => return Err(SemanticError::QdrantInitializationError),
+    }
+
+    Ok(())
+}
+
 impl Semantic {
     pub async fn initialize(
         model_dir: &Path,
         qdrant_url: &str,
         config: Arc<Configuration>,
     ) -> Result<Self, SemanticError> {
-        let qdrant = QdrantClient::new(Some(QdrantClientConfig::from_url(qdrant_url)))
-            .await
-            .unwrap();
-
-        match qdrant.has_collection(COLLECTION_NAME).await {
-            Ok(has_collection) => {
-                if has_collection.not() {
-                    let CollectionOperationResponse { result, time } = qdrant
-                        .create_collection(&collection_config())
-                        .await
-                        .unwrap();
-
-                    debug!(
-                        time,
-                        created = result,
-                        name = COLLECTION_NAME,
-                        "created qdrant collection"
-                    );
-
-                    assert!(result);
-                }
-            }
-            Err(_) => return Err(SemanticError::QdrantInitializationError),
-        }
+        let qdrant = Arc::new(
+            QdrantClient::new(Some(QdrantClientConfig::from_url(qdrant_url)))
+                .await
+                .unwrap(),
+        );
+
+        // The collection is checked while the model loads
+        let collection = tokio::spawn(ensure_collection(Arc::clone(&qdrant)));
 
         let environment = Arc::new(
             Environment::builder()
@@ -104,14 +183,17 @@ impl Semantic {
                 .build()?,
         );
 
-        let threads = if let Ok(v) = std::env::var("NUM_OMP_THREADS") {
-            str::parse(&v).unwrap_or(1)
-        } else {
-            1
-        };
+        let model_path = model_dir.join("model.onnx");
+        let model_id = model_id(&model_path)?;
+
+        let generation = Arc::new(AtomicU64::default());
 
-        Ok(Self {
-            qdrant: qdrant.into(),
+        let semantic = Self {
+            upserts: Arc::new(upsert::Upserts::new(
+                Arc::clone(&qdrant),
+                Arc::clone(&generation),
+            )),
+            qdrant,
             tokenizer: tokenizers::Tokenizer::from_file(model_dir.join("tokenizer.json"))
                 .unwrap()
                 .into(),
@@ -120,11 +202,28 @@ impl Semantic {
                 .into(),
             session: SessionBuilder::new(&environment)?
                 .with_optimization_level(GraphOptimizationLevel::Level3)?
-                .with_intra_threads(threads)?
-                .with_model_from_file(model_dir.join("model.onnx"))?
+                .with_intra_threads(config.embedding_threads.try_into().unwrap_or(1))?
+                .with_model_from_file(model_path)?
                 .into(),
+            model_id: model_id.into(),
+            embeddings: NonZeroUsize::new(config.embedding_cache_size)
+                .map(|size| Arc::new(Mutex::new(LruCache::new(size)))),
             config,
-        })
+            searches: Arc::new(Mutex::new(LruCache::new(
+                SEARCH_CACHE_SIZE.try_into().unwrap(),
+            ))),
+            generation,
+        };
+
+        collection
+            .await
+            .map_err(|_| SemanticError::QdrantInitializationError)??;
+
+        Ok(semantic)
+    }
+
+    pub fn model_id(&self) -> &str {
+        &self.model_id
     }
 
     pub async fn health_check(&self) -> anyhow::Result<()> {
@@ -132,7 +231,23 @@ impl Semantic {
         Ok(())
     }
 
+    /// Embed `sequence`, or look up its embedding if it was computed recently
     pub fn embed(&self, sequence: &str) -> anyhow::Result<Vec<f32>> {
+        let Some(embeddings) = &self.embeddings else {
+            return self.compute_embedding(sequence);
+        };
+
+        let key = blake3::hash(sequence.as_bytes());
+        if let Some(embedding) = embeddings.lock().unwrap().get(&key) {
+            return Ok(embedding.clone());
+        }
+
+        let embedding = self.compute_embedding(sequence)?;
+        embeddings.lock().unwrap().put(key, embedding.clone());
+        Ok(embedding)
+    }
+
+    fn compute_embedding(&self, sequence: &str) -> anyhow::Result<Vec<f32>> {
         let tokenizer_output = self.tokenizer.encode(sequence, true).unwrap();
 
         let input_ids = tokenizer_output.get_ids();
@@ -168,15 +283,38 @@ impl Semantic {
         Ok(pooled.to_owned().as_slice().unwrap().to_vec())
     }
 
+    /// Search for the points closest to the query, among the points of `repos` only, if given.
+    ///
+    /// Restricting the repos in the search itself, rather than filtering its results, keeps the
+    /// points of other tenants in qdrant, and doesn't take from the `limit`.
     pub async fn search<'a>(
         &self,
         parsed_query: &NLQuery<'a>,
+        repos: Option<&[String]>,
         limit: u64,
     ) -> anyhow::Result<Vec<ScoredPoint>> {
         let Some(query) = parsed_query.target() else {
             anyhow::bail!("no search target for query");
         };
 
+        // An empty `should` clause matches every point
+        if repos.map_or(false, <[String]>::is_empty) {
+            return Ok(vec![]);
+        }
+
+        let key = (
+            query.split_whitespace().collect::<Vec<_>>().join(" "),
+            parsed_query.repo().map(|r| r.to_string()),
+            parsed_query.lang().map(|l| l.to_ascii_lowercase()),
+            repos.map(<[String]>::to_vec),
+            limit,
+            self.generation.load(Ordering::Acquire),
+        );
+
+        if let Some(points) = self.searches.lock().unwrap().get(&key) {
+            return Ok(points.clone());
+        }
+
         let repo_filter = parsed_query
             .repo()
             .map(|r| make_kv_filter("repo_name", r).into());
@@ -185,7 +323,18 @@ impl Semantic {
             .lang()
             .map(|l| make_kv_filter("lang", l).into());
 
-        let filters = [repo_filter, lang_filter]
+        let access_filter = repos.map(|repos| {
+            Filter {
+                should: repos
+                    .iter()
+                    .map(|r| make_keyword_filter("repo_ref", r).into())
+                    .collect(),
+                ..Default::default()
+            }
+            .into()
+        });
+
+        let filters = [repo_filter, lang_filter, access_filter]
             .into_iter()
             .flatten()
             .collect::<Vec<_>>();
@@ -207,9 +356,22 @@ impl Semantic {
             })
             .await?;
 
+        self.searches
+            .lock()
+            .unwrap()
+            .put(key, response.result.clone());
+
         Ok(response.result)
     }
 
+    /// Stop reusing the results of earlier searches, once embeddings have changed
+    fn invalidate_searches(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Embed the chunks of a file, replacing any it had before, and return how many were
+    /// queued to be stored. They're stored in batches, the last of which is sent by
+    /// [`Semantic::flush_points`].
     #[tracing::instrument(skip(self, repo_ref, relative_path, buffer))]
     pub async fn insert_points_for_buffer(
         &self,
@@ -218,10 +380,14 @@ impl Semantic {
         relative_path: &str,
         buffer: &str,
         lang_str: &str,
-    ) {
+    ) -> usize {
         // Delete all points corresponding to the same path
-        self.delete_points_by_path(repo_ref, std::iter::once(relative_path))
-            .await;
+        if let Err(err) = self
+            .delete_points_by_path(repo_ref, std::iter::once(relative_path))
+            .await
+        {
+            warn!(?err, relative_path, "failed to delete previous embeddings");
+        }
 
         let chunks = chunk::by_tokens(
             repo_name,
@@ -270,27 +436,28 @@ impl Semantic {
             )
             .collect::<Vec<_>>();
 
-        if !datapoints.is_empty() {
-            let num_datapoints = datapoints.len();
-            debug!(point_count = num_datapoints, "updating docs");
-            let upserted = self.qdrant.upsert_points(COLLECTION_NAME, datapoints).await;
-            if upserted.is_ok() {
-                info!(
-                    ?chunk_prefix,
-                    "Successfully upserted {:?} vectors", num_datapoints
-                );
-            } else {
-                warn!(
-                    ?chunk_prefix,
-                    "Failed to upsert {:?} vectors", num_datapoints
-                );
-            }
-        } else {
+        if datapoints.is_empty() {
             warn!(?chunk_prefix, "No vectors to insert");
+            return 0;
         }
+
+        let num_datapoints = datapoints.len();
+        debug!(point_count = num_datapoints, "queueing docs");
+        self.upserts.push(datapoints).await;
+        num_datapoints
     }
 
-    pub async fn delete_points_by_path(&self, repo_ref: &str, paths: impl Iterator<Item = &str>) {
+    /// Store every embedding queued so far, waiting until they're stored. Fails if any
+    /// embedding of `repo_ref` couldn't be stored.
+    pub async fn flush_points(&self, repo_ref: &str) -> anyhow::Result<()> {
+        self.upserts.flush(repo_ref).await
+    }
+
+    pub async fn delete_points_by_path(
+        &self,
+        repo_ref: &str,
+        paths: impl Iterator<Item = &str>,
+    ) -> anyhow::Result<()> {
         let repo_filter = make_kv_filter("repo_ref", repo_ref).into();
         let file_filter = paths
             .map(|p| make_kv_filter("relative_path", p).into())
@@ -301,7 +468,198 @@ impl Semantic {
             ..Default::default()
         }
         .into();
-        let _ = self.qdrant.delete_points(COLLECTION_NAME, &selector).await;
+        self.qdrant
+            .delete_points(COLLECTION_NAME, &selector)
+            .await?;
+        self.invalidate_searches();
+        Ok(())
+    }
+
+    /// Delete embeddings by the ids they're stored under, waiting until they're deleted
+    pub async fn delete_points(&self, ids: Vec<PointId>) -> anyhow::Result<()> {
+        for batch in ids.chunks(DELETE_BATCH_SIZE) {
+            self.qdrant
+                .delete_points_blocking(COLLECTION_NAME, &batch.to_vec().into())
+                .await?;
+        }
+
+        self.invalidate_searches();
+        Ok(())
+    }
+
+    /// Move the embeddings of a repo that was renamed over to its new name
+    pub async fn rename_repo(
+        &self,
+        repo_ref: &str,
+        new_repo_ref: &str,
+        new_repo_name: &str,
+    ) -> anyhow::Result<()> {
+        let selector = Filter {
+            must: vec![make_kv_filter("repo_ref", repo_ref).into()],
+            ..Default::default()
+        }
+        .into();
+
+        let mut payload = Payload::new();
+        payload.insert("repo_ref", new_repo_ref);
+        payload.insert("repo_name", new_repo_name);
+
+        self.qdrant
+            .set_payload(COLLECTION_NAME, &selector, payload)
+            .await?;
+
+        self.invalidate_searches();
+        Ok(())
+    }
+
+    /// Count the embeddings stored for a repo
+    pub async fn count_points(&self, repo_ref: &str) -> anyhow::Result<u64> {
+        let response = self
+            .qdrant
+            .count(&CountPoints {
+                collection_name: COLLECTION_NAME.to_string(),
+                filter: Some(Filter {
+                    must: vec![make_kv_filter("repo_ref", repo_ref).into()],
+                    ..Default::default()
+                }),
+                exact: Some(true),
+            })
+            .await?;
+
+        Ok(response.result.map(|r| r.count).unwrap_or_default())
+    }
+
+    /// Pass every embedding stored for a repo to `sink`, a page at a time
+    pub async fn export_points(
+        &self,
+        repo_ref: &str,
+        mut sink: impl FnMut(StoredEmbedding) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        // Embeddings still queued would otherwise be left out
+        self.flush_points(repo_ref).await?;
+
+        let mut offset = None;
+        loop {
+            let response = self
+                .qdrant
+                .scroll(&ScrollPoints {
+                    collection_name: COLLECTION_NAME.to_string(),
+                    filter: Some(Filter {
+                        must: vec![make_kv_filter("repo_ref", repo_ref).into()],
+                        ..Default::default()
+                    }),
+                    offset,
+                    limit: Some(EXPORT_PAGE_SIZE),
+                    with_payload: Some(WithPayloadSelector {
+                        selector_options: Some(SelectorOptions::Enable(true)),
+                    }),
+                    with_vectors: Some(WithVectorsSelector {
+                        selector_options: Some(with_vectors_selector::SelectorOptions::Enable(
+                            true,
+                        )),
+                    }),
+                    ..Default::default()
+                })
+                .await?;
+
+            for point in response.result {
+                let Some(VectorsOptions::Vector(vector)) =
+                    point.vectors.and_then(|v| v.vectors_options)
+                else {
+                    anyhow::bail!("point {:?} has no vector", point.id);
+                };
+
+                let payload = point
+                    .payload
+                    .into_iter()
+                    .filter_map(|(key, value)| match value.kind {
+                        Some(Kind::StringValue(value)) => Some((key, value)),
+                        _ => None,
+                    })
+                    .collect();
+
+                sink(StoredEmbedding {
+                    vector: vector.data,
+                    payload,
+                })?;
+            }
+
+            match response.next_page_offset {
+                Some(next) => offset = Some(next),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// The ids of every embedding stored for a repo
+    pub async fn point_ids(&self, repo_ref: &str) -> anyhow::Result<Vec<PointId>> {
+        let mut ids = vec![];
+        let mut offset = None;
+
+        loop {
+            let response = self
+                .qdrant
+                .scroll(&ScrollPoints {
+                    collection_name: COLLECTION_NAME.to_string(),
+                    filter: Some(Filter {
+                        must: vec![make_kv_filter("repo_ref", repo_ref).into()],
+                        ..Default::default()
+                    }),
+                    offset,
+                    limit: Some(EXPORT_PAGE_SIZE),
+                    with_payload: Some(WithPayloadSelector {
+                        selector_options: Some(SelectorOptions::Enable(false)),
+                    }),
+                    with_vectors: Some(WithVectorsSelector {
+                        selector_options: Some(with_vectors_selector::SelectorOptions::Enable(
+                            false,
+                        )),
+                    }),
+                    ..Default::default()
+                })
+                .await?;
+
+            ids.extend(response.result.into_iter().filter_map(|point| point.id));
+
+            match response.next_page_offset {
+                Some(next) => offset = Some(next),
+                None => return Ok(ids),
+            }
+        }
+    }
+
+    /// Store embeddings exported by [`Semantic::export_points`], waiting until they're stored,
+    /// rather than queueing them as those of indexed files are.
+    ///
+    /// The ids they're stored under are added to `ids` before they're sent, so that they can be
+    /// deleted again even if storing them fails.
+    pub async fn import_points(
+        &self,
+        embeddings: Vec<StoredEmbedding>,
+        ids: &mut Vec<PointId>,
+    ) -> anyhow::Result<()> {
+        let points = embeddings
+            .into_iter()
+            .map(|embedding| PointStruct {
+                id: Some(PointId::from(uuid::Uuid::new_v4().to_string())),
+                vectors: Some(embedding.vector.into()),
+                payload: embedding
+                    .payload
+                    .into_iter()
+                    .map(|(key, value)| (key, value.into()))
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+
+        ids.extend(points.iter().filter_map(|point| point.id.clone()));
+        let stored = self
+            .qdrant
+            .upsert_points_blocking(COLLECTION_NAME, points)
+            .await;
+
+        self.invalidate_searches();
+        stored?;
+        Ok(())
     }
 
     pub fn gpt2_token_count(&self, input: &str) -> usize {
@@ -311,11 +669,30 @@ impl Semantic {
             .unwrap_or(0)
     }
 
+    /// Run the embedding model and both tokenizers once, as their first runs are far slower
+    /// than any after
+    pub fn warm_up(&self) -> anyhow::Result<()> {
+        self.embed("warm up")?;
+        self.gpt2_token_count("warm up");
+        Ok(())
+    }
+
     pub fn overlap_strategy(&self) -> chunk::OverlapStrategy {
         self.config.overlap.unwrap_or_default()
     }
 }
 
+/// A condition that `key` is exactly `value`
+fn make_keyword_filter(key: &str, value: &str) -> FieldCondition {
+    FieldCondition {
+        key: key.to_owned(),
+        r#match: Some(Match {
+            match_value: MatchValue::Keyword(value.to_owned()).into(),
+        }),
+        ..Default::default()
+    }
+}
+
 fn make_kv_filter(key: &str, value: &str) -> FieldCondition {
     let key = key.to_owned();
     let value = value.to_owned();
@@ -0,0 +1,237 @@
+//! Boosts learnt from feedback on answers.
+//!
+//! Every vote on an answer counts towards the file of the snippet it was based on, and each
+//! directory above it. Snippets of files, or directories, whose answers are consistently
+//! marked helpful then rank higher when snippets are picked for the next question, and those
+//! marked unhelpful lower.
+//!
+//! Only answers given since the server started can be voted on, and each voter's vote on an
+//! answer counts once.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::state::{pretty_write_file, read_file_or_default};
+
+/// Votes a path needs before its boost is half way to the limit
+const PRIOR: f32 = 5.0;
+
+/// The most a file's own votes change the score of its snippets, either way
+const FILE_BOOST: f32 = 0.2;
+
+/// The most the votes of a file's closest voted-on directory change the score of its snippets
+const DIRECTORY_BOOST: f32 = 0.1;
+
+/// The most answers kept to be voted on. Votes on older answers are rejected.
+const MAX_ANSWERS: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub(crate) struct Votes {
+    pub up: u32,
+    pub down: u32,
+}
+
+impl Votes {
+    /// From -1, when every vote is down, to 1, when every vote is up. Few votes count for less
+    /// than many.
+    fn helpfulness(self) -> f32 {
+        let total = (self.up + self.down) as f32;
+        (self.up as f32 - self.down as f32) / (total + PRIOR)
+    }
+}
+
+/// Votes by repository, then by file or directory. Directories end with a `/`.
+type VoteMap = HashMap<String, HashMap<String, Votes>>;
+
+/// An answer that can be voted on
+struct Answer {
+    /// Repository of the snippet the answer was based on
+    repo_ref: String,
+    /// File of the snippet the answer was based on
+    relative_path: String,
+    /// Whether each voter found the answer helpful
+    votes: HashMap<String, bool>,
+}
+
+/// Recent answers, by query id, and their ids, oldest first
+#[derive(Default)]
+struct Answers {
+    by_id: HashMap<uuid::Uuid, Answer>,
+    order: VecDeque<uuid::Uuid>,
+}
+
+pub(crate) struct Feedback {
+    path: PathBuf,
+    votes: RwLock<VoteMap>,
+    answers: Mutex<Answers>,
+}
+
+impl Feedback {
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_owned(),
+            votes: RwLock::new(read_file_or_default(path)?),
+            answers: Mutex::default(),
+        })
+    }
+
+    /// Keep an answer based on a snippet of `relative_path` to be voted on
+    pub(crate) fn answered(&self, query_id: uuid::Uuid, repo_ref: &str, relative_path: &str) {
+        let mut answers = self.answers.lock().unwrap();
+
+        while answers.order.len() >= MAX_ANSWERS {
+            let Some(oldest) = answers.order.pop_front() else {
+                break;
+            };
+            answers.by_id.remove(&oldest);
+        }
+
+        answers.order.push_back(query_id);
+        answers.by_id.insert(
+            query_id,
+            Answer {
+                repo_ref: repo_ref.to_owned(),
+                relative_path: relative_path.to_owned(),
+                votes: HashMap::new(),
+            },
+        );
+    }
+
+    /// Repository of the snippet an answer was based on, if it can be voted on
+    pub(crate) fn answer_repo(&self, query_id: uuid::Uuid) -> Option<String> {
+        let answers = self.answers.lock().unwrap();
+        answers.by_id.get(&query_id).map(|a| a.repo_ref.clone())
+    }
+
+    /// Count `voter`'s vote on an answer, replacing their earlier vote on it. Votes on answers
+    /// that can't be voted on aren't counted.
+    pub(crate) fn vote(&self, query_id: uuid::Uuid, voter: &str, helpful: bool) -> Result<()> {
+        let mut answers = self.answers.lock().unwrap();
+        let Some(answer) = answers.by_id.get_mut(&query_id) else {
+            return Ok(());
+        };
+
+        let previous = answer.votes.insert(voter.to_owned(), helpful);
+        if previous == Some(helpful) {
+            return Ok(());
+        }
+
+        let mut votes = self.votes.write().unwrap();
+        let repo = votes.entry(answer.repo_ref.clone()).or_default();
+        let relative_path = &answer.relative_path;
+
+        for path in std::iter::once(relative_path.to_owned()).chain(directories(relative_path)) {
+            let votes = repo.entry(path).or_default();
+            match previous {
+                Some(true) => votes.up -= 1,
+                Some(false) => votes.down -= 1,
+                None => {}
+            }
+            match helpful {
+                true => votes.up += 1,
+                false => votes.down += 1,
+            }
+        }
+
+        Ok(pretty_write_file(&self.path, &*votes)?)
+    }
+
+    /// The factor to scale the score of a snippet of `relative_path` by
+    pub(crate) fn boost(&self, repo_ref: &str, relative_path: &str) -> f32 {
+        let votes = self.votes.read().unwrap();
+        let Some(repo) = votes.get(repo_ref) else {
+            return 1.0;
+        };
+
+        let file = repo
+            .get(relative_path)
+            .map_or(0.0, |votes| votes.helpfulness());
+
+        // Directories are listed deepest first, so this is the closest one
+        let directory = directories(relative_path)
+            .find_map(|dir| repo.get(&dir))
+            .map_or(0.0, |votes| votes.helpfulness());
+
+        (1.0 + FILE_BOOST * file) * (1.0 + DIRECTORY_BOOST * directory)
+    }
+}
+
+/// The directories above `relative_path`, deepest first, each ending with a `/`
+fn directories(relative_path: &str) -> impl Iterator<Item = String> + '_ {
+    relative_path
+        .rmatch_indices('/')
+        .map(|(i, _)| relative_path[..=i].to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boosts() {
+        let dir = tempdir::TempDir::new("bleep").unwrap();
+        let path = dir.path().join("feedback.json");
+        let repo = "github.com/org/repo";
+
+        let feedback = Feedback::open(&path).unwrap();
+        for _ in 0..5 {
+            let query_id = uuid::Uuid::new_v4();
+            feedback.answered(query_id, repo, "src/query/parser.rs");
+            feedback.vote(query_id, "alice", true).unwrap();
+        }
+        let query_id = uuid::Uuid::new_v4();
+        feedback.answered(query_id, repo, "src/ui/button.ts");
+        feedback.vote(query_id, "alice", false).unwrap();
+
+        // Reopened, to check the votes were saved
+        let feedback = Feedback::open(&path).unwrap();
+        let parser = feedback.boost(repo, "src/query/parser.rs");
+        let planner = feedback.boost(repo, "src/query/planner.rs");
+        let button = feedback.boost(repo, "src/ui/button.ts");
+
+        assert!(parser > planner && planner > 1.0);
+        assert!(button < 1.0);
+        assert!(parser <= (1.0 + FILE_BOOST) * (1.0 + DIRECTORY_BOOST));
+        assert_eq!(feedback.boost(repo, "README.md"), 1.0);
+        assert_eq!(feedback.boost("github.com/org/other", "src/lib.rs"), 1.0);
+
+        assert_eq!(
+            directories("src/query/parser.rs").collect::<Vec<_>>(),
+            vec!["src/query/".to_owned(), "src/".to_owned()]
+        );
+    }
+
+    #[test]
+    fn votes_count_once_per_voter() {
+        let dir = tempdir::TempDir::new("bleep").unwrap();
+        let feedback = Feedback::open(&dir.path().join("feedback.json")).unwrap();
+        let repo = "github.com/org/repo";
+        let votes = |path: &str| feedback.votes.read().unwrap()[repo][path];
+
+        let query_id = uuid::Uuid::new_v4();
+        feedback.answered(query_id, repo, "src/lib.rs");
+        assert_eq!(feedback.answer_repo(query_id).as_deref(), Some(repo));
+
+        feedback.vote(query_id, "alice", true).unwrap();
+        feedback.vote(query_id, "alice", true).unwrap();
+        feedback.vote(query_id, "bob", true).unwrap();
+        assert_eq!(votes("src/lib.rs"), Votes { up: 2, down: 0 });
+
+        // Changing a vote replaces it
+        feedback.vote(query_id, "bob", false).unwrap();
+        assert_eq!(votes("src/lib.rs"), Votes { up: 1, down: 1 });
+        assert_eq!(votes("src/"), Votes { up: 1, down: 1 });
+
+        // Unknown answers can't be voted on
+        let unknown = uuid::Uuid::new_v4();
+        assert_eq!(feedback.answer_repo(unknown), None);
+        feedback.vote(unknown, "alice", true).unwrap();
+        assert_eq!(votes("src/lib.rs"), Votes { up: 1, down: 1 });
+    }
+}
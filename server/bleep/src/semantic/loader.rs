@@ -0,0 +1,150 @@
+//! The semantic subsystem is loaded in the background.
+//!
+//! Loading the embedding model and connecting to Qdrant takes a while, which users who only
+//! search lexically shouldn't wait for. Lexical search is served right away; semantic search
+//! and answers are unavailable until the subsystem is loaded, while indexing and changes to
+//! embeddings wait for it, so that no file goes without its embeddings.
+//!
+//! Loading that fails is retried with backoff, and indexing fails in the meantime, rather than
+//! caching files without their embeddings.
+
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+
+use tokio::{runtime::Handle, sync::watch};
+use tracing::{error, info, warn};
+
+use super::Semantic;
+use crate::Configuration;
+
+/// Wait before retrying to load, doubled for each retry after, up to [`MAX_RETRY_BACKOFF`]
+const RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone)]
+pub enum LoadState {
+    /// Qdrant isn't configured
+    Disabled,
+    Loading,
+    Ready(Semantic),
+    /// The last attempt at loading failed, and is retried after a while
+    Failed(Arc<str>),
+}
+
+#[derive(Clone)]
+pub struct SemanticLoader(watch::Receiver<LoadState>);
+
+impl SemanticLoader {
+    pub fn disabled() -> Self {
+        Self(watch::channel(LoadState::Disabled).1)
+    }
+
+    /// Start loading the embedding model in `model_dir`, and connecting to Qdrant at
+    /// `qdrant_url`, in the background, until it succeeds
+    pub fn load(model_dir: PathBuf, qdrant_url: String, config: Arc<Configuration>) -> Self {
+        let (sender, receiver) = watch::channel(LoadState::Loading);
+
+        tokio::spawn(async move {
+            let mut backoff = RETRY_BACKOFF;
+
+            loop {
+                let state =
+                    load_once(model_dir.clone(), qdrant_url.clone(), Arc::clone(&config)).await;
+
+                let ready = matches!(state, LoadState::Ready(_));
+                if sender.send(state).is_err() || ready {
+                    return;
+                }
+
+                warn!(?backoff, "retrying to load semantic search");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+
+                if sender.send(LoadState::Loading).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Self(receiver)
+    }
+
+    pub fn state(&self) -> LoadState {
+        self.0.borrow().clone()
+    }
+
+    /// The semantic subsystem, if it's loaded
+    pub fn get(&self) -> Option<Semantic> {
+        match &*self.0.borrow() {
+            LoadState::Ready(semantic) => Some(semantic.clone()),
+            _ => None,
+        }
+    }
+
+    /// Wait for the semantic subsystem to load, unless it's disabled or fails to load
+    pub async fn wait(&self) -> Option<Semantic> {
+        let mut receiver = self.0.clone();
+        while matches!(*receiver.borrow(), LoadState::Loading) {
+            if receiver.changed().await.is_err() {
+                break;
+            }
+        }
+
+        let state = receiver.borrow().clone();
+        match state {
+            LoadState::Ready(semantic) => Some(semantic),
+            _ => None,
+        }
+    }
+
+    /// Like [`SemanticLoader::wait`], but for callers that can't await, such as indexing,
+    /// which runs on the rayon pool.
+    ///
+    /// Fails if loading did, rather than treating semantic search as disabled.
+    pub fn wait_blocking(&self) -> anyhow::Result<Option<Semantic>> {
+        let semantic = tokio::task::block_in_place(|| Handle::current().block_on(self.wait()));
+
+        match self.state() {
+            LoadState::Failed(err) => Err(anyhow!("semantic search failed to load: {err}")),
+            _ => Ok(semantic),
+        }
+    }
+}
+
+async fn load_once(
+    model_dir: PathBuf,
+    qdrant_url: String,
+    config: Arc<Configuration>,
+) -> LoadState {
+    // The model is read from disk, and warmed up, without holding up the runtime
+    let loading = tokio::task::spawn_blocking(move || {
+        let start = Instant::now();
+        let semantic =
+            Handle::current().block_on(Semantic::initialize(&model_dir, &qdrant_url, config))?;
+
+        if let Err(err) = semantic.warm_up() {
+            warn!(?err, "failed to warm up semantic search");
+        }
+
+        info!(elapsed = ?start.elapsed(), "semantic search loaded");
+        Ok::<_, super::SemanticError>(semantic)
+    });
+
+    match loading.await {
+        Ok(Ok(semantic)) => LoadState::Ready(semantic),
+        Ok(Err(err)) => {
+            error!(?err, "failed to load semantic search");
+            LoadState::Failed(err.to_string().into())
+        }
+        Err(err) => {
+            error!(?err, "loading semantic search panicked");
+            LoadState::Failed("loading semantic search panicked".into())
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Embeddings are upserted in batches.
+//!
+//! Files are embedded far faster than their points can be sent one file at a time, so points
+//! are queued, and sent once enough of them are. Only a few batches are sent at once, and
+//! queueing more waits until one of them is stored, which keeps indexing from running ahead of
+//! Qdrant. Batches that fail are retried, as Qdrant is often only briefly unavailable.
+//!
+//! Batches that still fail are dropped, and counted against the repos of their points, so that
+//! flushing a repo's points fails. Its files then aren't recorded as embedded, and are embedded
+//! again by the next sync.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use qdrant_client::{
+    prelude::QdrantClient,
+    qdrant::{value::Kind, PointStruct},
+};
+use tokio::sync::Semaphore;
+use tracing::{debug, error, warn};
+
+use super::COLLECTION_NAME;
+
+/// Points sent at once
+const BATCH_SIZE: usize = 256;
+
+/// Batches sent at once, before queueing more points waits
+const MAX_IN_FLIGHT: u32 = 4;
+
+/// Attempts at sending a batch, before its points are dropped
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Wait before a batch is sent again, doubled after every failed attempt
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+pub(super) struct Upserts {
+    qdrant: Arc<QdrantClient>,
+    pending: Mutex<Vec<PointStruct>>,
+    in_flight: Arc<Semaphore>,
+    /// Incremented once a batch is stored. See [`super::Semantic::search`].
+    generation: Arc<AtomicU64>,
+    /// Points dropped since they were last flushed, by repo
+    dropped: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Upserts {
+    pub(super) fn new(qdrant: Arc<QdrantClient>, generation: Arc<AtomicU64>) -> Self {
+        Self {
+            qdrant,
+            pending: Mutex::default(),
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT as usize)),
+            generation,
+            dropped: Arc::default(),
+        }
+    }
+
+    /// Queue `points`, and send a batch if enough are queued
+    pub(super) async fn push(&self, points: Vec<PointStruct>) {
+        let batch = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.extend(points);
+            if pending.len() < BATCH_SIZE {
+                return;
+            }
+
+            std::mem::take(&mut *pending)
+        };
+
+        self.send(batch).await;
+    }
+
+    /// Send every queued point, and wait until every batch is stored. Fails if any point of
+    /// `repo_ref` was dropped since its points were last flushed.
+    pub(super) async fn flush(&self, repo_ref: &str) -> anyhow::Result<()> {
+        let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+        if !batch.is_empty() {
+            self.send(batch).await;
+        }
+
+        // Every permit is only available once no batch is in flight
+        _ = self.in_flight.acquire_many(MAX_IN_FLIGHT).await;
+
+        match self.dropped.lock().unwrap().remove(repo_ref) {
+            Some(points) => anyhow::bail!("failed to store {points} embeddings of {repo_ref}"),
+            None => Ok(()),
+        }
+    }
+
+    /// Send `batch` in the background, once fewer than [`MAX_IN_FLIGHT`] batches are
+    async fn send(&self, batch: Vec<PointStruct>) {
+        let permit = Arc::clone(&self.in_flight)
+            .acquire_owned()
+            .await
+            .expect("upsert semaphore is never closed");
+
+        let qdrant = Arc::clone(&self.qdrant);
+        let generation = Arc::clone(&self.generation);
+        let dropped = Arc::clone(&self.dropped);
+
+        tokio::spawn(async move {
+            let points = batch.len();
+            let mut backoff = RETRY_BACKOFF;
+
+            for attempt in 1..=MAX_ATTEMPTS {
+                match qdrant.upsert_points(COLLECTION_NAME, batch.clone()).await {
+                    Ok(_) => {
+                        debug!(points, "upserted batch");
+                        generation.fetch_add(1, Ordering::Release);
+                        break;
+                    }
+                    Err(err) if attempt < MAX_ATTEMPTS => {
+                        warn!(?err, points, attempt, "failed to upsert batch; retrying");
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 2;
+                    }
+                    Err(err) => {
+                        error!(?err, points, "failed to upsert batch; dropping it");
+                        count_dropped(&mut dropped.lock().unwrap(), &batch);
+                    }
+                }
+            }
+
+            drop(permit);
+        });
+    }
+}
+
+/// Count the points of `batch` against their repos
+fn count_dropped(dropped: &mut HashMap<String, usize>, batch: &[PointStruct]) {
+    for point in batch {
+        if let Some(Kind::StringValue(repo_ref)) =
+            point.payload.get("repo_ref").and_then(|v| v.kind.as_ref())
+        {
+            *dropped.entry(repo_ref.clone()).or_default() += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_points_are_counted_by_repo() {
+        let point = |repo_ref: &str| PointStruct {
+            payload: HashMap::from([("repo_ref".into(), repo_ref.into())]),
+            ..Default::default()
+        };
+
+        let mut dropped = HashMap::new();
+        count_dropped(
+            &mut dropped,
+            &[
+                point("github.com/a/b"),
+                point("github.com/a/b"),
+                point("local//c"),
+            ],
+        );
+
+        assert_eq!(dropped.get("github.com/a/b"), Some(&2));
+        assert_eq!(dropped.get("local//c"), Some(&1));
+    }
+}
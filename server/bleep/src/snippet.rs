@@ -7,7 +7,7 @@ use utoipa::ToSchema;
 use crate::{indexes, symbol::Symbol};
 use std::ops::Range;
 
-#[derive(Serialize, ToSchema, Debug, PartialEq, Eq)]
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq, Eq)]
 pub struct SnippedFile {
     pub relative_path: String,
     pub repo_name: String,
@@ -16,7 +16,7 @@ pub struct SnippedFile {
     pub snippets: Vec<Snippet>,
 }
 
-#[derive(Serialize, ToSchema, Debug, PartialEq, Eq)]
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq, Eq)]
 pub struct Snippet {
     pub data: String,
     pub highlights: Vec<Range<usize>>,
@@ -294,7 +294,7 @@ impl Snipper {
     }
 }
 
-#[derive(Serialize, ToSchema)]
+#[derive(Serialize, ToSchema, Clone)]
 pub struct HighlightedString {
     pub text: String,
 
@@ -1,6 +1,9 @@
 use crate::{
-    remotes::{gather_repo_roots, BackendCredential},
+    background::Jobs,
+    remotes::{gather_repo_roots, BackendCredential, SecretStore},
     repo::{Backend, RepoError, RepoRef, Repository, SyncStatus},
+    semantic::Feedback,
+    webserver::keys::ApiKey,
 };
 use anyhow::Result;
 use clap::Args;
@@ -19,6 +22,9 @@ include!(concat!(env!("OUT_DIR"), "/schema_version.rs"));
 
 pub(crate) type RepositoryPool = Arc<DashMap<RepoRef, Repository>>;
 
+/// Name of the state file in the index directory, unless another is given
+const STATE_FILE: &str = "repo_state.json";
+
 #[derive(Serialize, Deserialize, Args, Debug, Clone, Default, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub struct StateSource {
@@ -46,12 +52,41 @@ pub struct StateSource {
     #[clap(long)]
     #[serde(default)]
     cookie_key: Option<PathBuf>,
+
+    /// Local store of hashed API keys
+    #[clap(long)]
+    #[serde(default)]
+    api_keys: Option<PathBuf>,
+
+    /// Encrypted store of per-repository credentials
+    #[clap(long)]
+    #[serde(default)]
+    secrets: Option<PathBuf>,
+
+    /// Key file of the secret store
+    #[clap(long)]
+    #[serde(default)]
+    secrets_key: Option<PathBuf>,
+
+    /// Key file user ids are pseudonymized with
+    #[clap(long)]
+    #[serde(default)]
+    user_id_key: Option<PathBuf>,
+
+    /// Votes on answers, which snippets are ranked with
+    #[clap(long)]
+    #[serde(default)]
+    feedback: Option<PathBuf>,
+
+    /// Background jobs, which are resumed on startup if they didn't finish
+    #[clap(long)]
+    #[serde(default)]
+    jobs: Option<PathBuf>,
 }
 
 impl StateSource {
     pub(crate) fn set_default_dir(&mut self, dir: &Path) {
-        self.state_file
-            .get_or_insert_with(|| dir.join("repo_state.json"));
+        self.state_file.get_or_insert_with(|| dir.join(STATE_FILE));
 
         self.credentials
             .get_or_insert_with(|| dir.join("credentials.json"));
@@ -62,6 +97,22 @@ impl StateSource {
         self.cookie_key
             .get_or_insert_with(|| dir.join("cookie_key.bin"));
 
+        self.api_keys
+            .get_or_insert_with(|| dir.join("api_keys.json"));
+
+        self.secrets.get_or_insert_with(|| dir.join("secrets.json"));
+
+        self.secrets_key
+            .get_or_insert_with(|| dir.join("secrets_key.bin"));
+
+        self.user_id_key
+            .get_or_insert_with(|| dir.join("user_id_key.bin"));
+
+        self.feedback
+            .get_or_insert_with(|| dir.join("feedback.json"));
+
+        self.jobs.get_or_insert_with(|| dir.join("jobs.json"));
+
         self.directory.get_or_insert_with(|| {
             let target = dir.join("local_cache");
             std::fs::create_dir_all(&target).unwrap();
@@ -83,6 +134,14 @@ impl StateSource {
         self.directory.as_ref().unwrap().join(name)
     }
 
+    /// Where the repository `reporef` is kept on disk: local repositories where they are, and
+    /// remote ones cloned into the repository directory
+    pub(crate) fn repo_disk_path(&self, reporef: &RepoRef) -> PathBuf {
+        reporef
+            .local_path()
+            .unwrap_or_else(|| self.repo_path_for_name(&reporef.to_string().replace('/', "_")))
+    }
+
     pub(crate) fn initialize_pool(&self) -> Result<RepositoryPool, RepoError> {
         #[cfg(target = "windows")]
         use dunce::canonicalize;
@@ -166,6 +225,25 @@ impl StateSource {
         }
     }
 
+    /// Repos saved in the state file, which defaults to the one in `index_dir`, without looking
+    /// for new repos on disk. Removed repos are left out.
+    pub(crate) fn saved_repos(&self, index_dir: &Path) -> Result<Vec<RepoRef>, RepoError> {
+        let path = self
+            .state_file
+            .clone()
+            .unwrap_or_else(|| index_dir.join(STATE_FILE));
+
+        let pool: DashMap<RepoRef, Repository> = read_file_or_default(&path)?;
+        let mut repos = pool
+            .into_iter()
+            .filter(|(_, repo)| repo.sync_status != SyncStatus::Removed)
+            .map(|(reporef, _)| reporef)
+            .collect::<Vec<_>>();
+
+        repos.sort_by_key(ToString::to_string);
+        Ok(repos)
+    }
+
     pub(crate) fn initialize_credentials(
         &self,
     ) -> Result<DashMap<Backend, BackendCredential>, RepoError> {
@@ -182,6 +260,25 @@ impl StateSource {
         }
     }
 
+    pub(crate) fn initialize_api_keys(&self) -> Result<Vec<ApiKey>, RepoError> {
+        read_file_or_default(self.api_keys.as_ref().unwrap())
+    }
+
+    pub(crate) fn initialize_secrets(&self) -> Result<SecretStore> {
+        SecretStore::open(
+            self.secrets.as_ref().unwrap(),
+            self.secrets_key.as_ref().unwrap(),
+        )
+    }
+
+    pub(crate) fn initialize_feedback(&self) -> Result<Feedback> {
+        Feedback::open(self.feedback.as_ref().unwrap())
+    }
+
+    pub(crate) fn initialize_jobs(&self) -> Result<Jobs> {
+        Jobs::open(self.jobs.as_ref().unwrap())
+    }
+
     pub fn index_version_mismatch(&self) -> bool {
         let current: String = read_file_or_default(self.version_file.as_ref().unwrap()).unwrap();
 
@@ -192,6 +289,22 @@ impl StateSource {
         pretty_write_file(self.version_file.as_ref().unwrap(), SCHEMA_VERSION)
     }
 
+    /// The key user ids are pseudonymized with, generated on first use. Replacing it changes
+    /// every pseudonym.
+    pub(crate) fn initialize_user_id_key(&self) -> Result<ring::hmac::Key> {
+        let path = self.user_id_key.as_ref().unwrap();
+
+        let key = if path.exists() {
+            std::fs::read(path)?
+        } else {
+            let key = rand::thread_rng().gen::<[u8; 32]>().to_vec();
+            write_private(path, &key)?;
+            key
+        };
+
+        Ok(ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &key))
+    }
+
     pub fn initialize_cookie_key(&self) -> Result<axum_extra::extract::cookie::Key> {
         let path = self.cookie_key.as_ref().unwrap();
 
@@ -239,6 +352,23 @@ pub fn pretty_write_file<T: Serialize + ?Sized>(
     Ok(())
 }
 
+/// Write a file only its owner can read
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::File::options();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(contents)?;
+    Ok(())
+}
+
 pub fn read_file_or_default<T: Default + DeserializeOwned>(path: &Path) -> Result<T, RepoError> {
     if !path.exists() {
         return Ok(Default::default());
@@ -293,10 +423,7 @@ mod test {
 
         let repo_pool = StateSource {
             directory: Some(path.to_path_buf()),
-            credentials: None,
-            state_file: None,
-            version_file: None,
-            cookie_key: None,
+            ..Default::default()
         }
         .initialize_pool()
         .unwrap();
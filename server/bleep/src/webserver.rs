@@ -1,33 +1,83 @@
 use crate::{env::Feature, snippet, Application};
 
+use anyhow::Context;
 use axum::middleware;
-use axum::{http::StatusCode, response::IntoResponse, routing::get, Extension, Json};
+use axum::{
+    http::{HeaderName, HeaderValue, Method, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get, post, put},
+    Extension, Json,
+};
+use std::borrow::Cow;
 use std::sync::Arc;
-use std::{borrow::Cow, net::SocketAddr};
 use tower::Service;
 use tower_http::services::{ServeDir, ServeFile};
-use tower_http::{catch_panic::CatchPanicLayer, cors::CorsLayer};
-use tracing::info;
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    compression::{
+        predicate::{And, NotForContentType},
+        CompressionLayer, DefaultPredicate, Predicate,
+    },
+    cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer},
+};
 use utoipa::OpenApi;
 use utoipa::ToSchema;
 
 mod aaa;
+mod access;
+mod admin;
 pub mod answer;
+mod audit;
+mod auth;
 mod autocomplete;
+mod breaker;
+mod callback;
+pub mod cli;
+mod diff;
+mod etag;
+mod fields;
 mod file;
 mod github;
+mod graphql;
+mod grep;
+mod health;
 mod hoverable;
+mod idempotency;
 mod index;
 mod intelligence;
-mod query;
+pub mod keys;
+mod limits;
+mod listener;
+pub mod lsp;
+pub mod mcp;
+mod metrics;
+pub(crate) mod query;
+mod ratelimit;
 mod repos;
+mod review;
+mod sarif;
 mod semantic;
+mod shed;
+pub mod slack;
+mod stats;
+pub mod tenant;
+mod tls;
+mod tree;
+#[cfg(feature = "embedded-ui")]
+mod ui;
+mod version;
+mod webhooks;
 
 pub type Router<S = Application> = axum::Router<S>;
 
 #[allow(unused)]
 pub(in crate::webserver) mod prelude {
-    pub(in crate::webserver) use super::{json, EndpointError, Error, ErrorKind, Result};
+    pub(in crate::webserver) use super::{
+        access::RepoAccess,
+        json,
+        keys::{self, Scoped},
+        EndpointError, Error, ErrorCode, ErrorKind, Result,
+    };
     pub(in crate::webserver) use crate::indexes::Indexes;
     pub(in crate::webserver) use axum::{
         extract::Query, http::StatusCode, response::IntoResponse, Extension,
@@ -38,36 +88,62 @@ pub(in crate::webserver) mod prelude {
 }
 
 pub async fn start(app: Application) -> anyhow::Result<()> {
-    let bind = SocketAddr::new(app.config.host.parse()?, app.config.port);
+    // The answer endpoints share their HTTP client and rate limit
+    let answer_state = Arc::new(answer::AnswerState::new(&app.config)?);
+    let answer_limiter = ratelimit::RateLimiter::new(app.config.answer_rate_limit);
+
+    let audit_log = match app.config.audit_log.clone() {
+        Some(path) => Some(audit::AuditLog::open(path).await?),
+        None => None,
+    };
+
+    // Retries of requests that enqueue work are answered from this cache
+    let idempotency_cache = idempotency::IdempotencyCache::new();
+    let idempotent =
+        || middleware::from_fn_with_state(Arc::clone(&idempotency_cache), idempotency::deduplicate);
 
-    let mut api = Router::new()
+    // Cheap read requests have a concurrency budget of their own, so answers can't starve them
+    let searches = Router::new()
         // querying
         .route("/q", get(query::handle))
         // autocomplete
         .route("/autocomplete", get(autocomplete::handle))
+        .route("/grep", get(grep::handle))
+        .route("/file", get(file::content))
+        .route("/file/*ref", get(file::handle))
+        .route("/tree", get(tree::handle));
+
+    let mut api = limits::concurrency(searches, app.config.max_concurrent_searches)
         // indexing
         .route("/index", get(index::handle))
         // repo management
         .route("/repos", get(repos::available))
         .route(
             "/repos/indexed",
-            get(repos::indexed).put(repos::set_indexed),
+            get(repos::indexed)
+                .put(repos::set_indexed)
+                .layer(idempotent()),
         )
         .route(
             "/repos/indexed/*path",
-            get(repos::get_by_id).delete(repos::delete_by_id),
+            get(repos::get_by_id)
+                .delete(repos::delete_by_id)
+                .layer(idempotent()),
+        )
+        .route("/repos/sync/*path", get(repos::sync).layer(idempotent()))
+        .route("/repos/events", get(repos::events))
+        .route("/repos/groups", get(repos::groups))
+        .route("/repos/import", post(repos::import).layer(idempotent()))
+        .route(
+            "/repos/groups/*path",
+            put(repos::set_groups).layer(idempotent()),
         )
-        .route("/repos/sync/*path", get(repos::sync))
         // intelligence
         .route("/hoverable", get(hoverable::handle))
         .route("/token-info", get(intelligence::handle))
         // misc
-        .route("/file/*ref", get(file::handle))
         .route("/semantic/chunks", get(semantic::raw_chunks))
-        .route(
-            "/answer",
-            get(answer::handle).with_state(Arc::new(answer::AnswerState::default())),
-        );
+        .route("/answer/feedback", post(answer::feedback));
 
     if app.env.allow(Feature::GithubDeviceFlow) {
         api = api
@@ -80,22 +156,173 @@ pub async fn start(app: Application) -> anyhow::Result<()> {
         api = api.route("/repos/scan", get(repos::scan_local));
     }
 
+    api = api
+        .route("/admin/repos/reindex/*path", post(admin::reindex))
+        .route("/admin/repos/purge/*path", post(admin::purge))
+        .route(
+            "/admin/repos/credentials/*path",
+            put(admin::set_credentials).delete(admin::delete_credentials),
+        )
+        .route("/admin/compact", post(admin::compact))
+        .route("/admin/queues", get(admin::queues))
+        .route("/admin/jobs", get(admin::jobs))
+        .route("/admin/jobs/:id", delete(admin::cancel_job))
+        .route("/admin/queries", get(admin::queries))
+        .route("/stats/usage", get(stats::usage))
+        .route("/metrics", get(metrics::handle));
+
+    if let Some(audit_log) = &audit_log {
+        api = api.route(
+            "/admin/audit",
+            get(audit::query).with_state(Arc::clone(audit_log)),
+        );
+    }
+
+    // Answers take much longer than other requests, so they have their own timeout
+    let answers = Router::new()
+        .route(
+            "/graphql",
+            post(graphql::handle)
+                .with_state(graphql::schema(Arc::clone(&answer_state)))
+                .layer(middleware::from_fn_with_state(
+                    Arc::clone(&answer_limiter),
+                    ratelimit::limit,
+                )),
+        )
+        .route(
+            "/mcp",
+            post(mcp::handle)
+                .with_state(Arc::clone(&answer_state))
+                .layer(middleware::from_fn_with_state(
+                    Arc::clone(&answer_limiter),
+                    ratelimit::limit,
+                )),
+        )
+        .route(
+            "/answer",
+            get(answer::handle)
+                .with_state(Arc::clone(&answer_state))
+                .layer(middleware::from_fn_with_state(
+                    Arc::clone(&answer_limiter),
+                    ratelimit::limit,
+                )),
+        )
+        .route(
+            "/explain",
+            post(answer::explain)
+                .with_state(Arc::clone(&answer_state))
+                .layer(middleware::from_fn_with_state(
+                    Arc::clone(&answer_limiter),
+                    ratelimit::limit,
+                )),
+        )
+        .route(
+            "/review",
+            post(review::handle)
+                .with_state(Arc::clone(&answer_state))
+                .layer(middleware::from_fn_with_state(
+                    Arc::clone(&answer_limiter),
+                    ratelimit::limit,
+                )),
+        )
+        .route(
+            "/answer/diff",
+            post(diff::handle)
+                .with_state(Arc::clone(&answer_state))
+                .layer(middleware::from_fn_with_state(
+                    Arc::clone(&answer_limiter),
+                    ratelimit::limit,
+                )),
+        )
+        .route(
+            "/answer/batch",
+            post(answer::batch)
+                .with_state(Arc::clone(&answer_state))
+                .layer(idempotent())
+                .layer(middleware::from_fn_with_state(
+                    Arc::clone(&answer_limiter),
+                    ratelimit::limit,
+                )),
+        );
+
+    let answers = limits::timeout(answers, app.config.answer_timeout);
+    api = limits::timeout(api, app.config.request_timeout).merge(shed::shed(
+        answers,
+        app.config.max_concurrent_answers,
+        app.config.max_queued_answers,
+    ));
+
+    // Bundles take as long to upload and install as they are large, so imports aren't timed out
+    api = api.route("/index/import", post(index::import));
+
+    // Clients are identified by their credentials, so this must run after authentication.
+    api = api.layer(middleware::from_fn_with_state(
+        ratelimit::RateLimiter::new(app.config.rate_limit),
+        ratelimit::limit,
+    ));
+
+    if let Some(audit_log) = audit_log {
+        api = api.layer(middleware::from_fn_with_state(audit_log, audit::record));
+    }
+
+    api = api.layer(middleware::from_fn_with_state(
+        app.clone(),
+        keys::authenticate,
+    ));
+
     // Note: all routes above this point must be authenticated.
     if app.env.allow(Feature::AuthorizationRequired) {
         api = aaa::router(api, app.clone());
     }
 
+    if app.env.allow(Feature::AuthorizationRequired) || auth::enabled(&app) {
+        api = auth::router(api, app.clone());
+    }
+
+    // Webhook deliveries and Slack commands are authenticated by their signature instead.
+    if app.config.github_webhook_secret.is_some() {
+        api = api.route(
+            "/webhooks/github",
+            post(webhooks::github).with_state(Arc::clone(&answer_state)),
+        );
+    }
+
+    if app.config.gitlab_webhook_secret.is_some() {
+        api = api.route("/webhooks/gitlab", post(webhooks::gitlab));
+    }
+
+    if app.config.slack_signing_secret.is_some() {
+        api = api.route(
+            "/slack/command",
+            post(slack::command).with_state(slack::SlackState::new(
+                answer_state,
+                answer_limiter,
+                &app.config,
+            )),
+        );
+    }
+
     api = api
         .route("/api-doc/openapi.json", get(openapi_json::handle))
         .route("/api-doc/openapi.yaml", get(openapi_yaml::handle))
-        .route("/health", get(health));
+        .route("/health", get(health))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/version", get(version::handle));
+
+    api = limits::body_size(api, app.config.max_body_size);
+    api = limits::concurrency(api, app.config.max_concurrent_requests);
+
+    if !app.config.compression.is_empty() {
+        api = api.layer(compression(&app.config)?);
+    }
 
     let api: Router<()> = api
         .layer(Extension(app.indexes.clone()))
         .layer(Extension(app.semantic.clone()))
         .layer(Extension(app.clone()))
         .with_state(app.clone())
-        .layer(CorsLayer::permissive())
+        .layer(cors(&app.config)?)
         .layer(CatchPanicLayer::new());
 
     let mut router = Router::new().nest("/api", api);
@@ -116,14 +343,109 @@ pub async fn start(app: Application) -> anyhow::Result<()> {
         );
     }
 
-    info!(%bind, "starting webserver");
-    axum::Server::bind(&bind)
-        .serve(router.into_make_service())
-        .await?;
+    // A directory given with `--frontend-dist` takes precedence, so that a UI can be tried out
+    // without rebuilding the binary
+    #[cfg(feature = "embedded-ui")]
+    if app.config.frontend_dist.is_none() {
+        router = router.fallback(ui::handle);
+    }
+
+    let listeners = listener::Listener::from_config(&app.config)?;
+    let names = listeners.iter().map(ToString::to_string).collect();
+    let bound = listeners
+        .into_iter()
+        .map(listener::Listener::bind)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let tls = tls::config(&app.config).await?;
+    tokio::spawn(crate::readiness::signal(app, names));
+
+    futures::future::try_join_all(
+        bound
+            .into_iter()
+            .map(|listener| listener.serve(router.clone(), tls.clone())),
+    )
+    .await?;
 
     Ok(())
 }
 
+/// Build the CORS policy from the configuration.
+///
+/// Without any configured origins, cross-origin requests are allowed from anywhere.
+fn cors(config: &crate::Configuration) -> anyhow::Result<CorsLayer> {
+    if config.cors_allowed_origins.is_empty() {
+        return Ok(CorsLayer::permissive());
+    }
+
+    let origins = if config.cors_allowed_origins.iter().any(|o| o == "*") {
+        if config.cors_allow_credentials() {
+            anyhow::bail!("CORS credentials can't be allowed for any origin");
+        }
+
+        AllowOrigin::any()
+    } else {
+        config
+            .cors_allowed_origins
+            .iter()
+            .map(|o| o.parse())
+            .collect::<Result<Vec<HeaderValue>, _>>()
+            .context("invalid CORS origin")?
+            .into()
+    };
+
+    let methods = if config.cors_allowed_methods.is_empty() {
+        AllowMethods::mirror_request()
+    } else {
+        config
+            .cors_allowed_methods
+            .iter()
+            .map(|m| m.to_uppercase().parse())
+            .collect::<Result<Vec<Method>, _>>()
+            .context("invalid CORS method")?
+            .into()
+    };
+
+    let headers = if config.cors_allowed_headers.is_empty() {
+        AllowHeaders::mirror_request()
+    } else {
+        config
+            .cors_allowed_headers
+            .iter()
+            .map(|h| h.parse())
+            .collect::<Result<Vec<HeaderName>, _>>()
+            .context("invalid CORS header")?
+            .into()
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .allow_credentials(config.cors_allow_credentials()))
+}
+
+/// Build the response compression layer from the configuration.
+///
+/// Event streams are never compressed, as that would delay answers until the buffer fills up.
+fn compression(
+    config: &crate::Configuration,
+) -> anyhow::Result<CompressionLayer<And<DefaultPredicate, NotForContentType>>> {
+    let (mut gzip, mut br) = (false, false);
+    for algorithm in &config.compression {
+        match algorithm.as_str() {
+            "gzip" => gzip = true,
+            "br" => br = true,
+            other => anyhow::bail!("unsupported compression algorithm: {other}"),
+        }
+    }
+
+    Ok(CompressionLayer::new()
+        .gzip(gzip)
+        .br(br)
+        .compress_when(DefaultPredicate::new().and(NotForContentType::new("text/event-stream"))))
+}
+
 pub(in crate::webserver) fn json<'a, T>(val: T) -> Json<Response<'a>>
 where
     Response<'a>: From<T>,
@@ -151,8 +473,10 @@ impl Error {
         };
 
         let body = Json(Response::from(EndpointError {
+            code: ErrorCode::from(&kind),
             kind,
             message: message.into(),
+            retry_after_ms: None,
         }));
 
         Error { status, body }
@@ -163,23 +487,48 @@ impl Error {
         self
     }
 
-    fn internal<S: std::fmt::Display>(message: S) -> Self {
-        Error {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            body: Json(Response::from(EndpointError {
-                kind: ErrorKind::Internal,
-                message: message.to_string().into(),
-            })),
+    fn with_code(mut self, code: ErrorCode) -> Self {
+        if let Json(Response::Error(error)) = &mut self.body {
+            error.code = code;
         }
+        self
+    }
+
+    /// Tell clients to retry after `retry_after`, in the body and the `Retry-After` header
+    fn with_retry_after(mut self, retry_after: std::time::Duration) -> Self {
+        if let Json(Response::Error(error)) = &mut self.body {
+            error.retry_after_ms = Some(retry_after.as_millis() as u64);
+        }
+        self
+    }
+
+    fn internal<S: std::fmt::Display>(message: S) -> Self {
+        Error::new(ErrorKind::Internal, message.to_string())
     }
 
     fn user<S: std::fmt::Display>(message: S) -> Self {
-        Error {
-            status: StatusCode::BAD_REQUEST,
-            body: Json(Response::from(EndpointError {
-                kind: ErrorKind::User,
-                message: message.to_string().into(),
-            })),
+        Error::new(ErrorKind::User, message.to_string())
+    }
+
+    /// The semantic subsystem, or an error saying why it can't be used
+    fn semantic(loader: &crate::semantic::SemanticLoader) -> Result<crate::semantic::Semantic> {
+        use crate::semantic::LoadState;
+
+        match loader.state() {
+            LoadState::Ready(semantic) => Ok(semantic),
+            LoadState::Loading => Err(Error::new(
+                ErrorKind::UpstreamService,
+                "semantic search is still loading",
+            )
+            .with_status(StatusCode::SERVICE_UNAVAILABLE)),
+            LoadState::Failed(err) => Err(Error::new(
+                ErrorKind::UpstreamService,
+                format!("semantic search failed to load: {err}"),
+            )),
+            LoadState::Disabled => Err(Error::new(
+                ErrorKind::Configuration,
+                "Qdrant not configured",
+            )),
         }
     }
 
@@ -197,9 +546,30 @@ impl From<anyhow::Error> for Error {
     }
 }
 
+impl From<crate::query::parser::ParseError> for Error {
+    fn from(value: crate::query::parser::ParseError) -> Self {
+        Error::user(value).with_code(ErrorCode::QueryParseError)
+    }
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        (self.status, self.body).into_response()
+        let retry_after = match &self.body {
+            Json(Response::Error(EndpointError {
+                retry_after_ms: Some(ms),
+                ..
+            })) => Some(HeaderValue::from((ms + 999) / 1000)),
+            _ => None,
+        };
+
+        let mut response = (self.status, self.body).into_response();
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(axum::http::header::RETRY_AFTER, retry_after);
+        }
+
+        response
     }
 }
 
@@ -209,8 +579,15 @@ struct EndpointError<'a> {
     /// The kind of this error
     kind: ErrorKind,
 
+    /// A stable identifier of this error, for clients to act on
+    code: ErrorCode,
+
     /// A context aware message describing the error
     message: Cow<'a, str>,
+
+    /// Milliseconds to wait before retrying, when a service is overloaded or unavailable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
 }
 
 /// The kind of an error
@@ -231,6 +608,57 @@ enum ErrorKind {
     Custom,
 }
 
+/// Stable, machine-readable error codes.
+///
+/// Unlike messages, codes never change once introduced, so clients can rely on them.
+#[derive(serde::Serialize, PartialEq, Eq, ToSchema, Debug, Clone, Copy)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[non_exhaustive]
+enum ErrorCode {
+    /// The request was malformed
+    BadRequest,
+    /// The query could not be parsed
+    QueryParseError,
+    NotFound,
+    RepoNotFound,
+    FileNotFound,
+    /// Credentials are missing or invalid
+    Unauthenticated,
+    /// The credentials don't allow this request
+    Forbidden,
+    RateLimited,
+    /// The server is handling too many requests
+    ServerOverloaded,
+    Timeout,
+    /// A service needed to fulfill the request is not configured
+    NotConfigured,
+    /// A service needed to fulfill the request failed
+    UpstreamError,
+    /// A service needed to fulfill the request is over capacity
+    UpstreamOverloaded,
+    /// A service needed to fulfill the request keeps failing, and isn't sent requests for now
+    UpstreamUnavailable,
+    InternalError,
+    /// A request with the same idempotency key is still in progress
+    Conflict,
+    /// The idempotency key was used before, for a request with a different body
+    IdempotencyKeyReused,
+}
+
+impl From<&ErrorKind> for ErrorCode {
+    fn from(kind: &ErrorKind) -> Self {
+        match kind {
+            ErrorKind::User => ErrorCode::BadRequest,
+            ErrorKind::NotFound => ErrorCode::NotFound,
+            ErrorKind::Configuration => ErrorCode::NotConfigured,
+            ErrorKind::UpstreamService => ErrorCode::UpstreamError,
+            ErrorKind::Unknown | ErrorKind::Internal | ErrorKind::Custom => {
+                ErrorCode::InternalError
+            }
+        }
+    }
+}
+
 /// Every endpoint exposes a Response type
 #[derive(serde::Serialize)]
 #[serde(untagged)]
@@ -240,11 +668,23 @@ pub(in crate::webserver) enum Response<'a> {
     Repositories(repos::ReposResponse),
     Query(query::QueryResponse),
     Autocomplete(autocomplete::AutocompleteResponse),
+    Grep(grep::GrepResponse),
     Hoverable(hoverable::HoverableResponse),
     Intelligence(intelligence::TokenInfoResponse),
     File(file::FileResponse),
+    FileContent(file::FileContentResponse),
+    Tree(tree::TreeResponse),
     Semantic(semantic::SemanticResponse),
     Answer(answer::AnswerResponse),
+    AnswerBatch(answer::BatchAnswerResponse),
+    AnswerJob(answer::BatchAnswerJob),
+    Explain(answer::BatchAnswer),
+    Review(review::ReviewResponse),
+    DiffAnswer(diff::DiffAnswer),
+    Admin(admin::AdminResponse),
+    Audit(audit::AuditResponse),
+    Version(version::VersionResponse),
+    Usage(crate::analytics::UsageResponse),
     /// A blanket error response
     Error(EndpointError<'a>),
 }
@@ -305,33 +745,81 @@ impl<'a> From<EndpointError<'a>> for Response<'a> {
 
 #[derive(OpenApi)]
 #[openapi(
-    paths(query::handle, autocomplete::handle, hoverable::handle, intelligence::handle),
+    paths(
+        query::handle,
+        autocomplete::handle,
+        grep::handle,
+        hoverable::handle,
+        intelligence::handle,
+        file::content,
+        tree::handle,
+        health::healthz,
+        health::readyz,
+        version::handle
+    ),
     components(schemas(
         crate::symbol::Symbol,
         crate::text_range::TextRange,
         crate::text_range::Point,
         EndpointError<'_>,
         ErrorKind,
+        ErrorCode,
         autocomplete::AutocompleteResponse,
+        grep::GrepResponse,
+        grep::GrepMatch,
         query::QueryResponse,
         query::QueryResult,
         query::RepositoryResultData,
         query::FileResultData,
         query::FileData,
         query::DirectoryData,
+        query::ResultFormat,
         hoverable::HoverableResponse,
         intelligence::TokenInfoResponse,
         intelligence::SymbolOccurrence,
+        file::FileContentResponse,
+        file::HighlightSpan,
+        file::SpanRole,
+        tree::TreeResponse,
+        tree::TreeEntry,
+        tree::EntryKind,
         snippet::SnippedFile,
         snippet::Snippet,
         repos::ReposResponse,
         repos::Repo,
         repos::SetIndexed,
+        repos::SetGroups,
+        repos::ImportRequest,
+        crate::remotes::ForkPolicy,
         crate::repo::Backend,
         crate::repo::RepoRemote,
         crate::repo::SyncStatus,
         github::GithubResponse,
         github::GithubCredentialStatus,
+        health::Readiness,
+        health::Check,
+        health::Status,
+        answer::FeedbackRequest,
+        admin::AdminResponse,
+        admin::QueueState,
+        crate::background::Job,
+        crate::background::JobKind,
+        crate::background::JobClass,
+        crate::background::JobStatus,
+        crate::analytics::DeliveryStats,
+        admin::SetCredentials,
+        audit::AuditResponse,
+        audit::AuditEntry,
+        crate::analytics::QueryLogResponse,
+        crate::analytics::QueryLogEntry,
+        crate::analytics::QueryKind,
+        crate::analytics::SelectedSnippet,
+        crate::analytics::QueryStats,
+        crate::analytics::QueryCount,
+        crate::analytics::UsageResponse,
+        crate::analytics::DailyUsage,
+        crate::analytics::RepoCount,
+        version::VersionResponse,
     ))
 )]
 struct ApiDoc;
@@ -351,7 +839,7 @@ pub mod openapi_yaml {
 }
 
 async fn health(Extension(app): Extension<Application>) {
-    if let Some(ref semantic) = app.semantic {
+    if let Some(semantic) = app.semantic.get() {
         // panic is fine here, we don't need exact reporting of
         // subsystem checks at this stage
         semantic.health_check().await.unwrap()
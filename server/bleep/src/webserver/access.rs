@@ -0,0 +1,238 @@
+//! Per-repository access control.
+//!
+//! Access is granted through patterns matched against repository references. A pattern either
+//! names a single repository (`github.com/org/repo`), or every repository under it
+//! (`github.com/org`). The `*` pattern matches every repository.
+//!
+//! Both API keys and logged-in users can be restricted, as well as the tenant a request belongs
+//! to. If a request is subject to several restrictions, it can only access repositories granted
+//! by each of them.
+
+use std::{path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use axum::{extract::FromRequestParts, http::request::Parts};
+
+use super::{
+    auth::{self, Identity},
+    keys::Grant,
+    tenant::CurrentTenant,
+    Error,
+};
+use crate::{state::RepositoryPool, Application};
+
+/// The set of repositories a request is allowed to access
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RepoAccess {
+    /// Pattern lists that must all match a repository. No rules means no restrictions.
+    rules: Vec<Arc<[String]>>,
+}
+
+impl RepoAccess {
+    fn restrict(&mut self, patterns: &[String]) {
+        self.rules.push(patterns.into());
+    }
+
+    /// Access to the repository with the given reference only.
+    pub(crate) fn only(repo_ref: &str) -> Self {
+        let mut access = Self::default();
+        access.restrict(&[repo_ref.to_owned()]);
+        access
+    }
+
+    /// Access to the repositories matching `patterns`, of `tenant`.
+    pub(crate) fn granted(patterns: &[String], tenant: &CurrentTenant) -> Self {
+        let mut access = Self::default();
+        access.restrict(patterns);
+        access.restrict_to(tenant);
+        access
+    }
+
+    /// Restrict access to the repositories of the tenant a request belongs to.
+    fn restrict_to(&mut self, tenant: &CurrentTenant) {
+        match tenant {
+            CurrentTenant::Global => {}
+            CurrentTenant::Tenant(tenant) => self.restrict(&tenant.repos),
+            CurrentTenant::Unassigned => self.restrict(&[]),
+        }
+    }
+
+    /// Whether only some repositories can be accessed.
+    pub(crate) fn is_restricted(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// Whether the repository with the given reference can be accessed.
+    pub(crate) fn allows(&self, repo_ref: &str) -> bool {
+        self.rules
+            .iter()
+            .all(|patterns| patterns.iter().any(|p| pattern_matches(p, repo_ref)))
+    }
+
+    /// References of all accessible repositories, or `None` if access is unrestricted.
+    pub(crate) fn repo_refs(&self, repo_pool: &RepositoryPool) -> Option<Vec<String>> {
+        if self.rules.is_empty() {
+            return None;
+        }
+
+        Some(
+            repo_pool
+                .iter()
+                .map(|elem| elem.key().to_string())
+                .filter(|repo_ref| self.allows(repo_ref))
+                .collect(),
+        )
+    }
+
+    /// Disk paths of all accessible repositories, or `None` if access is unrestricted.
+    pub(crate) fn disk_paths(&self, repo_pool: &RepositoryPool) -> Option<Vec<PathBuf>> {
+        if self.rules.is_empty() {
+            return None;
+        }
+
+        Some(
+            repo_pool
+                .iter()
+                .filter(|elem| self.allows(&elem.key().to_string()))
+                .map(|elem| elem.value().disk_path.clone())
+                .collect(),
+        )
+    }
+}
+
+fn pattern_matches(pattern: &str, repo_ref: &str) -> bool {
+    let pattern = pattern.trim_end_matches('/');
+
+    pattern == "*"
+        || repo_ref
+            .strip_prefix(pattern)
+            .map(|rest| rest.is_empty() || rest.starts_with('/'))
+            .unwrap_or(false)
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RepoAccess
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let app = parts
+            .extensions
+            .get::<Application>()
+            .ok_or_else(|| Error::internal("application state is missing"))?;
+
+        let mut access = RepoAccess::default();
+
+        if let Some(Grant::Key(key)) = parts.extensions.get::<Grant>() {
+            if let Some(repos) = &key.repos {
+                access.restrict(repos);
+            }
+        }
+
+        // Once any user is listed, users without an entry can't access any repository.
+        if !app.config.user_repos.is_empty() {
+            match parts.extensions.get::<Identity>() {
+                Some(identity) => access.restrict(
+                    app.config
+                        .user_repos
+                        .get(&identity.login)
+                        .map(Vec::as_slice)
+                        .unwrap_or_default(),
+                ),
+                None if auth::enabled(app) => access.restrict(&[]),
+                None => {}
+            }
+        }
+
+        access.restrict_to(&CurrentTenant::resolve(app, &parts.extensions));
+        Ok(access)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::tenant::Tenant;
+
+    #[test]
+    fn patterns() {
+        assert!(pattern_matches("*", "local//tmp/repo"));
+        assert!(pattern_matches("github.com/org", "github.com/org/repo"));
+        assert!(pattern_matches("github.com/org/", "github.com/org/repo"));
+        assert!(pattern_matches(
+            "github.com/org/repo",
+            "github.com/org/repo"
+        ));
+        assert!(!pattern_matches(
+            "github.com/org",
+            "github.com/organization/repo"
+        ));
+        assert!(!pattern_matches(
+            "github.com/org/repo",
+            "github.com/org/repo2"
+        ));
+    }
+
+    #[test]
+    fn all_rules_must_match() {
+        let mut access = RepoAccess::default();
+        assert!(access.allows("github.com/org/repo"));
+
+        access.restrict(&["github.com/org".into()]);
+        access.restrict(&["github.com/org/repo".into(), "github.com/other".into()]);
+
+        assert!(access.allows("github.com/org/repo"));
+        assert!(!access.allows("github.com/org/secret"));
+        assert!(!access.allows("github.com/other/repo"));
+
+        access.restrict(&[]);
+        assert!(!access.allows("github.com/org/repo"));
+    }
+
+    #[test]
+    fn tenants_only_access_their_repos() {
+        let tenant = CurrentTenant::Tenant(Arc::new(Tenant {
+            name: "acme".into(),
+            repos: vec!["github.com/acme".into()],
+            members: vec![],
+        }));
+
+        let mut access = RepoAccess::only("github.com/acme/web");
+        access.restrict_to(&tenant);
+        assert!(access.allows("github.com/acme/web"));
+        assert!(!access.allows("github.com/acme/api"));
+
+        let mut access = RepoAccess::default();
+        access.restrict_to(&tenant);
+        assert!(access.allows("github.com/acme/api"));
+        assert!(!access.allows("github.com/globex/api"));
+
+        let mut access = RepoAccess::default();
+        access.restrict_to(&CurrentTenant::Global);
+        assert!(!access.is_restricted());
+
+        access.restrict_to(&CurrentTenant::Unassigned);
+        assert!(!access.allows("github.com/acme/api"));
+    }
+
+    #[test]
+    fn only_accessible_repos_are_searched() {
+        use crate::repo::{Backend, RepoRef, Repository};
+
+        let pool = RepositoryPool::default();
+        for path in ["/tmp/acme", "/tmp/globex"] {
+            let reporef = RepoRef::new(Backend::Local, path).unwrap();
+            pool.insert(reporef.clone(), Repository::local_from(&reporef));
+        }
+
+        assert_eq!(RepoAccess::default().repo_refs(&pool), None);
+
+        let access = RepoAccess::only("local//tmp/acme");
+        assert_eq!(
+            access.repo_refs(&pool),
+            Some(vec!["local//tmp/acme".into()])
+        );
+    }
+}
@@ -0,0 +1,366 @@
+use axum::{
+    extract::{Path, Query},
+    Json,
+};
+use secrecy::SecretString;
+
+use super::{prelude::*, tenant::CurrentTenant};
+use crate::{
+    analytics::{self, DeliveryStats, QueryLogEntry, QueryLogParams, QueryLogResponse},
+    background::{Job, JobId, JobKind},
+    remotes::RepoSecrets,
+    repo::{RepoRef, SyncStatus},
+    Application,
+};
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum AdminResponse {
+    ReindexQueued,
+    Purged,
+    Compacted,
+    Queues(QueueState),
+    CredentialsSet,
+    CredentialsRemoved,
+    Queries(QueryLogResponse),
+    Jobs(Vec<Job>),
+    JobCancelled,
+}
+
+impl From<AdminResponse> for super::Response<'static> {
+    fn from(res: AdminResponse) -> super::Response<'static> {
+        super::Response::Admin(res)
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(super) struct QueueState {
+    /// Jobs waiting to be picked up by the background executor
+    background_jobs: usize,
+    /// Whether an index writer is currently active
+    indexing: bool,
+    /// Repositories waiting to be synced
+    queued: Vec<RepoRef>,
+    /// Repositories being synced
+    syncing: Vec<RepoRef>,
+    /// Repositories being indexed
+    indexing_repos: Vec<RepoRef>,
+    /// Repositories that failed to sync or index
+    failed: Vec<RepoRef>,
+    /// Delivery of analytics events, if they're sent to RudderStack or PostHog
+    analytics: Option<DeliveryStats>,
+}
+
+fn parse_ref(app: &Application, access: &RepoAccess, path: Vec<String>) -> Result<RepoRef> {
+    let reporef = RepoRef::from_components(&app.config.source.directory(), path).map_err(|_| {
+        Error::new(ErrorKind::NotFound, "Can't find repository").with_code(ErrorCode::RepoNotFound)
+    })?;
+
+    if !app.repo_pool.contains_key(&reporef) || !access.allows(&reporef.to_string()) {
+        return Err(
+            Error::new(ErrorKind::NotFound, "Repo not found").with_code(ErrorCode::RepoNotFound)
+        );
+    }
+
+    Ok(reporef)
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(super) struct SetCredentials {
+    /// Access token to clone and fetch the repository with, in place of the backend's
+    #[schema(value_type = Option<String>)]
+    token: Option<SecretString>,
+    /// Passphrase of the SSH key, in place of `ssh_key_passphrase`
+    #[schema(value_type = Option<String>)]
+    ssh_key_passphrase: Option<SecretString>,
+}
+
+/// Credentials can be set for repositories that aren't indexed yet, but local repositories
+/// don't use any
+fn parse_remote_ref(app: &Application, access: &RepoAccess, path: Vec<String>) -> Result<RepoRef> {
+    let reporef = RepoRef::from_components(&app.config.source.directory(), path).map_err(|_| {
+        Error::new(ErrorKind::NotFound, "Can't find repository").with_code(ErrorCode::RepoNotFound)
+    })?;
+
+    if !access.allows(&reporef.to_string()) {
+        return Err(
+            Error::new(ErrorKind::NotFound, "Repo not found").with_code(ErrorCode::RepoNotFound)
+        );
+    }
+
+    if reporef.is_local() {
+        return Err(Error::user("local repositories don't use credentials"));
+    }
+
+    Ok(reporef)
+}
+
+/// Re-index a repository from scratch
+///
+/// Unlike a regular sync, this discards the file cache, so every file is re-indexed even if it
+/// has not changed.
+#[utoipa::path(post, path = "/admin/repos/reindex/:ref",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn reindex(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
+    Path(path): Path<Vec<String>>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let reporef = parse_ref(&app, &access, path)?;
+
+    app.jobs.queue(&app, JobKind::Reindex { reporef });
+    Ok(json(AdminResponse::ReindexQueued))
+}
+
+/// Remove a repository and all data derived from it
+///
+/// This waits for the removal to complete, and reports any failures.
+#[utoipa::path(post, path = "/admin/repos/purge/:ref",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn purge(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
+    Path(path): Path<Vec<String>>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let reporef = parse_ref(&app, &access, path)?;
+
+    app.write_index()
+        .delete_repo(reporef)
+        .await
+        .map_err(Error::internal)?;
+
+    Ok(json(AdminResponse::Purged))
+}
+
+/// Merge index segments and clean up unused index files
+#[utoipa::path(post, path = "/admin/compact",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn compact(
+    _: Scoped<keys::Admin>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    app.jobs
+        .run(JobKind::Compact, async {
+            app.indexes.writers().await?.compact().await
+        })
+        .await
+        .map_err(Error::internal)?;
+
+    Ok(json(AdminResponse::Compacted))
+}
+
+/// Dump the state of the internal work queues
+#[utoipa::path(get, path = "/admin/queues",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+    ),
+)]
+pub(super) async fn queues(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
+    Extension(app): Extension<Application>,
+) -> impl IntoResponse {
+    let mut state = QueueState {
+        background_jobs: app.background.pending(),
+        indexing: app.indexes.is_writing(),
+        queued: vec![],
+        syncing: vec![],
+        indexing_repos: vec![],
+        failed: vec![],
+        analytics: analytics::delivery_stats(),
+    };
+
+    for elem in app
+        .repo_pool
+        .iter()
+        .filter(|elem| access.allows(&elem.key().to_string()))
+    {
+        let list = match elem.sync_status {
+            SyncStatus::Queued => &mut state.queued,
+            SyncStatus::Syncing => &mut state.syncing,
+            SyncStatus::Indexing => &mut state.indexing_repos,
+            SyncStatus::Error { .. } => &mut state.failed,
+            _ => continue,
+        };
+
+        list.push(elem.key().clone());
+    }
+
+    json(AdminResponse::Queues(state))
+}
+
+/// List background jobs
+///
+/// Lists every job that hasn't finished, and the most recent that have, oldest first.
+#[utoipa::path(get, path = "/admin/jobs",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+    ),
+)]
+pub(super) async fn jobs(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
+    Extension(app): Extension<Application>,
+) -> impl IntoResponse {
+    let jobs = app
+        .jobs
+        .list()
+        .into_iter()
+        .filter(|job| {
+            job.kind
+                .reporef()
+                .map_or(true, |reporef| access.allows(&reporef.to_string()))
+        })
+        .collect();
+
+    json(AdminResponse::Jobs(jobs))
+}
+
+/// Cancel a background job
+///
+/// Queued jobs are never run, and running jobs are stopped.
+#[utoipa::path(delete, path = "/admin/jobs/:id",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 404, description = "Job not found, or already finished", body = EndpointError),
+    ),
+)]
+pub(super) async fn cancel_job(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
+    Path(id): Path<JobId>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let not_found = || Error::new(ErrorKind::NotFound, "Can't find an unfinished job");
+
+    let job = app.jobs.get(id).ok_or_else(not_found)?;
+    if let Some(reporef) = job.kind.reporef() {
+        if !access.allows(&reporef.to_string()) {
+            return Err(not_found());
+        }
+    }
+
+    if !app.jobs.cancel(id) {
+        return Err(not_found());
+    }
+
+    Ok(json(AdminResponse::JobCancelled))
+}
+
+/// Set or rotate the credentials a repository is synced with
+///
+/// These replace any that were set before, are stored encrypted, and are used from the next
+/// sync on. They are never returned by the API.
+#[utoipa::path(put, path = "/admin/repos/credentials/:ref",
+    request_body = SetCredentials,
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 400, description = "Local repository", body = EndpointError),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn set_credentials(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
+    Path(path): Path<Vec<String>>,
+    Extension(app): Extension<Application>,
+    Json(request): Json<SetCredentials>,
+) -> Result<impl IntoResponse> {
+    let reporef = parse_remote_ref(&app, &access, path)?;
+    let secrets = RepoSecrets {
+        token: request.token,
+        ssh_key_passphrase: request.ssh_key_passphrase,
+    };
+
+    app.secrets
+        .set(&reporef, secrets)
+        .map_err(Error::internal)?;
+
+    Ok(json(AdminResponse::CredentialsSet))
+}
+
+/// Remove the credentials set for a repository, going back to the backend's
+#[utoipa::path(delete, path = "/admin/repos/credentials/:ref",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 404, description = "No credentials set", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn delete_credentials(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
+    Path(path): Path<Vec<String>>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let reporef = parse_remote_ref(&app, &access, path)?;
+
+    match app.secrets.remove(&reporef).map_err(Error::internal)? {
+        true => Ok(json(AdminResponse::CredentialsRemoved)),
+        false => {
+            Err(Error::new(ErrorKind::NotFound, "No credentials set")
+                .with_code(ErrorCode::NotFound))
+        }
+    }
+}
+
+/// Search the query log, with totals over every matching query
+#[utoipa::path(get, path = "/admin/queries",
+    params(QueryLogParams),
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn queries(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
+    tenant: CurrentTenant,
+    Extension(app): Extension<Application>,
+    Query(params): Query<QueryLogParams>,
+) -> Result<impl IntoResponse> {
+    let Some(ref query_log) = app.query_log else {
+        return Err(Error::new(
+            ErrorKind::Configuration,
+            "the query log is not enabled",
+        ));
+    };
+
+    let response = tokio::task::block_in_place(|| {
+        query_log.search(&params, |entry| sees_query(&tenant, &access, entry))
+    })
+    .map_err(Error::internal)?;
+
+    Ok(json(AdminResponse::Queries(response)))
+}
+
+/// Whether a query log entry can be seen by a caller: it was made in their tenant, and the
+/// snippet it was answered from, if any, is of a repository they can access
+pub(super) fn sees_query(
+    tenant: &CurrentTenant,
+    access: &RepoAccess,
+    entry: &QueryLogEntry,
+) -> bool {
+    tenant.sees(entry.tenant.as_deref())
+        && entry
+            .selected
+            .as_ref()
+            .map_or(true, |s| access.allows(&s.repo_ref))
+}
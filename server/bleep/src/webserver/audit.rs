@@ -0,0 +1,309 @@
+//! An append-only log of authenticated API calls.
+//!
+//! Every call is recorded as a line of JSON, with the client that made it, the route, the
+//! repository it concerned where known, and the resulting status. The log is only ever
+//! appended to, and can be queried by administrators. Every entry records the tenant the call
+//! was made in, and the administrators of a tenant only see its own entries.
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use axum::{
+    extract::{MatchedPath, State},
+    http::Request,
+    middleware::Next,
+};
+use chrono::{DateTime, Utc};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+use tracing::error;
+
+use super::{prelude::*, ratelimit, tenant::CurrentTenant};
+use crate::Application;
+
+/// The most entries returned by a single query
+const MAX_LIMIT: usize = 1000;
+
+const fn default_limit() -> usize {
+    100
+}
+
+pub(super) struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq)]
+pub(super) struct AuditEntry {
+    #[schema(value_type = String)]
+    timestamp: DateTime<Utc>,
+    /// The API key, user, or address that made the call
+    client: String,
+    method: String,
+    /// The route that handled the call
+    route: String,
+    /// The requested path and query string
+    uri: String,
+    /// The repository the call concerned, if it names one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repo: Option<String>,
+    /// The tenant the call was made in, unless it was made by the operator
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    status: u16,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(super) struct AuditResponse {
+    /// Matching entries, most recent first
+    entries: Vec<AuditEntry>,
+}
+
+impl From<AuditResponse> for super::Response<'static> {
+    fn from(res: AuditResponse) -> super::Response<'static> {
+        super::Response::Audit(res)
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub(super) struct AuditParams {
+    /// Only return calls made by this client
+    client: Option<String>,
+    /// Only return calls concerning this repository
+    repo: Option<String>,
+    /// Only return calls made at or after this time
+    #[param(value_type = Option<String>)]
+    since: Option<DateTime<Utc>>,
+    /// Only return calls made before this time
+    #[param(value_type = Option<String>)]
+    until: Option<DateTime<Utc>>,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+impl AuditParams {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.client.as_ref().map_or(true, |c| c == &entry.client)
+            && self
+                .repo
+                .as_ref()
+                .map_or(true, |r| Some(r) == entry.repo.as_ref())
+            && self.since.map_or(true, |t| entry.timestamp >= t)
+            && self.until.map_or(true, |t| entry.timestamp < t)
+    }
+}
+
+impl AuditLog {
+    pub(super) async fn open(path: PathBuf) -> anyhow::Result<Arc<Self>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("failed to open audit log {}", path.display()))?;
+
+        Ok(Arc::new(Self {
+            path,
+            file: Mutex::new(file),
+        }))
+    }
+
+    async fn append(&self, entry: &AuditEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    async fn query(
+        &self,
+        params: &AuditParams,
+        tenant: &CurrentTenant,
+    ) -> anyhow::Result<Vec<AuditEntry>> {
+        let file = File::open(&self.path).await?;
+        let mut lines = BufReader::new(file).lines();
+        let limit = params.limit.min(MAX_LIMIT);
+
+        let mut entries = std::collections::VecDeque::with_capacity(limit);
+        while let Some(line) = lines.next_line().await? {
+            // A line may be partially written if the server stopped mid-write
+            let Ok(entry) = serde_json::from_str::<AuditEntry>(&line) else {
+                continue;
+            };
+
+            if !tenant.sees(entry.tenant.as_deref()) || !params.matches(&entry) {
+                continue;
+            }
+
+            if entries.len() == limit {
+                entries.pop_front();
+            }
+            entries.push_back(entry);
+        }
+
+        Ok(entries.into_iter().rev().collect())
+    }
+}
+
+/// The repository a request concerns, if it names one.
+///
+/// Repositories are named with a `repo_ref` query parameter, or by the wildcard path of the
+/// repository management routes.
+fn repo<B>(request: &Request<B>, route: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct RepoParam {
+        repo_ref: Option<String>,
+    }
+
+    let from_query = request
+        .uri()
+        .query()
+        .and_then(|query| serde_urlencoded::from_str::<RepoParam>(query).ok())
+        .and_then(|param| param.repo_ref);
+
+    from_query.or_else(|| {
+        // The matched route includes the prefix the API is nested under, but the URI doesn't
+        let (_, wildcard) = route.strip_suffix("*path")?.split_once("/repos/")?;
+        let (_, repo) = request
+            .uri()
+            .path()
+            .split_once(&format!("/repos/{wildcard}"))?;
+
+        Some(repo.to_owned())
+    })
+}
+
+pub(super) async fn record<B>(
+    State(log): State<Arc<AuditLog>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> axum::response::Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_default();
+
+    let tenant = request.extensions().get::<Application>().and_then(|app| {
+        CurrentTenant::resolve(app, request.extensions())
+            .name()
+            .map(str::to_owned)
+    });
+
+    let mut entry = AuditEntry {
+        timestamp: Utc::now(),
+        client: ratelimit::client(&request),
+        method: request.method().to_string(),
+        repo: repo(&request, &route),
+        route,
+        uri: request.uri().to_string(),
+        tenant,
+        status: 0,
+    };
+
+    let response = next.run(request).await;
+    entry.status = response.status().as_u16();
+
+    if let Err(err) = log.append(&entry).await {
+        error!(?err, "failed to write to the audit log");
+    }
+
+    response
+}
+
+/// Query the audit log
+#[utoipa::path(get, path = "/admin/audit",
+    params(AuditParams),
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn query(
+    _: Scoped<keys::Admin>,
+    tenant: CurrentTenant,
+    State(log): State<Arc<AuditLog>>,
+    Query(params): Query<AuditParams>,
+) -> Result<impl IntoResponse> {
+    let entries = log.query(&params, &tenant).await.map_err(Error::internal)?;
+    Ok(json(AuditResponse { entries }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_repo() {
+        let request = |uri: &str| Request::get(uri).body(()).unwrap();
+
+        assert_eq!(
+            repo(
+                &request("/file?repo_ref=github.com/foo/bar&path=a"),
+                "/file"
+            )
+            .as_deref(),
+            Some("github.com/foo/bar")
+        );
+        assert_eq!(
+            repo(
+                &request("/repos/sync/github.com/foo/bar"),
+                "/api/repos/sync/*path"
+            )
+            .as_deref(),
+            Some("github.com/foo/bar")
+        );
+        assert_eq!(repo(&request("/q?q=foo"), "/q"), None);
+    }
+
+    #[tokio::test]
+    async fn tenants_only_see_their_own_entries() {
+        use crate::webserver::tenant::Tenant;
+
+        let dir = tempdir::TempDir::new("audit").unwrap();
+        let log = AuditLog::open(dir.path().join("audit.jsonl"))
+            .await
+            .unwrap();
+
+        for tenant in [Some("acme"), Some("globex"), None] {
+            log.append(&AuditEntry {
+                timestamp: Utc::now(),
+                client: "key:ci".into(),
+                method: "GET".into(),
+                route: "/q".into(),
+                uri: "/q?q=foo".into(),
+                repo: None,
+                tenant: tenant.map(str::to_owned),
+                status: 200,
+            })
+            .await
+            .unwrap();
+        }
+
+        let params = AuditParams {
+            client: None,
+            repo: None,
+            since: None,
+            until: None,
+            limit: default_limit(),
+        };
+        let acme = CurrentTenant::Tenant(Arc::new(Tenant {
+            name: "acme".into(),
+            repos: vec![],
+            members: vec![],
+        }));
+
+        let entries = log.query(&params, &acme).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].tenant.as_deref(), Some("acme"));
+
+        let entries = log.query(&params, &CurrentTenant::Global).await.unwrap();
+        assert_eq!(entries.len(), 3);
+    }
+}
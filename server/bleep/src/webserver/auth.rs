@@ -0,0 +1,359 @@
+use std::{
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::*;
+use anyhow::{Context, Result};
+use axum::{
+    extract::Query,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::Redirect,
+};
+use axum_extra::extract::cookie::{Cookie, PrivateCookieJar};
+use dashmap::DashMap;
+use octocrab::Octocrab;
+use rand::{distributions::Alphanumeric, Rng};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use time::Duration;
+use tracing::warn;
+
+const MAX_PARALLEL_PENDING_LOGINS: usize = 512;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(super) struct GithubAuthToken {
+    pub(super) expires_in: u64,
+    #[serde(serialize_with = "crate::config::serialize_secret_str")]
+    pub(super) refresh_token: SecretString,
+    #[serde(serialize_with = "crate::config::serialize_secret_str")]
+    pub(super) access_token: SecretString,
+    // Ignore other fields here ...
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(super) struct AuthCookie {
+    pub(super) github_token: GithubAuthToken,
+    created_at: u64,
+    member_checked_at: Option<u64>,
+
+    /// The GitHub login of the user this session belongs to.
+    ///
+    /// Cookies issued before identities were tracked do not carry this field.
+    #[serde(default)]
+    pub(super) login: Option<String>,
+}
+
+impl AuthCookie {
+    pub(super) const COOKIE_NAME: &str = "auth_cookie";
+
+    fn new(github_token: GithubAuthToken, login: String) -> Self {
+        Self {
+            github_token,
+            created_at: unix_time_sec(),
+            member_checked_at: None,
+            login: Some(login),
+        }
+    }
+
+    pub(super) fn from_jar(jar: &PrivateCookieJar) -> Result<Self> {
+        serde_json::from_str(
+            jar.get(AuthCookie::COOKIE_NAME)
+                .context("missing auth cookie")?
+                .value(),
+        )
+        .context("invalid auth cookie")
+    }
+
+    pub(super) fn member_checked(&self) -> bool {
+        const MEMBERSHIP_CHECK_DURATION_SECS: u64 = 60 * 5;
+
+        self.member_checked_at
+            .map(|t| t + MEMBERSHIP_CHECK_DURATION_SECS >= unix_time_sec())
+            .unwrap_or(false)
+    }
+
+    pub(super) fn need_refresh(&self) -> bool {
+        self.created_at + self.github_token.expires_in <= unix_time_sec()
+    }
+
+    pub(super) fn set_member_checked(&mut self) {
+        self.member_checked_at = Some(unix_time_sec());
+    }
+
+    pub(super) fn update_token(&mut self, github_token: GithubAuthToken) {
+        self.created_at = unix_time_sec();
+        self.github_token = github_token;
+    }
+
+    pub(super) fn to_cookie(&self) -> Cookie<'static> {
+        let mut c = Cookie::new(
+            AuthCookie::COOKIE_NAME,
+            serde_json::to_string(self).unwrap(),
+        );
+        c.set_path("/");
+        c.set_max_age(Duration::weeks(52));
+        c
+    }
+}
+
+/// The authenticated user making a request.
+///
+/// This is attached to requests carrying a valid session cookie. As the cookie is encrypted
+/// with the server's private key, the identity can't be forged by clients.
+#[derive(Clone, Debug)]
+pub(crate) struct Identity {
+    pub(crate) login: String,
+}
+
+const STATE_LEN: usize = 32;
+type State = String;
+
+/// Whether the web login flow is configured for this instance.
+pub(super) fn enabled(app: &Application) -> bool {
+    app.config.github_client_id_and_secret().is_some() && app.config.instance_domain.is_some()
+}
+
+/// Initiate a new login using a web-based OAuth flow.
+#[utoipa::path(get, path = "/auth/login/start",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn login(
+    Extension(app): Extension<Application>,
+    Extension(auth_layer): Extension<Arc<AuthLayer>>,
+) -> impl IntoResponse {
+    auth_layer.clean_old_states();
+    if auth_layer.initialized_login.len() >= MAX_PARALLEL_PENDING_LOGINS {
+        panic!("too many parallel authorization requests");
+    }
+
+    let state = rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(STATE_LEN)
+        .map(|c| c as char)
+        .collect::<String>();
+
+    let client_id = app
+        .config
+        .github_client_id
+        .as_ref()
+        .expect("github client id must be provided")
+        .expose_secret();
+
+    let redirect_uri = format!(
+        "https://{}/api/auth/login/complete",
+        app.config
+            .instance_domain
+            .as_ref()
+            .expect("instance domain must be provided")
+    );
+
+    let github_oauth_url = &format!(
+        "https://github.com/login/oauth/authorize\
+         ?client_id={client_id}\
+         &state={state}\
+         &allow_signup=false\
+         &redirect_uri={redirect_uri}",
+    );
+
+    auth_layer.initialized_login.insert(state, Instant::now());
+    serde_json::json!({ "oauth_url": github_oauth_url }).to_string()
+}
+
+#[derive(Deserialize)]
+pub(super) struct AuthorizedParams {
+    state: State,
+    code: String,
+}
+
+/// Complete the login flow.
+///
+/// Takes the `state` that has been established previously so we don't leak the actual keys.
+#[utoipa::path(post, path = "/auth/login/complete",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn authorized(
+    Extension(app): Extension<Application>,
+    Extension(auth_layer): Extension<Arc<AuthLayer>>,
+    Query(params): Query<AuthorizedParams>,
+    jar: PrivateCookieJar,
+) -> Result<impl IntoResponse, super::Error> {
+    let AuthorizedParams { state, code } = params;
+
+    if auth_layer.initialized_login.remove(&state).is_none() {
+        return Err(super::Error::user("invalid state key")
+            .with_status(StatusCode::UNAUTHORIZED)
+            .with_code(ErrorCode::Unauthenticated));
+    }
+
+    let (client_id, client_secret) = app
+        .config
+        .github_client_id_and_secret()
+        .expect("github client id and secret must be provided");
+
+    let gh_token: GithubAuthToken = auth_layer
+        .client
+        .post(format!(
+            "https://github.com/login/oauth/access_token\
+            ?client_id={client_id}\
+            &client_secret={client_secret}\
+            &code={code}"
+        ))
+        .header("Accept", "application/json")
+        .send()
+        .await
+        .map_err(super::Error::internal)?
+        .json()
+        .await
+        .map_err(super::Error::internal)?;
+
+    let login = current_user(&gh_token.access_token)
+        .await
+        .map_err(super::Error::internal)?;
+
+    Ok((
+        jar.add(AuthCookie::new(gh_token, login).to_cookie()),
+        Redirect::to("/"),
+    ))
+}
+
+/// End the current session.
+#[utoipa::path(get, path = "/auth/logout",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+    ),
+)]
+pub(super) async fn logout(jar: PrivateCookieJar) -> impl IntoResponse {
+    let mut cookie = Cookie::named(AuthCookie::COOKIE_NAME);
+    cookie.set_path("/");
+
+    (jar.remove(cookie), Redirect::to("/"))
+}
+
+/// Get the identity attached to the current session.
+#[utoipa::path(get, path = "/auth/me",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 401, description = "Not logged in", body = EndpointError),
+    ),
+)]
+pub(super) async fn me(identity: Option<Extension<Identity>>) -> impl IntoResponse {
+    match identity {
+        Some(Extension(identity)) => Ok(serde_json::json!({ "login": identity.login }).to_string()),
+        None => Err(super::Error::user("not logged in")
+            .with_status(StatusCode::UNAUTHORIZED)
+            .with_code(ErrorCode::Unauthenticated)),
+    }
+}
+
+/// Get the login of the user owning an access token.
+pub(super) async fn current_user(access_token: &SecretString) -> Result<String> {
+    // An octocrab instance based on the user's access token.
+    let octocrab = Octocrab::builder()
+        .personal_token(access_token.expose_secret().clone())
+        .build()
+        .context("failed to build octocrab instance")?;
+
+    Ok(octocrab
+        .current()
+        .user()
+        .await
+        .context("failed to get user")?
+        .login)
+}
+
+pub(super) fn unix_time_sec() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Attach the [`Identity`] of a logged-in user to the request.
+///
+/// Requests without a session pass through untouched; it is up to the endpoints, or the
+/// private-server middleware, to reject anonymous requests.
+async fn identify<B>(
+    jar: PrivateCookieJar,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    match AuthCookie::from_jar(&jar) {
+        Ok(AuthCookie {
+            login: Some(login), ..
+        }) => {
+            request.extensions_mut().insert(Identity { login });
+        }
+        Ok(_) => {}
+        Err(err) if jar.get(AuthCookie::COOKIE_NAME).is_some() => {
+            warn!(?err, "ignoring malformed session cookie");
+        }
+        Err(_) => {}
+    }
+
+    next.run(request).await
+}
+
+pub(super) fn router(router: Router, app: Application) -> Router {
+    router
+        .route("/auth/login/complete", get(authorized))
+        .route("/auth/login/start", get(login))
+        .route("/auth/logout", get(logout))
+        .route("/auth/me", get(me))
+        .layer(middleware::from_fn_with_state(app, identify))
+        .layer(Extension(Arc::new(AuthLayer::default())))
+}
+
+pub(crate) struct AuthLayer {
+    /// Logins that have been initiated, but not completed.
+    ///
+    /// Maps to the time this login attempt was created.
+    initialized_login: DashMap<State, Instant>,
+
+    /// The HTTP client.
+    pub(super) client: reqwest::Client,
+}
+
+impl Default for AuthLayer {
+    fn default() -> Self {
+        Self {
+            initialized_login: DashMap::default(),
+            client: crate::proxy::client(),
+        }
+    }
+}
+
+impl AuthLayer {
+    fn clean_old_states(&self) {
+        const MAX_AGE: Duration = Duration::seconds(60 * 5);
+        let now = Instant::now();
+        self.initialized_login.retain(|_, t| now - *t < MAX_AGE);
+    }
+}
+
+/// Resolve the user id for a request.
+///
+/// When web login is enabled, only the session identity is trusted. Otherwise, as is the case
+/// for local installations, we fall back to the identifier supplied by the client.
+pub(super) fn user_id(
+    app: &Application,
+    identity: Option<Extension<Identity>>,
+    fallback: impl FnOnce() -> String,
+) -> Result<String, super::Error> {
+    match identity {
+        Some(Extension(identity)) => Ok(identity.login),
+        None if enabled(app) => Err(super::Error::user("login required")
+            .with_status(StatusCode::UNAUTHORIZED)
+            .with_code(ErrorCode::Unauthenticated)),
+        None => Ok(fallback()),
+    }
+}
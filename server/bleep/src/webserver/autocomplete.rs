@@ -10,10 +10,13 @@ use crate::{
         parser,
         parser::{Literal, Target},
     },
-    webserver::query::{ApiQuery, ExecuteQuery, QueryResult},
+    webserver::query::{ndjson, wants_ndjson, ApiQuery, ExecuteQuery, QueryResult},
+    Application,
 };
 
-use axum::{extract::Query, response::IntoResponse as IntoAxumResponse, Extension};
+use axum::{
+    extract::Query, http::HeaderMap, response::IntoResponse as IntoAxumResponse, Extension,
+};
 use futures::{stream, StreamExt, TryStreamExt};
 use serde::Serialize;
 use utoipa::ToSchema;
@@ -29,14 +32,18 @@ use utoipa::ToSchema;
     ),
 )]
 pub(super) async fn handle(
+    _: Scoped<keys::Read>,
+    access: RepoAccess,
     Query(mut api_params): Query<ApiQuery>,
     Extension(indexes): Extension<Arc<Indexes>>,
-) -> Result<impl IntoAxumResponse> {
+    Extension(app): Extension<Application>,
+    headers: HeaderMap,
+) -> Result<axum::response::Response> {
     // Override page_size and set to low value
     api_params.page = 0;
     api_params.page_size = 3;
 
-    let queries = parser::parse(&api_params.q).map_err(Error::user)?;
+    let queries = parser::parse(&api_params.q)?;
     let mut autocomplete_results = vec![];
 
     // Only execute prefix search on flag names if there is a non-regex content target.
@@ -69,9 +76,12 @@ pub(super) async fn handle(
 
     // If no flags completion, run a search with full query
     if autocomplete_results.is_empty() {
-        let contents = ContentReader.execute(&indexes.file, &queries, &api_params);
-        let repos = RepoReader.execute(&indexes.repo, &queries, &api_params);
-        let files = FileReader.execute(&indexes.file, &queries, &api_params);
+        let allowed = access.disk_paths(&app.repo_pool);
+        let allowed = allowed.as_deref();
+
+        let contents = ContentReader.execute(&indexes.file, &queries, &api_params, allowed);
+        let repos = RepoReader.execute(&indexes.repo, &queries, &api_params, allowed);
+        let files = FileReader.execute(&indexes.file, &queries, &api_params, allowed);
 
         autocomplete_results = stream::iter([contents, repos, files])
             // Buffer several readers at the same time. The exact number is not important; this is
@@ -85,11 +95,16 @@ pub(super) async fn handle(
             .map_err(Error::internal)?;
     }
 
+    // Completions are few, and streamed without the count, one per line
+    if wants_ndjson(api_params.format, &headers) {
+        return ndjson(autocomplete_results);
+    }
+
     let count = autocomplete_results.len();
     let data = autocomplete_results;
     let response = AutocompleteResponse { count, data };
 
-    Ok(json(response))
+    Ok(json(response).into_response())
 }
 
 fn complete_flag(q: &str) -> impl Iterator<Item = &str> + '_ {
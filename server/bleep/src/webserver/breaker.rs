@@ -0,0 +1,138 @@
+//! A circuit breaker for the answer API.
+//!
+//! Every request to the answer API would otherwise wait out its timeouts and retries while the
+//! API is down, and spend quota on attempts that fail. After a few attempts in a row fail, the
+//! circuit opens and requests fail right away. Once the probe interval has passed, one request
+//! is let through to probe the API: the circuit closes again if it succeeds, and stays open for
+//! another interval if it fails.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed {
+        /// Attempts that failed since the last one that succeeded
+        failures: u32,
+    },
+    /// Requests fail fast until `until`, when the next one is let through as a probe
+    Open { until: Instant },
+}
+
+pub(super) struct CircuitBreaker {
+    state: Mutex<State>,
+    max_failures: u32,
+    probe_interval: Duration,
+}
+
+impl CircuitBreaker {
+    /// A breaker that opens after `max_failures` attempts in a row fail, or never if it's 0
+    pub(super) fn new(max_failures: u32, probe_interval: Duration) -> Self {
+        Self {
+            state: Mutex::new(State::Closed { failures: 0 }),
+            max_failures,
+            probe_interval,
+        }
+    }
+
+    /// Whether a request can be sent now, or how long until the next probe if the circuit is
+    /// open. A request let through as a probe holds the circuit open for everyone else until
+    /// it's done, or until the probe interval passes again if its outcome is never recorded.
+    pub(super) fn admit(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } => {
+                let now = Instant::now();
+                if now < until {
+                    return Err(until - now);
+                }
+
+                *state = State::Open {
+                    until: now + self.probe_interval,
+                };
+                Ok(())
+            }
+        }
+    }
+
+    pub(super) fn succeeded(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let State::Open { .. } = *state {
+            info!("answer API recovered; closing the circuit");
+        }
+
+        *state = State::Closed { failures: 0 };
+    }
+
+    pub(super) fn failed(&self) {
+        let mut state = self.state.lock().unwrap();
+        let failures = match *state {
+            State::Closed { failures } => failures.saturating_add(1),
+            // A probe failed
+            State::Open { .. } => self.max_failures,
+        };
+
+        *state = if self.max_failures > 0 && failures >= self.max_failures {
+            if let State::Closed { .. } = *state {
+                warn!(
+                    failures,
+                    interval = ?self.probe_interval,
+                    "answer API keeps failing; opening the circuit"
+                );
+            }
+
+            State::Open {
+                until: Instant::now() + self.probe_interval,
+            }
+        } else {
+            State::Closed { failures }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_failures_and_probes() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(20));
+
+        breaker.failed();
+        breaker.succeeded();
+        breaker.failed();
+        assert!(breaker.admit().is_ok());
+
+        breaker.failed();
+        assert!(breaker.admit().is_err());
+
+        // One probe is let through once the interval passes, and fails
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(breaker.admit().is_ok());
+        assert!(breaker.admit().is_err());
+        breaker.failed();
+        assert!(breaker.admit().is_err());
+
+        // The next one succeeds
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(breaker.admit().is_ok());
+        breaker.succeeded();
+        assert!(breaker.admit().is_ok());
+        assert!(breaker.admit().is_ok());
+    }
+
+    #[test]
+    fn never_opens_without_a_limit() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(60));
+        for _ in 0..100 {
+            breaker.failed();
+        }
+
+        assert!(breaker.admit().is_ok());
+    }
+}
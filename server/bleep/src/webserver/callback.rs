@@ -0,0 +1,306 @@
+//! Callbacks that deliver the answers of batches asked for in the background.
+//!
+//! Batches asked for with a `callback_url` are answered as a background job, and the response is
+//! posted to the URL once the job finishes, so automation doesn't have to poll for it.
+//! Deliveries are signed with `--answer-callback-secret`: `X-Bleep-Signature` is `v1=` and the
+//! hex HMAC-SHA256 of `v1:{timestamp}:{body}`, where the timestamp is `X-Bleep-Timestamp`, in
+//! seconds since the epoch. Receivers should reject deliveries signed long ago, as they may be
+//! replayed.
+//!
+//! Anyone who can ask for answers picks the URL, so unless its host is on
+//! `--answer-callback-hosts`, it must resolve to public addresses only. It's resolved again for
+//! every delivery, and the delivery connects to the address that was checked. Redirects aren't
+//! followed, as they could lead anywhere.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use axum::http::header::CONTENT_TYPE;
+use reqwest::{redirect, Url};
+use ring::hmac;
+use secrecy::{ExposeSecret, SecretString};
+use tracing::{info, warn};
+
+use super::prelude::*;
+
+const SIGNATURE_HEADER: &str = "x-bleep-signature";
+const TIMESTAMP_HEADER: &str = "x-bleep-timestamp";
+
+/// Attempts at a delivery before it's given up on
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry, doubled for each one after
+const RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+/// How long a receiver has to respond to a delivery
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where the answers of a batch are posted
+pub(super) struct Target {
+    url: Url,
+    /// Whether the host is on `--answer-callback-hosts`, so that it can resolve to any address
+    allowed: bool,
+}
+
+/// The callback URL of a request, which must be an HTTP or HTTPS URL, to one of `hosts` if any
+/// are given, or else to a host with public addresses only
+pub(super) async fn parse_url(url: &str, hosts: &[String]) -> Result<Target> {
+    let url = Url::parse(url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https") && url.host_str().is_some())
+        .ok_or_else(|| Error::user("callback_url must be an http or https URL"))?;
+
+    let host = url.host_str().unwrap_or_default();
+    if !hosts.is_empty() {
+        return if allowed(hosts, host) {
+            Ok(Target { url, allowed: true })
+        } else {
+            Err(Error::user("callback_url host is not allowed"))
+        };
+    }
+
+    match resolve(&url).await {
+        Ok(_) => Ok(Target {
+            url,
+            allowed: false,
+        }),
+        Err(err) => Err(Error::user(format!("invalid callback_url: {err}"))),
+    }
+}
+
+/// Whether `host` is one of `hosts`
+fn allowed(hosts: &[String], host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    hosts.iter().any(|allowed| {
+        allowed
+            .trim()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .eq_ignore_ascii_case(host)
+    })
+}
+
+/// Resolve the host of `url` to an address to connect to, as long as every address it resolves
+/// to is public
+async fn resolve(url: &Url) -> anyhow::Result<SocketAddr> {
+    let host = url.host_str().context("no host")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().context("no port")?;
+
+    let addrs = tokio::net::lookup_host((host, port))
+        .await
+        .with_context(|| format!("failed to resolve {host}"))?
+        .collect::<Vec<_>>();
+
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        bail!("{host} resolves to the non-public address {}", addr.ip());
+    }
+
+    addrs
+        .into_iter()
+        .next()
+        .with_context(|| format!("{host} has no addresses"))
+}
+
+/// Whether `ip` is routable on the internet, rather than a private, loopback, link-local or
+/// otherwise reserved address
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    let shared = a == 100 && (64..128).contains(&b);
+    let this_network = a == 0;
+
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || shared
+        || this_network)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    let embedded = |high: u16, low: u16| {
+        let [a, b] = high.to_be_bytes();
+        let [c, d] = low.to_be_bytes();
+        Ipv4Addr::new(a, b, c, d)
+    };
+
+    // NAT64 and 6to4 addresses reach the IPv4 address they embed
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        return is_public_v4(embedded(segments[6], segments[7]));
+    }
+    if segments[0] == 0x2002 {
+        return is_public_v4(embedded(segments[1], segments[2]));
+    }
+
+    let first = segments[0];
+    let unique_local = first & 0xfe00 == 0xfc00;
+    let link_local = first & 0xffc0 == 0xfe80;
+
+    !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || unique_local || link_local)
+}
+
+/// A client that delivers to `target` without following redirects, connecting to the address
+/// its host was checked to resolve to.
+///
+/// Only explicitly allowed hosts are reached through the configured proxy, as the proxy would
+/// resolve the host again itself.
+async fn client(target: &Target) -> anyhow::Result<reqwest::Client> {
+    let builder = if target.allowed {
+        crate::proxy::client_builder()
+    } else {
+        let addr = resolve(&target.url).await?;
+        let host = target.url.host_str().unwrap_or_default();
+        reqwest::Client::builder().no_proxy().resolve(host, addr)
+    };
+
+    Ok(builder.redirect(redirect::Policy::none()).build()?)
+}
+
+/// The signature of a delivery of `body` at `timestamp`
+fn sign(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let mut signed = format!("v1:{timestamp}:").into_bytes();
+    signed.extend_from_slice(body);
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    format!("v1={}", hex::encode(hmac::sign(&key, &signed)))
+}
+
+/// Post `body` to `target`, retrying with backoff while the receiver fails or can't be reached.
+/// Deliveries the receiver rejects with a client error other than `429 Too Many Requests`, or
+/// redirects, aren't retried.
+pub(super) async fn deliver(secret: &SecretString, target: Target, body: Vec<u8>) {
+    let url = &target.url;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let client = match client(&target).await {
+            Ok(client) => client,
+            Err(err) => {
+                warn!(%url, ?err, "refusing to deliver answer callback");
+                return;
+            }
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let response = client
+            .post(url.clone())
+            .timeout(DELIVERY_TIMEOUT)
+            .header(CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, timestamp)
+            .header(
+                SIGNATURE_HEADER,
+                sign(secret.expose_secret(), timestamp, &body),
+            )
+            .body(body.clone())
+            .send()
+            .await;
+
+        match response {
+            Ok(response) if response.status().is_success() => {
+                info!(%url, attempt, "delivered answer callback");
+                return;
+            }
+            Ok(response)
+                if response.status().is_redirection()
+                    || (response.status().is_client_error()
+                        && response.status() != StatusCode::TOO_MANY_REQUESTS) =>
+            {
+                warn!(%url, status = %response.status(), "answer callback was rejected");
+                return;
+            }
+            Ok(response) => {
+                warn!(%url, attempt, status = %response.status(), "answer callback failed")
+            }
+            Err(err) => warn!(%url, attempt, ?err, "answer callback failed"),
+        }
+
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+        }
+    }
+
+    warn!(%url, "gave up on answer callback");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deliveries_are_signed() {
+        let signature = sign("secret", 1700000000, br#"{"job_id":1}"#);
+        assert_eq!(
+            signature,
+            "v1=d46b055eb20ae652a90c5e5a1d744903ef919f773842cb986850eaa225973dbe"
+        );
+
+        assert_ne!(signature, sign("secret", 1700000001, br#"{"job_id":1}"#));
+        assert_ne!(signature, sign("other", 1700000000, br#"{"job_id":1}"#));
+    }
+
+    #[tokio::test]
+    async fn only_http_urls_are_called_back() {
+        assert!(parse_url("https://93.184.216.34/hooks/bleep", &[])
+            .await
+            .is_ok());
+        assert!(parse_url("file:///etc/passwd", &[]).await.is_err());
+        assert!(parse_url("not a url", &[]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn private_addresses_are_not_called_back() {
+        for url in [
+            "http://localhost:8080/",
+            "http://127.0.0.1/",
+            "http://10.0.0.1/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[::ffff:192.168.0.1]/",
+            "http://[64:ff9b::a00:1]/",
+            "http://[2002:a9fe:a9fe::]/",
+        ] {
+            assert!(parse_url(url, &[]).await.is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn embedded_ipv4_addresses_are_checked() {
+        // 93.184.216.34 behind NAT64 and 6to4
+        assert!(is_public("64:ff9b::5db8:d822".parse().unwrap()));
+        assert!(is_public("2002:5db8:d822::1".parse().unwrap()));
+
+        assert!(!is_public("64:ff9b::7f00:1".parse().unwrap()));
+        assert!(!is_public("2002:c0a8:1::1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn allowed_hosts_are_called_back() {
+        let hosts = ["ci.internal".to_owned(), "10.0.0.1".to_owned()];
+
+        assert!(parse_url("http://CI.internal/hooks", &hosts).await.is_ok());
+        assert!(parse_url("http://10.0.0.1/", &hosts).await.is_ok());
+        assert!(parse_url("https://ci.example.com/", &hosts).await.is_err());
+        assert!(parse_url("http://localhost:8080/", &hosts).await.is_err());
+    }
+}
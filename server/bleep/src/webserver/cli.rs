@@ -0,0 +1,377 @@
+//! One-shot commands: queries, answers and indexing from the command line.
+//!
+//! `bleep query` and `bleep answer` read the local indexes, or ask a running instance given with
+//! `--remote`. Either way, responses are the JSON the API returns, printed as is with `--json`,
+//! so scripts can switch between the two.
+//!
+//! `bleep index` adds a repository to the local indexes and exits once it's indexed, with an
+//! error if it couldn't be, so that CI can onboard repositories without running the server.
+//! `bleep export` then bundles its indexes, for instances that serve queries to `bleep import`.
+
+use std::{
+    io::{IsTerminal, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use reqwest::Url;
+use serde_json::Value;
+
+use super::{
+    answer::{self, AnswerState, BatchAnswerResponse, Params},
+    fields::Fields,
+    keys::API_KEY_HEADER,
+    prelude::*,
+    query::ApiQuery,
+};
+use crate::{
+    background::RepoEvent,
+    client::{resolve, sync_and_index},
+    config::OneShot,
+    Application, Command,
+};
+
+/// Run `command` against the local indexes
+pub async fn run_local(app: Application, command: &Command) -> anyhow::Result<()> {
+    let (response, options) = match command {
+        Command::Query { q, options } => (local_query(&app, q, options.limit).await?, options),
+        Command::Answer { q, options } => (local_answer(&app, q).await?, options),
+        Command::Index { target } => return index(app, target).await,
+        Command::Export { repo, output } => return export(app, repo, output).await,
+        Command::Import { bundle } => return import(app, bundle).await,
+        Command::Lsp | Command::Mcp | Command::Config { .. } | Command::Completions { .. } => {
+            bail!("not a one-shot command")
+        }
+    };
+
+    print(command, &response, options.json)
+}
+
+/// Run `command` against the instance it names with `--remote`
+pub async fn run_remote(command: &Command) -> anyhow::Result<()> {
+    let (response, options) = match command {
+        Command::Query { q, options } => {
+            let page_size = options.limit.to_string();
+            let request = client(options)?
+                .get(endpoint(options, "q")?)
+                .query(&[("q", q.as_str()), ("page_size", &page_size)]);
+
+            (send(request).await?, options)
+        }
+        Command::Answer { q, options } => {
+            let request = client(options)?
+                .post(endpoint(options, "answer/batch")?)
+                .json(&serde_json::json!({ "questions": [q] }));
+
+            (send(request).await?, options)
+        }
+        Command::Index { .. }
+        | Command::Export { .. }
+        | Command::Import { .. }
+        | Command::Lsp
+        | Command::Mcp
+        | Command::Config { .. }
+        | Command::Completions { .. } => bail!("not a remote command"),
+    };
+
+    print(command, &response, options.json)
+}
+
+async fn local_query(app: &Application, q: &str, limit: usize) -> anyhow::Result<Value> {
+    let response = Arc::new(ApiQuery::new(q.to_owned(), 0, limit))
+        .query(Arc::clone(&app.indexes), &app.repo_pool, None)
+        .await
+        .map_err(|err| anyhow::anyhow!("{}", err.message()))?;
+
+    Ok(serde_json::to_value(response)?)
+}
+
+async fn local_answer(app: &Application, q: &str) -> anyhow::Result<Value> {
+    if app.semantic.wait().await.is_none() {
+        bail!("answers need semantic search, which is disabled or failed to load");
+    }
+
+    let user_id = answer::default_user_id();
+    let params = Params {
+        q: q.to_owned(),
+        thread_id: uuid::Uuid::new_v4().to_string(),
+        limit: answer::default_limit(),
+        user_id: user_id.clone(),
+        fields: Fields::default(),
+        answer_lang: None,
+        access: RepoAccess::default(),
+        llm_permits: None,
+    };
+
+    let state = AnswerState::new(&app.config)?;
+    let answer = answer::answer_one(&state, params, app, None).await;
+
+    Ok(serde_json::to_value(BatchAnswerResponse {
+        user_id,
+        answers: vec![answer],
+    })?)
+}
+
+/// Add the repository `target` names, sync and index it, drawing a progress bar on stderr
+async fn index(app: Application, target: &str) -> anyhow::Result<()> {
+    let mut progress = Progress::new();
+    let indexed = sync_and_index(&app, resolve(target)?, |event| progress.update(event)).await;
+
+    progress.finish();
+    eprintln!("{} is indexed", indexed?);
+    Ok(())
+}
+
+/// Write a bundle of the indexes of the repository `target` names to `output`
+async fn export(app: Application, target: &str, output: &Path) -> anyhow::Result<()> {
+    let manifest = crate::bundle::export(&app, &resolve(target)?, output).await?;
+
+    eprintln!(
+        "Exported {} to {}, with {} embeddings",
+        manifest.repo_ref,
+        output.display(),
+        manifest.embeddings
+    );
+    Ok(())
+}
+
+/// Install the bundle at `path`
+async fn import(app: Application, path: &Path) -> anyhow::Result<()> {
+    let manifest = crate::bundle::import(&app, path).await?;
+
+    eprintln!(
+        "Imported {}, with {} embeddings",
+        manifest.repo_ref, manifest.embeddings
+    );
+    Ok(())
+}
+
+/// Progress of syncing and indexing a repository, drawn as a bar on a terminal, and as a line
+/// every few percent otherwise, such as in CI logs
+struct Progress {
+    terminal: bool,
+    /// Percent last drawn
+    percent: Option<usize>,
+}
+
+impl Progress {
+    /// Width of the bar, in characters
+    const WIDTH: usize = 30;
+
+    /// Percent between lines, if not on a terminal
+    const STEP: usize = 10;
+
+    fn new() -> Self {
+        Self {
+            terminal: std::io::stderr().is_terminal(),
+            percent: None,
+        }
+    }
+
+    fn update(&mut self, event: &RepoEvent) {
+        match event {
+            RepoEvent::Renamed { from, to } => {
+                self.finish();
+                eprintln!("{from} was renamed to {to}");
+            }
+            RepoEvent::SyncStarted { reporef } => eprintln!("Syncing {reporef}"),
+            RepoEvent::IndexStarted { reporef } => eprintln!("Indexing {reporef}"),
+            RepoEvent::IndexProgress { indexed, total, .. } => self.draw(*indexed, *total),
+            RepoEvent::FilesIndexed {
+                files,
+                chunks,
+                duration_ms,
+                ..
+            } => {
+                self.finish();
+                eprintln!(
+                    "Indexed {files} files, {chunks} chunks, in {:.1}s",
+                    *duration_ms as f64 / 1000.0
+                );
+            }
+            _ => {}
+        }
+    }
+
+    fn draw(&mut self, indexed: usize, total: usize) {
+        let percent = (indexed * 100 / total.max(1)).min(100);
+        if !self.terminal {
+            if self
+                .percent
+                .map_or(false, |last| percent < last + Self::STEP)
+            {
+                return;
+            }
+
+            eprintln!("{percent:>3}% ({indexed}/{total} files)");
+            self.percent = Some(percent);
+            return;
+        }
+
+        let filled = percent * Self::WIDTH / 100;
+        eprint!(
+            "\r[{}{}] {percent:>3}% ({indexed}/{total} files)",
+            "#".repeat(filled),
+            " ".repeat(Self::WIDTH - filled)
+        );
+        _ = std::io::stderr().flush();
+        self.percent = Some(percent);
+    }
+
+    /// End the bar, so that whatever's printed next starts on its own line
+    fn finish(&mut self) {
+        if self.terminal && self.percent.is_some() {
+            eprintln!();
+        }
+
+        self.percent = None;
+    }
+}
+
+fn client(options: &OneShot) -> anyhow::Result<reqwest::Client> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(ref key) = options.api_key {
+        headers.insert(API_KEY_HEADER, key.parse().context("invalid API key")?);
+    }
+
+    Ok(crate::proxy::client_builder()
+        .default_headers(headers)
+        .build()?)
+}
+
+/// The URL of the API endpoint at `path` of the remote instance
+fn endpoint(options: &OneShot, path: &str) -> anyhow::Result<Url> {
+    let mut base = options.remote.clone().context("no remote instance given")?;
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+
+    Ok(base.join("api/")?.join(path)?)
+}
+
+async fn send(request: reqwest::RequestBuilder) -> anyhow::Result<Value> {
+    let response = request.send().await?;
+    let status = response.status();
+    let body = response.json::<Value>().await?;
+
+    if !status.is_success() {
+        let message = body["message"].as_str().unwrap_or_else(|| status.as_str());
+        bail!("{message}");
+    }
+
+    Ok(body)
+}
+
+fn print(command: &Command, response: &Value, json: bool) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(response)?);
+        return Ok(());
+    }
+
+    match command {
+        Command::Answer { .. } => print_answers(response),
+        _ => print_results(response),
+    }
+}
+
+fn print_results(response: &Value) -> anyhow::Result<()> {
+    let results = response["data"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or(&[]);
+    if results.is_empty() {
+        println!("No results");
+        return Ok(());
+    }
+
+    for result in results {
+        let data = &result["data"];
+        match result["kind"].as_str() {
+            Some("snippets") => {
+                println!(
+                    "{} {}",
+                    text(&data["repo_name"]),
+                    text(&data["relative_path"])
+                );
+                for snippet in data["snippets"].as_array().into_iter().flatten() {
+                    let start = snippet["line_range"]["start"].as_u64().unwrap_or_default();
+                    let lines = snippet["data"].as_str().unwrap_or_default().lines();
+                    for (n, line) in (start + 1..).zip(lines) {
+                        println!("{n:>6}  {line}");
+                    }
+                    println!();
+                }
+            }
+            Some("repository_result") => println!("{}", text(&data["name"])),
+            _ => println!(
+                "{} {}",
+                text(&data["repo_name"]),
+                text(&data["relative_path"])
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+fn print_answers(response: &Value) -> anyhow::Result<()> {
+    for answer in response["answers"].as_array().into_iter().flatten() {
+        if let Some(error) = answer["error"].as_str() {
+            bail!("{error}");
+        }
+
+        println!("{}", answer["answer"].as_str().unwrap_or_default());
+
+        let sources = answer["snippets"]["matches"].as_array();
+        for (i, snippet) in sources.into_iter().flatten().enumerate() {
+            if i == 0 {
+                println!("\nSources:");
+            }
+
+            println!(
+                "  {} {}:{}",
+                text(&snippet["repo_name"]),
+                text(&snippet["relative_path"]),
+                snippet["start_line"].as_u64().unwrap_or_default() + 1
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The text of a plain or highlighted string
+fn text(value: &Value) -> &str {
+    value
+        .as_str()
+        .or_else(|| value["text"].as_str())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoints_are_under_api() {
+        let options = |remote: &str| OneShot {
+            remote: Some(remote.parse().unwrap()),
+            api_key: None,
+            json: false,
+            limit: 10,
+        };
+
+        assert_eq!(
+            endpoint(&options("http://localhost:7878"), "q")
+                .unwrap()
+                .as_str(),
+            "http://localhost:7878/api/q"
+        );
+        assert_eq!(
+            endpoint(&options("https://example.com/bleep"), "answer/batch")
+                .unwrap()
+                .as_str(),
+            "https://example.com/bleep/api/answer/batch"
+        );
+    }
+}
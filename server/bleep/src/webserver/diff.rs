@@ -0,0 +1,688 @@
+//! Answers about changes, such as "what does this change break?".
+//!
+//! Rather than the whole repository, answers are based on a diff, given as is or as a range of
+//! commits of a repository that's cloned locally, and on the indexed code related to it through
+//! the symbol index: code that refers to what changed lines define, and code that defines what
+//! they refer to.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::Path,
+    time::Instant,
+};
+
+use anyhow::Context;
+use axum::extract::State;
+use tracing::debug;
+
+use super::{
+    answer::{self, api, AnswerSnippets, AnswerState, Snippet},
+    auth::{self, Identity},
+    file,
+    prelude::*,
+    tenant::CurrentTenant,
+};
+use crate::{
+    analytics::{QueryEvent, Stage},
+    indexes::reader::ContentDocument,
+    intelligence::{code_navigation::RepoWideHandler, NodeKind},
+    metrics::AnswerStage,
+    repo::RepoRef,
+    symbol::SymbolLocations,
+    Application,
+};
+
+/// The most files of a diff whose related code is looked up
+const MAX_TOUCHED_FILES: usize = 20;
+
+/// Hunks with more lines are cut short in prompts
+pub(super) const MAX_HUNK_LINES: usize = 80;
+
+/// The most places related code is taken from, in each related file
+const MAX_OCCURRENCES_PER_FILE: usize = 2;
+
+/// Lines of related code taken above and below where it's related
+const CONTEXT_LINES: usize = 5;
+
+/// Tokens the diff can take in the prompt
+const MAX_DIFF_TOKENS: usize = 4000;
+
+/// Tokens the prompt can take, leaving the rest of the model's context for the answer
+const MAX_PROMPT_TOKENS: usize = 6000;
+
+/// The most tokens an answer can take
+const MAX_ANSWER_TOKENS: u32 = 500;
+
+/// A question about a diff of a repository, given either as is or as a range of commits
+#[derive(Deserialize, ToSchema, Debug)]
+pub struct DiffRequest {
+    pub q: String,
+    pub repo_ref: String,
+    /// A unified diff, as `git diff` prints it
+    #[serde(default)]
+    pub diff: Option<String>,
+    /// A range of commits of the repository, such as `main..feature`, or `main...feature` for
+    /// the changes since the commits' merge base
+    #[serde(default)]
+    pub range: Option<String>,
+    #[serde(default = "answer::default_user_id")]
+    pub user_id: String,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct DiffAnswer {
+    pub q: String,
+    pub query_id: uuid::Uuid,
+    pub answer: String,
+    /// Files the diff touches
+    pub touched: Vec<String>,
+    /// Indexed files that depend on the touched files, or that they depend on
+    pub related: Vec<String>,
+    /// Related code the answer is based on, besides the diff
+    pub snippets: AnswerSnippets,
+}
+
+impl From<DiffAnswer> for super::Response<'static> {
+    fn from(res: DiffAnswer) -> super::Response<'static> {
+        super::Response::DiffAnswer(res)
+    }
+}
+
+/// A changed hunk of a file
+#[derive(Debug, PartialEq)]
+pub(super) struct Hunk {
+    pub(super) path: String,
+    /// First line of the hunk in the new version of the file, counting from 1
+    pub(super) start_line: usize,
+    /// Lines of the hunk, with their `+`, `-` or ` ` prefix
+    pub(super) lines: Vec<String>,
+}
+
+impl Hunk {
+    /// The lines that changed, without their prefix
+    pub(super) fn changes(&self) -> String {
+        self.lines
+            .iter()
+            .filter_map(|l| l.strip_prefix('+').or_else(|| l.strip_prefix('-')))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// The hunks of a unified diff of any number of files
+pub(super) fn parse_diff(diff: &str) -> Vec<Hunk> {
+    parse(diff, None)
+}
+
+/// The hunks of the patch of a single file, such as GitHub shows for pull requests
+pub(super) fn parse_patch(path: &str, patch: &str) -> Vec<Hunk> {
+    parse(patch, Some(path.to_owned()))
+}
+
+fn parse(diff: &str, mut path: Option<String>) -> Vec<Hunk> {
+    let mut hunks = vec![];
+    let mut old_path = None;
+
+    // Lines left in the current hunk, of the old and new versions of the file. Hunks are read by
+    // their length, as removed lines can look like file headers.
+    let (mut old_left, mut new_left) = (0usize, 0usize);
+
+    for line in diff.lines() {
+        if old_left > 0 || new_left > 0 {
+            match line.chars().next() {
+                Some('+') => new_left = new_left.saturating_sub(1),
+                Some('-') => old_left = old_left.saturating_sub(1),
+                // Markers such as `\ No newline at end of file` aren't part of the code
+                Some('\\') => continue,
+                _ => {
+                    old_left = old_left.saturating_sub(1);
+                    new_left = new_left.saturating_sub(1);
+                }
+            }
+
+            if let Some(hunk) = hunks.last_mut() {
+                hunk.lines.push(line.to_owned());
+            }
+        } else if let Some(header) = line.strip_prefix("@@ ") {
+            // @@ -start,count +start,count @@ context
+            let mut ranges = header.split_whitespace();
+            let old = ranges.next().and_then(|r| r.strip_prefix('-'));
+            let new = ranges.next().and_then(|r| r.strip_prefix('+'));
+            let (Some(old), Some(new)) = (old.map(hunk_range), new.map(hunk_range)) else {
+                continue;
+            };
+
+            (old_left, new_left) = (old.1, new.1);
+            hunks.push(Hunk {
+                path: path.clone().unwrap_or_default(),
+                start_line: new.0,
+                lines: vec![],
+            });
+        } else if let Some(name) = line.strip_prefix("--- ") {
+            old_path = file_path(name);
+        } else if let Some(name) = line.strip_prefix("+++ ") {
+            // Deleted files are only named in the old version
+            path = file_path(name).or_else(|| old_path.take());
+        }
+    }
+
+    hunks.retain(|hunk| !hunk.path.is_empty());
+    hunks
+}
+
+/// The start and length of a range of a hunk header, such as `12,7`
+fn hunk_range(range: &str) -> (usize, usize) {
+    let mut parts = range.split(',').map(|n| n.parse().unwrap_or(0));
+    let start = parts.next().unwrap_or(0);
+    let len = parts.next().unwrap_or(1);
+    (start, len)
+}
+
+/// The path of a file of a diff header, unless the file doesn't exist on that side
+fn file_path(name: &str) -> Option<String> {
+    let name = name.split('\t').next().unwrap_or(name).trim();
+    if name == "/dev/null" {
+        return None;
+    }
+
+    let name = name
+        .strip_prefix("a/")
+        .or_else(|| name.strip_prefix("b/"))
+        .unwrap_or(name);
+    Some(name.to_owned())
+}
+
+/// The first `count` lines of `text`
+pub(super) fn first_lines(text: &str, count: usize) -> String {
+    let mut lines = text.lines();
+    let mut cut = lines.by_ref().take(count).collect::<Vec<_>>().join("\n");
+    if lines.next().is_some() {
+        cut.push_str("\n...");
+    }
+
+    cut
+}
+
+/// The diff of the commits `range` of the repository cloned at `disk_path`
+fn range_diff(disk_path: &Path, range: &str) -> anyhow::Result<String> {
+    fn commit<'r>(repo: &'r git2::Repository, rev: &str) -> anyhow::Result<git2::Commit<'r>> {
+        Ok(repo.revparse_single(rev.trim())?.peel_to_commit()?)
+    }
+
+    let repo = git2::Repository::open(disk_path)?;
+    let (base, head) = match range.split_once("...") {
+        Some((base, head)) => {
+            let head = commit(&repo, head)?;
+            let base = repo.merge_base(commit(&repo, base)?.id(), head.id())?;
+            (repo.find_commit(base)?, head)
+        }
+        None => {
+            let (base, head) = range
+                .split_once("..")
+                .context("ranges are written as `base..head`, or `base...head`")?;
+            (commit(&repo, base)?, commit(&repo, head)?)
+        }
+    };
+
+    let diff = repo.diff_tree_to_tree(Some(&base.tree()?), Some(&head.tree()?), None)?;
+
+    let mut patch = String::new();
+    diff.print(git2::DiffFormat::Patch, |_, _, line| {
+        if matches!(line.origin(), '+' | '-' | ' ') {
+            patch.push(line.origin());
+        }
+        patch.push_str(&String::from_utf8_lossy(line.content()));
+        true
+    })?;
+
+    Ok(patch)
+}
+
+/// Names that look like identifiers in `text`
+fn identifiers(text: &str) -> HashSet<&str> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| word.len() > 2 && !word.starts_with(|c: char| c.is_ascii_digit()))
+        .collect()
+}
+
+/// Snippets of the files of `docs` that aren't `touched`, where they refer to what the touched
+/// files define, or define what they refer to, where either is named in the `changed` lines
+fn related_code(
+    touched: &[ContentDocument],
+    docs: &[ContentDocument],
+    changed: &HashSet<&str>,
+) -> Vec<Snippet> {
+    // Names defined at the top level of touched files, whose uses elsewhere may break, and names
+    // they use, which may be defined elsewhere
+    let mut defined = HashSet::new();
+    let mut used = HashSet::new();
+    for doc in touched {
+        let SymbolLocations::TreeSitter(ref graph) = doc.symbol_locations else {
+            continue;
+        };
+
+        let src = doc.content.as_bytes();
+        for idx in graph.graph.node_indices() {
+            let (name, names) = match &graph.graph[idx] {
+                NodeKind::Def(d) if graph.is_top_level(idx) => (d.name(src), &mut defined),
+                NodeKind::Ref(r) => (r.name(src), &mut used),
+                _ => continue,
+            };
+
+            if let Ok(name) = std::str::from_utf8(name) {
+                if changed.contains(name) {
+                    names.insert(name.to_owned());
+                }
+            }
+        }
+    }
+
+    let touched = touched
+        .iter()
+        .map(|doc| doc.relative_path.as_str())
+        .collect::<HashSet<_>>();
+
+    let mut snippets = vec![];
+    for doc in docs
+        .iter()
+        .filter(|d| !touched.contains(d.relative_path.as_str()))
+    {
+        let SymbolLocations::TreeSitter(ref scope_graph) = doc.symbol_locations else {
+            continue;
+        };
+
+        let mut lines = vec![];
+        for token in &defined {
+            let handler = RepoWideHandler {
+                token: token.as_bytes(),
+                kind: None,
+                scope_graph,
+                doc,
+            };
+            lines.extend(
+                handler
+                    .handle_definition()
+                    .into_iter()
+                    .map(|r| r.start.line),
+            );
+        }
+
+        for token in &used {
+            let handler = RepoWideHandler {
+                token: token.as_bytes(),
+                kind: None,
+                scope_graph,
+                doc,
+            };
+            lines.extend(
+                handler
+                    .handle_reference()
+                    .0
+                    .into_iter()
+                    .map(|r| r.start.line),
+            );
+        }
+
+        // Places close to each other share a snippet
+        lines.sort_unstable();
+        lines.dedup_by(|next, prev| *next - *prev <= CONTEXT_LINES);
+
+        snippets.extend(
+            lines
+                .into_iter()
+                .take(MAX_OCCURRENCES_PER_FILE)
+                .map(|line| snippet_around(doc, line)),
+        );
+    }
+
+    snippets
+}
+
+/// A snippet of `doc`, of a few lines around `line`
+fn snippet_around(doc: &ContentDocument, line: usize) -> Snippet {
+    let start_line = line.saturating_sub(CONTEXT_LINES);
+    let end_line = line + CONTEXT_LINES;
+
+    let len = doc.content.len();
+    let start_byte = file::line_start(&doc.line_end_indices, len, start_line);
+    let end_byte = file::line_start(&doc.line_end_indices, len, end_line + 1);
+    let text = doc.content[start_byte..end_byte].to_owned();
+
+    Snippet {
+        lang: doc.lang.clone().unwrap_or_default(),
+        repo_name: doc.repo_name.clone(),
+        repo_ref: doc.repo_ref.clone(),
+        relative_path: doc.relative_path.clone(),
+        end_line: start_line + text.lines().count().max(1) - 1,
+        start_line,
+        text,
+        start_byte,
+        end_byte,
+        score: 1.0,
+    }
+}
+
+/// The prompt asking about a diff, and the code related to it, with how many of the hunks and
+/// snippets fit in the prompt
+fn build_diff_prompt(
+    reporef: &RepoRef,
+    q: &str,
+    hunks: &[Hunk],
+    related: &[Snippet],
+    count_tokens: impl Fn(&str) -> usize,
+) -> (api::Messages, usize, usize) {
+    let mut system = format!(
+        "Below is a diff of the repository {reporef}, followed by code from the rest of the \
+        repository that relates to it: code that refers to what the diff changes, or that the \
+        diff refers to.\n"
+    );
+
+    let instructions = r#"
+Use the diff and the related code to write a concise, precise answer to the question.
+
+- If the change breaks related code, such as callers whose arguments no longer match, name the code it breaks with its path.
+- Format your response in GitHub Markdown. Paths, function names and code extracts should be enclosed in backticks.
+- Keep your response short. Do NOT copy long chunks of code into the response.
+- If the diff and related code don't contain enough information to answer the question, just say "Sorry, I'm not sure.".
+- Do NOT try to make up an answer or answer with regard to information that is not in the diff or related code."#;
+
+    let mut tokens = count_tokens(&system) + count_tokens(instructions) + count_tokens(q);
+
+    let mut hunks_covered = 0;
+    for hunk in hunks {
+        let section = format!(
+            "\n=========\n{} (from line {})\n```diff\n{}\n```\n",
+            hunk.path,
+            hunk.start_line,
+            first_lines(&hunk.lines.join("\n"), MAX_HUNK_LINES)
+        );
+
+        let section_tokens = count_tokens(&section);
+        if hunks_covered > 0 && tokens + section_tokens > MAX_DIFF_TOKENS {
+            break;
+        }
+
+        tokens += section_tokens;
+        system.push_str(&section);
+        hunks_covered += 1;
+    }
+
+    system.push_str("=========\nRelated code:\n");
+
+    let mut snippets_covered = 0;
+    for snippet in related {
+        let section = format!(
+            "\n{} lines {}-{}\n```\n{}\n```\n",
+            snippet.relative_path,
+            snippet.start_line + 1,
+            snippet.end_line + 1,
+            snippet.text.trim_end()
+        );
+
+        let section_tokens = count_tokens(&section);
+        if tokens + section_tokens > MAX_PROMPT_TOKENS {
+            break;
+        }
+
+        tokens += section_tokens;
+        system.push_str(&section);
+        snippets_covered += 1;
+    }
+
+    system.push_str("=========\n");
+    system.push_str(instructions);
+
+    let messages = vec![
+        api::Message {
+            role: "system".to_string(),
+            content: system,
+        },
+        api::Message {
+            role: "user".to_string(),
+            content: q.to_string(),
+        },
+    ];
+
+    (api::Messages { messages }, hunks_covered, snippets_covered)
+}
+
+/// Answer a question about a diff
+///
+/// The code the diff touches, and indexed code related to it through the symbol index, is what
+/// the answer is based on, rather than snippets of the whole repository.
+pub(super) async fn handle(
+    _: Scoped<keys::Answer>,
+    access: RepoAccess,
+    tenant: CurrentTenant,
+    State(state): State<Arc<AnswerState>>,
+    Extension(app): Extension<Application>,
+    identity: Option<Extension<Identity>>,
+    Json(request): Json<DiffRequest>,
+) -> Result<impl IntoResponse> {
+    let q = request.q.trim().to_owned();
+    if q.is_empty() {
+        return Err(Error::user("no question was asked"));
+    }
+
+    let reporef = request
+        .repo_ref
+        .parse::<RepoRef>()
+        .ok()
+        .filter(|reporef| access.allows(&reporef.to_string()))
+        .ok_or_else(|| {
+            Error::new(ErrorKind::NotFound, "Repo not found").with_code(ErrorCode::RepoNotFound)
+        })?;
+
+    let disk_path = app
+        .repo_pool
+        .get(&reporef)
+        .map(|repo| repo.disk_path.clone())
+        .ok_or_else(|| {
+            Error::new(ErrorKind::NotFound, "Repo not found").with_code(ErrorCode::RepoNotFound)
+        })?;
+
+    let diff = match (request.diff, request.range) {
+        (Some(diff), None) => diff,
+        (None, Some(range)) => tokio::task::spawn_blocking(move || range_diff(&disk_path, &range))
+            .await
+            .map_err(Error::internal)?
+            .map_err(Error::user)?,
+        _ => {
+            return Err(Error::user(
+                "either a `diff` or a commit `range` must be given",
+            ))
+        }
+    };
+
+    let hunks = parse_diff(&diff);
+    if hunks.is_empty() {
+        return Err(Error::user("the diff changes nothing"));
+    }
+
+    let user_id = auth::user_id(&app, identity, || request.user_id.clone())?;
+    let semantic = Error::semantic(&app.semantic)?;
+
+    let query_id = uuid::Uuid::new_v4();
+    let mut event = QueryEvent {
+        user_id,
+        query_id,
+        session_id: uuid::Uuid::new_v4().to_string(),
+        tenant: tenant.name().map(str::to_owned),
+        stages: vec![Stage::new("raw_query", &q)],
+        ..QueryEvent::default()
+    };
+
+    let result = async {
+        let retrieved = Instant::now();
+
+        let mut touched = hunks.iter().map(|h| h.path.clone()).collect::<Vec<_>>();
+        touched.dedup();
+
+        let mut touched_docs = vec![];
+        for path in touched.iter().take(MAX_TOUCHED_FILES) {
+            match app.indexes.file.by_path(&reporef, path).await {
+                Ok(doc) => touched_docs.push(doc),
+                // Files the diff adds, or that aren't indexed
+                Err(err) => debug!(?err, path, "touched file is not indexed"),
+            }
+        }
+
+        // Symbols are only looked up between files of the same language
+        let mut by_lang = HashMap::<Option<String>, Vec<ContentDocument>>::new();
+        for doc in touched_docs {
+            by_lang.entry(doc.lang.clone()).or_default().push(doc);
+        }
+
+        let changes = hunks
+            .iter()
+            .map(Hunk::changes)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut related = vec![];
+        for (lang, docs) in by_lang {
+            let all_docs = app.indexes.file.by_repo(&reporef, lang.as_deref()).await;
+            let changes = changes.clone();
+            let found = tokio::task::spawn_blocking(move || {
+                related_code(&docs, &all_docs, &identifiers(&changes))
+            })
+            .await
+            .map_err(Error::internal)?;
+            related.extend(found);
+        }
+
+        // Code that's related to the question, among the files related to the diff, comes first
+        let paths = touched
+            .iter()
+            .chain(related.iter().map(|s| &s.relative_path))
+            .cloned()
+            .collect::<HashSet<_>>();
+        let relevant = answer::related_snippets(&semantic, &app.feedback, &reporef, &q)
+            .await?
+            .into_iter()
+            .filter(|s| paths.contains(&s.relative_path));
+        let mut related = relevant.chain(related).collect::<Vec<_>>();
+        event.time(AnswerStage::Retrieve, retrieved.elapsed());
+
+        let (prompt, hunks_covered, snippets_covered) =
+            build_diff_prompt(&reporef, &q, &hunks, &related, |s| {
+                semantic.gpt2_token_count(s)
+            });
+        debug!(hunks_covered, snippets_covered, "built diff prompt");
+
+        let tokens_used = prompt
+            .messages
+            .iter()
+            .map(|m| semantic.gpt2_token_count(&m.content))
+            .sum::<usize>();
+        let max_tokens = 8000u32
+            .saturating_sub(tokens_used as u32)
+            .clamp(1, MAX_ANSWER_TOKENS);
+
+        let explained = Instant::now();
+        let answer = answer::complete(&state, &app, &prompt, max_tokens, 0.5).await?;
+
+        let elapsed = explained.elapsed();
+        event.time(AnswerStage::Explain, elapsed);
+        event
+            .stages
+            .push(Stage::new("answer", &answer).with_time(elapsed));
+
+        related.truncate(snippets_covered);
+
+        Ok::<_, Error>(DiffAnswer {
+            q: q.clone(),
+            query_id,
+            answer,
+            related: related
+                .iter()
+                .map(|s| s.relative_path.clone())
+                .filter(|path| !touched.contains(path))
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+            snippets: AnswerSnippets::new(&related),
+            touched,
+        })
+    }
+    .await;
+
+    if let Err(e) = &result {
+        event
+            .stages
+            .push(Stage::new("error", (e.status.as_u16(), e.message())));
+    }
+    app.track_query(&event);
+
+    Ok(json(result?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patches_are_parsed() {
+        let patch = "@@ -1,2 +1,3 @@ fn main() {\n \
+            use std::io;\n\
+            +use std::fs;\n \
+            \n\
+            @@ -20 +21 @@\n\
+            -    old();\n\
+            +    new();\n\
+            \\ No newline at end of file";
+
+        let hunks = parse_patch("src/main.rs", patch);
+        assert_eq!(
+            hunks,
+            [
+                Hunk {
+                    path: "src/main.rs".into(),
+                    start_line: 1,
+                    lines: vec![" use std::io;".into(), "+use std::fs;".into(), " ".into()],
+                },
+                Hunk {
+                    path: "src/main.rs".into(),
+                    start_line: 21,
+                    lines: vec!["-    old();".into(), "+    new();".into()],
+                },
+            ]
+        );
+
+        assert_eq!(hunks[1].changes(), "    old();\n    new();");
+    }
+
+    #[test]
+    fn diffs_are_parsed() {
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs\n\
+            index 83db48f..bf269f4 100644\n\
+            --- a/src/lib.rs\n\
+            +++ b/src/lib.rs\n\
+            @@ -3,2 +3,2 @@\n\
+            --- a removed comment that looks like a header\n\
+            +// a comment\n \
+            fn lib() {}\n\
+            diff --git a/src/old.rs b/src/old.rs\n\
+            deleted file mode 100644\n\
+            --- a/src/old.rs\n\
+            +++ /dev/null\n\
+            @@ -1 +0,0 @@\n\
+            -fn old() {}\n";
+
+        let hunks = parse_diff(diff);
+        assert_eq!(
+            hunks
+                .iter()
+                .map(|h| (h.path.as_str(), h.start_line, h.lines.len()))
+                .collect::<Vec<_>>(),
+            [("src/lib.rs", 3, 3), ("src/old.rs", 0, 1)]
+        );
+    }
+
+    #[test]
+    fn identifiers_are_found() {
+        assert_eq!(
+            identifiers("let indexed = Indexer::new(&repo, 10);"),
+            HashSet::from(["let", "indexed", "Indexer", "new", "repo"])
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Conditional requests for content served from the index.
+//!
+//! Entity tags combine the generation of the index with the parameters identifying the
+//! resource, so they change whenever the index is written to.
+
+use axum::{
+    http::{
+        header::{ETAG, IF_NONE_MATCH},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+
+pub(super) struct ETag(String);
+
+impl ETag {
+    pub(super) fn new(generation: &str, resource: &[&str]) -> Self {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(generation.as_bytes());
+        for part in resource {
+            // Prefix every part with its length, so that parts can't run into each other
+            hasher.update(&(part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+
+        Self(format!("\"{}\"", &hasher.finalize().to_hex()[..32]))
+    }
+
+    /// Whether the client already holds this version, according to `If-None-Match`.
+    pub(super) fn matches(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get_all(IF_NONE_MATCH)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            // `If-None-Match` uses weak comparison
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == self.0)
+    }
+
+    pub(super) fn not_modified(&self) -> Response {
+        (StatusCode::NOT_MODIFIED, self.header()).into_response()
+    }
+
+    /// Attach this tag to a response.
+    pub(super) fn attach(&self, response: impl IntoResponse) -> Response {
+        (self.header(), response).into_response()
+    }
+
+    fn header(&self) -> [(axum::http::HeaderName, HeaderValue); 1] {
+        [(
+            ETAG,
+            HeaderValue::from_str(&self.0).expect("entity tags are hex strings"),
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn if_none_match() {
+        let etag = ETag::new("gen", &["repo", "path"]);
+        let mut headers = HeaderMap::new();
+        assert!(!etag.matches(&headers));
+
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_str(&etag.0).unwrap());
+        assert!(etag.matches(&headers));
+
+        let weak = format!("\"other\", W/{}", etag.0);
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_str(&weak).unwrap());
+        assert!(etag.matches(&headers));
+
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("*"));
+        assert!(etag.matches(&headers));
+
+        // A new index generation invalidates the tag
+        let stale = ETag::new("gen2", &["repo", "path"]);
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_str(&stale.0).unwrap());
+        assert!(!ETag::new("gen", &["repo", "path"]).matches(&headers));
+
+        // Parts can't be confused with each other
+        assert_ne!(
+            ETag::new("gen", &["ab", "c"]).0,
+            ETag::new("gen", &["a", "bc"]).0
+        );
+    }
+}
@@ -0,0 +1,185 @@
+//! Sparse fieldsets, to leave heavy fields out of responses.
+//!
+//! Clients that only need paths and scores, such as list views, can pass the heavy fields they
+//! do need with `fields=`, e.g. `fields=highlights`, or an empty `fields=` for none of them.
+//! Every field is included if the parameter is missing.
+
+use std::{collections::HashSet, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer};
+use serde_json::Value;
+
+/// A heavy field that can be left out of responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Field {
+    /// The text of search result and answer snippets
+    Text,
+    /// Highlighted ranges of search result snippets
+    Highlights,
+    /// Symbols in search result snippets
+    Symbols,
+    /// The contents of files returned by `open:` queries
+    Contents,
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Field::Text),
+            "highlights" => Ok(Field::Highlights),
+            "symbols" => Ok(Field::Symbols),
+            "contents" => Ok(Field::Contents),
+            _ => Err(format!("unknown field `{s}`")),
+        }
+    }
+}
+
+/// The heavy fields to include in a response, all of them if unset
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fields(Option<HashSet<Field>>);
+
+impl<'de> Deserialize<'de> for Fields {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let fields = String::deserialize(deserializer)?;
+        fields
+            .split(',')
+            .map(str::trim)
+            .filter(|f| !f.is_empty())
+            .map(Field::from_str)
+            .collect::<Result<_, _>>()
+            .map(|fields| Fields(Some(fields)))
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Fields {
+    pub(super) fn is_all(&self) -> bool {
+        self.0.is_none()
+    }
+
+    fn includes(&self, field: Field) -> bool {
+        self.0.as_ref().map_or(true, |f| f.contains(&field))
+    }
+
+    /// Remove excluded fields from a serialized search response.
+    pub(super) fn prune_search(&self, response: &mut Value) {
+        if let Some(results) = response.get_mut("data") {
+            self.prune_result(results);
+        }
+    }
+
+    /// Remove excluded fields from a serialized search result.
+    pub(super) fn prune_result(&self, result: &mut Value) {
+        self.prune(result, &["data", "snippets", "data"], Field::Text);
+        self.prune(
+            result,
+            &["data", "snippets", "highlights"],
+            Field::Highlights,
+        );
+        self.prune(result, &["data", "snippets", "symbols"], Field::Symbols);
+        self.prune(result, &["data", "contents"], Field::Contents);
+    }
+
+    /// Remove excluded fields from a serialized answer.
+    pub(super) fn prune_answer(&self, response: &mut Value) {
+        self.prune(response, &["snippets", "matches", "text"], Field::Text);
+    }
+
+    /// Remove excluded fields from a serialized batch of answers.
+    pub(super) fn prune_batch(&self, response: &mut Value) {
+        self.prune(
+            response,
+            &["answers", "snippets", "matches", "text"],
+            Field::Text,
+        );
+    }
+
+    fn prune(&self, value: &mut Value, path: &[&str], field: Field) {
+        if !self.includes(field) {
+            remove(value, path);
+        }
+    }
+}
+
+/// Remove the value at `path`, descending into every element of the arrays along the way.
+fn remove(value: &mut Value, path: &[&str]) {
+    match (value, path) {
+        (Value::Array(items), _) => items.iter_mut().for_each(|v| remove(v, path)),
+        (Value::Object(map), [key]) => {
+            map.remove(*key);
+        }
+        (Value::Object(map), [key, rest @ ..]) => {
+            if let Some(v) = map.get_mut(*key) {
+                remove(v, rest);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(s: &str) -> Result<Fields, serde_json::Error> {
+        serde_json::from_value(json!(s))
+    }
+
+    #[test]
+    fn parse_fields() {
+        assert_eq!(parse("").unwrap(), Fields(Some(HashSet::new())));
+        assert_eq!(
+            parse("text, symbols").unwrap(),
+            Fields(Some([Field::Text, Field::Symbols].into()))
+        );
+        assert!(parse("text,score").is_err());
+        assert!(Fields::default().is_all());
+    }
+
+    #[test]
+    fn prune_search() {
+        let mut response = json!({
+            "count": 2,
+            "data": [
+                {
+                    "kind": "snippets",
+                    "data": {
+                        "relative_path": "src/main.rs",
+                        "snippets": [
+                            { "data": "fn main", "highlights": [], "symbols": [], "line_range": {} }
+                        ]
+                    }
+                },
+                {
+                    "kind": "file",
+                    "data": { "relative_path": "README.md", "contents": "# Hello" }
+                }
+            ]
+        });
+
+        parse("highlights").unwrap().prune_search(&mut response);
+
+        assert_eq!(
+            response,
+            json!({
+                "count": 2,
+                "data": [
+                    {
+                        "kind": "snippets",
+                        "data": {
+                            "relative_path": "src/main.rs",
+                            "snippets": [{ "highlights": [], "line_range": {} }]
+                        }
+                    },
+                    {
+                        "kind": "file",
+                        "data": { "relative_path": "README.md" }
+                    }
+                ]
+            })
+        );
+    }
+}
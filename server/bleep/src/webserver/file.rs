@@ -2,11 +2,16 @@ use std::sync::Arc;
 
 use axum::{
     extract::{Path, Query},
+    http::HeaderMap,
     response::IntoResponse,
     Extension,
 };
 
-use super::prelude::*;
+use super::{etag::ETag, prelude::*};
+use crate::{
+    intelligence::NodeKind, repo::RepoRef, symbol::SymbolLocations, text_range::TextRange,
+    Application,
+};
 
 #[derive(Debug, serde::Deserialize)]
 pub struct Params {
@@ -19,22 +24,232 @@ pub struct FileResponse {
 }
 
 pub async fn handle(
+    _: Scoped<keys::Read>,
+    access: RepoAccess,
     Path(path): Path<String>,
     Query(params): Query<Params>,
     Extension(indexes): Extension<Arc<Indexes>>,
+    Extension(app): Extension<Application>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
     // Strip leading slash, always present.
     let file_disk_path = &path[1..];
 
+    // Don't reveal whether files exist in repositories the user can't access
+    let accessible = access.disk_paths(&app.repo_pool).map_or(true, |repos| {
+        repos
+            .iter()
+            .any(|repo| std::path::Path::new(file_disk_path).starts_with(repo))
+    });
+
+    if !accessible {
+        return Err(
+            Error::new(ErrorKind::NotFound, "file not found").with_code(ErrorCode::FileNotFound)
+        );
+    }
+
     if params.rev.is_some() {
         return Err(Error::internal("the `rev` parameter is not yet supported"));
     }
 
+    let etag = ETag::new(&indexes.file.generation().await, &[file_disk_path]);
+    if etag.matches(&headers) {
+        return Ok(etag.not_modified());
+    }
+
     let contents = indexes
         .file
         .file_body(file_disk_path)
         .await
         .map_err(Error::internal)?;
 
-    Ok(json(FileResponse { contents }))
+    Ok(etag.attach(json(FileResponse { contents })))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub(super) struct ContentParams {
+    /// The repo_ref of the file of interest
+    repo_ref: String,
+
+    /// The path to the file of interest, relative to the repo root
+    path: String,
+
+    /// First line to return, 0-indexed and inclusive. Defaults to the start of the file.
+    start_line: Option<usize>,
+
+    /// Last line to return, 0-indexed and exclusive. Defaults to the end of the file.
+    end_line: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum SpanRole {
+    Definition,
+    Reference,
+}
+
+/// A range of the file that should be highlighted as a symbol
+#[derive(Serialize, ToSchema)]
+pub(super) struct HighlightSpan {
+    /// Position of the span in the whole file
+    range: TextRange,
+    role: SpanRole,
+    /// Kind of the symbol, such as `function` or `variable`, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kind: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(super) struct FileContentResponse {
+    repo_ref: String,
+    path: String,
+    lang: Option<String>,
+    /// The requested lines of the file
+    content: String,
+    start_line: usize,
+    end_line: usize,
+    /// Byte offset of `content` in the file, to map highlight ranges onto `content`
+    start_byte: usize,
+    highlights: Vec<HighlightSpan>,
+}
+
+impl From<FileContentResponse> for super::Response<'static> {
+    fn from(res: FileContentResponse) -> super::Response<'static> {
+        super::Response::FileContent(res)
+    }
+}
+
+/// Byte offset of the start of a 0-indexed line.
+pub(super) fn line_start(line_end_indices: &[u32], content_len: usize, line: usize) -> usize {
+    match line.checked_sub(1) {
+        None => 0,
+        Some(prev) => line_end_indices
+            .get(prev)
+            .map(|&idx| idx as usize + 1)
+            .unwrap_or(content_len)
+            .min(content_len),
+    }
+}
+
+fn highlight_spans(symbol_locations: SymbolLocations) -> Vec<HighlightSpan> {
+    match symbol_locations {
+        SymbolLocations::TreeSitter(graph) => graph
+            .graph
+            .node_indices()
+            .filter_map(|idx| {
+                let (range, role) = match &graph.graph[idx] {
+                    NodeKind::Def(d) => (d.range, SpanRole::Definition),
+                    NodeKind::Ref(r) => (r.range, SpanRole::Reference),
+                    NodeKind::Scope(_) => return None,
+                };
+
+                Some(HighlightSpan {
+                    range,
+                    role,
+                    kind: graph.symbol_name_of(idx).map(str::to_owned),
+                })
+            })
+            .collect(),
+        SymbolLocations::Ctags(symbols) => symbols
+            .into_iter()
+            .map(|symbol| HighlightSpan {
+                range: symbol.range,
+                role: SpanRole::Definition,
+                kind: Some(symbol.kind),
+            })
+            .collect(),
+        SymbolLocations::Empty => vec![],
+    }
+}
+
+/// Get the contents of a file, optionally limited to a range of lines
+///
+/// The response includes the symbols found in the requested lines, so clients can highlight
+/// them without parsing the file themselves.
+#[utoipa::path(get, path = "/file",
+    params(ContentParams),
+    responses(
+        (status = 200, description = "Execute query successfully", body = FileContentResponse),
+        (status = 304, description = "Not modified since the version given in `If-None-Match`"),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 404, description = "File not found", body = EndpointError),
+    ),
+)]
+pub(super) async fn content(
+    _: Scoped<keys::Read>,
+    access: RepoAccess,
+    Query(params): Query<ContentParams>,
+    Extension(indexes): Extension<Arc<Indexes>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse> {
+    let repo_ref = params.repo_ref.parse::<RepoRef>().map_err(Error::user)?;
+
+    if !access.allows(&repo_ref.to_string()) {
+        return Err(
+            Error::new(ErrorKind::NotFound, "file not found").with_code(ErrorCode::FileNotFound)
+        );
+    }
+
+    let range = format!("{:?}..{:?}", params.start_line, params.end_line);
+    let etag = ETag::new(
+        &indexes.file.generation().await,
+        &[&params.repo_ref, &params.path, &range],
+    );
+
+    if etag.matches(&headers) {
+        return Ok(etag.not_modified());
+    }
+
+    let doc = indexes
+        .file
+        .by_path(&repo_ref, &params.path)
+        .await
+        .map_err(|_| {
+            Error::new(ErrorKind::NotFound, "file not found").with_code(ErrorCode::FileNotFound)
+        })?;
+
+    let start_line = params.start_line.unwrap_or(0);
+    let end_line = params.end_line.unwrap_or(usize::MAX);
+    if end_line < start_line {
+        return Err(Error::user("`end_line` must not be before `start_line`"));
+    }
+
+    let start_byte = line_start(&doc.line_end_indices, doc.content.len(), start_line);
+    let end_byte = line_start(&doc.line_end_indices, doc.content.len(), end_line);
+
+    let highlights = highlight_spans(doc.symbol_locations)
+        .into_iter()
+        .filter(|span| span.range.start.byte < end_byte && span.range.end.byte > start_byte)
+        .collect();
+
+    // Report the lines actually returned, if the range goes past the end of the file
+    let last_line = doc.content[..end_byte].lines().count();
+
+    Ok(etag.attach(json(FileContentResponse {
+        content: doc.content[start_byte..end_byte].to_owned(),
+        start_line: start_line.min(last_line),
+        end_line: end_line.min(last_line),
+        start_byte,
+        highlights,
+        lang: doc.lang,
+        path: doc.relative_path,
+        repo_ref: doc.repo_ref,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_offsets() {
+        let content = "foo\nbar\nbaz";
+        let ends = [3, 7];
+
+        assert_eq!(line_start(&ends, content.len(), 0), 0);
+        assert_eq!(line_start(&ends, content.len(), 1), 4);
+        assert_eq!(line_start(&ends, content.len(), 2), 8);
+        assert_eq!(line_start(&ends, content.len(), 3), content.len());
+        assert_eq!(line_start(&ends, content.len(), usize::MAX), content.len());
+    }
 }
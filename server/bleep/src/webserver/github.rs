@@ -1,10 +1,17 @@
 use super::prelude::*;
-use crate::{repo::Backend, Application};
-
-use either::Either;
-use octocrab::{auth::DeviceCodes, Octocrab};
+use crate::{
+    remotes::{
+        self,
+        github::{State, TokenResponse},
+    },
+    repo::Backend,
+    Application,
+};
+
+use chrono::Utc;
+use octocrab::auth::DeviceCodes;
 use reqwest::header::ACCEPT;
-use secrecy::SecretString;
+use secrecy::{ExposeSecret, SecretString};
 use tracing::{error, warn};
 
 use std::time::{Duration, Instant};
@@ -32,7 +39,10 @@ pub(super) enum GithubCredentialStatus {
         (status = 500, description = "Server error", body = EndpointError),
     ),
 )]
-pub(super) async fn status(Extension(app): Extension<Application>) -> impl IntoResponse {
+pub(super) async fn status(
+    _: Scoped<keys::Read>,
+    Extension(app): Extension<Application>,
+) -> impl IntoResponse {
     (
         StatusCode::OK,
         json(GithubResponse::Status(
@@ -53,7 +63,10 @@ pub(super) async fn status(Extension(app): Extension<Application>) -> impl IntoR
         (status = 500, description = "Server error", body = EndpointError),
     ),
 )]
-pub(super) async fn login(Extension(app): Extension<Application>) -> impl IntoResponse {
+pub(super) async fn login(
+    _: Scoped<keys::Admin>,
+    Extension(app): Extension<Application>,
+) -> impl IntoResponse {
     let client_id = match app.config.github_client_id.as_ref() {
         Some(id) => id.clone(),
         None => {
@@ -76,12 +89,7 @@ pub(super) async fn login(Extension(app): Extension<Application>) -> impl IntoRe
         .await
         .unwrap();
 
-    tokio::spawn(poll_for_oauth_token(
-        github,
-        client_id,
-        codes.clone(),
-        app.clone(),
-    ));
+    tokio::spawn(poll_for_oauth_token(client_id, codes.clone(), app.clone()));
 
     Ok(json(GithubResponse::AuthenticationNeeded {
         url: codes.verification_uri,
@@ -98,7 +106,10 @@ pub(super) async fn login(Extension(app): Extension<Application>) -> impl IntoRe
         (status = 500, description = "Server error", body = EndpointError),
     ),
 )]
-pub(super) async fn logout(Extension(app): Extension<Application>) -> impl IntoResponse {
+pub(super) async fn logout(
+    _: Scoped<keys::Admin>,
+    Extension(app): Extension<Application>,
+) -> impl IntoResponse {
     let deleted = app.credentials.remove(&Backend::Github).is_some();
     if deleted {
         let saved = app
@@ -121,12 +132,9 @@ pub(super) async fn logout(Extension(app): Extension<Application>) -> impl IntoR
     )))
 }
 
-async fn poll_for_oauth_token(
-    github: Octocrab,
-    client_id: SecretString,
-    codes: DeviceCodes,
-    app: Application,
-) {
+/// Wait for the user to authorize the device, asking for the token directly rather than through
+/// octocrab, which drops the refresh token of apps that expire their tokens
+async fn poll_for_oauth_token(client_id: SecretString, codes: DeviceCodes, app: Application) {
     let start = Instant::now();
 
     let mut interval = Duration::from_secs(codes.interval);
@@ -140,10 +148,16 @@ async fn poll_for_oauth_token(
             return;
         }
 
-        match codes.poll_once(&github, &client_id).await {
-            Ok(Either::Left(auth)) => break auth,
-            Ok(Either::Right(cont)) => match cont {
-                octocrab::auth::Continue::SlowDown => {
+        let response = remotes::github::request_token(&[
+            ("client_id", client_id.expose_secret()),
+            ("device_code", &codes.device_code),
+            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+        ])
+        .await;
+
+        match response {
+            Ok(TokenResponse::Error { error, .. }) => match error.as_str() {
+                "slow_down" => {
                     // We were request to slow down. We add five seconds to the polling
                     // duration.
                     interval += Duration::from_secs(5);
@@ -151,10 +165,21 @@ async fn poll_for_oauth_token(
                     // The first tick happens instantly, so we tick that off immediately.
                     clock.tick().await;
                 }
-                octocrab::auth::Continue::AuthorizationPending => {
+                "authorization_pending" => {
                     // The user has not clicked authorize yet, but nothing has gone wrong.
                     // We keep polling.
                 }
+                _ => {
+                    warn!(%error, "GitHub authorization failed");
+                    return;
+                }
+            },
+            Ok(token) => match token.into_auth(Utc::now()) {
+                Ok(auth) => break auth,
+                Err(err) => {
+                    warn!(?err, "GitHub authorization failed");
+                    return;
+                }
             },
             Err(err) => {
                 warn!(?err, "GitHub authorization failed");
@@ -163,7 +188,7 @@ async fn poll_for_oauth_token(
         }
     };
 
-    app.credentials.set_github(auth.into());
+    app.credentials.set_github(State::with_auth(auth));
     let saved = app
         .config
         .source
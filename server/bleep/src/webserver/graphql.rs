@@ -0,0 +1,396 @@
+//! A GraphQL view of repositories, files, search results and answers.
+//!
+//! This lets clients fetch composite views, such as search results together with the contents
+//! of every matching file, in a single round trip. Access control is the same as for the
+//! equivalent REST endpoints.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use async_graphql::{
+    ComplexObject, Context, EmptyMutation, EmptySubscription, Object, Schema, SimpleObject,
+};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequestParts, State},
+    http::request::Parts,
+};
+
+use super::{
+    answer::{self, AnswerState, Params},
+    auth::{self, Identity},
+    fields::Fields,
+    keys::{Grant, Scope},
+    prelude::*,
+    query::{ApiQuery, QueryResult},
+    tenant::CurrentTenant,
+};
+use crate::{
+    repo::{RepoRef, Repository},
+    Application,
+};
+
+/// Deeper queries are rejected, to bound the work done by a single request
+const MAX_DEPTH: usize = 12;
+
+/// Queries selecting more fields than this are rejected, aliases included
+const MAX_COMPLEXITY: usize = 500;
+
+/// Answers a single document can ask for. Each one is as costly as a call to `/answer`, which
+/// aliases would otherwise multiply past the rate limit.
+const MAX_ANSWERS: usize = 1;
+
+pub(super) type ApiSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+pub(super) fn schema(answer_state: Arc<AnswerState>) -> ApiSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .data(answer_state)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+/// Everything the resolvers need to know about the request
+pub(super) struct RequestContext {
+    pub(super) app: Application,
+    pub(super) access: RepoAccess,
+    pub(super) grant: Grant,
+    pub(super) tenant: CurrentTenant,
+    /// Unset if the user must log in to be identified
+    pub(super) user_id: Option<String>,
+    /// Answers resolved so far
+    answers: AtomicUsize,
+}
+
+fn gql_error(err: Error) -> async_graphql::Error {
+    async_graphql::Error::new(err.message())
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RequestContext
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let access = RepoAccess::from_request_parts(parts, state).await?;
+
+        let app = parts
+            .extensions
+            .get::<Application>()
+            .cloned()
+            .ok_or_else(|| Error::internal("application state is missing"))?;
+
+        let grant = parts.extensions.get::<Grant>().cloned().ok_or_else(|| {
+            Error::user("missing API key")
+                .with_status(StatusCode::UNAUTHORIZED)
+                .with_code(ErrorCode::Unauthenticated)
+        })?;
+
+        let identity = parts.extensions.get::<Identity>().cloned().map(Extension);
+
+        Ok(Self {
+            user_id: auth::user_id(&app, identity, answer::default_user_id).ok(),
+            tenant: CurrentTenant::resolve(&app, &parts.extensions),
+            answers: AtomicUsize::new(0),
+            access,
+            grant,
+            app,
+        })
+    }
+}
+
+pub(super) async fn handle(
+    _: Scoped<keys::Read>,
+    context: RequestContext,
+    State(schema): State<ApiSchema>,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    schema
+        .execute(request.into_inner().data(context))
+        .await
+        .into()
+}
+
+pub(super) struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Indexed repositories
+    async fn repos(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<RepoObject>> {
+        let rc = ctx.data::<RequestContext>()?;
+
+        Ok(rc
+            .app
+            .repo_pool
+            .iter()
+            .filter(|elem| rc.access.allows(&elem.key().to_string()))
+            .map(|elem| RepoObject::new(elem.key(), elem.value()))
+            .collect())
+    }
+
+    /// A single indexed repository
+    async fn repo(
+        &self,
+        ctx: &Context<'_>,
+        repo_ref: String,
+    ) -> async_graphql::Result<Option<RepoObject>> {
+        load_repo(ctx.data::<RequestContext>()?, &repo_ref)
+    }
+
+    /// A file of an indexed repository
+    async fn file(
+        &self,
+        ctx: &Context<'_>,
+        repo_ref: String,
+        path: String,
+    ) -> async_graphql::Result<Option<FileObject>> {
+        load_file(ctx.data::<RequestContext>()?, &repo_ref, &path).await
+    }
+
+    /// Files matching a query written in the bloop query language
+    async fn search(
+        &self,
+        ctx: &Context<'_>,
+        q: String,
+        #[graphql(default = 0)] page: usize,
+        #[graphql(default = 20)] page_size: usize,
+    ) -> async_graphql::Result<Vec<SearchMatch>> {
+        let rc = ctx.data::<RequestContext>()?;
+        let repos = rc.access.disk_paths(&rc.app.repo_pool);
+
+        let response = Arc::new(ApiQuery::new(q, page, page_size))
+            .query(
+                Arc::clone(&rc.app.indexes),
+                &rc.app.repo_pool,
+                repos.as_deref(),
+            )
+            .await
+            .map_err(gql_error)?;
+
+        Ok(response
+            .data
+            .into_iter()
+            .filter_map(|result| match result {
+                QueryResult::Snippets(file) => Some(SearchMatch {
+                    repo_ref: file.repo_ref,
+                    path: file.relative_path,
+                    lang: file.lang,
+                    snippets: file
+                        .snippets
+                        .into_iter()
+                        .map(|snippet| SearchSnippet {
+                            text: snippet.data,
+                            start_line: snippet.line_range.start,
+                            end_line: snippet.line_range.end,
+                        })
+                        .collect(),
+                }),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Answer a question about the indexed code, without any conversation history
+    async fn answer(&self, ctx: &Context<'_>, q: String) -> async_graphql::Result<AnswerObject> {
+        let rc = ctx.data::<RequestContext>()?;
+        let state = ctx.data::<Arc<AnswerState>>()?;
+
+        if !rc.grant.allows(Scope::Answer) {
+            return Err("API key lacks the required scope".into());
+        }
+
+        let Some(user_id) = rc.user_id.clone() else {
+            return Err("login required".into());
+        };
+
+        if rc.answers.fetch_add(1, Ordering::Relaxed) >= MAX_ANSWERS {
+            return Err(format!("at most {MAX_ANSWERS} answer can be asked for per query").into());
+        }
+
+        let params = Params {
+            q,
+            thread_id: uuid::Uuid::new_v4().to_string(),
+            limit: answer::default_limit(),
+            user_id,
+            fields: Fields::default(),
+            answer_lang: None,
+            access: rc.access.clone(),
+            llm_permits: None,
+        };
+
+        let answer = answer::answer_one(state, params, &rc.app, rc.tenant.name()).await;
+
+        Ok(AnswerObject {
+            query_id: answer.query_id.to_string(),
+            answer: answer.answer,
+            error: answer.error,
+            snippets: answer
+                .snippets
+                .map(|snippets| snippets.matches)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|snippet| AnswerSnippet {
+                    repo_ref: snippet.repo_ref,
+                    path: snippet.relative_path,
+                    text: snippet.text,
+                    start_line: snippet.start_line,
+                    end_line: snippet.end_line,
+                })
+                .collect(),
+        })
+    }
+}
+
+fn load_repo(rc: &RequestContext, repo_ref: &str) -> async_graphql::Result<Option<RepoObject>> {
+    let repo_ref = repo_ref.parse::<RepoRef>()?;
+    if !rc.access.allows(&repo_ref.to_string()) {
+        return Ok(None);
+    }
+
+    Ok(rc
+        .app
+        .repo_pool
+        .get(&repo_ref)
+        .map(|elem| RepoObject::new(elem.key(), elem.value())))
+}
+
+async fn load_file(
+    rc: &RequestContext,
+    repo_ref: &str,
+    path: &str,
+) -> async_graphql::Result<Option<FileObject>> {
+    let repo_ref = repo_ref.parse::<RepoRef>()?;
+    if !rc.access.allows(&repo_ref.to_string()) {
+        return Ok(None);
+    }
+
+    Ok(rc
+        .app
+        .indexes
+        .file
+        .by_path(&repo_ref, path)
+        .await
+        .ok()
+        .map(|doc| FileObject {
+            repo_ref: doc.repo_ref,
+            path: doc.relative_path,
+            lang: doc.lang,
+            content: doc.content.to_string(),
+        }))
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Repo", complex)]
+pub(super) struct RepoObject {
+    repo_ref: String,
+    name: String,
+    sync_status: String,
+    last_indexed_commit: Option<String>,
+    last_index_unix_secs: u64,
+    most_common_lang: Option<String>,
+}
+
+impl RepoObject {
+    fn new(repo_ref: &RepoRef, repo: &Repository) -> Self {
+        Self {
+            repo_ref: repo_ref.to_string(),
+            name: repo_ref.display_name(),
+            sync_status: serde_json::to_value(&repo.sync_status)
+                .ok()
+                .and_then(|status| match status {
+                    serde_json::Value::String(status) => Some(status),
+                    // Statuses carrying data, such as errors, serialize as a single-key map
+                    serde_json::Value::Object(map) => map.keys().next().cloned(),
+                    _ => None,
+                })
+                .unwrap_or_default(),
+            last_indexed_commit: repo.last_commit_hash.clone(),
+            last_index_unix_secs: repo.last_index_unix_secs,
+            most_common_lang: repo.most_common_lang.clone(),
+        }
+    }
+}
+
+#[ComplexObject]
+impl RepoObject {
+    /// A file of this repository
+    async fn file(
+        &self,
+        ctx: &Context<'_>,
+        path: String,
+    ) -> async_graphql::Result<Option<FileObject>> {
+        load_file(ctx.data::<RequestContext>()?, &self.repo_ref, &path).await
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "File", complex)]
+pub(super) struct FileObject {
+    repo_ref: String,
+    path: String,
+    lang: Option<String>,
+    content: String,
+}
+
+#[ComplexObject]
+impl FileObject {
+    /// The repository this file belongs to
+    async fn repo(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<RepoObject>> {
+        load_repo(ctx.data::<RequestContext>()?, &self.repo_ref)
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub(super) struct SearchMatch {
+    repo_ref: String,
+    path: String,
+    lang: Option<String>,
+    snippets: Vec<SearchSnippet>,
+}
+
+#[ComplexObject]
+impl SearchMatch {
+    /// The file containing this match
+    async fn file(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<FileObject>> {
+        load_file(ctx.data::<RequestContext>()?, &self.repo_ref, &self.path).await
+    }
+}
+
+#[derive(SimpleObject)]
+pub(super) struct SearchSnippet {
+    text: String,
+    /// 0-indexed, inclusive
+    start_line: usize,
+    /// 0-indexed, exclusive
+    end_line: usize,
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Answer")]
+pub(super) struct AnswerObject {
+    query_id: String,
+    answer: Option<String>,
+    error: Option<String>,
+    snippets: Vec<AnswerSnippet>,
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub(super) struct AnswerSnippet {
+    repo_ref: String,
+    path: String,
+    text: String,
+    start_line: usize,
+    end_line: usize,
+}
+
+#[ComplexObject]
+impl AnswerSnippet {
+    /// The file this snippet was taken from
+    async fn file(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<FileObject>> {
+        load_file(ctx.data::<RequestContext>()?, &self.repo_ref, &self.path).await
+    }
+}
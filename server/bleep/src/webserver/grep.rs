@@ -0,0 +1,330 @@
+//! Literal and regex scans of indexed code, for scripts and editors that want the lines matching
+//! a pattern, like `ripgrep` would find them, without the query language getting in the way.
+//!
+//! The pattern is never parsed as a query, so `repo:`, `or` and parentheses in it are searched
+//! for as they're written. Files are narrowed down with the content index's trigrams, and then
+//! scanned line by line.
+
+use std::{borrow::Cow, ops::Range, time::Instant};
+
+use axum::http::HeaderMap;
+use regex::{bytes::RegexBuilder as ByteRegexBuilder, Regex, RegexBuilder};
+use tantivy::collector::{MultiCollector, TopDocs};
+
+use super::{
+    prelude::*,
+    query::{ndjson, wants_ndjson, ResultFormat},
+    tenant::CurrentTenant,
+};
+use crate::{
+    analytics::QueryLogEntry,
+    collector::BytesFilterCollector,
+    indexes::{
+        reader::{ContentDocument, ContentReader},
+        Indexes,
+    },
+    query::{
+        languages,
+        parser::{self, Literal, Target},
+    },
+    Application,
+};
+
+/// Lines of context shown either side of a match can't exceed this
+const MAX_CONTEXT: usize = 10;
+
+/// The most matching lines a request can ask for
+const MAX_LIMIT: usize = 10_000;
+
+/// Files scanned for matches, at most. The response is truncated if more files could match.
+const MAX_FILES: usize = 1_000;
+
+fn default_limit() -> usize {
+    100
+}
+
+#[derive(Deserialize, IntoParams, Debug)]
+pub(super) struct GrepParams {
+    /// The text to search for
+    pattern: String,
+    /// Search for `pattern` as a regular expression, rather than literally
+    #[serde(default)]
+    regex: bool,
+    #[serde(default)]
+    ignore_case: bool,
+    /// Only search repos whose name contains this
+    repo: Option<String>,
+    /// Only search files whose path contains this
+    path: Option<String>,
+    lang: Option<String>,
+    /// Lines shown before and after each match, up to 10
+    #[serde(default)]
+    context: usize,
+    /// The most matching lines returned, up to 10000
+    #[serde(default = "default_limit")]
+    limit: usize,
+    /// Matches can be streamed as newline-delimited JSON, one per line
+    #[serde(default)]
+    format: ResultFormat,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq, Eq)]
+pub(super) struct GrepMatch {
+    repo_name: String,
+    repo_ref: String,
+    relative_path: String,
+    /// 1-based
+    line_number: usize,
+    text: String,
+    /// Byte ranges of the matches within `text`
+    ranges: Vec<Range<usize>>,
+    before: Vec<String>,
+    after: Vec<String>,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub(super) struct GrepResponse {
+    count: usize,
+    /// Whether there are more matches than `limit`, or more files that could match than are
+    /// scanned
+    truncated: bool,
+    matches: Vec<GrepMatch>,
+}
+
+impl From<GrepResponse> for super::Response<'static> {
+    fn from(res: GrepResponse) -> super::Response<'static> {
+        super::Response::Grep(res)
+    }
+}
+
+/// Find the lines of indexed files that match a pattern
+#[utoipa::path(get, path = "/grep",
+    params(GrepParams),
+    responses(
+        (status = 200, description = "Lines matching the pattern", body = GrepResponse),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn handle(
+    _: Scoped<keys::Read>,
+    access: RepoAccess,
+    tenant: CurrentTenant,
+    Query(params): Query<GrepParams>,
+    Extension(indexes): Extension<Arc<Indexes>>,
+    Extension(app): Extension<Application>,
+    headers: HeaderMap,
+) -> Result<axum::response::Response> {
+    let repos = access.disk_paths(&app.repo_pool);
+
+    let mut entry = QueryLogEntry::search(&params.pattern);
+    entry.tenant = tenant.name().map(str::to_owned);
+    let start = Instant::now();
+
+    let response = grep(&indexes, &params, repos.as_deref()).await;
+
+    entry.latency_ms = start.elapsed().as_millis() as u64;
+    match &response {
+        Ok(response) => entry.result_count = response.count,
+        Err(err) => entry.error = Some(err.message().to_string()),
+    }
+
+    app.log_query(&entry);
+    let response = response?;
+
+    if wants_ndjson(params.format, &headers) {
+        return ndjson(response.matches);
+    }
+
+    Ok(json(response).into_response())
+}
+
+async fn grep(
+    indexes: &Indexes,
+    params: &GrepParams,
+    repos: Option<&[std::path::PathBuf]>,
+) -> Result<GrepResponse> {
+    if params.pattern.is_empty() {
+        return Err(Error::user("pattern must not be empty"));
+    }
+
+    let regex_str = if params.regex {
+        Cow::Borrowed(params.pattern.as_str())
+    } else {
+        Cow::Owned(regex::escape(&params.pattern))
+    };
+
+    let line_regex = RegexBuilder::new(&regex_str)
+        .case_insensitive(params.ignore_case)
+        .build()
+        .map_err(|err| Error::user(format!("invalid pattern: {err}")))?;
+
+    let byte_regex = ByteRegexBuilder::new(&regex_str)
+        .multi_line(true)
+        .case_insensitive(params.ignore_case)
+        .build()
+        .map_err(|err| Error::user(format!("invalid pattern: {err}")))?;
+
+    let literal = if params.regex {
+        Literal::Regex(params.pattern.as_str().into())
+    } else {
+        Literal::Plain(params.pattern.as_str().into())
+    };
+
+    let query = parser::Query {
+        case_sensitive: Some(!params.ignore_case),
+        repo: params.repo.as_deref().map(|r| Literal::Plain(r.into())),
+        path: params.path.as_deref().map(|p| Literal::Plain(p.into())),
+        lang: params
+            .lang
+            .as_deref()
+            .map(|l| languages::parse_alias(l.into())),
+        target: Some(Target::Content(literal)),
+        ..Default::default()
+    };
+
+    let indexer = &indexes.file;
+    let collector = BytesFilterCollector::new(
+        indexer.source.raw_content,
+        move |b| byte_regex.is_match(b),
+        // One more than is scanned, to tell whether there are more
+        (TopDocs::with_limit(MAX_FILES + 1), MultiCollector::new()),
+    );
+
+    let results = indexer
+        .query(std::iter::once(&query), &ContentReader, collector, repos)
+        .await
+        .map_err(Error::internal)?;
+
+    let context = params.context.min(MAX_CONTEXT);
+    let limit = params.limit.clamp(1, MAX_LIMIT);
+
+    // Documents are read as they're scanned, and scanning a thousand files can take a while
+    let (matches, truncated) =
+        tokio::task::spawn_blocking(move || scan(results.docs, &line_regex, context, limit))
+            .await
+            .map_err(Error::internal)?;
+
+    Ok(GrepResponse {
+        count: matches.len(),
+        truncated,
+        matches,
+    })
+}
+
+/// Up to `limit` lines of the first `MAX_FILES` of `docs` that `regex` matches, and whether
+/// there are more
+fn scan(
+    docs: impl Iterator<Item = ContentDocument>,
+    regex: &Regex,
+    context: usize,
+    limit: usize,
+) -> (Vec<GrepMatch>, bool) {
+    let mut matches = vec![];
+
+    for (i, doc) in docs.enumerate() {
+        if i == MAX_FILES {
+            return (matches, true);
+        }
+
+        for line in matching_lines(&doc.content, regex, context) {
+            if matches.len() == limit {
+                return (matches, true);
+            }
+
+            matches.push(GrepMatch {
+                repo_name: doc.repo_name.clone(),
+                repo_ref: doc.repo_ref.clone(),
+                relative_path: doc.relative_path.clone(),
+                ..line
+            });
+        }
+    }
+
+    (matches, false)
+}
+
+/// The lines of `content` that `regex` matches, each with the `context` lines around it, and
+/// without the document they were found in
+fn matching_lines<'a>(
+    content: &'a str,
+    regex: &'a Regex,
+    context: usize,
+) -> impl Iterator<Item = GrepMatch> + 'a {
+    let lines = content.lines().collect::<Vec<_>>();
+
+    (0..lines.len()).filter_map(move |i| {
+        let ranges = regex
+            .find_iter(lines[i])
+            .map(|m| m.range())
+            .collect::<Vec<_>>();
+        if ranges.is_empty() {
+            return None;
+        }
+
+        let owned = |range: Range<usize>| -> Vec<String> {
+            lines[range].iter().map(|l| l.to_string()).collect()
+        };
+        Some(GrepMatch {
+            repo_name: String::new(),
+            repo_ref: String::new(),
+            relative_path: String::new(),
+            line_number: i + 1,
+            text: lines[i].to_owned(),
+            ranges,
+            before: owned(i.saturating_sub(context)..i),
+            after: owned(i + 1..(i + 1 + context).min(lines.len())),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_matched_with_context() {
+        let content = "fn main() {\n    let x = 1;\n    println!(\"{x}\");\n}\n";
+        let regex = Regex::new("x").unwrap();
+
+        let matches = matching_lines(content, &regex, 1).collect::<Vec<_>>();
+        assert_eq!(matches.len(), 2);
+
+        assert_eq!(matches[0].line_number, 2);
+        assert_eq!(matches[0].ranges, vec![8..9]);
+        assert_eq!(matches[0].before, vec!["fn main() {"]);
+        assert_eq!(matches[0].after, vec!["    println!(\"{x}\");"]);
+
+        assert_eq!(matches[1].line_number, 3);
+        assert_eq!(matches[1].after, vec!["}"]);
+
+        let matches = matching_lines(content, &regex, 0).collect::<Vec<_>>();
+        assert!(matches[0].before.is_empty() && matches[0].after.is_empty());
+    }
+
+    #[test]
+    fn scans_are_truncated() {
+        let regex = Regex::new("x").unwrap();
+        let docs = |n: usize| {
+            (0..n).map(|i| ContentDocument {
+                content: "x\nx\n".into(),
+                relative_path: format!("src/{i}.rs"),
+                ..Default::default()
+            })
+        };
+
+        let (matches, truncated) = scan(docs(2), &regex, 0, 4);
+        assert_eq!(matches.len(), 4);
+        assert!(!truncated);
+
+        let (matches, truncated) = scan(docs(3), &regex, 0, 4);
+        assert_eq!(matches.len(), 4);
+        assert!(truncated);
+
+        // Files past the cap might have matched too
+        let (matches, truncated) = scan(docs(MAX_FILES + 1), &regex, 0, MAX_LIMIT);
+        assert_eq!(matches.len(), 2 * MAX_FILES);
+        assert_eq!(matches.last().unwrap().relative_path, "src/999.rs");
+        assert!(truncated);
+    }
+}
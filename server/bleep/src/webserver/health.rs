@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use super::prelude::*;
+use crate::{semantic::LoadState, Application};
+
+use futures::future;
+
+/// Time allowed for each dependency to respond to a readiness check
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Status {
+    Ok,
+    Error,
+    /// The dependency is not configured on this instance
+    Disabled,
+    /// The dependency is still being set up, and what needs it is unavailable until it is
+    Loading,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(super) struct Check {
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl Check {
+    fn ok(message: impl Into<Option<String>>) -> Self {
+        Self {
+            status: Status::Ok,
+            message: message.into(),
+        }
+    }
+
+    fn error(message: impl ToString) -> Self {
+        Self {
+            status: Status::Error,
+            message: Some(message.to_string()),
+        }
+    }
+
+    fn disabled() -> Self {
+        Self {
+            status: Status::Disabled,
+            message: None,
+        }
+    }
+
+    fn loading() -> Self {
+        Self {
+            status: Status::Loading,
+            message: None,
+        }
+    }
+
+    fn is_error(&self) -> bool {
+        matches!(self.status, Status::Error)
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(super) struct Readiness {
+    ready: bool,
+    indexes: Check,
+    qdrant: Check,
+    answer_api: Check,
+}
+
+/// Liveness probe, succeeds as long as the process is able to serve requests
+#[utoipa::path(get, path = "/healthz",
+    responses(
+        (status = 200, description = "The server is up"),
+    ),
+)]
+pub(super) async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Readiness probe, reports the status of every dependency
+///
+/// Fails with `503 Service Unavailable` if any configured dependency can't be reached. Semantic
+/// search loads in the background, and lexical search is served while it's `loading`.
+#[utoipa::path(get, path = "/readyz",
+    responses(
+        (status = 200, description = "The server is ready to accept traffic", body = Readiness),
+        (status = 503, description = "Some dependencies are unavailable", body = Readiness),
+    ),
+)]
+pub(super) async fn readyz(Extension(app): Extension<Application>) -> impl IntoResponse {
+    let (indexes, qdrant, answer_api) = future::join3(
+        check_indexes(&app),
+        check_qdrant(&app),
+        check_answer_api(&app),
+    )
+    .await;
+
+    let ready = ![&indexes, &qdrant, &answer_api]
+        .into_iter()
+        .any(Check::is_error);
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        axum::Json(Readiness {
+            ready,
+            indexes,
+            qdrant,
+            answer_api,
+        }),
+    )
+}
+
+async fn check_indexes(app: &Application) -> Check {
+    let repos = app.indexes.repo.num_docs().await;
+    let files = app.indexes.file.num_docs().await;
+
+    Check::ok(format!("{repos} repositories, {files} files"))
+}
+
+async fn check_qdrant(app: &Application) -> Check {
+    let semantic = match app.semantic.state() {
+        LoadState::Ready(semantic) => semantic,
+        LoadState::Loading => return Check::loading(),
+        LoadState::Failed(err) => return Check::error(err),
+        LoadState::Disabled => return Check::disabled(),
+    };
+
+    match tokio::time::timeout(CHECK_TIMEOUT, semantic.health_check()).await {
+        Ok(Ok(())) => Check::ok(None),
+        Ok(Err(err)) => Check::error(err),
+        Err(_) => Check::error("timed out"),
+    }
+}
+
+async fn check_answer_api(app: &Application) -> Check {
+    // Answers can't be produced without semantic search, so the answer API is not used
+    if matches!(app.semantic.state(), LoadState::Disabled) {
+        return Check::disabled();
+    }
+
+    let client = match crate::proxy::client_builder()
+        .timeout(CHECK_TIMEOUT)
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => return Check::error(err),
+    };
+
+    // Any response means the service is reachable, regardless of the status code
+    match client.get(&app.config.answer_api_url).send().await {
+        Ok(_) => Check::ok(None),
+        Err(err) => Check::error(err),
+    }
+}
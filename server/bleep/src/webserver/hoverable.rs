@@ -34,11 +34,19 @@ pub(super) struct HoverableResponse {
     ),
 )]
 pub(super) async fn handle(
+    _: Scoped<keys::Read>,
+    access: RepoAccess,
     Query(payload): Query<HoverableRequest>,
     Extension(indexes): Extension<Arc<Indexes>>,
 ) -> impl IntoResponse {
     let repo_ref = &payload.repo_ref.parse::<RepoRef>().map_err(Error::user)?;
 
+    if !access.allows(&repo_ref.to_string()) {
+        return Err(
+            Error::new(ErrorKind::NotFound, "file not found").with_code(ErrorCode::FileNotFound)
+        );
+    }
+
     let document = match indexes.file.by_path(repo_ref, &payload.relative_path).await {
         Ok(doc) => doc,
         Err(e) => return Err(Error::user(e)),
@@ -0,0 +1,330 @@
+//! Deduplication of retried requests.
+//!
+//! Clients may send an `Idempotency-Key` header with requests that enqueue expensive work. The
+//! first response for each key is kept for a while, and replayed to any retry carrying the same
+//! key, instead of doing the work again. Retries must send the same body as the original request.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{self, Body, Bytes, HttpBody},
+    extract::State,
+    http::{HeaderMap, HeaderName, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+use dashmap::{mapref::entry::Entry as MapEntry, DashMap};
+use tracing::debug;
+
+use super::{ratelimit, Error, ErrorCode};
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+
+/// How long responses are kept for replay
+const WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Longest accepted idempotency key
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Largest request body accepted along with an idempotency key
+const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Number of stored responses above which expired entries are dropped
+const MAX_ENTRIES: usize = 4096;
+
+#[derive(Default)]
+pub(super) struct IdempotencyCache {
+    entries: DashMap<String, Entry>,
+}
+
+struct Entry {
+    created: Instant,
+    /// Hash of the body of the original request
+    body_hash: blake3::Hash,
+    /// The response of the original request, or `None` while it is still being processed
+    response: Option<StoredResponse>,
+}
+
+/// What is known about an earlier request with the same key
+enum Seen {
+    InProgress,
+    Done(StoredResponse),
+    /// The earlier request had a different body
+    Mismatch,
+}
+
+#[derive(Clone)]
+struct StoredResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl IdempotencyCache {
+    pub(super) fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    /// Claim `key` for a new request, or return what is known about an earlier one.
+    fn claim(&self, key: String, body_hash: blake3::Hash, now: Instant) -> Result<(), Seen> {
+        let entry = Entry {
+            created: now,
+            body_hash,
+            response: None,
+        };
+
+        let result = match self.entries.entry(key) {
+            MapEntry::Occupied(seen) if now.duration_since(seen.get().created) < WINDOW => {
+                let seen = seen.get();
+                Err(match &seen.response {
+                    _ if seen.body_hash != body_hash => Seen::Mismatch,
+                    Some(response) => Seen::Done(response.clone()),
+                    None => Seen::InProgress,
+                })
+            }
+            MapEntry::Occupied(mut seen) => {
+                seen.insert(entry);
+                Ok(())
+            }
+            MapEntry::Vacant(vacant) => {
+                vacant.insert(entry);
+                Ok(())
+            }
+        };
+
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries
+                .retain(|_, e| now.duration_since(e.created) < WINDOW);
+        }
+
+        result
+    }
+
+    fn complete(&self, key: &str, response: StoredResponse) {
+        if let Some(mut entry) = self.entries.get_mut(key) {
+            entry.response = Some(response);
+        }
+    }
+
+    fn release(&self, key: &str) {
+        self.entries.remove(key);
+    }
+}
+
+/// A claimed key, released unless a response is stored for it. Handlers can be dropped before
+/// they respond, when the client disconnects or the request times out, and the request must
+/// then be retriable with the same key.
+struct Claim {
+    cache: Arc<IdempotencyCache>,
+    key: String,
+    completed: bool,
+}
+
+impl Claim {
+    fn complete(mut self, response: StoredResponse) {
+        self.cache.complete(&self.key, response);
+        self.completed = true;
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.cache.release(&self.key);
+        }
+    }
+}
+
+impl StoredResponse {
+    fn replay(self) -> axum::response::Response {
+        let mut response = (self.status, self.headers, self.body).into_response();
+        response
+            .headers_mut()
+            .insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+        response
+    }
+}
+
+/// Replay the stored response of requests whose `Idempotency-Key` has been seen before.
+///
+/// Keys are scoped to the client, method and URI of the request. Requests that fail with a
+/// server error are not stored, so they can be retried with the same key. Reusing a key for a
+/// request with a different body is an error.
+pub(super) async fn deduplicate(
+    State(cache): State<Arc<IdempotencyCache>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> axum::response::Response {
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY) else {
+        return next.run(request).await;
+    };
+
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key,
+        _ => {
+            return Error::user("invalid idempotency key")
+                .with_status(StatusCode::BAD_REQUEST)
+                .into_response()
+        }
+    };
+
+    let key = format!(
+        "{} {} {} {key}",
+        ratelimit::client(&request),
+        request.method(),
+        request.uri(),
+    );
+
+    let (parts, mut body) = request.into_parts();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) if buf.len() + chunk.len() <= MAX_BODY_SIZE => buf.extend_from_slice(&chunk),
+            Ok(_) => {
+                return Error::user("request body is too large")
+                    .with_status(StatusCode::PAYLOAD_TOO_LARGE)
+                    .into_response()
+            }
+            Err(err) => return Error::user(err).into_response(),
+        }
+    }
+
+    let body_hash = blake3::hash(&buf);
+    let request = Request::from_parts(parts, Body::from(buf));
+
+    match cache.claim(key.clone(), body_hash, Instant::now()) {
+        Ok(()) => {}
+        Err(Seen::Done(stored)) => {
+            debug!(%key, "replaying idempotent request");
+            return stored.replay();
+        }
+        Err(Seen::InProgress) => {
+            return Error::user("a request with this idempotency key is still in progress")
+                .with_status(StatusCode::CONFLICT)
+                .with_code(ErrorCode::Conflict)
+                .into_response()
+        }
+        Err(Seen::Mismatch) => {
+            return Error::user("this idempotency key was used for a different request")
+                .with_status(StatusCode::UNPROCESSABLE_ENTITY)
+                .with_code(ErrorCode::IdempotencyKeyReused)
+                .into_response()
+        }
+    }
+
+    let claim = Claim {
+        cache: Arc::clone(&cache),
+        key,
+        completed: false,
+    };
+
+    let response = next.run(request).await;
+    if response.status().is_server_error() {
+        return response;
+    }
+
+    let (parts, mut body) = response.into_parts();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => buf.extend_from_slice(&chunk),
+            Err(err) => return Error::internal(err).into_response(),
+        }
+    }
+
+    let body = Bytes::from(buf);
+    let headers = parts
+        .headers
+        .iter()
+        .filter(|(name, _)| !is_hop_by_hop(name))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+
+    claim.complete(StoredResponse {
+        status: parts.status,
+        headers,
+        body: body.clone(),
+    });
+
+    axum::response::Response::from_parts(parts, body::boxed(body::Full::new(body)))
+}
+
+/// Headers that describe the original connection rather than the response itself
+fn is_hop_by_hop(name: &HeaderName) -> bool {
+    matches!(
+        name.as_str(),
+        "connection" | "content-length" | "transfer-encoding" | "date"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash() -> blake3::Hash {
+        blake3::hash(b"{}")
+    }
+
+    #[test]
+    fn claims_expire() {
+        let cache = IdempotencyCache::new();
+        let start = Instant::now();
+
+        assert!(cache.claim("a".into(), hash(), start).is_ok());
+        assert!(matches!(
+            cache.claim("a".into(), hash(), start),
+            Err(Seen::InProgress)
+        ));
+        assert!(cache.claim("b".into(), hash(), start).is_ok());
+
+        cache.complete(
+            "a",
+            StoredResponse {
+                status: StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: Bytes::from_static(b"done"),
+            },
+        );
+        assert!(matches!(
+            cache.claim("a".into(), hash(), start + Duration::from_secs(1)),
+            Err(Seen::Done(StoredResponse { body, .. })) if body == "done"
+        ));
+
+        // Failed requests can be retried
+        cache.release("b");
+        assert!(cache.claim("b".into(), hash(), start).is_ok());
+
+        // Keys can be reused once the window has passed
+        assert!(cache.claim("a".into(), hash(), start + WINDOW).is_ok());
+    }
+
+    #[test]
+    fn keys_are_bound_to_bodies() {
+        let cache = IdempotencyCache::new();
+        let start = Instant::now();
+
+        assert!(cache.claim("a".into(), hash(), start).is_ok());
+        assert!(matches!(
+            cache.claim("a".into(), blake3::hash(b"[]"), start),
+            Err(Seen::Mismatch)
+        ));
+    }
+
+    #[test]
+    fn abandoned_claims_are_released() {
+        let cache = IdempotencyCache::new();
+        let start = Instant::now();
+
+        assert!(cache.claim("a".into(), hash(), start).is_ok());
+        drop(Claim {
+            cache: Arc::clone(&cache),
+            key: "a".into(),
+            completed: false,
+        });
+        assert!(cache.claim("a".into(), hash(), start).is_ok());
+    }
+}
@@ -1,7 +1,58 @@
-use crate::Application;
+use super::{
+    access::RepoAccess,
+    keys::{self, Scoped},
+    Error, ErrorCode, Result,
+};
+use crate::{
+    bundle::{Manifest, Staging, Unpacked},
+    Application,
+};
 
-use axum::{response::IntoResponse, Extension};
+use axum::{extract::BodyStream, http::StatusCode, response::IntoResponse, Extension, Json};
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
 
-pub(super) async fn handle(Extension(app): Extension<Application>) -> impl IntoResponse {
-    tokio::task::spawn(app.write_index().startup_scan());
+pub(super) async fn handle(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
+    Extension(app): Extension<Application>,
+) -> impl IntoResponse {
+    let repos = app
+        .repo_pool
+        .iter()
+        .filter(|elem| access.allows(&elem.key().to_string()))
+        .map(|elem| elem.key().clone())
+        .collect();
+    app.write_index().queue_sync_and_index(repos);
+}
+
+/// Install a bundle written by `bleep export`, sent as the request body, replacing the indexes
+/// and embeddings of its repository if it's indexed already
+pub(super) async fn import(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
+    Extension(app): Extension<Application>,
+    mut body: BodyStream,
+) -> Result<Json<Manifest>> {
+    let upload = Staging::new(&app.config)?;
+    let path = upload.join("bundle.bloop");
+
+    let mut file = tokio::fs::File::create(&path)
+        .await
+        .map_err(Error::internal)?;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(Error::user)?;
+        file.write_all(&chunk).await.map_err(Error::internal)?;
+    }
+    file.flush().await.map_err(Error::internal)?;
+
+    // Bundles that can't be installed here are the client's to fix
+    let unpacked = Unpacked::open(&app, &path).await.map_err(Error::user)?;
+    if !access.allows(&unpacked.manifest().repo_ref.to_string()) {
+        return Err(Error::user("repository is not accessible")
+            .with_status(StatusCode::FORBIDDEN)
+            .with_code(ErrorCode::Forbidden));
+    }
+
+    Ok(Json(unpacked.install(&app).await?))
 }
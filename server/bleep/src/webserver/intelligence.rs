@@ -51,10 +51,10 @@ pub(super) enum TokenInfoResponse {
 pub(super) struct FileSymbols {
     // FIXME: choose a better name
     /// The file to which the following occurrences belong
-    file: String,
+    pub(super) file: String,
 
     /// A collection of symbol locations with context in this file
-    data: Vec<SymbolOccurrence>,
+    pub(super) data: Vec<SymbolOccurrence>,
 }
 
 impl FileSymbols {
@@ -228,14 +228,41 @@ fn merge(
     ),
 )]
 pub(super) async fn handle(
+    _: Scoped<keys::Read>,
+    access: RepoAccess,
     Query(payload): Query<TokenInfoRequest>,
     Extension(indexes): Extension<Arc<Indexes>>,
 ) -> Result<impl IntoResponse> {
     let repo_ref = &payload.repo_ref.parse::<RepoRef>().map_err(Error::user)?;
 
+    if !access.allows(&repo_ref.to_string()) {
+        return Err(
+            Error::new(ErrorKind::NotFound, "file not found").with_code(ErrorCode::FileNotFound)
+        );
+    }
+
+    token_info(
+        &indexes,
+        repo_ref,
+        &payload.relative_path,
+        payload.start,
+        payload.end,
+    )
+    .await
+    .map(json)
+}
+
+/// The definitions and references of the token at the byte range `start..end` of a file
+pub(super) async fn token_info(
+    indexes: &Indexes,
+    repo_ref: &RepoRef,
+    relative_path: &str,
+    start: usize,
+    end: usize,
+) -> Result<TokenInfoResponse> {
     let content = indexes
         .file
-        .by_path(repo_ref, &payload.relative_path)
+        .by_path(repo_ref, relative_path)
         .await
         .map_err(Error::user)?;
 
@@ -244,7 +271,7 @@ pub(super) async fn handle(
         _ => return Err(Error::user("Intelligence is unavailable for this language")),
     };
 
-    let node = scope_graph.node_by_range(payload.start, payload.end);
+    let node = scope_graph.node_by_range(start, end);
 
     let idx = match node {
         None => return Err(Error::user("provided range is not a valid token")),
@@ -277,7 +304,7 @@ pub(super) async fn handle(
             // merge the two
             let references = merge([local_references], repo_wide_references);
 
-            Ok(json(TokenInfoResponse::Definition { references }))
+            Ok(TokenInfoResponse::Definition { references })
         }
 
         // we are at a reference:
@@ -302,10 +329,10 @@ pub(super) async fn handle(
             let definitions = merge([local_definitions], repo_wide_definitions);
             let references = merge([local_references], repo_wide_references);
 
-            Ok(json(TokenInfoResponse::Reference {
+            Ok(TokenInfoResponse::Reference {
                 definitions,
                 references,
-            }))
+            })
         }
         _ => Err(Error::user(
             "provided range is not eligible for intelligence",
@@ -0,0 +1,220 @@
+//! Static API key authentication.
+//!
+//! Keys are never stored in plain text. Both the configuration file and the local key store
+//! only hold the hex-encoded blake3 hash of a key, together with the scopes it grants.
+//!
+//! When no keys are configured, every request is granted all scopes, which preserves the
+//! behaviour of local installations.
+
+use std::{marker::PhantomData, sync::Arc};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequestParts, State},
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use super::{Error, ErrorCode, ErrorKind};
+use crate::Application;
+
+pub(super) const API_KEY_HEADER: &str = "x-api-key";
+
+/// The permissions a key can be granted
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Search, browse files and use code navigation
+    Read,
+    /// Use the answer API
+    Answer,
+    /// Manage repositories and indexes. Implies every other scope.
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ApiKey {
+    /// Human-readable identifier of this key, used for logging
+    pub name: String,
+
+    /// Hex-encoded blake3 hash of the key
+    pub hash: String,
+
+    /// Scopes granted to this key
+    #[serde(default)]
+    pub scopes: Vec<Scope>,
+
+    /// Patterns of the repositories this key can access. All repositories are accessible if
+    /// this is not set.
+    #[serde(default)]
+    pub repos: Option<Vec<String>>,
+
+    /// Name of the tenant this key belongs to. Keys without a tenant can access the data of
+    /// every tenant.
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+impl ApiKey {
+    fn matches(&self, key: &str) -> bool {
+        blake3::hash(key.as_bytes())
+            .to_hex()
+            .eq_ignore_ascii_case(self.hash.trim())
+    }
+
+    fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+}
+
+/// The permissions attached to a request after authentication
+#[derive(Clone, Debug)]
+pub(crate) enum Grant {
+    /// No keys are configured, everything is allowed
+    Unrestricted,
+    /// The request presented a valid key
+    Key(Arc<ApiKey>),
+}
+
+impl Grant {
+    pub(crate) fn allows(&self, scope: Scope) -> bool {
+        match self {
+            Grant::Unrestricted => true,
+            Grant::Key(key) => key.allows(scope),
+        }
+    }
+}
+
+/// Look up the key presented in the request headers.
+///
+/// The `X-Api-Key` header takes precedence over an `Authorization: Bearer` token.
+fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(API_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| {
+            headers
+                .get(AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+        })
+        .map(str::trim)
+}
+
+pub(super) async fn authenticate<B>(
+    State(app): State<Application>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> axum::response::Response {
+    let grant = if app.api_keys.is_empty() {
+        Grant::Unrestricted
+    } else {
+        let Some(presented) = presented_key(request.headers()) else {
+            return Error::user("missing API key")
+                .with_status(StatusCode::UNAUTHORIZED)
+                .with_code(ErrorCode::Unauthenticated)
+                .into_response();
+        };
+
+        match app.api_keys.iter().find(|key| key.matches(presented)) {
+            Some(key) => {
+                debug!(key = key.name, "authenticated API key");
+                Grant::Key(Arc::new(key.clone()))
+            }
+            None => {
+                warn!("request presented an unknown API key");
+                return Error::user("invalid API key")
+                    .with_status(StatusCode::UNAUTHORIZED)
+                    .with_code(ErrorCode::Unauthenticated)
+                    .into_response();
+            }
+        }
+    };
+
+    request.extensions_mut().insert(grant);
+    next.run(request).await
+}
+
+/// Marker types for the scopes that can be required by an endpoint
+pub(super) trait RequiredScope {
+    const SCOPE: Scope;
+}
+
+pub(super) struct Read;
+pub(super) struct Answer;
+pub(super) struct Admin;
+
+impl RequiredScope for Read {
+    const SCOPE: Scope = Scope::Read;
+}
+
+impl RequiredScope for Answer {
+    const SCOPE: Scope = Scope::Answer;
+}
+
+impl RequiredScope for Admin {
+    const SCOPE: Scope = Scope::Admin;
+}
+
+/// Extractor that rejects requests whose key was not granted the scope `S`
+pub(super) struct Scoped<S>(PhantomData<fn() -> S>);
+
+#[async_trait]
+impl<S, St> FromRequestParts<St> for Scoped<S>
+where
+    S: RequiredScope,
+    St: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &St) -> Result<Self, Self::Rejection> {
+        match parts.extensions.get::<Grant>() {
+            Some(grant) if grant.allows(S::SCOPE) => Ok(Scoped(PhantomData)),
+            Some(_) => Err(
+                Error::new(ErrorKind::User, "API key lacks the required scope")
+                    .with_status(StatusCode::FORBIDDEN)
+                    .with_code(ErrorCode::Forbidden),
+            ),
+            None => Err(Error::user("missing API key")
+                .with_status(StatusCode::UNAUTHORIZED)
+                .with_code(ErrorCode::Unauthenticated)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(scopes: Vec<Scope>) -> ApiKey {
+        ApiKey {
+            name: "test".into(),
+            hash: blake3::hash(b"secret").to_hex().to_string(),
+            scopes,
+            repos: None,
+            tenant: None,
+        }
+    }
+
+    #[test]
+    fn matches_hashed_key() {
+        let key = key(vec![Scope::Read]);
+        assert!(key.matches("secret"));
+        assert!(!key.matches("not the secret"));
+    }
+
+    #[test]
+    fn admin_implies_all_scopes() {
+        let admin = key(vec![Scope::Admin]);
+        assert!(admin.allows(Scope::Read));
+        assert!(admin.allows(Scope::Answer));
+
+        let read = key(vec![Scope::Read]);
+        assert!(read.allows(Scope::Read));
+        assert!(!read.allows(Scope::Answer));
+        assert!(!read.allows(Scope::Admin));
+    }
+}
@@ -0,0 +1,138 @@
+//! Limits that stop a single client or request from monopolizing the server.
+
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    body::{boxed, HttpBody},
+    error_handling::HandleErrorLayer,
+    extract::{DefaultBodyLimit, State},
+    http::{Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    BoxError, Router,
+};
+use tokio::sync::Semaphore;
+use tower::{timeout::error::Elapsed, ServiceBuilder};
+use tracing::warn;
+
+use super::{Error, ErrorCode, ErrorKind};
+
+/// Fail requests that don't start responding within `secs` seconds.
+pub(super) fn timeout<S>(router: Router<S>, secs: Option<u64>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let Some(secs) = secs.filter(|&s| s > 0) else {
+        return router;
+    };
+
+    router.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(handle_error))
+            .timeout(Duration::from_secs(secs)),
+    )
+}
+
+/// Reject requests with `503 Service Unavailable` while `max` requests to any of the routes of
+/// `router` are in flight.
+pub(super) fn concurrency<S>(router: Router<S>, max: Option<usize>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let Some(max) = max.filter(|&m| m > 0) else {
+        return router;
+    };
+
+    // Layers wrap every route separately, so the routes share the permits through the state
+    router.layer(middleware::from_fn_with_state(
+        Arc::new(Semaphore::new(max)),
+        admit,
+    ))
+}
+
+async fn admit<B>(
+    State(permits): State<Arc<Semaphore>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Ok(permit) = permits.try_acquire_owned() else {
+        return Error::new(ErrorKind::Internal, "too many concurrent requests")
+            .with_status(StatusCode::SERVICE_UNAVAILABLE)
+            .with_code(ErrorCode::ServerOverloaded)
+            .into_response();
+    };
+
+    // Held until the response body has been sent, so streamed responses count while they stream
+    next.run(request).await.map(|body| {
+        boxed(body.map_data(move |data| {
+            let _permit = &permit;
+            data
+        }))
+    })
+}
+
+/// Reject request bodies larger than `max` bytes.
+pub(super) fn body_size<S>(router: Router<S>, max: Option<usize>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    match max {
+        Some(max) => router.layer(DefaultBodyLimit::max(max)),
+        None => router,
+    }
+}
+
+async fn handle_error(err: BoxError) -> Error {
+    if err.is::<Elapsed>() {
+        warn!("request timed out");
+        return Error::new(ErrorKind::Internal, "request timed out")
+            .with_status(StatusCode::GATEWAY_TIMEOUT)
+            .with_code(ErrorCode::Timeout);
+    }
+
+    Error::internal(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::get};
+    use tokio::sync::Notify;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn routes_share_the_concurrency_limit() {
+        let release = Arc::new(Notify::new());
+        let router = concurrency(
+            Router::new()
+                .route(
+                    "/slow",
+                    get({
+                        let release = Arc::clone(&release);
+                        move || {
+                            let release = Arc::clone(&release);
+                            async move { release.notified().await }
+                        }
+                    }),
+                )
+                .route("/fast", get(|| async {})),
+            Some(1),
+        );
+        let get = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        let mut slow = Box::pin(router.clone().oneshot(get("/slow")));
+        assert!(futures::poll!(&mut slow).is_pending());
+
+        let fast = router.clone().oneshot(get("/fast")).await.unwrap();
+        assert_eq!(fast.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        release.notify_one();
+        let slow = slow.await.unwrap();
+        assert_eq!(slow.status(), StatusCode::OK);
+        drop(slow);
+
+        let fast = router.oneshot(get("/fast")).await.unwrap();
+        assert_eq!(fast.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,191 @@
+//! Addresses the webserver accepts connections on.
+
+use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use tracing::info;
+
+use crate::Configuration;
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// Longest pause after failing to accept a connection, such as when out of file descriptors
+#[cfg(unix)]
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Listener {
+    Tcp(SocketAddr),
+    /// A unix domain socket, for use behind a local reverse proxy
+    Unix(PathBuf),
+}
+
+impl Listener {
+    /// Listeners configured with `listen`, or `host` and `port` if none are given.
+    pub(super) fn from_config(config: &Configuration) -> anyhow::Result<Vec<Self>> {
+        if config.listen.is_empty() {
+            let addr = SocketAddr::new(config.host.parse()?, config.port);
+            return Ok(vec![Listener::Tcp(addr)]);
+        }
+
+        config.listen.iter().map(|l| l.parse()).collect()
+    }
+
+    /// Bind this listener, so that connections are queued until it's served
+    pub(super) fn bind(self) -> anyhow::Result<Bound> {
+        info!(listener = %self, "binding webserver");
+
+        match self {
+            Listener::Tcp(addr) => {
+                let listener = std::net::TcpListener::bind(addr)
+                    .with_context(|| format!("failed to bind {addr}"))?;
+                listener.set_nonblocking(true)?;
+                Ok(Bound::Tcp(listener))
+            }
+
+            #[cfg(unix)]
+            Listener::Unix(path) => {
+                // A socket left behind by a previous run would prevent binding
+                match std::fs::remove_file(&path) {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(err) => {
+                        return Err(err)
+                            .with_context(|| format!("failed to remove {}", path.display()))
+                    }
+                }
+
+                let listener = tokio::net::UnixListener::bind(&path)
+                    .with_context(|| format!("failed to bind {}", path.display()))?;
+                Ok(Bound::Unix(listener))
+            }
+
+            #[cfg(not(unix))]
+            Listener::Unix(_) => {
+                anyhow::bail!("unix sockets are not supported on this platform")
+            }
+        }
+    }
+}
+
+/// A listener that's bound, but not yet accepting connections
+pub(super) enum Bound {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Bound {
+    /// Serve `router` on this listener. TCP listeners use TLS if it is configured.
+    pub(super) async fn serve(
+        self,
+        router: axum::Router,
+        tls: Option<RustlsConfig>,
+    ) -> anyhow::Result<()> {
+        match (self, tls) {
+            (Bound::Tcp(listener), Some(tls)) => {
+                axum_server::from_tcp_rustls(listener, tls)
+                    .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+                    .await?
+            }
+
+            (Bound::Tcp(listener), None) => {
+                axum::Server::from_tcp(listener)?
+                    .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+                    .await?
+            }
+
+            #[cfg(unix)]
+            (Bound::Unix(listener), _) => {
+                let incoming = futures::stream::unfold(listener, |listener| async move {
+                    let stream = accept(&listener).await;
+                    Some((Ok::<_, std::io::Error>(stream), listener))
+                });
+
+                axum::Server::builder(hyper::server::accept::from_stream(incoming))
+                    .serve(router.into_make_service())
+                    .await?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Accept the next connection on a unix socket. Failing to accept a connection doesn't stop the
+/// server: connections that failed while queued are skipped, and other errors, which are
+/// usually transient, are retried after a pause.
+#[cfg(unix)]
+async fn accept(listener: &tokio::net::UnixListener) -> tokio::net::UnixStream {
+    use std::io::ErrorKind;
+    use tracing::{debug, warn};
+
+    let mut backoff = Duration::from_millis(5);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => return stream,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset
+                ) =>
+            {
+                debug!(?err, "connection failed before it was accepted");
+            }
+            Err(err) => {
+                warn!(?err, ?backoff, "failed to accept a connection");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+        }
+    }
+}
+
+impl FromStr for Listener {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => anyhow::bail!("missing unix socket path"),
+            Some(path) => Ok(Listener::Unix(path.into())),
+            None => s
+                .parse()
+                .map(Listener::Tcp)
+                .with_context(|| format!("invalid listen address `{s}`")),
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(addr) => write!(f, "{addr}"),
+            Listener::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "127.0.0.1:7878".parse::<Listener>().unwrap(),
+            Listener::Tcp(SocketAddr::from(([127, 0, 0, 1], 7878)))
+        );
+        assert_eq!(
+            "[::1]:80".parse::<Listener>().unwrap().to_string(),
+            "[::1]:80"
+        );
+        assert_eq!(
+            "unix:/run/bleep.sock".parse::<Listener>().unwrap(),
+            Listener::Unix("/run/bleep.sock".into())
+        );
+
+        assert!("unix:".parse::<Listener>().is_err());
+        assert!("localhost".parse::<Listener>().is_err());
+    }
+}
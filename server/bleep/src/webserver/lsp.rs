@@ -0,0 +1,532 @@
+//! The Language Server Protocol, served over stdin and stdout by `bleep lsp`.
+//!
+//! Editors get the same code navigation and answers as the HTTP API, without a plugin of their
+//! own: workspace symbols, go-to-definition and references are answered from the indexes, and
+//! the custom `bleep/explainSelection` request asks the answer pipeline about a selection.
+//!
+//! Files are looked up by their path, in whichever indexed repository they're in. Positions
+//! are in bytes for clients that offer `utf-8` positions, and in UTF-16 code units, as the
+//! protocol has them by default, for the rest.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tantivy::collector::{MultiCollector, TopDocs};
+use tower_lsp::{
+    jsonrpc,
+    lsp_types::{
+        GotoDefinitionParams, GotoDefinitionResponse, InitializeParams, InitializeResult,
+        InitializedParams, Location, MessageType, OneOf, Position, PositionEncodingKind, Range,
+        ReferenceParams, ServerCapabilities, ServerInfo, SymbolInformation, SymbolKind,
+        TextDocumentIdentifier, TextDocumentPositionParams, Url, WorkspaceSymbolParams,
+    },
+    Client, LanguageServer, LspService, Server,
+};
+use tracing::info;
+
+use super::{
+    access::RepoAccess,
+    answer::{self, AnswerState, BatchAnswer},
+    intelligence::{self, FileSymbols, TokenInfoResponse},
+};
+use crate::{
+    indexes::reader::{ContentDocument, ContentReader},
+    query::parser,
+    repo::RepoRef,
+    text_range::{Point, TextRange},
+    Application,
+};
+
+/// Most workspace symbols returned for a query
+const MAX_SYMBOLS: usize = 100;
+
+/// Files searched for workspace symbols
+const SYMBOL_FILES: usize = 50;
+
+/// Longest selection sent to the answer pipeline, in bytes
+const MAX_SELECTION: usize = 2000;
+
+/// Serve the Language Server Protocol over stdin and stdout, until the client exits
+pub async fn serve(app: Application) -> anyhow::Result<()> {
+    let answers = AnswerState::new(&app.config)?;
+
+    let (service, socket) = LspService::build(|client| Backend {
+        client,
+        app,
+        answers,
+        utf8: AtomicBool::new(false),
+    })
+    .custom_method("bleep/explainSelection", Backend::explain_selection)
+    .finish();
+
+    info!("serving the language server protocol over stdio");
+    Server::new(tokio::io::stdin(), tokio::io::stdout(), socket)
+        .serve(service)
+        .await;
+
+    Ok(())
+}
+
+struct Backend {
+    client: Client,
+    app: Application,
+    answers: AnswerState,
+    /// Whether the client counts the characters of positions in bytes, rather than UTF-16 code
+    /// units
+    utf8: AtomicBool,
+}
+
+/// A file of an indexed repository
+struct IndexedFile {
+    repo_ref: RepoRef,
+    disk_path: PathBuf,
+    relative_path: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExplainSelectionParams {
+    text_document: TextDocumentIdentifier,
+    range: Range,
+    /// Asked about the selection instead of what it does
+    #[serde(default)]
+    question: Option<String>,
+}
+
+#[async_trait]
+impl LanguageServer for Backend {
+    async fn initialize(&self, params: InitializeParams) -> jsonrpc::Result<InitializeResult> {
+        let utf8 = params
+            .capabilities
+            .general
+            .and_then(|general| general.position_encodings)
+            .map_or(false, |encodings| {
+                encodings.contains(&PositionEncodingKind::UTF8)
+            });
+        self.utf8.store(utf8, Ordering::Relaxed);
+
+        Ok(InitializeResult {
+            capabilities: ServerCapabilities {
+                position_encoding: utf8.then_some(PositionEncodingKind::UTF8),
+                workspace_symbol_provider: Some(OneOf::Left(true)),
+                definition_provider: Some(OneOf::Left(true)),
+                references_provider: Some(OneOf::Left(true)),
+                ..ServerCapabilities::default()
+            },
+            server_info: Some(ServerInfo {
+                name: "bleep".to_owned(),
+                version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            }),
+        })
+    }
+
+    async fn initialized(&self, _: InitializedParams) {
+        let repos = self.app.repo_pool.len();
+        self.client
+            .log_message(
+                MessageType::INFO,
+                format!("bleep: {repos} repositories indexed"),
+            )
+            .await;
+    }
+
+    async fn shutdown(&self) -> jsonrpc::Result<()> {
+        Ok(())
+    }
+
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> jsonrpc::Result<Option<Vec<SymbolInformation>>> {
+        let query = params.query.trim();
+        if query.is_empty() {
+            return Ok(None);
+        }
+
+        let query_str = format!("symbol:{query}");
+        let Ok(queries) = parser::parse(&query_str) else {
+            return Ok(None);
+        };
+
+        let collector = (TopDocs::with_limit(SYMBOL_FILES), MultiCollector::new());
+        let results = self
+            .app
+            .indexes
+            .file
+            .query(queries.iter(), &ContentReader, collector, None)
+            .await
+            .map_err(internal)?;
+
+        let needle = query.to_lowercase();
+        let utf8 = self.utf8();
+        let symbols = results
+            .docs
+            .filter_map(|doc| {
+                let disk_path = self.disk_path(&doc.repo_ref)?;
+                Some((disk_path, doc))
+            })
+            .flat_map(|(disk_path, doc)| {
+                doc.symbol_locations
+                    .list()
+                    .into_iter()
+                    .filter_map(|symbol| {
+                        let name = doc
+                            .content
+                            .get(symbol.range.start.byte..symbol.range.end.byte)?;
+                        if !name.to_lowercase().contains(&needle) {
+                            return None;
+                        }
+
+                        #[allow(deprecated)]
+                        Some(SymbolInformation {
+                            name: name.to_owned(),
+                            kind: symbol_kind(&symbol.kind),
+                            tags: None,
+                            deprecated: None,
+                            location: location(
+                                &disk_path,
+                                &doc.relative_path,
+                                &doc.content,
+                                symbol.range,
+                                utf8,
+                            )?,
+                            container_name: Some(doc.relative_path.clone()),
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .take(MAX_SYMBOLS)
+            .collect();
+
+        Ok(Some(symbols))
+    }
+
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> jsonrpc::Result<Option<GotoDefinitionResponse>> {
+        let Some((file, info)) = self
+            .token_info(params.text_document_position_params)
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let definitions = match info {
+            TokenInfoResponse::Reference { definitions, .. } => definitions,
+            // Already at the definition
+            TokenInfoResponse::Definition { .. } => return Ok(None),
+        };
+
+        Ok(Some(GotoDefinitionResponse::Array(
+            self.locations(&file, definitions).await,
+        )))
+    }
+
+    async fn references(&self, params: ReferenceParams) -> jsonrpc::Result<Option<Vec<Location>>> {
+        let include_declaration = params.context.include_declaration;
+        let Some((file, info)) = self.token_info(params.text_document_position).await? else {
+            return Ok(None);
+        };
+
+        let symbols = match info {
+            TokenInfoResponse::Definition { references } => references,
+            TokenInfoResponse::Reference {
+                definitions,
+                references,
+            } if include_declaration => definitions.into_iter().chain(references).collect(),
+            TokenInfoResponse::Reference { references, .. } => references,
+        };
+
+        Ok(Some(self.locations(&file, symbols).await))
+    }
+}
+
+impl Backend {
+    /// Answer a question about the selected code, with the repository it's in as context
+    async fn explain_selection(
+        &self,
+        params: ExplainSelectionParams,
+    ) -> jsonrpc::Result<BatchAnswer> {
+        let file = self
+            .indexed_file(&params.text_document.uri)
+            .ok_or_else(|| {
+                jsonrpc::Error::invalid_params("file is not in an indexed repository")
+            })?;
+        let doc = self.document(&file).await?;
+
+        let utf8 = self.utf8();
+        let selection = byte_offset(&doc, params.range.start, utf8)
+            .zip(byte_offset(&doc, params.range.end, utf8))
+            .and_then(|(start, end)| doc.content.get(start..end))
+            .map(str::trim)
+            .filter(|selection| !selection.is_empty())
+            .ok_or_else(|| jsonrpc::Error::invalid_params("the selection is empty"))?;
+
+        let selection = truncate(selection, MAX_SELECTION);
+        let question = params
+            .question
+            .unwrap_or_else(|| "What does this code do?".to_owned());
+
+        let params = answer::Params {
+            q: format!("{question} In {}:\n{selection}", file.relative_path),
+            thread_id: uuid::Uuid::new_v4().to_string(),
+            limit: answer::default_limit(),
+            user_id: answer::default_user_id(),
+            fields: Default::default(),
+            answer_lang: None,
+            access: RepoAccess::only(&file.repo_ref.to_string()),
+            llm_permits: None,
+        };
+
+        let answer = answer::answer_one(&self.answers, params, &self.app, None).await;
+        match answer.error {
+            Some(error) => Err(internal(error)),
+            None => Ok(answer),
+        }
+    }
+
+    /// The definitions and references of the token at a position, if there is one
+    async fn token_info(
+        &self,
+        position: TextDocumentPositionParams,
+    ) -> jsonrpc::Result<Option<(IndexedFile, TokenInfoResponse)>> {
+        let Some(file) = self.indexed_file(&position.text_document.uri) else {
+            return Ok(None);
+        };
+
+        let doc = self.document(&file).await?;
+        let Some(offset) = byte_offset(&doc, position.position, self.utf8()) else {
+            return Ok(None);
+        };
+
+        // Positions that aren't on a symbol, or in a language without scope graphs, have no
+        // definitions or references
+        match intelligence::token_info(
+            &self.app.indexes,
+            &file.repo_ref,
+            &file.relative_path,
+            offset,
+            offset,
+        )
+        .await
+        {
+            Ok(info) => Ok(Some((file, info))),
+            Err(_) => Ok(None),
+        }
+    }
+
+    async fn document(&self, file: &IndexedFile) -> jsonrpc::Result<ContentDocument> {
+        self.app
+            .indexes
+            .file
+            .by_path(&file.repo_ref, &file.relative_path)
+            .await
+            .map_err(|err| jsonrpc::Error::invalid_params(err.to_string()))
+    }
+
+    /// The indexed repository `uri` is in, and its path in it. If repositories are nested, the
+    /// innermost one wins.
+    fn indexed_file(&self, uri: &Url) -> Option<IndexedFile> {
+        let path = uri.to_file_path().ok()?;
+        let path = crate::canonicalize(&path).unwrap_or(path);
+
+        self.app
+            .repo_pool
+            .iter()
+            .filter_map(|repo| {
+                let relative_path = path.strip_prefix(&repo.disk_path).ok()?;
+                Some(IndexedFile {
+                    repo_ref: repo.key().clone(),
+                    disk_path: repo.disk_path.clone(),
+                    relative_path: relative_path.to_string_lossy().into_owned(),
+                })
+            })
+            .max_by_key(|file| file.disk_path.components().count())
+    }
+
+    /// Where the occurrences of symbols in the repository of `file` are
+    async fn locations(&self, file: &IndexedFile, symbols: Vec<FileSymbols>) -> Vec<Location> {
+        let utf8 = self.utf8();
+        let mut contents = HashMap::new();
+        let mut locations = vec![];
+
+        for symbols in symbols {
+            // Columns are counted in bytes already, so files are only read to count them in
+            // UTF-16 code units
+            if !utf8 && !contents.contains_key(&symbols.file) {
+                let content = self
+                    .app
+                    .indexes
+                    .file
+                    .by_path(&file.repo_ref, &symbols.file)
+                    .await
+                    .map(|doc| doc.content)
+                    .unwrap_or_default();
+                contents.insert(symbols.file.clone(), content);
+            }
+
+            let content = contents.get(&symbols.file).map_or("", String::as_str);
+            locations.extend(symbols.data.into_iter().filter_map(|occurrence| {
+                location(
+                    &file.disk_path,
+                    &symbols.file,
+                    content,
+                    occurrence.range,
+                    utf8,
+                )
+            }));
+        }
+
+        locations
+    }
+
+    fn utf8(&self) -> bool {
+        self.utf8.load(Ordering::Relaxed)
+    }
+
+    fn disk_path(&self, repo_ref: &str) -> Option<PathBuf> {
+        let repo_ref = repo_ref.parse::<RepoRef>().ok()?;
+        Some(self.app.repo_pool.get(&repo_ref)?.disk_path.clone())
+    }
+}
+
+/// The byte offset of `position` in `doc`, with its character counted in bytes if `utf8` is
+/// set, or else in UTF-16 code units
+fn byte_offset(doc: &ContentDocument, position: Position, utf8: bool) -> Option<usize> {
+    let line = position.line as usize;
+    let line_start = match line {
+        0 => 0,
+        line => *doc.line_end_indices.get(line - 1)? as usize + 1,
+    };
+
+    let character = position.character as usize;
+    let column = if utf8 {
+        character
+    } else {
+        let line_end = doc
+            .line_end_indices
+            .get(line)
+            .map_or(doc.content.len(), |&end| end as usize);
+        utf16_to_byte(doc.content.get(line_start..line_end)?, character)
+    };
+
+    Some((line_start + column).min(doc.content.len()))
+}
+
+/// The byte offset in `line` of the character that starts `units` UTF-16 code units into it
+fn utf16_to_byte(line: &str, units: usize) -> usize {
+    let mut counted = 0;
+    for (offset, c) in line.char_indices() {
+        if counted >= units {
+            return offset;
+        }
+        counted += c.len_utf16();
+    }
+
+    line.len()
+}
+
+fn truncate(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    &text[..end]
+}
+
+/// The position of `point` in `content`, with its character counted as in [`byte_offset`]
+fn position(content: &str, point: Point, utf8: bool) -> Position {
+    let character = match content.get(point.byte.saturating_sub(point.column)..point.byte) {
+        Some(line) if !utf8 => line.encode_utf16().count(),
+        _ => point.column,
+    };
+
+    Position::new(point.line as u32, character as u32)
+}
+
+fn location(
+    disk_path: &Path,
+    relative_path: &str,
+    content: &str,
+    range: TextRange,
+    utf8: bool,
+) -> Option<Location> {
+    Some(Location::new(
+        Url::from_file_path(disk_path.join(relative_path)).ok()?,
+        Range::new(
+            position(content, range.start, utf8),
+            position(content, range.end, utf8),
+        ),
+    ))
+}
+
+/// The kind of a symbol, named as in the scope queries of each language
+fn symbol_kind(kind: &str) -> SymbolKind {
+    match kind {
+        "function" | "func" | "macro" => SymbolKind::FUNCTION,
+        "method" => SymbolKind::METHOD,
+        "class" => SymbolKind::CLASS,
+        "struct" => SymbolKind::STRUCT,
+        "enum" => SymbolKind::ENUM,
+        "enumerator" | "variant" => SymbolKind::ENUM_MEMBER,
+        "interface" | "trait" => SymbolKind::INTERFACE,
+        "module" | "namespace" => SymbolKind::MODULE,
+        "const" | "constant" => SymbolKind::CONSTANT,
+        "field" | "property" => SymbolKind::FIELD,
+        "type" | "typedef" | "alias" => SymbolKind::TYPE_PARAMETER,
+        _ => SymbolKind::VARIABLE,
+    }
+}
+
+fn internal(err: impl ToString) -> jsonrpc::Error {
+    jsonrpc::Error {
+        code: jsonrpc::ErrorCode::InternalError,
+        message: err.to_string(),
+        data: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_on_char_boundaries() {
+        assert_eq!(truncate("hello", 10), "hello");
+        assert_eq!(truncate("hello", 3), "hel");
+        assert_eq!(truncate("héllo", 2), "h");
+    }
+
+    #[test]
+    fn utf16_positions_are_converted() {
+        // "é" is 2 bytes and 1 code unit, "😀" is 4 bytes and 2 code units
+        let content = "let x = \"é😀\";\ny";
+        let line = "let x = \"é😀\";";
+
+        assert_eq!(utf16_to_byte(line, 9), 9);
+        assert_eq!(utf16_to_byte(line, 10), 11);
+        assert_eq!(utf16_to_byte(line, 12), 15);
+        assert_eq!(utf16_to_byte(line, 100), line.len());
+
+        let point = |byte, line, column| Point { byte, line, column };
+        assert_eq!(
+            position(content, point(15, 0, 15), false),
+            Position::new(0, 12)
+        );
+        assert_eq!(
+            position(content, point(15, 0, 15), true),
+            Position::new(0, 15)
+        );
+        assert_eq!(
+            position(content, point(18, 1, 0), false),
+            Position::new(1, 0)
+        );
+    }
+}
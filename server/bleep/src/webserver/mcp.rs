@@ -0,0 +1,433 @@
+//! The Model Context Protocol, so that LLM agents and IDE assistants can search, read and ask
+//! about indexed code as tools.
+//!
+//! Messages are JSON-RPC, served over stdin and stdout by `bleep mcp`, one per line, and over
+//! HTTP at `/api/mcp`, one per request. Over HTTP, tools are subject to the same access control
+//! as the equivalent REST endpoints; over stdio, whoever runs `bleep` can access everything.
+
+use std::fmt::Write as _;
+
+use axum::{body::Bytes, extract::State, response::Response};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::mpsc,
+};
+use tracing::info;
+
+use super::{
+    answer::{self, AnswerState, Params},
+    fields::Fields,
+    graphql::RequestContext,
+    keys::Scope,
+    prelude::*,
+    query::{ApiQuery, QueryResult},
+};
+use crate::{repo::RepoRef, Application};
+
+/// The version of the protocol this server speaks
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+/// Files returned by a search, unless the tool is asked for another number
+const DEFAULT_SEARCH_LIMIT: usize = 10;
+
+/// The most files returned by a search
+const MAX_SEARCH_LIMIT: usize = 50;
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+#[derive(Deserialize)]
+struct Request {
+    /// Unset for notifications, which get no response
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct ToolCall {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+#[derive(Deserialize)]
+struct SearchArguments {
+    query: String,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ReadFileArguments {
+    repo_ref: String,
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct AnswerArguments {
+    question: String,
+}
+
+/// Who tools are called for
+struct Caller {
+    access: RepoAccess,
+    /// Whether the `answer` tool can be called
+    can_answer: bool,
+    tenant: Option<String>,
+    /// Unset if the user must log in to be identified
+    user_id: Option<String>,
+}
+
+impl Caller {
+    /// Whoever runs `bleep`, who can access everything
+    fn local() -> Self {
+        Self {
+            access: RepoAccess::default(),
+            can_answer: true,
+            tenant: None,
+            user_id: Some(answer::default_user_id()),
+        }
+    }
+}
+
+struct McpServer {
+    app: Application,
+    answers: Arc<AnswerState>,
+}
+
+/// Serve the Model Context Protocol over stdin and stdout, until stdin is closed
+pub async fn serve_stdio(app: Application) -> anyhow::Result<()> {
+    let server = Arc::new(McpServer {
+        answers: Arc::new(AnswerState::new(&app.config)?),
+        app,
+    });
+    let caller = Arc::new(Caller::local());
+
+    let (responses, mut outgoing) = mpsc::unbounded_channel::<Value>();
+    let writer = tokio::spawn(async move {
+        let mut stdout = tokio::io::stdout();
+        while let Some(response) = outgoing.recv().await {
+            let mut line = response.to_string().into_bytes();
+            line.push(b'\n');
+            stdout.write_all(&line).await?;
+            stdout.flush().await?;
+        }
+
+        Ok::<_, std::io::Error>(())
+    });
+
+    info!("serving the model context protocol over stdio");
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // Requests are handled concurrently, so that an answer doesn't hold up searches
+        let (server, caller, responses) =
+            (Arc::clone(&server), Arc::clone(&caller), responses.clone());
+        tokio::spawn(async move {
+            if let Some(response) = server.handle_message(&caller, line.as_bytes()).await {
+                _ = responses.send(response);
+            }
+        });
+    }
+
+    drop(responses);
+    writer.await??;
+    Ok(())
+}
+
+pub(super) async fn handle(
+    _: Scoped<keys::Read>,
+    context: RequestContext,
+    State(answers): State<Arc<AnswerState>>,
+    body: Bytes,
+) -> Response {
+    let caller = Caller {
+        access: context.access,
+        can_answer: context.grant.allows(Scope::Answer),
+        tenant: context.tenant.name().map(str::to_owned),
+        user_id: context.user_id,
+    };
+
+    let server = McpServer {
+        app: context.app,
+        answers,
+    };
+
+    match server.handle_message(&caller, &body).await {
+        Some(response) => axum::Json(response).into_response(),
+        None => StatusCode::ACCEPTED.into_response(),
+    }
+}
+
+impl McpServer {
+    /// The response to a message, unless it's a notification
+    async fn handle_message(&self, caller: &Caller, message: &[u8]) -> Option<Value> {
+        let request = match serde_json::from_slice::<Request>(message) {
+            Ok(request) => request,
+            Err(err) => return Some(error_response(Value::Null, PARSE_ERROR, err.to_string())),
+        };
+
+        let result = self.dispatch(caller, &request.method, request.params).await;
+        let id = request.id?;
+
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => error_response(id, code, message),
+        })
+    }
+
+    async fn dispatch(
+        &self,
+        caller: &Caller,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, (i64, String)> {
+        match method {
+            "initialize" => Ok(json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": { "tools": {} },
+                "serverInfo": { "name": "bleep", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": tools(caller) })),
+            "tools/call" => {
+                let call = serde_json::from_value::<ToolCall>(params)
+                    .map_err(|err| (INVALID_PARAMS, err.to_string()))?;
+
+                // Tools that fail are reported as results, so that the model can see why
+                let (text, is_error) = match self.call_tool(caller, call).await {
+                    Ok(text) => (text, false),
+                    Err(text) => (text, true),
+                };
+
+                Ok(json!({
+                    "content": [{ "type": "text", "text": text }],
+                    "isError": is_error,
+                }))
+            }
+            // Notifications, such as `notifications/initialized`, need no handling
+            _ if method.starts_with("notifications/") => Ok(Value::Null),
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method `{method}`"))),
+        }
+    }
+
+    async fn call_tool(&self, caller: &Caller, call: ToolCall) -> Result<String, String> {
+        match call.name.as_str() {
+            "search" => self.search(caller, arguments(call.arguments)?).await,
+            "read_file" => self.read_file(caller, arguments(call.arguments)?).await,
+            "answer" if caller.can_answer => self.answer(caller, arguments(call.arguments)?).await,
+            name => Err(format!("unknown tool `{name}`")),
+        }
+    }
+
+    async fn search(&self, caller: &Caller, args: SearchArguments) -> Result<String, String> {
+        let limit = args
+            .limit
+            .unwrap_or(DEFAULT_SEARCH_LIMIT)
+            .clamp(1, MAX_SEARCH_LIMIT);
+        let repos = caller.access.disk_paths(&self.app.repo_pool);
+
+        let response = Arc::new(ApiQuery::new(args.query, 0, limit))
+            .query(
+                Arc::clone(&self.app.indexes),
+                &self.app.repo_pool,
+                repos.as_deref(),
+            )
+            .await
+            .map_err(|err| err.message().to_string())?;
+
+        let mut out = String::new();
+        for result in response.data {
+            match result {
+                QueryResult::Snippets(file) => {
+                    _ = writeln!(out, "{} {}", file.repo_ref, file.relative_path);
+                    for snippet in file.snippets {
+                        _ = writeln!(
+                            out,
+                            "lines {}-{}:\n```\n{}\n```",
+                            snippet.line_range.start + 1,
+                            snippet.line_range.end,
+                            snippet.data.trim_end()
+                        );
+                    }
+                }
+                other => {
+                    if let Ok(value) = serde_json::to_value(other) {
+                        _ = writeln!(out, "{value}");
+                    }
+                }
+            }
+        }
+
+        if out.is_empty() {
+            out.push_str("No results");
+        }
+
+        Ok(out)
+    }
+
+    async fn read_file(&self, caller: &Caller, args: ReadFileArguments) -> Result<String, String> {
+        let repo_ref = args
+            .repo_ref
+            .parse::<RepoRef>()
+            .map_err(|e| e.to_string())?;
+        if !caller.access.allows(&repo_ref.to_string()) {
+            return Err("Repo not found".to_owned());
+        }
+
+        let doc = self
+            .app
+            .indexes
+            .file
+            .by_path(&repo_ref, &args.path)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        Ok(doc.content.to_string())
+    }
+
+    async fn answer(&self, caller: &Caller, args: AnswerArguments) -> Result<String, String> {
+        let Some(user_id) = caller.user_id.clone() else {
+            return Err("login required".to_owned());
+        };
+
+        let params = Params {
+            q: args.question,
+            thread_id: uuid::Uuid::new_v4().to_string(),
+            limit: answer::default_limit(),
+            user_id,
+            fields: Fields::default(),
+            answer_lang: None,
+            access: caller.access.clone(),
+            llm_permits: None,
+        };
+
+        let answer =
+            answer::answer_one(&self.answers, params, &self.app, caller.tenant.as_deref()).await;
+
+        if let Some(error) = answer.error {
+            return Err(error);
+        }
+
+        let mut out = answer.answer.unwrap_or_default();
+        let sources = answer.snippets.map(|s| s.matches).unwrap_or_default();
+        if !sources.is_empty() {
+            out.push_str("\n\nSources:");
+            for snippet in sources {
+                _ = write!(
+                    out,
+                    "\n- {} {} (lines {}-{})",
+                    snippet.repo_ref,
+                    snippet.relative_path,
+                    snippet.start_line + 1,
+                    snippet.end_line + 1
+                );
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+fn arguments<T: serde::de::DeserializeOwned>(arguments: Value) -> Result<T, String> {
+    serde_json::from_value(arguments).map_err(|err| format!("invalid arguments: {err}"))
+}
+
+fn error_response(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+/// The tools `caller` can call
+fn tools(caller: &Caller) -> Vec<Value> {
+    let mut tools = vec![
+        json!({
+            "name": "search",
+            "description": "Search the indexed repositories with a query in the bloop query \
+                language, e.g. `symbol:Indexer lang:rust`, `path:webserver anyhow` or \
+                `repo:bloop \"fn main\"`. Returns matching files with snippets.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "query": { "type": "string", "description": "The query" },
+                    "limit": {
+                        "type": "integer",
+                        "description": "The most files to return",
+                        "minimum": 1,
+                        "maximum": MAX_SEARCH_LIMIT,
+                    },
+                },
+                "required": ["query"],
+            },
+        }),
+        json!({
+            "name": "read_file",
+            "description": "Read a file of an indexed repository.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "repo_ref": {
+                        "type": "string",
+                        "description": "The repository, as returned by `search`",
+                    },
+                    "path": {
+                        "type": "string",
+                        "description": "The path of the file, relative to the repository root",
+                    },
+                },
+                "required": ["repo_ref", "path"],
+            },
+        }),
+    ];
+
+    if caller.can_answer {
+        tools.push(json!({
+            "name": "answer",
+            "description": "Answer a question about the indexed code in natural language, \
+                citing the snippets the answer is based on.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "question": { "type": "string", "description": "The question" },
+                },
+                "required": ["question"],
+            },
+        }));
+    }
+
+    tools
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answer_tool_requires_scope() {
+        let mut caller = Caller::local();
+        let names = |caller: &Caller| {
+            tools(caller)
+                .into_iter()
+                .map(|tool| tool["name"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(&caller), ["search", "read_file", "answer"]);
+
+        caller.can_answer = false;
+        assert_eq!(names(&caller), ["search", "read_file"]);
+    }
+}
@@ -0,0 +1,22 @@
+use axum::http::header::CONTENT_TYPE;
+
+use super::{prelude::*, tenant::CurrentTenant};
+
+/// The content type of the Prometheus text format
+const PROMETHEUS: &str = "text/plain; version=0.0.4";
+
+/// Latency histograms of answering questions and of index operations, for Prometheus to scrape.
+///
+/// Metrics cover every tenant, so only the operator's admin keys can read them.
+pub(super) async fn handle(
+    _: Scoped<keys::Admin>,
+    tenant: CurrentTenant,
+) -> Result<impl IntoResponse> {
+    if !matches!(tenant, CurrentTenant::Global) {
+        return Err(Error::user("metrics are only available to the operator")
+            .with_status(StatusCode::FORBIDDEN)
+            .with_code(ErrorCode::Forbidden));
+    }
+
+    Ok(([(CONTENT_TYPE, PROMETHEUS)], crate::metrics::render()))
+}
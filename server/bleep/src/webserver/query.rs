@@ -1,11 +1,14 @@
 use std::{
     collections::{HashMap, HashSet},
-    path::MAIN_SEPARATOR,
-    sync::Arc,
+    convert::Infallible,
+    path::{PathBuf, MAIN_SEPARATOR},
+    sync::{Arc, Mutex},
+    time::Instant,
 };
 
-use super::prelude::*;
+use super::{fields::Fields, prelude::*, sarif, tenant::CurrentTenant};
 use crate::{
+    analytics::QueryLogEntry,
     collector::{BytesFilterCollector, FrequencyCollector},
     indexes::{
         reader::{base_name, ContentReader, FileReader, OpenReader, RepoReader},
@@ -13,10 +16,24 @@ use crate::{
     },
     query::{parser, ranking::DocumentTweaker},
     snippet::{HighlightedString, SnippedFile, Snipper},
+    state::RepositoryPool,
+    Application,
 };
 
 use async_trait::async_trait;
-use axum::{extract::Query, response::IntoResponse as IntoAxumResponse, Extension};
+use axum::{
+    body::StreamBody,
+    extract::Query,
+    http::{
+        header::{ACCEPT, CONTENT_TYPE},
+        HeaderMap,
+    },
+    response::IntoResponse as IntoAxumResponse,
+    Extension,
+};
+use futures::{stream, StreamExt};
+use lru::LruCache;
+use once_cell::sync::Lazy;
 use regex::{
     bytes::{Regex as ByteRegex, RegexBuilder as ByteRegexBuilder},
     Regex,
@@ -24,8 +41,38 @@ use regex::{
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use tantivy::collector::{MultiCollector, TopDocs};
+use tokio::sync::oneshot;
+use tracing::error;
 use utoipa::{IntoParams, ToSchema};
 
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Lines of results that are read ahead of what the client has received
+const STREAM_BUFFER: usize = 16;
+
+/// Responses to recent searches that are kept, as the UI repeats the same search whenever it's
+/// refreshed
+const RESULT_CACHE_SIZE: usize = 128;
+
+/// Responses to recent searches. Responses read from previous generations of the indexes are
+/// never looked up again, and make way for new ones.
+static RESULTS: Lazy<Mutex<LruCache<ResultKey, QueryResponse>>> =
+    Lazy::new(|| Mutex::new(LruCache::new(RESULT_CACHE_SIZE.try_into().unwrap())));
+
+/// Everything the response to a search depends on
+#[derive(PartialEq, Eq, Hash)]
+struct ResultKey {
+    /// The parsed query, which is the same however the query is spaced
+    query: String,
+    page: usize,
+    page_size: usize,
+    context_before: usize,
+    context_after: usize,
+    repos: Option<Vec<PathBuf>>,
+    /// Generations of the repo and file indexes
+    generations: (String, String),
+}
+
 const fn default_page_size() -> usize {
     100
 }
@@ -67,6 +114,17 @@ pub struct ApiQuery {
     #[serde(default = "default_true")]
     pub calculate_totals: bool,
 
+    /// The format of the response. SARIF and newline-delimited JSON can also be requested with
+    /// the `Accept` header.
+    #[serde(default)]
+    pub format: ResultFormat,
+
+    /// Comma-separated heavy fields to include: `text`, `highlights`, `symbols` and `contents`.
+    /// All of them are included if unset.
+    #[serde(default)]
+    #[param(value_type = Option<String>)]
+    fields: Fields,
+
     /// The number of lines of context in the snippet before the search result
     #[serde(alias = "cb", default = "default_context")]
     context_before: usize,
@@ -76,7 +134,40 @@ pub struct ApiQuery {
     context_after: usize,
 }
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ResultFormat {
+    #[default]
+    Json,
+    Sarif,
+    /// Paging metadata and stats on the first line, then one result per line, each sent as
+    /// soon as it's read from the index
+    Ndjson,
+}
+
 impl ApiQuery {
+    pub(crate) fn new(q: String, page: usize, page_size: usize) -> Self {
+        Self {
+            q,
+            page,
+            page_size,
+            calculate_totals: false,
+            format: ResultFormat::Json,
+            fields: Fields::default(),
+            context_before: default_context(),
+            context_after: default_context(),
+        }
+    }
+
+    /// Run the query outside of a request, against every repository
+    pub(crate) async fn run(self, app: &Application) -> anyhow::Result<Vec<QueryResult>> {
+        Arc::new(self)
+            .query(Arc::clone(&app.indexes), &app.repo_pool, None)
+            .await
+            .map(|response| response.data)
+            .map_err(|err| anyhow::anyhow!("{}", err.message()))
+    }
+
     fn limit(&self) -> usize {
         // do not permit a page-size of 0
         self.page_size.max(1)
@@ -86,9 +177,109 @@ impl ApiQuery {
         self.page_size * self.page
     }
 
-    async fn query(self: Arc<Self>, indexes: Arc<Indexes>) -> Result<QueryResponse> {
-        let queries = parser::parse(&self.q).map_err(Error::user)?;
+    pub(super) async fn query(
+        self: Arc<Self>,
+        indexes: Arc<Indexes>,
+        repo_pool: &RepositoryPool,
+        repos: Option<&[PathBuf]>,
+    ) -> Result<QueryResponse> {
+        let queries = parser::parse(&self.q)?;
+        let grouped = group_paths(&queries, repo_pool, repos);
+        let repos = grouped.as_deref().or(repos);
+
+        let key = ResultKey {
+            query: format!("{queries:?}"),
+            page: self.page,
+            page_size: self.page_size,
+            context_before: self.context_before,
+            context_after: self.context_after,
+            repos: repos.map(|repos| {
+                let mut repos = repos.to_vec();
+                repos.sort();
+                repos
+            }),
+            generations: (
+                indexes.repo.generation().await,
+                indexes.file.generation().await,
+            ),
+        };
+
+        if let Some(response) = RESULTS.lock().unwrap().get(&key) {
+            return Ok(response.clone());
+        }
+
+        let response = self.results(&indexes, &queries, repos).await?.collect();
+        RESULTS.lock().unwrap().put(key, response.clone());
+        Ok(response)
+    }
+
+    /// Send each result to `lines` as a line of JSON, as soon as it's read from the index. The
+    /// paging metadata and stats are sent to `header` before any result, or the error if the
+    /// query failed.
+    ///
+    /// The index is read no further once `lines` is dropped.
+    pub(super) async fn stream(
+        self: Arc<Self>,
+        indexes: Arc<Indexes>,
+        repo_pool: RepositoryPool,
+        repos: Option<Vec<PathBuf>>,
+        header: oneshot::Sender<Result<StreamHeader>>,
+        lines: flume::Sender<Vec<u8>>,
+    ) {
+        let queries = match parser::parse(&self.q) {
+            Ok(queries) => queries,
+            Err(err) => {
+                _ = header.send(Err(err.into()));
+                return;
+            }
+        };
+
+        let grouped = group_paths(&queries, &repo_pool, repos.as_deref());
+        let repos = grouped.as_deref().or(repos.as_deref());
+
+        let results = match self.results(&indexes, &queries, repos).await {
+            Ok(results) => results,
+            Err(err) => {
+                _ = header.send(Err(err));
+                return;
+            }
+        };
+
+        let stream_header = StreamHeader {
+            metadata: results.metadata,
+            stats: results.stats,
+        };
+
+        if header.send(Ok(stream_header)).is_err() {
+            return;
+        }
+
+        for result in results.data {
+            let mut value = match serde_json::to_value(result) {
+                Ok(value) => value,
+                Err(err) => {
+                    error!(?err, "failed to serialize search result");
+                    continue;
+                }
+            };
 
+            self.fields.prune_result(&mut value);
+
+            let mut line = value.to_string().into_bytes();
+            line.push(b'\n');
+
+            if lines.send_async(line).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn results<'a>(
+        &self,
+        indexes: &'a Indexes,
+        queries: &'a [parser::Query<'a>],
+        repos: Option<&'a [PathBuf]>,
+    ) -> Result<QueryResults<'a>> {
         // FIXME: this for-loop prevents us from ever producing heterogenous
         // results.
         //
@@ -113,34 +304,67 @@ impl ApiQuery {
         // For the time-being, we take the easy way out by prioritizing the first
         // target of the query, in this case `symbol:foo`. Queries that produce
         // homogenous results will work as expected: `repo:foo or repo:bar`.
-        for q in &queries {
+        for q in queries {
+            // Only content results are read lazily. The others are few, or need every
+            // document to be read before the first result is known.
             if ContentReader.query_matches(q) {
-                return ContentReader
-                    .execute(&indexes.file, &queries, &self)
+                return content_results(&indexes.file, queries, self, repos)
                     .await
                     .map_err(Error::internal);
-            } else if RepoReader.query_matches(q) {
-                return RepoReader
-                    .execute(&indexes.repo, &queries, &self)
+            }
+
+            let response = if RepoReader.query_matches(q) {
+                RepoReader
+                    .execute(&indexes.repo, queries, self, repos)
                     .await
-                    .map_err(Error::internal);
             } else if FileReader.query_matches(q) {
-                return FileReader
-                    .execute(&indexes.file, &queries, &self)
+                FileReader
+                    .execute(&indexes.file, queries, self, repos)
                     .await
-                    .map_err(Error::internal);
             } else if OpenReader.query_matches(q) {
-                return OpenReader
-                    .execute(&indexes.file, &queries, &self)
+                OpenReader
+                    .execute(&indexes.file, queries, self, repos)
                     .await
-                    .map_err(Error::internal);
-            }
+            } else {
+                continue;
+            };
+
+            return response.map(QueryResults::from).map_err(Error::internal);
         }
 
-        Err(Error::user("mangled query"))
+        Err(Error::user("mangled query").with_code(ErrorCode::QueryParseError))
     }
 }
 
+/// Disk paths of the repositories in the groups named with `group:`, out of those that can be
+/// searched. `None` if no group is named.
+///
+/// The repositories searched are shared by every query in an `or`, so groups named in different
+/// branches are combined.
+fn group_paths(
+    queries: &[parser::Query<'_>],
+    repo_pool: &RepositoryPool,
+    repos: Option<&[PathBuf]>,
+) -> Option<Vec<PathBuf>> {
+    let groups = queries
+        .iter()
+        .filter_map(|q| q.group.as_deref())
+        .collect::<HashSet<_>>();
+
+    if groups.is_empty() {
+        return None;
+    }
+
+    Some(
+        repo_pool
+            .iter()
+            .filter(|elem| elem.groups.iter().any(|g| groups.contains(g.as_str())))
+            .map(|elem| elem.disk_path.clone())
+            .filter(|path| repos.map_or(true, |allowed| allowed.contains(path)))
+            .collect(),
+    )
+}
+
 #[utoipa::path(
     get,
     path = "/q",
@@ -152,13 +376,148 @@ impl ApiQuery {
     ),
 )]
 pub(super) async fn handle(
+    _: Scoped<keys::Read>,
+    access: RepoAccess,
+    tenant: CurrentTenant,
     Query(api_params): Query<ApiQuery>,
     Extension(indexes): Extension<Arc<Indexes>>,
-) -> impl IntoAxumResponse {
-    Arc::new(api_params).query(indexes).await.map(json)
+    Extension(app): Extension<Application>,
+    headers: HeaderMap,
+) -> Result<axum::response::Response> {
+    let repos = access.disk_paths(&app.repo_pool);
+
+    let sarif = api_params.format == ResultFormat::Sarif || accepts(&headers, sarif::CONTENT_TYPE);
+    let ndjson = wants_ndjson(api_params.format, &headers);
+
+    let mut entry = QueryLogEntry::search(&api_params.q);
+    entry.tenant = tenant.name().map(str::to_owned);
+    let start = Instant::now();
+
+    let api_params = Arc::new(api_params);
+    if ndjson {
+        let (header_tx, header) = oneshot::channel();
+        let (lines_tx, lines) = flume::bounded(STREAM_BUFFER);
+        tokio::spawn(Arc::clone(&api_params).stream(
+            indexes,
+            app.repo_pool.clone(),
+            repos,
+            header_tx,
+            lines_tx,
+        ));
+
+        let header = header.await.map_err(Error::internal)?;
+
+        // Results are counted before they're read, so the log doesn't wait on the client
+        entry.latency_ms = start.elapsed().as_millis() as u64;
+        match &header {
+            Ok(header) => entry.result_count = header.metadata.total_count.unwrap_or_default(),
+            Err(err) => entry.error = Some(err.message().to_string()),
+        }
+
+        app.log_query(&entry);
+
+        let mut first = serde_json::to_vec(&header?).map_err(Error::internal)?;
+        first.push(b'\n');
+
+        let body = stream::once(async move { first })
+            .chain(lines.into_stream())
+            .map(Ok::<_, Infallible>);
+
+        return Ok(([(CONTENT_TYPE, NDJSON_CONTENT_TYPE)], StreamBody::new(body)).into_response());
+    }
+
+    let response = Arc::clone(&api_params)
+        .query(indexes, &app.repo_pool, repos.as_deref())
+        .await;
+
+    entry.latency_ms = start.elapsed().as_millis() as u64;
+    match &response {
+        Ok(response) => {
+            entry.result_count = response.metadata.total_count.unwrap_or(response.count)
+        }
+        Err(err) => entry.error = Some(err.message().to_string()),
+    }
+
+    app.log_query(&entry);
+    let response = response?;
+
+    if sarif {
+        let log = sarif::Log::new(&api_params.q, &response);
+        Ok(([(CONTENT_TYPE, sarif::CONTENT_TYPE)], axum::Json(log)).into_response())
+    } else if api_params.fields.is_all() {
+        Ok(json(response).into_response())
+    } else {
+        let mut value =
+            serde_json::to_value(super::Response::from(response)).map_err(Error::internal)?;
+        api_params.fields.prune_search(&mut value);
+        Ok(axum::Json(value).into_response())
+    }
+}
+
+fn accepts(headers: &HeaderMap, content_type: &str) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.contains(content_type))
+}
+
+/// Whether newline-delimited JSON was asked for, with `format=ndjson` or the `Accept` header
+pub(super) fn wants_ndjson(format: ResultFormat, headers: &HeaderMap) -> bool {
+    format == ResultFormat::Ndjson || accepts(headers, NDJSON_CONTENT_TYPE)
 }
 
-#[derive(Serialize, ToSchema)]
+/// A newline-delimited JSON response of `values`, one per line, for endpoints whose results are
+/// all known before the response starts
+pub(super) fn ndjson<T: Serialize>(
+    values: impl IntoIterator<Item = T>,
+) -> Result<axum::response::Response> {
+    let mut body = vec![];
+    for value in values {
+        serde_json::to_writer(&mut body, &value).map_err(Error::internal)?;
+        body.push(b'\n');
+    }
+
+    Ok(([(CONTENT_TYPE, NDJSON_CONTENT_TYPE)], body).into_response())
+}
+
+/// Results of a query, which may be read from the index as they're iterated over
+pub(super) struct QueryResults<'a> {
+    metadata: PagingMetadata,
+    stats: ResultStats,
+    data: Box<dyn Iterator<Item = QueryResult> + Send + 'a>,
+}
+
+impl QueryResults<'_> {
+    fn collect(self) -> QueryResponse {
+        let data = self.data.collect::<Vec<_>>();
+
+        QueryResponse {
+            count: data.len(),
+            metadata: self.metadata,
+            data,
+            stats: self.stats,
+        }
+    }
+}
+
+impl From<QueryResponse> for QueryResults<'_> {
+    fn from(response: QueryResponse) -> Self {
+        Self {
+            metadata: response.metadata,
+            stats: response.stats,
+            data: Box::new(response.data.into_iter()),
+        }
+    }
+}
+
+/// The first line of a streamed response
+#[derive(Serialize)]
+pub(super) struct StreamHeader {
+    metadata: PagingMetadata,
+    stats: ResultStats,
+}
+
+#[derive(Serialize, ToSchema, Clone)]
 pub struct QueryResponse {
     /// Number of search results in this response
     count: usize,
@@ -171,7 +530,7 @@ pub struct QueryResponse {
 }
 
 /// Metadata pertaining to the query response, such as paging info
-#[derive(Default, Serialize, ToSchema)]
+#[derive(Default, Serialize, ToSchema, Clone)]
 pub(super) struct PagingMetadata {
     /// Page number passed in the request
     page: usize,
@@ -198,7 +557,7 @@ impl PagingMetadata {
     }
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
 pub struct ResultStats {
     pub lang: HashMap<String, usize>,
     pub repo: HashMap<String, usize>,
@@ -231,7 +590,7 @@ impl ResultStats {
     }
 }
 
-#[derive(Serialize, ToSchema)]
+#[derive(Serialize, ToSchema, Clone)]
 #[non_exhaustive]
 #[serde(tag = "kind", content = "data")]
 pub enum QueryResult {
@@ -258,21 +617,21 @@ pub enum QueryResult {
     Lang(String),
 }
 
-#[derive(Serialize, ToSchema)]
+#[derive(Serialize, ToSchema, Clone)]
 pub struct RepositoryResultData {
     name: HighlightedString,
     repo_ref: String,
 }
 
-#[derive(Serialize, ToSchema)]
+#[derive(Serialize, ToSchema, Clone)]
 pub struct FileResultData {
-    repo_name: String,
-    relative_path: HighlightedString,
-    repo_ref: String,
-    lang: Option<String>,
+    pub(crate) repo_name: String,
+    pub(crate) relative_path: HighlightedString,
+    pub(crate) repo_ref: String,
+    pub(crate) lang: Option<String>,
 }
 
-#[derive(Serialize, ToSchema, Debug)]
+#[derive(Serialize, ToSchema, Debug, Clone)]
 pub struct FileData {
     repo_name: String,
     relative_path: String,
@@ -282,7 +641,7 @@ pub struct FileData {
     siblings: Vec<DirEntry>,
 }
 
-#[derive(Serialize, ToSchema)]
+#[derive(Serialize, ToSchema, Clone)]
 pub struct DirectoryData {
     repo_name: String,
     relative_path: String,
@@ -311,6 +670,7 @@ pub trait ExecuteQuery {
         indexer: &Indexer<Self::Index>,
         queries: &[parser::Query<'_>],
         q: &ApiQuery,
+        repos: Option<&[PathBuf]>,
     ) -> anyhow::Result<QueryResponse>;
 }
 
@@ -323,104 +683,113 @@ impl ExecuteQuery for ContentReader {
         indexer: &Indexer<Self::Index>,
         queries: &[parser::Query<'_>],
         q: &ApiQuery,
+        repos: Option<&[PathBuf]>,
     ) -> anyhow::Result<QueryResponse> {
-        // queries that produce content results
-        let relevant_queries = queries.iter().filter(|q| self.query_matches(q));
-
-        // a list of targets, for a query of the form `symbol:foo or bar`, this is:
-        // - a symbol target: foo
-        // - a content target: bar
-        let targets = relevant_queries
-            .filter_map(|q| Some((q.target.as_ref()?, q.is_case_sensitive())))
-            .collect::<SmallVec<[_; 2]>>();
-
-        // a regex filter to get rid of docs that contain the trigrams but not the text
-        let byte_regexes = targets
-            .iter()
-            .filter_map(|(target, case)| {
-                ByteRegexBuilder::new(&target.literal().regex_str())
-                    .multi_line(true)
-                    .case_insensitive(!case)
-                    .build()
-                    .ok()
-            })
-            .collect::<Vec<_>>();
-
-        let raw_content = indexer.source.raw_content;
-        let repo_field = indexer.source.raw_repo_name;
-        let lang_field = indexer.source.lang;
-
-        // our results will consist of the top-k docs...
-        let top_k = TopDocs::with_limit(q.limit())
-            .and_offset(q.offset())
-            .tweak_score(DocumentTweaker(indexer.source.clone()));
-
-        // ...plus some rich search metadata
-        let total_count_collector = tantivy::collector::Count;
-        let lang_stats_collector = FrequencyCollector(lang_field);
-        let repo_stats_collector = FrequencyCollector(repo_field);
-
-        let mut metadata_collector = MultiCollector::new();
-        let total_count_handle = metadata_collector.add_collector(total_count_collector);
-        let lang_stats_handle = metadata_collector.add_collector(lang_stats_collector);
-        let repo_stats_handle = metadata_collector.add_collector(repo_stats_collector);
-
-        // our final search results contain top-k, total count, language stats, repo stats,
-        // filtered by the target regex
-        let collector = BytesFilterCollector::new(
-            raw_content,
-            move |b| byte_regexes.iter().any(|r| r.is_match(b)), // a doc is accepted if it contains atleast 1 target
-            (top_k, metadata_collector),
-        );
-
-        let mut results = indexer.query(queries.iter(), self, collector).await?;
-        let data = results
-            .docs
-            .filter_map(|doc| {
-                let snipper = Snipper::default().context(q.context_before, q.context_after);
-                let mut all_snippets = None::<SnippedFile>;
-
-                for (target, case_sensitive) in &targets {
-                    let (is_symbol, lit) = match target {
-                        parser::Target::Symbol(lit) => (true, lit),
-                        parser::Target::Content(lit) => (false, lit),
-                    };
-
-                    if let Some(snippets) = snipper
-                        .find_symbols(is_symbol)
-                        .case_sensitive(*case_sensitive)
-                        .all_for_doc(&lit.regex_str(), &doc)
-                        .unwrap()
-                    {
-                        all_snippets = if let Some(data) = all_snippets {
-                            Some(data.merge(snippets))
-                        } else {
-                            Some(snippets)
-                        };
-                    }
-                }
-
-                Some(QueryResult::Snippets(all_snippets?))
-            })
-            .collect::<Vec<QueryResult>>();
-
-        let total_count = total_count_handle.extract(&mut results.metadata);
+        Ok(content_results(indexer, queries, q, repos).await?.collect())
+    }
+}
 
-        let stats = ResultStats::default()
-            .with_lang_freqs(lang_stats_handle.extract(&mut results.metadata))
-            .with_repo_freqs(repo_stats_handle.extract(&mut results.metadata));
+/// Search the content index, snipping each document as it's read from the index
+async fn content_results<'a>(
+    indexer: &'a Indexer<File>,
+    queries: &'a [parser::Query<'a>],
+    q: &ApiQuery,
+    repos: Option<&'a [PathBuf]>,
+) -> anyhow::Result<QueryResults<'a>> {
+    // queries that produce content results
+    let relevant_queries = queries.iter().filter(|q| ContentReader.query_matches(q));
+
+    // a list of targets, for a query of the form `symbol:foo or bar`, this is:
+    // - a symbol target: foo
+    // - a content target: bar
+    let targets = relevant_queries
+        .filter_map(|q| Some((q.target.as_ref()?, q.is_case_sensitive())))
+        .collect::<SmallVec<[_; 2]>>();
+
+    // a regex filter to get rid of docs that contain the trigrams but not the text
+    let byte_regexes = targets
+        .iter()
+        .filter_map(|(target, case)| {
+            ByteRegexBuilder::new(&target.literal().regex_str())
+                .multi_line(true)
+                .case_insensitive(!case)
+                .build()
+                .ok()
+        })
+        .collect::<Vec<_>>();
+
+    let raw_content = indexer.source.raw_content;
+    let repo_field = indexer.source.raw_repo_name;
+    let lang_field = indexer.source.lang;
+
+    // our results will consist of the top-k docs...
+    let top_k = TopDocs::with_limit(q.limit())
+        .and_offset(q.offset())
+        .tweak_score(DocumentTweaker(indexer.source.clone()));
+
+    // ...plus some rich search metadata
+    let total_count_collector = tantivy::collector::Count;
+    let lang_stats_collector = FrequencyCollector(lang_field);
+    let repo_stats_collector = FrequencyCollector(repo_field);
+
+    let mut metadata_collector = MultiCollector::new();
+    let total_count_handle = metadata_collector.add_collector(total_count_collector);
+    let lang_stats_handle = metadata_collector.add_collector(lang_stats_collector);
+    let repo_stats_handle = metadata_collector.add_collector(repo_stats_collector);
+
+    // our final search results contain top-k, total count, language stats, repo stats,
+    // filtered by the target regex
+    let collector = BytesFilterCollector::new(
+        raw_content,
+        move |b| byte_regexes.iter().any(|r| r.is_match(b)), // a doc is accepted if it contains atleast 1 target
+        (top_k, metadata_collector),
+    );
+
+    let mut results = indexer
+        .query(queries.iter(), &ContentReader, collector, repos)
+        .await?;
+
+    let total_count = total_count_handle.extract(&mut results.metadata);
+
+    let stats = ResultStats::default()
+        .with_lang_freqs(lang_stats_handle.extract(&mut results.metadata))
+        .with_repo_freqs(repo_stats_handle.extract(&mut results.metadata));
+
+    let metadata = PagingMetadata::new(q.page, q.page_size, Some(total_count));
+
+    let (context_before, context_after) = (q.context_before, q.context_after);
+    let data = results.docs.filter_map(move |doc| {
+        let snipper = Snipper::default().context(context_before, context_after);
+        let mut all_snippets = None::<SnippedFile>;
+
+        for (target, case_sensitive) in &targets {
+            let (is_symbol, lit) = match target {
+                parser::Target::Symbol(lit) => (true, lit),
+                parser::Target::Content(lit) => (false, lit),
+            };
+
+            if let Some(snippets) = snipper
+                .find_symbols(is_symbol)
+                .case_sensitive(*case_sensitive)
+                .all_for_doc(&lit.regex_str(), &doc)
+                .unwrap()
+            {
+                all_snippets = if let Some(data) = all_snippets {
+                    Some(data.merge(snippets))
+                } else {
+                    Some(snippets)
+                };
+            }
+        }
 
-        let metadata = PagingMetadata::new(q.page, q.page_size, Some(total_count));
+        Some(QueryResult::Snippets(all_snippets?))
+    });
 
-        let count = data.len();
-        let response = QueryResponse {
-            count,
-            metadata,
-            data,
-            stats,
-        };
-        Ok(response)
-    }
+    Ok(QueryResults {
+        metadata,
+        stats,
+        data: Box::new(data),
+    })
 }
 
 #[async_trait]
@@ -432,6 +801,7 @@ impl ExecuteQuery for FileReader {
         indexer: &Indexer<File>,
         queries: &[parser::Query<'_>],
         q: &ApiQuery,
+        repos: Option<&[PathBuf]>,
     ) -> anyhow::Result<QueryResponse> {
         let (filter_regexes, byte_filter_regexes): (Vec<_>, Vec<_>) = queries
             .iter()
@@ -465,7 +835,9 @@ impl ExecuteQuery for FileReader {
             (top_k, metadata_collector),
         );
 
-        let mut results = indexer.query(queries.iter(), self, collector).await?;
+        let mut results = indexer
+            .query(queries.iter(), self, collector, repos)
+            .await?;
 
         let data = results
             .docs
@@ -513,6 +885,7 @@ impl ExecuteQuery for RepoReader {
         indexer: &Indexer<Self::Index>,
         queries: &[parser::Query<'_>],
         q: &ApiQuery,
+        repos: Option<&[PathBuf]>,
     ) -> anyhow::Result<QueryResponse> {
         let (filter_regexes, byte_filter_regexes): (Vec<_>, Vec<_>) = queries
             .iter()
@@ -541,7 +914,9 @@ impl ExecuteQuery for RepoReader {
             (top_k, metadata_collector),
         );
 
-        let mut results = indexer.query(queries.iter(), self, collector).await?;
+        let mut results = indexer
+            .query(queries.iter(), self, collector, repos)
+            .await?;
 
         let data = results
             .docs
@@ -585,6 +960,7 @@ impl ExecuteQuery for OpenReader {
         indexer: &Indexer<Self::Index>,
         queries: &[parser::Query<'_>],
         _q: &ApiQuery,
+        repos: Option<&[PathBuf]>,
     ) -> anyhow::Result<QueryResponse> {
         #[derive(Debug)]
         struct Directive<'a> {
@@ -640,7 +1016,9 @@ impl ExecuteQuery for OpenReader {
             (top_docs, empty_collector),
         );
 
-        let results = indexer.query(queries.iter(), self, collector).await?;
+        let results = indexer
+            .query(queries.iter(), self, collector, repos)
+            .await?;
 
         // Map of (repo_name, relative_path) -> (String, entry set)
         //
@@ -820,4 +1198,14 @@ mod tests {
 
         assert_eq!(expected, observed);
     }
+
+    #[test]
+    fn ndjson_is_negotiated() {
+        let mut headers = HeaderMap::new();
+        assert!(!wants_ndjson(ResultFormat::Json, &headers));
+        assert!(wants_ndjson(ResultFormat::Ndjson, &headers));
+
+        headers.insert(ACCEPT, "application/x-ndjson".parse().unwrap());
+        assert!(wants_ndjson(ResultFormat::Json, &headers));
+    }
 }
@@ -0,0 +1,220 @@
+//! Per-client rate limiting.
+//!
+//! Clients are identified by their API key, their login, or failing that, their IP address.
+//! Every client gets a token bucket that holds up to a minute's worth of requests and refills
+//! continuously.
+
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header::RETRY_AFTER, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+use dashmap::DashMap;
+use tracing::debug;
+
+use super::{auth::Identity, keys::Grant, Error, ErrorCode, ErrorKind};
+
+/// Number of tracked clients above which idle buckets are dropped
+const MAX_TRACKED_CLIENTS: usize = 4096;
+
+const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+const RATELIMIT_RESET: &str = "ratelimit-reset";
+
+pub(super) struct RateLimiter {
+    /// Requests allowed per minute for each client. No limit is enforced if unset.
+    per_minute: Option<u32>,
+    buckets: DashMap<String, Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+enum Decision {
+    Allowed { remaining: u32, reset: Duration },
+    Limited { retry_after: Duration },
+}
+
+impl RateLimiter {
+    pub(super) fn new(per_minute: Option<u32>) -> Arc<Self> {
+        Arc::new(Self {
+            per_minute: per_minute.filter(|&n| n > 0),
+            buckets: DashMap::new(),
+        })
+    }
+
+    /// Take one of `client`'s requests, for requests that don't come through [`limit`], with
+    /// how long until the client can retry if it has none left.
+    pub(super) fn try_take(&self, client: &str) -> Result<(), Duration> {
+        let Some(capacity) = self.per_minute else {
+            return Ok(());
+        };
+
+        match self.check(client, capacity, Instant::now()) {
+            Decision::Allowed { .. } => Ok(()),
+            Decision::Limited { retry_after } => Err(retry_after),
+        }
+    }
+
+    fn check(&self, client: &str, capacity: u32, now: Instant) -> Decision {
+        let capacity = capacity as f64;
+        let rate = capacity / 60.0;
+
+        let decision = {
+            let mut bucket = self
+                .buckets
+                .entry(client.to_owned())
+                .or_insert_with(|| Bucket {
+                    tokens: capacity,
+                    updated: now,
+                });
+
+            bucket.tokens = bucket.refilled(now, capacity, rate);
+            bucket.updated = now;
+
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                Decision::Allowed {
+                    remaining: bucket.tokens as u32,
+                    reset: Duration::from_secs_f64((capacity - bucket.tokens) / rate),
+                }
+            } else {
+                Decision::Limited {
+                    retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / rate),
+                }
+            }
+        };
+
+        if self.buckets.len() > MAX_TRACKED_CLIENTS {
+            self.buckets
+                .retain(|_, b| b.refilled(now, capacity, rate) < capacity);
+        }
+
+        decision
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant, capacity: f64, rate: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate).min(capacity)
+    }
+}
+
+/// Identify the client making a request.
+pub(super) fn client<B>(request: &Request<B>) -> String {
+    let extensions = request.extensions();
+
+    if let Some(Grant::Key(key)) = extensions.get::<Grant>() {
+        return format!("key:{}", key.name);
+    }
+
+    if let Some(identity) = extensions.get::<Identity>() {
+        return format!("user:{}", identity.login);
+    }
+
+    extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| format!("ip:{}", addr.ip()))
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// Round up to whole seconds, as used by the rate limiting headers.
+fn secs(duration: Duration) -> HeaderValue {
+    HeaderValue::from(duration.as_secs_f64().ceil() as u64)
+}
+
+pub(super) async fn limit<B>(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> axum::response::Response {
+    let Some(capacity) = limiter.per_minute else {
+        return next.run(request).await;
+    };
+
+    let client = client(&request);
+    let mut headers = HeaderMap::new();
+    headers.insert(RATELIMIT_LIMIT, HeaderValue::from(capacity));
+
+    match limiter.check(&client, capacity, Instant::now()) {
+        Decision::Allowed { remaining, reset } => {
+            headers.insert(RATELIMIT_REMAINING, HeaderValue::from(remaining));
+            headers.insert(RATELIMIT_RESET, secs(reset));
+
+            let mut response = next.run(request).await;
+            response.headers_mut().extend(headers);
+            response
+        }
+        Decision::Limited { retry_after } => {
+            debug!(%client, "rate limit exceeded");
+
+            headers.insert(RATELIMIT_REMAINING, HeaderValue::from(0));
+            headers.insert(RATELIMIT_RESET, secs(retry_after));
+            headers.insert(RETRY_AFTER, secs(retry_after));
+
+            let error = Error::new(ErrorKind::User, "rate limit exceeded")
+                .with_status(StatusCode::TOO_MANY_REQUESTS)
+                .with_code(ErrorCode::RateLimited);
+
+            (headers, error).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let limiter = RateLimiter::new(Some(2));
+        let start = Instant::now();
+
+        assert!(matches!(
+            limiter.check("a", 2, start),
+            Decision::Allowed { remaining: 1, .. }
+        ));
+        assert!(matches!(
+            limiter.check("a", 2, start),
+            Decision::Allowed { remaining: 0, .. }
+        ));
+        assert!(matches!(
+            limiter.check("a", 2, start),
+            Decision::Limited { retry_after } if retry_after.as_secs_f64().round() == 30.0
+        ));
+
+        // Other clients have their own bucket
+        assert!(matches!(
+            limiter.check("b", 2, start),
+            Decision::Allowed { .. }
+        ));
+
+        // One token is refilled every 30 seconds
+        let later = start + Duration::from_secs(31);
+        assert!(matches!(
+            limiter.check("a", 2, later),
+            Decision::Allowed { remaining: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn taking_without_a_limit_is_allowed() {
+        let limiter = RateLimiter::new(None);
+        assert!((0..100).all(|_| limiter.try_take("a").is_ok()));
+
+        let limiter = RateLimiter::new(Some(1));
+        assert!(limiter.try_take("a").is_ok());
+        assert!(limiter.try_take("a").is_err());
+    }
+}
@@ -1,17 +1,25 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
 use crate::{
+    background,
+    remotes::{self, azure, bitbucket, gitlab, ForkPolicy, ImportFilter, RemoteError},
     repo::{Backend, RepoRef, Repository, SyncStatus},
     Application,
 };
 use axum::{
     extract::{Path, Query},
     http::StatusCode,
-    response::IntoResponse,
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Sse,
+    },
     Extension, Json,
 };
 use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::future;
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
 use utoipa::{IntoParams, ToSchema};
 
 use super::prelude::*;
@@ -26,7 +34,19 @@ pub(super) struct Repo {
     pub(super) sync_status: SyncStatus,
     pub(super) last_update: DateTime<Utc>,
     pub(super) last_index: Option<DateTime<Utc>>,
+    /// The commit that was indexed last
+    pub(super) last_indexed_commit: Option<String>,
     pub(super) most_common_lang: Option<String>,
+    /// Size of the checkout on disk, as of the last index
+    pub(super) disk_usage_bytes: Option<u64>,
+    /// Number of files and directories in the search index
+    pub(super) file_count: Option<usize>,
+    /// Number of embeddings stored for semantic search
+    pub(super) vector_count: Option<u64>,
+    /// Groups the repository belongs to
+    pub(super) groups: Vec<String>,
+    /// The tag or commit the repository is pinned to, if it's never synced
+    pub(super) pinned: Option<String>,
 }
 
 impl From<(&RepoRef, &Repository)> for Repo {
@@ -47,7 +67,13 @@ impl From<(&RepoRef, &Repository)> for Repo {
                     .and_local_timezone(Utc)
                     .unwrap(),
             ),
+            last_indexed_commit: repo.last_commit_hash.clone(),
             most_common_lang: repo.most_common_lang.clone(),
+            disk_usage_bytes: Some(repo.disk_usage_bytes),
+            file_count: None,
+            vector_count: None,
+            groups: repo.groups.iter().cloned().collect(),
+            pinned: key.revision().map(str::to_owned),
         }
     }
 }
@@ -66,9 +92,102 @@ impl Repo {
             name,
             last_update: origin.pushed_at.unwrap(),
             last_index: None,
+            last_indexed_commit: None,
             most_common_lang: None,
+            disk_usage_bytes: None,
+            file_count: None,
+            vector_count: None,
+            groups: vec![],
+            pinned: None,
         }
     }
+
+    pub(crate) fn from_gitlab(local_duplicates: Vec<RepoRef>, origin: &gitlab::Project) -> Self {
+        let name = origin.path_with_namespace.clone();
+        Repo {
+            provider: Backend::Gitlab,
+            repo_ref: RepoRef::new(Backend::Gitlab, &name).unwrap(),
+            sync_status: SyncStatus::Uninitialized,
+            local_duplicates,
+            name,
+            last_update: origin.last_activity_at,
+            last_index: None,
+            last_indexed_commit: None,
+            most_common_lang: None,
+            disk_usage_bytes: None,
+            file_count: None,
+            vector_count: None,
+            groups: vec![],
+            pinned: None,
+        }
+    }
+
+    pub(crate) fn from_bitbucket(local_duplicates: Vec<RepoRef>, origin: &bitbucket::Repo) -> Self {
+        let name = origin.full_name.clone();
+        Repo {
+            provider: Backend::Bitbucket,
+            repo_ref: RepoRef::new(Backend::Bitbucket, &name).unwrap(),
+            sync_status: SyncStatus::Uninitialized,
+            local_duplicates,
+            name,
+            // Bitbucket Server doesn't report when repositories were last updated
+            last_update: origin.updated_on.unwrap_or_default(),
+            last_index: None,
+            last_indexed_commit: None,
+            most_common_lang: None,
+            disk_usage_bytes: None,
+            file_count: None,
+            vector_count: None,
+            groups: vec![],
+            pinned: None,
+        }
+    }
+
+    pub(crate) fn from_azure(local_duplicates: Vec<RepoRef>, origin: &azure::Repo) -> Self {
+        let name = origin.full_name();
+        Repo {
+            provider: Backend::AzureDevops,
+            repo_ref: RepoRef::new(Backend::AzureDevops, &name).unwrap(),
+            sync_status: SyncStatus::Uninitialized,
+            local_duplicates,
+            name,
+            // Azure DevOps doesn't report when repositories were last pushed to
+            last_update: DateTime::default(),
+            last_index: None,
+            last_indexed_commit: None,
+            most_common_lang: None,
+            disk_usage_bytes: None,
+            file_count: None,
+            vector_count: None,
+            groups: vec![],
+            pinned: None,
+        }
+    }
+
+    /// Fill in the number of documents stored in each index for this repo
+    async fn with_counts(mut self, app: &Application) -> Self {
+        let repo_ref = self.repo_ref.to_string();
+        let vectors = async {
+            match app.semantic.get() {
+                Some(semantic) => Some(semantic.count_points(&repo_ref).await),
+                None => None,
+            }
+        };
+
+        let (files, vectors) =
+            futures::join!(app.indexes.file.count_by_repo(&self.repo_ref), vectors);
+
+        self.file_count = files
+            .map_err(|err| warn!(%err, repo_ref = %self.repo_ref, "failed to count files"))
+            .ok();
+        self.vector_count = vectors.and_then(|count| {
+            count
+                .map_err(|err| warn!(%err, repo_ref = %self.repo_ref, "failed to count vectors"))
+                .ok()
+        });
+
+        self
+    }
 }
 
 #[derive(Serialize, ToSchema)]
@@ -78,6 +197,10 @@ pub(super) enum ReposResponse {
     Item(Repo),
     SyncQueued,
     Deleted,
+    /// Repositories in each group
+    Groups(BTreeMap<String, Vec<RepoRef>>),
+    /// Repositories queued for indexing by an import
+    Imported(Vec<RepoRef>),
 }
 
 /// Retrieve all indexed repositories
@@ -89,16 +212,23 @@ pub(super) enum ReposResponse {
         (status = 500, description = "Server error", body = EndpointError),
     ),
 )]
-pub(super) async fn indexed(Extension(app): Extension<Application>) -> impl IntoResponse {
+pub(super) async fn indexed(
+    _: Scoped<keys::Read>,
+    access: RepoAccess,
+    Extension(app): Extension<Application>,
+) -> impl IntoResponse {
+    let repos = app
+        .repo_pool
+        .iter()
+        .filter(|elem| access.allows(&elem.key().to_string()))
+        .map(|elem| Repo::from((elem.key(), elem.value())))
+        .collect::<Vec<_>>();
+
+    let repos = future::join_all(repos.into_iter().map(|repo| repo.with_counts(&app))).await;
+
     (
         StatusCode::OK,
-        Json(ReposResponse::List(
-            app.repo_pool
-                .iter()
-                .map(|elem| Repo::from((elem.key(), elem.value())))
-                .collect(),
-        ))
-        .into_response(),
+        Json(ReposResponse::List(repos)).into_response(),
     )
 }
 
@@ -111,49 +241,177 @@ pub(super) async fn indexed(Extension(app): Extension<Application>) -> impl Into
     ),
 )]
 pub(super) async fn get_by_id(
+    _: Scoped<keys::Read>,
+    access: RepoAccess,
     Path(path): Path<Vec<String>>,
     Extension(app): Extension<Application>,
 ) -> Result<impl IntoResponse> {
     let Ok(reporef) = RepoRef::from_components(&app.config.source.directory(), path) else {
-        return Err(Error::new(ErrorKind::NotFound, "Can't find repository"));
+        return Err(Error::new(ErrorKind::NotFound, "Can't find repository")
+            .with_code(ErrorCode::RepoNotFound));
     };
 
-    match app.repo_pool.get(&reporef) {
-        Some(result) => Ok(json(ReposResponse::Item(Repo::from((
-            result.key(),
-            result.value(),
-        ))))),
-        None => Err(Error::new(ErrorKind::NotFound, "Can't find repository")),
+    if !access.allows(&reporef.to_string()) {
+        return Err(Error::new(ErrorKind::NotFound, "Can't find repository")
+            .with_code(ErrorCode::RepoNotFound));
     }
+
+    let repo = match app.repo_pool.get(&reporef) {
+        Some(result) => Repo::from((result.key(), result.value())),
+        None => {
+            return Err(Error::new(ErrorKind::NotFound, "Can't find repository")
+                .with_code(ErrorCode::RepoNotFound))
+        }
+    };
+
+    Ok(json(ReposResponse::Item(repo.with_counts(&app).await)))
 }
 
-/// Delete a repository from the disk and any indexes
+/// Delete a repository from the disk, the search indexes, and the semantic index
+///
+/// This waits for the removal to complete. Syncs queued for the repository are cancelled.
 //
 #[utoipa::path(delete, path = "/repos/indexed/:ref",
     responses(
         (status = 200, description = "Execute query successfully", body = Response),
         (status = 400, description = "Bad request", body = EndpointError),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 409, description = "Repository is being synced", body = EndpointError),
         (status = 500, description = "Server error", body = EndpointError),
     ),
 )]
 pub(super) async fn delete_by_id(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
     Path(path): Path<Vec<String>>,
     Extension(app): Extension<Application>,
 ) -> impl IntoResponse {
     let Ok(reporef) = RepoRef::from_components(&app.config.source.directory(), path) else {
-        return Err(Error::new(ErrorKind::NotFound, "Can't find repository"));
+        return Err(Error::new(ErrorKind::NotFound, "Can't find repository")
+            .with_code(ErrorCode::RepoNotFound));
+    };
+
+    if !access.allows(&reporef.to_string()) {
+        return Err(
+            Error::new(ErrorKind::NotFound, "Repo not found").with_code(ErrorCode::RepoNotFound)
+        );
+    }
+
+    let status = app.repo_pool.get(&reporef).map(|r| r.sync_status.clone());
+    match status {
+        None => {
+            return Err(Error::new(ErrorKind::NotFound, "Repo not found")
+                .with_code(ErrorCode::RepoNotFound))
+        }
+        // The checkout is in use, and deleting it now would leave a partial one behind
+        Some(SyncStatus::Syncing) => {
+            return Err(Error::user("repository is being synced, try again later")
+                .with_status(StatusCode::CONFLICT)
+                .with_code(ErrorCode::Conflict))
+        }
+        Some(_) => {}
+    }
+
+    app.write_index()
+        .delete_repo(reporef)
+        .await
+        .map_err(Error::internal)?;
+
+    Ok(json(ReposResponse::Deleted))
+}
+
+/// List repository groups, along with the repositories in each
+//
+#[utoipa::path(get, path = "/repos/groups",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn groups(
+    _: Scoped<keys::Read>,
+    access: RepoAccess,
+    Extension(app): Extension<Application>,
+) -> impl IntoResponse {
+    let mut groups = BTreeMap::<String, Vec<RepoRef>>::new();
+
+    for elem in app.repo_pool.iter() {
+        if !access.allows(&elem.key().to_string()) {
+            continue;
+        }
+
+        for group in &elem.groups {
+            groups
+                .entry(group.clone())
+                .or_default()
+                .push(elem.key().clone());
+        }
+    }
+
+    json(ReposResponse::Groups(groups))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(super) struct SetGroups {
+    groups: Vec<String>,
+}
+
+/// Group names are used as `group:` terms in queries, so they can't contain separators
+fn valid_group(name: &str) -> bool {
+    !name.is_empty()
+        && !name
+            .chars()
+            .any(|c| c.is_whitespace() || c == '(' || c == ')')
+}
+
+/// Replace the groups a repository belongs to
+//
+#[utoipa::path(put, path = "/repos/groups/:ref", request_body = SetGroups,
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn set_groups(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
+    Path(path): Path<Vec<String>>,
+    Extension(app): Extension<Application>,
+    Json(request): Json<SetGroups>,
+) -> Result<impl IntoResponse> {
+    let Ok(reporef) = RepoRef::from_components(&app.config.source.directory(), path) else {
+        return Err(Error::new(ErrorKind::NotFound, "Can't find repository")
+            .with_code(ErrorCode::RepoNotFound));
     };
 
-    let deleted = match app.repo_pool.get_mut(&reporef) {
+    if !access.allows(&reporef.to_string()) {
+        return Err(Error::new(ErrorKind::NotFound, "Can't find repository")
+            .with_code(ErrorCode::RepoNotFound));
+    }
+
+    if let Some(name) = request.groups.iter().find(|name| !valid_group(name)) {
+        return Err(Error::user(format!("invalid group name `{name}`")));
+    }
+
+    let repo = match app.repo_pool.get_mut(&reporef) {
         Some(mut result) => {
-            result.value_mut().mark_removed();
-            app.write_index().queue_sync_and_index(vec![reporef]);
-            Ok(())
+            result.value_mut().groups = request.groups.into_iter().collect();
+            Repo::from((result.key(), result.value()))
+        }
+        None => {
+            return Err(Error::new(ErrorKind::NotFound, "Can't find repository")
+                .with_code(ErrorCode::RepoNotFound))
         }
-        None => Err(Error::new(ErrorKind::NotFound, "Repo not found")),
     };
 
-    Ok(deleted.map(|_| json(ReposResponse::Deleted)))
+    app.config
+        .source
+        .save_pool(app.repo_pool.clone())
+        .map_err(Error::internal)?;
+
+    Ok(json(ReposResponse::Item(repo)))
 }
 
 /// Synchronize a repo by its id
@@ -165,17 +423,56 @@ pub(super) async fn delete_by_id(
     ),
 )]
 pub(super) async fn sync(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
     Path(path): Path<Vec<String>>,
     Extension(app): Extension<Application>,
 ) -> impl IntoResponse {
     let Ok(reporef) = RepoRef::from_components(&app.config.source.directory(), path) else {
-        return Err(Error::new(ErrorKind::NotFound, "Can't find repository"));
+        return Err(Error::new(ErrorKind::NotFound, "Can't find repository")
+            .with_code(ErrorCode::RepoNotFound));
     };
 
+    if !access.allows(&reporef.to_string()) {
+        return Err(Error::new(ErrorKind::NotFound, "Can't find repository")
+            .with_code(ErrorCode::RepoNotFound));
+    }
+
     app.write_index().queue_sync_and_index(vec![reporef]);
     Ok(json(ReposResponse::SyncQueued))
 }
 
+/// Stream changes in the state of repositories as server-sent events
+///
+/// Each event is a JSON object whose `event` field is one of `sync_started`, `sync_finished`,
+/// `renamed`, `remote_removed`, `index_started`, `index_progress`, `index_finished`, `removed`,
+/// or `error`. Clients that fall behind receive a `lagged` event with the number of events they
+/// missed, and should fetch the state of repositories again.
+pub(super) async fn events(_: Scoped<keys::Read>, access: RepoAccess) -> impl IntoResponse {
+    let mut events = background::subscribe();
+
+    let stream = async_stream::stream! {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if access.allows(&event.reporef().to_string()) {
+                        yield Event::default().json_data(&event);
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    yield Event::default().json_data(serde_json::json!({
+                        "event": "lagged",
+                        "missed": missed,
+                    }));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
 /// List all repositories that are either indexed, or available for indexing
 //
 #[utoipa::path(get, path = "/repos",
@@ -185,7 +482,11 @@ pub(super) async fn sync(
         (status = 500, description = "Server error", body = EndpointError),
     ),
 )]
-pub(super) async fn available(Extension(app): Extension<Application>) -> impl IntoResponse {
+pub(super) async fn available(
+    _: Scoped<keys::Read>,
+    access: RepoAccess,
+    Extension(app): Extension<Application>,
+) -> impl IntoResponse {
     let unknown_github = app
         .credentials
         .github()
@@ -222,6 +523,75 @@ pub(super) async fn available(Extension(app): Extension<Application>) -> impl In
         })
         .collect::<Vec<_>>();
 
+    let unknown_gitlab = app
+        .credentials
+        .gitlab()
+        .map(|gl| gl.repositories)
+        .unwrap_or_default()
+        .iter()
+        .map(|project| {
+            let local_duplicates = app
+                .repo_pool
+                .iter()
+                .filter(|elem| {
+                    [&project.ssh_url_to_repo, &project.http_url_to_repo]
+                        .map(|url| url.to_lowercase())
+                        .contains(&elem.remote.to_string().to_lowercase())
+                })
+                .map(|elem| elem.key().clone())
+                .collect();
+
+            Repo::from_gitlab(local_duplicates, project)
+        })
+        .collect::<Vec<_>>();
+
+    let unknown_bitbucket = app
+        .credentials
+        .bitbucket()
+        .map(|bb| bb.repositories)
+        .unwrap_or_default()
+        .iter()
+        .map(|repo| {
+            let local_duplicates = app
+                .repo_pool
+                .iter()
+                .filter(|elem| {
+                    let remote = elem.remote.to_string().to_lowercase();
+                    repo.clone_urls
+                        .iter()
+                        .any(|url| url.to_lowercase() == remote)
+                })
+                .map(|elem| elem.key().clone())
+                .collect();
+
+            Repo::from_bitbucket(local_duplicates, repo)
+        })
+        .collect::<Vec<_>>();
+
+    let unknown_azure = app
+        .credentials
+        .azure()
+        .map(|az| az.repositories)
+        .unwrap_or_default()
+        .iter()
+        .map(|repo| {
+            let local_duplicates = app
+                .repo_pool
+                .iter()
+                .filter(|elem| {
+                    let remote = elem.remote.to_string().to_lowercase();
+                    [Some(&repo.remote_url), repo.ssh_url.as_ref()]
+                        .into_iter()
+                        .flatten()
+                        .any(|url| url.to_lowercase() == remote)
+                })
+                .map(|elem| elem.key().clone())
+                .collect();
+
+            Repo::from_azure(local_duplicates, repo)
+        })
+        .collect::<Vec<_>>();
+
     (
         StatusCode::OK,
         Json(ReposResponse::List(
@@ -229,6 +599,10 @@ pub(super) async fn available(Extension(app): Extension<Application>) -> impl In
                 .iter()
                 .map(|elem| Repo::from((elem.key(), elem.value())))
                 .chain(unknown_github)
+                .chain(unknown_gitlab)
+                .chain(unknown_bitbucket)
+                .chain(unknown_azure)
+                .filter(|repo| access.allows(&repo.repo_ref.to_string()))
                 .collect(),
         )),
     )
@@ -250,12 +624,25 @@ pub(super) struct SetIndexed {
     ),
 )]
 pub(super) async fn set_indexed(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
     Extension(app): Extension<Application>,
     Json(new_list): Json<SetIndexed>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse> {
     let mut repo_list = new_list.indexed.into_iter().collect::<HashSet<_>>();
 
+    if repo_list.iter().any(|r| !access.allows(&r.to_string())) {
+        return Err(Error::user("repository is not accessible")
+            .with_status(StatusCode::FORBIDDEN)
+            .with_code(ErrorCode::Forbidden));
+    }
+
+    // Repositories that can't be accessed are managed by someone else, leave them alone.
     for mut existing in app.repo_pool.iter_mut() {
+        if !access.allows(&existing.key().to_string()) {
+            continue;
+        }
+
         if !repo_list.contains(existing.key()) {
             existing.value_mut().mark_removed();
             repo_list.insert(existing.key().to_owned());
@@ -265,7 +652,87 @@ pub(super) async fn set_indexed(
     app.write_index()
         .queue_sync_and_index(repo_list.into_iter().collect());
 
-    json(ReposResponse::SyncQueued)
+    Ok(json(ReposResponse::SyncQueued))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(super) struct ImportRequest {
+    /// `github` or `gitlab`
+    backend: Backend,
+    /// GitHub organization, or GitLab group
+    org: String,
+    /// Repository names to import, such as `org/*-service`. Every repository is imported if
+    /// empty.
+    #[serde(default)]
+    include: Vec<String>,
+    /// Repository names to leave out
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// Primary languages of the repositories to import. Any language is imported if empty.
+    #[serde(default)]
+    languages: Vec<String>,
+    /// Import archived repositories too
+    #[serde(default)]
+    archived: bool,
+    /// What to do with forks of repositories that are indexed, or imported along with them
+    #[serde(default)]
+    forks: ForkPolicy,
+}
+
+/// Import every repository of a GitHub organization or GitLab group
+///
+/// Repositories that are already indexed are left alone. The others that match the filters
+/// are queued to be synced and indexed, and listed in the response. Forks of repositories
+/// that are indexed, or imported at the same time, are skipped by default.
+//
+#[utoipa::path(post, path = "/repos/import", request_body = ImportRequest,
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 404, description = "Organization not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn import(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
+    Extension(app): Extension<Application>,
+    Json(request): Json<ImportRequest>,
+) -> Result<impl IntoResponse> {
+    let filter = ImportFilter {
+        include: request.include,
+        exclude: request.exclude,
+        languages: request.languages,
+        archived: request.archived,
+        forks: request.forks,
+    };
+
+    let map_err = |err: RemoteError| match err {
+        RemoteError::RemoteNotFound => Error::new(ErrorKind::NotFound, "Organization not found"),
+        RemoteError::Configuration(_) | RemoteError::NotSupported(_) => Error::user(err),
+        err => Error::new(ErrorKind::UpstreamService, err.to_string())
+            .with_code(ErrorCode::UpstreamError),
+    };
+
+    let repos = remotes::list_org(&app, request.backend, &request.org, &filter)
+        .await
+        .map_err(map_err)?
+        .into_iter()
+        .filter(|repo| filter.matches(repo))
+        .collect();
+
+    let imported = remotes::dedup_forks(&app, repos, filter.forks)
+        .await
+        .map_err(map_err)?
+        .into_iter()
+        .map(|repo| repo.reporef)
+        .filter(|reporef| access.allows(&reporef.to_string()))
+        .filter(|reporef| !app.repo_pool.contains_key(reporef))
+        .collect::<Vec<_>>();
+
+    app.write_index().queue_sync_and_index(imported.clone());
+
+    Ok(json(ReposResponse::Imported(imported)))
 }
 
 #[derive(Deserialize, IntoParams)]
@@ -276,6 +743,8 @@ pub(super) struct ScanRequest {
 
 /// Gather recognized repository types from the filesystem
 ///
+/// A directory without any repositories in it is returned itself, to be indexed as a plain
+/// directory.
 #[utoipa::path(get, path = "/repos/scan",
     responses(
         (status = 200, description = "Execute query successfully", body = Response),
@@ -284,14 +753,25 @@ pub(super) struct ScanRequest {
     ),
 )]
 pub(super) async fn scan_local(
+    _: Scoped<keys::Admin>,
     Query(scan_request): Query<ScanRequest>,
     Extension(app): Extension<Application>,
 ) -> impl IntoResponse {
     let root = std::path::Path::new(&scan_request.path);
 
     if app.allow_path(root) {
+        let mut reporefs = crate::remotes::gather_repo_roots(&root, app.config.source.repo_dir())
+            .collect::<Vec<_>>();
+
+        if reporefs.is_empty() {
+            if let Ok(root) = crate::canonicalize(root) {
+                reporefs.push(RepoRef::from(&root));
+            }
+        }
+
         Ok(json(ReposResponse::List(
-            crate::remotes::gather_repo_roots(&root, app.config.source.repo_dir())
+            reporefs
+                .into_iter()
                 .map(|reporef| {
                     let mut repo = Repository::local_from(&reporef);
                     repo.sync_status = SyncStatus::Uninitialized;
@@ -301,6 +781,8 @@ pub(super) async fn scan_local(
                 .collect(),
         )))
     } else {
-        Err(Error::user("scanning not allowed").with_status(StatusCode::UNAUTHORIZED))
+        Err(Error::user("scanning not allowed")
+            .with_status(StatusCode::UNAUTHORIZED)
+            .with_code(ErrorCode::Forbidden))
     }
 }
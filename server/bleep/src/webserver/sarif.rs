@@ -0,0 +1,234 @@
+//! Search results in the [SARIF] format, for use with code scanning tools.
+//!
+//! [SARIF]: https://docs.oasis-open.org/sarif/sarif/v2.1.0/sarif-v2.1.0.html
+
+use serde::Serialize;
+
+use super::query::{QueryResponse, QueryResult};
+use crate::snippet::Snippet;
+
+pub(super) const CONTENT_TYPE: &str = "application/sarif+json";
+
+const SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+const RULE_ID: &str = "search-match";
+
+#[derive(Serialize)]
+pub(super) struct Log {
+    #[serde(rename = "$schema")]
+    schema: &'static str,
+    version: &'static str,
+    runs: Vec<Run>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Run {
+    tool: Tool,
+    column_kind: &'static str,
+    results: Vec<SarifResult>,
+}
+
+#[derive(Serialize)]
+struct Tool {
+    driver: Driver,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Driver {
+    name: &'static str,
+    version: &'static str,
+    information_uri: &'static str,
+    rules: Vec<Rule>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Rule {
+    id: &'static str,
+    short_description: Message,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct Message {
+    text: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifResult {
+    rule_id: &'static str,
+    level: &'static str,
+    message: Message,
+    locations: Vec<Location>,
+    properties: Properties,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Properties {
+    repo_ref: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Location {
+    physical_location: PhysicalLocation,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PhysicalLocation {
+    artifact_location: ArtifactLocation,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    region: Option<Region>,
+}
+
+#[derive(Serialize)]
+struct ArtifactLocation {
+    uri: String,
+}
+
+/// A range of a file. Lines and columns are 1-indexed, columns count unicode code points.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct Region {
+    start_line: usize,
+    start_column: usize,
+    end_line: usize,
+    end_column: usize,
+    snippet: Message,
+}
+
+impl Log {
+    pub(super) fn new(query: &str, response: &QueryResponse) -> Self {
+        let message = || Message {
+            text: format!("Match for `{query}`"),
+        };
+
+        let results = response
+            .data
+            .iter()
+            .flat_map(|result| match result {
+                QueryResult::Snippets(file) => file
+                    .snippets
+                    .iter()
+                    .flat_map(|snippet| regions(snippet))
+                    .map(|region| SarifResult {
+                        rule_id: RULE_ID,
+                        level: "note",
+                        message: message(),
+                        locations: vec![Location::new(&file.relative_path, Some(region))],
+                        properties: Properties {
+                            repo_ref: file.repo_ref.clone(),
+                        },
+                    })
+                    .collect(),
+                QueryResult::FileResult(file) => vec![SarifResult {
+                    rule_id: RULE_ID,
+                    level: "note",
+                    message: message(),
+                    locations: vec![Location::new(&file.relative_path.text, None)],
+                    properties: Properties {
+                        repo_ref: file.repo_ref.clone(),
+                    },
+                }],
+                // Repositories, directories and opened files have no location to report
+                _ => vec![],
+            })
+            .collect();
+
+        Self {
+            schema: SCHEMA,
+            version: "2.1.0",
+            runs: vec![Run {
+                tool: Tool {
+                    driver: Driver {
+                        name: "bloop",
+                        version: env!("CARGO_PKG_VERSION"),
+                        information_uri: "https://bloop.ai",
+                        rules: vec![Rule {
+                            id: RULE_ID,
+                            short_description: Message {
+                                text: "Search result".to_owned(),
+                            },
+                        }],
+                    },
+                },
+                column_kind: "unicodeCodePoints",
+                results,
+            }],
+        }
+    }
+}
+
+impl Location {
+    fn new(relative_path: &str, region: Option<Region>) -> Self {
+        Self {
+            physical_location: PhysicalLocation {
+                artifact_location: ArtifactLocation {
+                    // SARIF URIs always use forward slashes
+                    uri: relative_path.replace(std::path::MAIN_SEPARATOR, "/"),
+                },
+                region,
+            },
+        }
+    }
+}
+
+/// Convert the highlights of a snippet into regions of the whole file.
+fn regions(snippet: &Snippet) -> impl Iterator<Item = Region> + '_ {
+    // 1-indexed line and column of a byte offset in the snippet
+    let position = |offset: usize| {
+        let before = &snippet.data[..offset];
+        let line = before.matches('\n').count();
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let column = before[line_start..].chars().count();
+
+        (snippet.line_range.start + line + 1, column + 1)
+    };
+
+    snippet.highlights.iter().map(move |range| {
+        let (start_line, start_column) = position(range.start);
+        let (end_line, end_column) = position(range.end);
+
+        Region {
+            start_line,
+            start_column,
+            end_line,
+            end_column,
+            snippet: Message {
+                text: snippet.data[range.clone()].to_owned(),
+            },
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlight_regions() {
+        let snippet = Snippet {
+            data: "fn main() {\n    let ñ = foo();\n}\n".to_owned(),
+            highlights: vec![25..28],
+            symbols: vec![],
+            line_range: 9..12,
+        };
+
+        let regions = regions(&snippet).collect::<Vec<_>>();
+        assert_eq!(
+            regions,
+            vec![Region {
+                start_line: 11,
+                start_column: 13,
+                end_line: 11,
+                end_column: 16,
+                snippet: Message {
+                    text: "foo".to_owned()
+                },
+            }]
+        );
+    }
+}
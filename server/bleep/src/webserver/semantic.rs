@@ -1,7 +1,11 @@
-use super::prelude::*;
-use crate::{query::parser, semantic::Semantic};
+use super::{
+    prelude::*,
+    query::{ndjson, wants_ndjson, ResultFormat},
+};
+use crate::{query::parser, semantic::SemanticLoader, Application};
 use tracing::error;
 
+use axum::http::HeaderMap;
 use qdrant_client::qdrant::value::Kind;
 use std::collections::HashMap;
 
@@ -9,6 +13,9 @@ use std::collections::HashMap;
 pub(super) struct Args {
     limit: u64,
     query: String,
+    /// Chunks can be streamed as newline-delimited JSON, one per line
+    #[serde(default)]
+    format: ResultFormat,
 }
 
 #[derive(Serialize)]
@@ -26,14 +33,30 @@ pub(super) struct SemanticResponse {
     ),
 )]
 pub(super) async fn raw_chunks(
+    _: Scoped<keys::Read>,
+    access: RepoAccess,
     Query(args): Query<Args>,
-    Extension(semantic): Extension<Option<Semantic>>,
-) -> impl IntoResponse {
-    if let Some(semantic) = semantic {
-        let Args { ref query, limit } = args;
-        let query = parser::parse_nl(query).unwrap();
-        let result = semantic.search(&query, limit).await.and_then(|raw| {
+    Extension(app): Extension<Application>,
+    Extension(semantic): Extension<SemanticLoader>,
+    headers: HeaderMap,
+) -> Result<axum::response::Response> {
+    let semantic = Error::semantic(&semantic)?;
+    let Args {
+        ref query,
+        limit,
+        format,
+    } = args;
+    let query = parser::parse_nl(query).unwrap();
+    let repos = access.repo_refs(&app.repo_pool);
+    let result = semantic
+        .search(&query, repos.as_deref(), limit)
+        .await
+        .and_then(|raw| {
             raw.into_iter()
+                .filter(|v| match v.payload.get("repo_ref").map(|r| &r.kind) {
+                    Some(Some(Kind::StringValue(repo_ref))) => access.allows(repo_ref),
+                    _ => false,
+                })
                 .map(|v| {
                     v.payload
                         .into_iter()
@@ -45,20 +68,17 @@ pub(super) async fn raw_chunks(
                 .map_err(|e| e.into())
         });
 
-        if let Err(err) = result {
-            error!(?err, "qdrant query failed");
-            return Err(Error::new(ErrorKind::UpstreamService, "error"));
-        };
+    if let Err(err) = result {
+        error!(?err, "qdrant query failed");
+        return Err(Error::new(ErrorKind::UpstreamService, "error"));
+    };
 
-        Ok(json(SemanticResponse {
-            chunks: result.unwrap(),
-        }))
-    } else {
-        Err(Error::new(
-            ErrorKind::Configuration,
-            "Qdrant not configured",
-        ))
+    let chunks = result.unwrap();
+    if wants_ndjson(format, &headers) {
+        return ndjson(chunks);
     }
+
+    Ok(json(SemanticResponse { chunks }).into_response())
 }
 
 fn kind_to_value(kind: Option<Kind>) -> serde_json::Value {
@@ -0,0 +1,190 @@
+//! Answer requests are shed once too many of them are waiting.
+//!
+//! An answer holds one of a few slots for as long as the LLM takes to stream it, and requests
+//! queue for a slot once every one is taken. A request at the back of a long queue would likely
+//! time out anyway, while holding up the ones behind it, so once the queue is full further
+//! requests are rejected right away, with a `Retry-After` of about how long it takes for the
+//! queue to drain.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{boxed, HttpBody},
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+use super::{Error, ErrorCode, ErrorKind};
+
+/// Assumed time an answer holds a slot for, until one has finished
+const INITIAL_ESTIMATE: Duration = Duration::from_secs(10);
+
+struct LoadShedder {
+    slots: Arc<Semaphore>,
+    max_in_flight: usize,
+    max_queued: usize,
+    queued: AtomicUsize,
+    /// Moving average of how long answers hold a slot, in milliseconds
+    average_millis: AtomicU64,
+}
+
+impl LoadShedder {
+    fn new(max_in_flight: usize, max_queued: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max_in_flight)),
+            max_in_flight,
+            max_queued,
+            queued: AtomicUsize::new(0),
+            average_millis: AtomicU64::new(INITIAL_ESTIMATE.as_millis() as u64),
+        }
+    }
+
+    /// Take a slot, waiting in the queue if every one is taken. `None` if the queue is full.
+    async fn admit(self: &Arc<Self>) -> Option<Slot> {
+        let permit = match Arc::clone(&self.slots).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let _queued = self.enqueue()?;
+                Arc::clone(&self.slots)
+                    .acquire_owned()
+                    .await
+                    .expect("answer slots are never closed")
+            }
+        };
+
+        Some(Slot {
+            shedder: Arc::clone(self),
+            started: Instant::now(),
+            _permit: permit,
+        })
+    }
+
+    fn enqueue(&self) -> Option<Queued<'_>> {
+        self.queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                (queued < self.max_queued).then_some(queued + 1)
+            })
+            .ok()
+            .map(|_| Queued(&self.queued))
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let sample = elapsed.as_millis() as u64;
+        _ = self
+            .average_millis
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                Some(average - average / 8 + sample / 8)
+            });
+    }
+
+    /// How long until a request made now would likely be admitted without queueing
+    fn retry_after(&self) -> Duration {
+        let average = Duration::from_millis(self.average_millis.load(Ordering::Relaxed));
+        let ahead = self.queued.load(Ordering::Relaxed) + 1;
+        (average * ahead as u32 / self.max_in_flight as u32).max(Duration::from_secs(1))
+    }
+}
+
+/// A place in the queue, given up once a slot is taken or the request is dropped
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Held until the response body has been sent, so that a streamed answer keeps its slot
+struct Slot {
+    shedder: Arc<LoadShedder>,
+    started: Instant,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.shedder.observe(self.started.elapsed());
+    }
+}
+
+/// Handle at most `max_in_flight` requests at once, queueing up to `max_queued` more, and reject
+/// further ones with `503 Service Unavailable` and a `Retry-After`.
+pub(super) fn shed<S>(
+    router: Router<S>,
+    max_in_flight: Option<usize>,
+    max_queued: usize,
+) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let Some(max_in_flight) = max_in_flight.filter(|&m| m > 0) else {
+        return router;
+    };
+
+    router.layer(middleware::from_fn_with_state(
+        Arc::new(LoadShedder::new(max_in_flight, max_queued)),
+        admit,
+    ))
+}
+
+async fn admit<B>(
+    State(shedder): State<Arc<LoadShedder>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(slot) = shedder.admit().await else {
+        let retry_after = shedder.retry_after();
+        debug!(?retry_after, "answer queue is full; shedding request");
+
+        return Error::new(ErrorKind::Internal, "too many concurrent requests")
+            .with_status(StatusCode::SERVICE_UNAVAILABLE)
+            .with_code(ErrorCode::ServerOverloaded)
+            .with_retry_after(retry_after)
+            .into_response();
+    };
+
+    next.run(request).await.map(|body| {
+        boxed(body.map_data(move |data| {
+            let _slot = &slot;
+            data
+        }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sheds_once_queue_is_full() {
+        let shedder = Arc::new(LoadShedder::new(1, 1));
+
+        let first = shedder.admit().await.expect("a slot is free");
+
+        let queued = tokio::spawn({
+            let shedder = Arc::clone(&shedder);
+            async move { shedder.admit().await.is_some() }
+        });
+        while shedder.queued.load(Ordering::Acquire) == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(shedder.admit().await.is_none());
+        assert!(shedder.retry_after() >= Duration::from_secs(1));
+
+        drop(first);
+        assert!(queued.await.unwrap());
+        assert_eq!(shedder.queued.load(Ordering::Acquire), 0);
+    }
+}
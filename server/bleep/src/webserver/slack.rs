@@ -0,0 +1,445 @@
+//! A Slack slash command, so that teams can ask about their code from Slack.
+//!
+//! Slack can't present API keys or session cookies, so requests are authenticated by their
+//! signature, keyed by the Slack app's signing secret, and questions are answered about every
+//! indexed repository. Answers take longer than the 3 seconds Slack waits for a response, so the
+//! command is acknowledged at once, and the answer is posted to the command's `response_url`.
+//!
+//! Slack users have no API key or login to grant access by, so each workspace is mapped to the
+//! repositories it can ask about, and the tenant it asks in, with `slack_teams`. Commands from
+//! workspaces that aren't mapped are turned away.
+//!
+//! Since these answers run in the background, they skip the answer routes' rate limiting and
+//! load shedding. Instead, each Slack user takes from the same `--answer-rate-limit`, and once
+//! too many answers are running or waiting, further commands are told to try again later.
+
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{body::Bytes, extract::State, http::HeaderMap, Json};
+use reqwest::Url;
+use ring::hmac;
+use secrecy::ExposeSecret;
+use serde_json::{json, Value};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, warn};
+
+use super::{
+    answer::{self, AnswerState, BatchAnswer, Params},
+    fields::Fields,
+    prelude::*,
+    ratelimit::RateLimiter,
+    tenant::{CurrentTenant, Tenant},
+};
+use crate::{Application, Configuration};
+
+const SIGNATURE_HEADER: &str = "x-slack-signature";
+const TIMESTAMP_HEADER: &str = "x-slack-request-timestamp";
+
+/// Seconds a request is accepted for after it's signed, so that it can't be replayed later
+const MAX_REQUEST_AGE: u64 = 5 * 60;
+
+/// Answers can only be posted back to Slack
+const RESPONSE_URL_PREFIX: &str = "https://hooks.slack.com/";
+
+/// Slack rejects section blocks with longer text
+const MAX_SECTION_LEN: usize = 3000;
+
+/// Answers running at once, if `--max-concurrent-answers` isn't set
+const DEFAULT_CONCURRENT_ANSWERS: usize = 8;
+
+/// What the members of a Slack workspace can ask about
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SlackTeam {
+    /// Patterns of the repositories the workspace can ask about
+    pub repos: Vec<String>,
+
+    /// The tenant the workspace asks in. Its questions are only answered from the tenant's
+    /// repositories that `repos` matches.
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+pub(super) struct SlackState {
+    answers: Arc<AnswerState>,
+    limiter: Arc<RateLimiter>,
+    /// Answers running in the background or waiting for one of `running`
+    pending: Arc<Semaphore>,
+    /// Answers running at once
+    running: Arc<Semaphore>,
+}
+
+impl SlackState {
+    pub(super) fn new(
+        answers: Arc<AnswerState>,
+        limiter: Arc<RateLimiter>,
+        config: &Configuration,
+    ) -> Arc<Self> {
+        let running = config
+            .max_concurrent_answers
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_CONCURRENT_ANSWERS);
+
+        Arc::new(Self {
+            answers,
+            limiter,
+            pending: Arc::new(Semaphore::new(running + config.max_queued_answers)),
+            running: Arc::new(Semaphore::new(running)),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct SlashCommand {
+    /// Whatever follows the command, which is the question
+    text: String,
+    team_id: String,
+    user_id: String,
+    response_url: String,
+}
+
+/// Check the `X-Slack-Signature` header, an HMAC-SHA256 of the timestamp and body keyed by the
+/// signing secret, and that the request was signed recently
+fn verify_signature(secret: &str, headers: &HeaderMap, body: &[u8], now: u64) -> bool {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+
+    let Some(timestamp) = header(TIMESTAMP_HEADER) else {
+        return false;
+    };
+
+    let fresh = timestamp
+        .parse::<u64>()
+        .map_or(false, |signed| now.abs_diff(signed) <= MAX_REQUEST_AGE);
+
+    let Some(signature) = header(SIGNATURE_HEADER)
+        .and_then(|v| v.strip_prefix("v0="))
+        .and_then(|v| hex::decode(v).ok())
+    else {
+        return false;
+    };
+
+    let mut signed = format!("v0:{timestamp}:").into_bytes();
+    signed.extend_from_slice(body);
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    fresh && hmac::verify(&key, &signed, &signature).is_ok()
+}
+
+/// Answer a question asked with a Slack slash command
+pub(super) async fn command(
+    State(state): State<Arc<SlackState>>,
+    Extension(app): Extension<Application>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse> {
+    let Some(secret) = app.config.slack_signing_secret.as_ref() else {
+        return Err(Error::new(
+            ErrorKind::Configuration,
+            "slack signing secret not configured",
+        ));
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(Error::internal)?
+        .as_secs();
+
+    if !verify_signature(secret.expose_secret(), &headers, &body, now) {
+        warn!("rejected slack command with an invalid signature");
+        return Err(Error::user("invalid signature")
+            .with_status(StatusCode::UNAUTHORIZED)
+            .with_code(ErrorCode::Unauthenticated));
+    }
+
+    let command: SlashCommand = serde_urlencoded::from_bytes(&body).map_err(Error::user)?;
+    if !command.response_url.starts_with(RESPONSE_URL_PREFIX) {
+        return Err(Error::user("invalid response URL"));
+    }
+
+    let Some((access, tenant)) = team_access(
+        &app.config.slack_teams,
+        &app.config.tenants,
+        &command.team_id,
+    ) else {
+        warn!(
+            team = command.team_id,
+            "rejected slack command from an unknown team"
+        );
+        return Ok(Json(ephemeral(
+            "This workspace isn't allowed to ask about code on this instance.",
+        )));
+    };
+
+    let question = command.text.trim().to_owned();
+    if question.is_empty() {
+        return Ok(Json(ephemeral(
+            "Ask a question about your code, e.g. `/bloop where are files indexed?`",
+        )));
+    }
+
+    let client = format!("slack:{}:{}", command.team_id, command.user_id);
+    if let Err(retry_after) = state.limiter.try_take(&client) {
+        debug!(%client, "rate limit exceeded");
+        return Ok(Json(ephemeral(&format!(
+            "You've asked too many questions, try again in {} seconds.",
+            retry_after.as_secs_f64().ceil()
+        ))));
+    }
+
+    let Ok(pending) = Arc::clone(&state.pending).try_acquire_owned() else {
+        warn!(
+            team = command.team_id,
+            "too many slack commands waiting for an answer"
+        );
+        return Ok(Json(ephemeral(
+            "I'm busy answering other questions, try again in a minute.",
+        )));
+    };
+
+    info!(team = command.team_id, "answering slack command");
+
+    let params = Params {
+        q: question.clone(),
+        thread_id: uuid::Uuid::new_v4().to_string(),
+        limit: answer::default_limit(),
+        user_id: client,
+        fields: Fields::default(),
+        answer_lang: None,
+        access,
+        llm_permits: None,
+    };
+
+    tokio::spawn(async move {
+        let _pending = pending;
+        let _running = Arc::clone(&state.running)
+            .acquire_owned()
+            .await
+            .expect("answer slots are never closed");
+
+        let answer = answer::answer_one(&state.answers, params, &app, tenant.name()).await;
+        let message = reply(&answer, app.config.instance_domain.as_deref());
+
+        let posted = crate::proxy::client()
+            .post(&command.response_url)
+            .json(&message)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        if let Err(err) = posted {
+            warn!(?err, "failed to post answer to slack");
+        }
+    });
+
+    Ok(Json(ephemeral(&format!(
+        "Looking for an answer to _{}_ ...",
+        escape(&question)
+    ))))
+}
+
+/// The repositories a Slack workspace can ask about, and the tenant it asks in, or `None` if
+/// it isn't allowed to ask at all
+fn team_access(
+    teams: &HashMap<String, SlackTeam>,
+    tenants: &[Tenant],
+    team_id: &str,
+) -> Option<(RepoAccess, CurrentTenant)> {
+    let team = teams.get(team_id)?;
+    let tenant = match team.tenant {
+        Some(ref name) => CurrentTenant::named(tenants, name),
+        None => CurrentTenant::Global,
+    };
+
+    Some((RepoAccess::granted(&team.repos, &tenant), tenant))
+}
+
+/// A message only the user who ran the command sees
+fn ephemeral(text: &str) -> Value {
+    json!({ "response_type": "ephemeral", "text": text })
+}
+
+/// The message posted to the channel with an answer: its text, the code it's based on, and a
+/// link to the code in the UI, if the instance has a domain
+fn reply(answer: &BatchAnswer, domain: Option<&str>) -> Value {
+    if let Some(ref error) = answer.error {
+        return ephemeral(&format!("Sorry, I couldn't answer that: {}", escape(error)));
+    }
+
+    let text = answer.answer.as_deref().unwrap_or_default();
+    let mut blocks = vec![
+        section(&format!("> {}", escape(&answer.q))),
+        section(&mrkdwn(text)),
+    ];
+
+    let source = answer.snippets.as_ref().and_then(|s| s.matches.first());
+    if let Some(snippet) = source {
+        let code = escape(snippet.text.trim_end());
+        let code = truncate(&code, MAX_SECTION_LEN - "```\n\n```".len());
+        blocks.push(section(&format!("```\n{code}\n```")));
+
+        let location = format!(
+            "{} {} (lines {}-{})",
+            snippet.repo_name,
+            snippet.relative_path,
+            snippet.start_line + 1,
+            snippet.end_line + 1
+        );
+        let location =
+            match domain.and_then(|d| ui_link(d, &snippet.repo_name, &snippet.relative_path)) {
+                Some(url) => format!("<{url}|{}>", escape(&location)),
+                None => escape(&location),
+            };
+        blocks.push(json!({
+            "type": "context",
+            "elements": [{ "type": "mrkdwn", "text": location }],
+        }));
+    }
+
+    json!({
+        "response_type": "in_channel",
+        "text": escape(text),
+        "blocks": blocks,
+    })
+}
+
+fn section(text: &str) -> Value {
+    json!({
+        "type": "section",
+        "text": { "type": "mrkdwn", "text": truncate(text, MAX_SECTION_LEN) },
+    })
+}
+
+/// The URL of a file in the UI of the instance at `domain`
+fn ui_link(domain: &str, repo_name: &str, path: &str) -> Option<Url> {
+    let mut url = Url::parse(&format!("https://{domain}/search")).ok()?;
+    url.set_fragment(Some(&format!("open:true repo:{repo_name} path:{path}")));
+    Some(url)
+}
+
+/// Escape the characters Slack treats as control characters in message text
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Convert the Markdown answers are written in to Slack's own markup, which marks bold text
+/// with single asterisks and has no headings
+fn mrkdwn(markdown: &str) -> String {
+    escape(markdown)
+        .lines()
+        .map(|line| match line.trim_start_matches('#') {
+            heading if heading.len() < line.len() && heading.starts_with(' ') => {
+                format!("*{}*", heading.trim())
+            }
+            _ => line.replace("**", "*"),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Cut `text` to at most `max` bytes, on a character boundary
+fn truncate(text: &str, max: usize) -> &str {
+    if text.len() <= max {
+        return text;
+    }
+
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature() {
+        // Example from the Slack documentation on verifying requests
+        let body = "token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J&team_domain=testteamnow\
+            &channel_id=G8PSS9T3V&channel_name=foobar&user_id=U2CERLKJA&user_name=roadrunner\
+            &command=%2Fwebhook-collect&text=&response_url=https%3A%2F%2Fhooks.slack.com%2F\
+            commands%2FT1DC2JH3J%2F397700885554%2F96rGlfmibIGlgcZRskXaIFfN\
+            &trigger_id=398738663015.47445629121.803a0bc887a14d10d2c447fce8b6703c";
+        let secret = "8f742231b10e8888abcd99yyyzzz85a5";
+        let signed = 1531420618;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMESTAMP_HEADER, signed.to_string().parse().unwrap());
+        headers.insert(
+            SIGNATURE_HEADER,
+            "v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503"
+                .parse()
+                .unwrap(),
+        );
+
+        assert!(verify_signature(secret, &headers, body.as_bytes(), signed));
+        assert!(verify_signature(
+            secret,
+            &headers,
+            body.as_bytes(),
+            signed + 60
+        ));
+        assert!(!verify_signature(
+            secret,
+            &headers,
+            body.as_bytes(),
+            signed + 3600
+        ));
+        assert!(!verify_signature(secret, &headers, b"text=hello", signed));
+        assert!(!verify_signature(
+            secret,
+            &HeaderMap::new(),
+            body.as_bytes(),
+            signed
+        ));
+    }
+
+    #[test]
+    fn markdown_is_converted() {
+        assert_eq!(
+            mrkdwn("## Indexing\nFiles are **indexed** by `Indexer<File>`"),
+            "*Indexing*\nFiles are *indexed* by `Indexer&lt;File&gt;`"
+        );
+        assert_eq!(mrkdwn("#[derive(Debug)]"), "#[derive(Debug)]");
+    }
+
+    #[test]
+    fn teams_only_ask_about_their_repos() {
+        let tenants = vec![Tenant {
+            name: "acme".into(),
+            repos: vec!["github.com/acme".into()],
+            members: vec![],
+        }];
+        let team = |repos: &[&str], tenant: Option<&str>| SlackTeam {
+            repos: repos.iter().map(|r| r.to_string()).collect(),
+            tenant: tenant.map(str::to_owned),
+        };
+        let teams = HashMap::from([
+            ("T1".to_owned(), team(&["github.com/acme/web"], None)),
+            ("T2".to_owned(), team(&["*"], Some("acme"))),
+            ("T3".to_owned(), team(&["*"], Some("globex"))),
+        ]);
+
+        assert!(team_access(&teams, &tenants, "T0").is_none());
+
+        let (access, tenant) = team_access(&teams, &tenants, "T1").unwrap();
+        assert!(access.allows("github.com/acme/web"));
+        assert!(!access.allows("github.com/acme/api"));
+        assert_eq!(tenant.name(), None);
+
+        let (access, tenant) = team_access(&teams, &tenants, "T2").unwrap();
+        assert!(access.allows("github.com/acme/api"));
+        assert!(!access.allows("github.com/globex/api"));
+        assert_eq!(tenant.name(), Some("acme"));
+
+        // Unknown tenants can't access anything
+        let (access, _) = team_access(&teams, &tenants, "T3").unwrap();
+        assert!(!access.allows("github.com/acme/api"));
+    }
+}
@@ -0,0 +1,85 @@
+use super::{admin::sees_query, prelude::*, tenant::CurrentTenant};
+use crate::{
+    analytics::{UsageParams, UsageResponse},
+    Application,
+};
+
+impl From<UsageResponse> for super::Response<'static> {
+    fn from(res: UsageResponse) -> super::Response<'static> {
+        super::Response::Usage(res)
+    }
+}
+
+/// Searches, answers, users, tokens and the most queried repositories of each day, from the
+/// query log. The administrators of a tenant only get the totals of the queries made in it.
+#[utoipa::path(get, path = "/stats/usage",
+    params(UsageParams),
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn usage(
+    _: Scoped<keys::Admin>,
+    access: RepoAccess,
+    tenant: CurrentTenant,
+    Extension(app): Extension<Application>,
+    Query(params): Query<UsageParams>,
+) -> Result<impl IntoResponse> {
+    let Some(ref query_log) = app.query_log else {
+        return Err(Error::new(
+            ErrorKind::Configuration,
+            "the query log is not enabled",
+        ));
+    };
+
+    let response = tokio::task::block_in_place(|| {
+        query_log.usage(&params, |entry| sees_query(&tenant, &access, entry))
+    })
+    .map_err(Error::internal)?;
+
+    Ok(json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        analytics::{QueryLog, QueryLogEntry},
+        webserver::tenant::Tenant,
+    };
+
+    #[test]
+    fn tenants_only_count_their_own_queries() {
+        let dir = tempdir::TempDir::new("bleep").unwrap();
+        let log = QueryLog::open(&dir.path().join("queries.jsonl")).unwrap();
+
+        for (tenant, count) in [("acme", 1), ("globex", 2)] {
+            for _ in 0..count {
+                log.append(&QueryLogEntry {
+                    tenant: Some(tenant.into()),
+                    ..QueryLogEntry::search("parser")
+                })
+                .unwrap();
+            }
+        }
+
+        let params = serde_urlencoded::from_str::<UsageParams>("").unwrap();
+        let searches = |tenant: &CurrentTenant| {
+            let usage = log
+                .usage(&params, |e| sees_query(tenant, &RepoAccess::default(), e))
+                .unwrap();
+            let usage = serde_json::to_value(usage).unwrap();
+            usage["days"][0]["searches"].as_u64()
+        };
+
+        let acme = CurrentTenant::Tenant(Arc::new(Tenant {
+            name: "acme".into(),
+            repos: vec![],
+            members: vec![],
+        }));
+        assert_eq!(searches(&acme), Some(1));
+        assert_eq!(searches(&CurrentTenant::Global), Some(3));
+        assert_eq!(searches(&CurrentTenant::Unassigned), None);
+    }
+}
@@ -0,0 +1,204 @@
+//! Tenants partition a single instance between isolated teams.
+//!
+//! Each tenant owns a set of repositories, matched by the same patterns used for access
+//! control. Requests are attributed to a tenant through their API key or the logged-in user,
+//! and can only see repositories, search results, and answers of that tenant.
+//!
+//! Documents and embeddings are stored with the repository they belong to, so searches are
+//! restricted to the tenant's repositories within the content index and qdrant, rather than
+//! filtered afterwards. Analytics events record the tenant they were made in.
+//!
+//! Keys that don't name a tenant act on behalf of the operator, and see every tenant's data.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::{extract::FromRequestParts, http::request::Parts};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    auth::{self, Identity},
+    keys::Grant,
+    Error,
+};
+use crate::Application;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Tenant {
+    /// Unique name of the tenant
+    pub name: String,
+
+    /// Patterns of the repositories that belong to this tenant
+    #[serde(default)]
+    pub repos: Vec<String>,
+
+    /// GitHub logins of the members of this tenant
+    #[serde(default)]
+    pub members: Vec<String>,
+}
+
+/// The tenant a request is made on behalf of
+#[derive(Clone, Debug)]
+pub(crate) enum CurrentTenant {
+    /// No tenants are configured, or the request was made by the operator
+    Global,
+    Tenant(Arc<Tenant>),
+    /// The request could not be attributed to any tenant, and can't access any data
+    Unassigned,
+}
+
+impl CurrentTenant {
+    /// The tenant named `name`, or `Unassigned` if there is no such tenant
+    pub(crate) fn named(tenants: &[Tenant], name: &str) -> Self {
+        match tenants.iter().find(|t| t.name == name) {
+            Some(tenant) => CurrentTenant::Tenant(Arc::new(tenant.clone())),
+            None => CurrentTenant::Unassigned,
+        }
+    }
+
+    pub(crate) fn resolve(app: &Application, extensions: &axum::http::Extensions) -> Self {
+        Self::find(&app.config.tenants, auth::enabled(app), extensions)
+    }
+
+    /// The tenant among `tenants` that a request with these extensions belongs to
+    fn find(tenants: &[Tenant], login_enabled: bool, extensions: &axum::http::Extensions) -> Self {
+        if tenants.is_empty() {
+            return CurrentTenant::Global;
+        }
+
+        let found = |tenant: Option<&Tenant>| match tenant {
+            Some(tenant) => CurrentTenant::Tenant(Arc::new(tenant.clone())),
+            None => CurrentTenant::Unassigned,
+        };
+
+        if let Some(Grant::Key(key)) = extensions.get::<Grant>() {
+            return match &key.tenant {
+                Some(name) => Self::named(tenants, name),
+                None => CurrentTenant::Global,
+            };
+        }
+
+        match extensions.get::<Identity>() {
+            Some(identity) => found(tenants.iter().find(|t| t.members.contains(&identity.login))),
+            None if login_enabled => CurrentTenant::Unassigned,
+            // Local installations without any authentication
+            None => CurrentTenant::Global,
+        }
+    }
+
+    pub(crate) fn name(&self) -> Option<&str> {
+        match self {
+            CurrentTenant::Tenant(tenant) => Some(&tenant.name),
+            _ => None,
+        }
+    }
+
+    /// Whether records made in `tenant`, such as analytics events or audit entries, can be seen
+    /// from this tenant. The operator sees every tenant's records.
+    pub(crate) fn sees(&self, tenant: Option<&str>) -> bool {
+        match self {
+            CurrentTenant::Global => true,
+            CurrentTenant::Tenant(own) => tenant == Some(own.name.as_str()),
+            CurrentTenant::Unassigned => false,
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CurrentTenant
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let app = parts
+            .extensions
+            .get::<Application>()
+            .ok_or_else(|| Error::internal("application state is missing"))?;
+
+        Ok(CurrentTenant::resolve(app, &parts.extensions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::keys::ApiKey;
+
+    fn tenants() -> Vec<Tenant> {
+        vec![Tenant {
+            name: "acme".into(),
+            repos: vec!["github.com/acme".into()],
+            members: vec!["wile".into()],
+        }]
+    }
+
+    fn key(tenant: Option<&str>) -> axum::http::Extensions {
+        let mut extensions = axum::http::Extensions::new();
+        extensions.insert(Grant::Key(Arc::new(ApiKey {
+            name: "key".into(),
+            hash: String::new(),
+            scopes: vec![],
+            repos: None,
+            tenant: tenant.map(str::to_owned),
+        })));
+        extensions
+    }
+
+    fn user(login: &str) -> axum::http::Extensions {
+        let mut extensions = axum::http::Extensions::new();
+        extensions.insert(Identity {
+            login: login.into(),
+        });
+        extensions
+    }
+
+    #[test]
+    fn requests_are_attributed_to_tenants() {
+        let tenants = tenants();
+
+        let found = CurrentTenant::find(&tenants, true, &key(Some("acme")));
+        assert_eq!(found.name(), Some("acme"));
+        let found = CurrentTenant::find(&tenants, true, &user("wile"));
+        assert_eq!(found.name(), Some("acme"));
+
+        assert!(matches!(
+            CurrentTenant::find(&tenants, true, &key(None)),
+            CurrentTenant::Global
+        ));
+        assert!(matches!(
+            CurrentTenant::find(&[], true, &user("wile")),
+            CurrentTenant::Global
+        ));
+        assert!(matches!(
+            CurrentTenant::find(&tenants, false, &Default::default()),
+            CurrentTenant::Global
+        ));
+    }
+
+    #[test]
+    fn tenants_only_see_their_own_records() {
+        let acme = CurrentTenant::Tenant(Arc::new(tenants().remove(0)));
+
+        assert!(acme.sees(Some("acme")));
+        assert!(!acme.sees(Some("globex")));
+        assert!(!acme.sees(None));
+
+        assert!(CurrentTenant::Global.sees(Some("globex")));
+        assert!(CurrentTenant::Global.sees(None));
+        assert!(!CurrentTenant::Unassigned.sees(None));
+    }
+
+    #[test]
+    fn unknown_requests_are_unassigned() {
+        let tenants = tenants();
+
+        for extensions in [key(Some("globex")), user("road"), Default::default()] {
+            assert!(matches!(
+                CurrentTenant::find(&tenants, true, &extensions),
+                CurrentTenant::Unassigned
+            ));
+        }
+    }
+}
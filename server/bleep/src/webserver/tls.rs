@@ -0,0 +1,54 @@
+//! Serving the API over HTTPS, without a separate proxy.
+
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+
+use crate::Configuration;
+
+/// Load the configured certificate, if any.
+///
+/// On unix, the certificate and key are read again on `SIGHUP`, so renewed certificates can be
+/// picked up without a restart.
+pub(super) async fn config(config: &Configuration) -> anyhow::Result<Option<RustlsConfig>> {
+    let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+        (None, None) => return Ok(None),
+        _ => anyhow::bail!("both `tls_cert` and `tls_key` must be set to enable TLS"),
+    };
+
+    let rustls = RustlsConfig::from_pem_file(&cert, &key)
+        .await
+        .with_context(|| format!("failed to load TLS certificate {}", cert.display()))?;
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(rustls.clone(), cert, key));
+
+    Ok(Some(rustls))
+}
+
+#[cfg(unix)]
+async fn reload_on_hangup(rustls: RustlsConfig, cert: std::path::PathBuf, key: std::path::PathBuf) {
+    use tokio::signal::unix::{signal, SignalKind};
+    use tracing::{error, info, warn};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            warn!(
+                ?err,
+                "failed to listen for SIGHUP, TLS certificates won't be reloaded"
+            );
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        match rustls.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => info!(cert = %cert.display(), "reloaded TLS certificate"),
+            Err(err) => error!(
+                ?err,
+                "failed to reload TLS certificate, keeping the current one"
+            ),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::path::MAIN_SEPARATOR;
+
+use axum::http::HeaderMap;
+
+use super::{etag::ETag, prelude::*};
+use crate::{repo::RepoRef, Application};
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub(super) struct TreeParams {
+    /// The repo_ref of the repository to browse
+    repo_ref: String,
+
+    /// The directory to list, relative to the repo root. Defaults to the root.
+    #[serde(default)]
+    path: String,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum EntryKind {
+    File,
+    Directory,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(super) struct TreeEntry {
+    name: String,
+    /// Path relative to the repo root
+    path: String,
+    kind: EntryKind,
+    /// Size in bytes, for files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lang: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(super) struct TreeResponse {
+    repo_ref: String,
+    path: String,
+    /// Directories first, then files, each sorted by name
+    entries: Vec<TreeEntry>,
+}
+
+impl From<TreeResponse> for super::Response<'static> {
+    fn from(res: TreeResponse) -> super::Response<'static> {
+        super::Response::Tree(res)
+    }
+}
+
+/// List the contents of a directory in an indexed repository
+#[utoipa::path(get, path = "/tree",
+    params(TreeParams),
+    responses(
+        (status = 200, description = "Execute query successfully", body = TreeResponse),
+        (status = 304, description = "Not modified since the version given in `If-None-Match`"),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 404, description = "Repository or directory not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn handle(
+    _: Scoped<keys::Read>,
+    access: RepoAccess,
+    Query(params): Query<TreeParams>,
+    Extension(app): Extension<Application>,
+    Extension(indexes): Extension<Arc<Indexes>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse> {
+    let repo_ref = params.repo_ref.parse::<RepoRef>().map_err(Error::user)?;
+
+    if !access.allows(&repo_ref.to_string()) {
+        return Err(
+            Error::new(ErrorKind::NotFound, "Repo not found").with_code(ErrorCode::RepoNotFound)
+        );
+    }
+
+    let disk_path = app
+        .repo_pool
+        .get(&repo_ref)
+        .map(|repo| repo.disk_path.clone())
+        .ok_or_else(|| {
+            Error::new(ErrorKind::NotFound, "Repo not found").with_code(ErrorCode::RepoNotFound)
+        })?;
+
+    let dir = params.path.trim_matches(MAIN_SEPARATOR);
+
+    let etag = ETag::new(&indexes.file.generation().await, &[&params.repo_ref, dir]);
+
+    if etag.matches(&headers) {
+        return Ok(etag.not_modified());
+    }
+    let listing = indexes
+        .file
+        .list_directory(&disk_path, dir)
+        .await
+        .map_err(Error::internal)?;
+
+    // Empty directories are not indexed, so an empty listing means the directory is unknown
+    if listing.is_empty() && !dir.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, "directory not found")
+            .with_code(ErrorCode::FileNotFound));
+    }
+
+    let mut entries = listing
+        .into_iter()
+        .map(|entry| {
+            let kind = if entry.is_dir() {
+                EntryKind::Directory
+            } else {
+                EntryKind::File
+            };
+
+            let path = entry.relative_path.trim_end_matches(MAIN_SEPARATOR);
+            let name = path
+                .rsplit(MAIN_SEPARATOR)
+                .next()
+                .unwrap_or(path)
+                .to_owned();
+
+            TreeEntry {
+                name,
+                path: entry.relative_path,
+                kind,
+                size: entry.size,
+                lang: entry.lang,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    entries.sort_by(|a, b| {
+        let is_file = |e: &TreeEntry| matches!(e.kind, EntryKind::File);
+        is_file(a)
+            .cmp(&is_file(b))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+
+    Ok(etag.attach(json(TreeResponse {
+        repo_ref: repo_ref.to_string(),
+        path: dir.to_owned(),
+        entries,
+    })))
+}
@@ -0,0 +1,54 @@
+//! The web UI, built into the binary with the `embedded-ui` feature, so that a single `bleep`
+//! binary serves it without a `--frontend-dist` directory to deploy alongside.
+//!
+//! The UI is built in `client/` with `npm run build` before the server is compiled. Paths that
+//! aren't assets are served `index.html`, as the UI routes them itself.
+
+use axum::{
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE},
+        HeaderMap, HeaderValue, StatusCode, Uri,
+    },
+    response::{IntoResponse, Response},
+};
+use rust_embed::RustEmbed;
+
+use super::etag::ETag;
+
+#[derive(RustEmbed)]
+#[folder = "../../client/dist"]
+struct Assets;
+
+const INDEX: &str = "index.html";
+
+/// Vite fingerprints the names of the assets it builds, so they never change under their name
+const ASSETS_DIR: &str = "assets/";
+
+pub(super) async fn handle(uri: Uri, headers: HeaderMap) -> Response {
+    let path = uri.path().trim_start_matches('/');
+    let (path, file) = match Assets::get(path) {
+        Some(file) if !path.is_empty() => (path, file),
+        _ => match Assets::get(INDEX) {
+            Some(file) => (INDEX, file),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        },
+    };
+
+    let etag = ETag::new(path, &[&hex::encode(file.metadata.sha256_hash())]);
+    if etag.matches(&headers) {
+        return etag.not_modified();
+    }
+
+    let content_type = HeaderValue::from_str(file.metadata.mimetype())
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+    let cache_control = HeaderValue::from_static(if path.starts_with(ASSETS_DIR) {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    });
+
+    etag.attach((
+        [(CONTENT_TYPE, content_type), (CACHE_CONTROL, cache_control)],
+        file.data,
+    ))
+}
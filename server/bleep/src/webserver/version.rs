@@ -0,0 +1,52 @@
+use super::prelude::*;
+use crate::{state::SCHEMA_VERSION, Application};
+
+#[derive(Serialize, ToSchema)]
+pub(super) struct VersionResponse {
+    /// Version of the server
+    version: &'static str,
+    /// Commit the server was built from
+    commit: &'static str,
+    /// Features enabled in the environment the server runs in
+    features: Vec<&'static str>,
+    /// Features the server was compiled with
+    build_features: Vec<&'static str>,
+    /// Hash of the embedding model, if semantic search is configured
+    embedding_model: Option<String>,
+    /// Version of the search index schema
+    index_schema: &'static str,
+}
+
+impl From<VersionResponse> for super::Response<'static> {
+    fn from(res: VersionResponse) -> super::Response<'static> {
+        super::Response::Version(res)
+    }
+}
+
+fn build_features() -> Vec<&'static str> {
+    [
+        ("debug", cfg!(feature = "debug")),
+        ("dynamic-ort", cfg!(feature = "dynamic-ort")),
+        ("static-ort", cfg!(feature = "static-ort")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect()
+}
+
+/// Identify the build and configuration of this server
+#[utoipa::path(get, path = "/version",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+    ),
+)]
+pub(super) async fn handle(Extension(app): Extension<Application>) -> impl IntoResponse {
+    json(VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("BLEEP_GIT_COMMIT"),
+        features: app.env.features(),
+        build_features: build_features(),
+        embedding_model: app.semantic.get().map(|s| s.model_id().to_owned()),
+        index_schema: SCHEMA_VERSION,
+    })
+}
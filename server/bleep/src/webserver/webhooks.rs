@@ -0,0 +1,292 @@
+//! Receivers for repository events pushed by code hosts.
+//!
+//! These endpoints are called by the code host directly, and can't present API keys or session
+//! cookies. Instead, every delivery is signed with, or carries, a secret shared with the host.
+
+use axum::{body::Bytes, extract::State, http::HeaderMap};
+use ring::{constant_time, hmac};
+use secrecy::ExposeSecret;
+use tracing::{debug, info, warn};
+
+use super::{answer::AnswerState, prelude::*, review};
+use crate::{
+    repo::{Backend, RepoRef},
+    Application,
+};
+
+const SIGNATURE_HEADER: &str = "x-hub-signature-256";
+const EVENT_HEADER: &str = "x-github-event";
+
+const GITLAB_TOKEN_HEADER: &str = "x-gitlab-token";
+const GITLAB_EVENT_HEADER: &str = "x-gitlab-event";
+
+#[derive(Deserialize)]
+struct PushEvent {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    repository: PushRepository,
+}
+
+#[derive(Deserialize)]
+struct PushRepository {
+    full_name: String,
+    default_branch: String,
+}
+
+#[derive(Deserialize)]
+struct PullRequestEvent {
+    action: String,
+    number: u64,
+    pull_request: PullRequestState,
+    repository: PushRepository,
+}
+
+#[derive(Deserialize)]
+struct PullRequestState {
+    #[serde(default)]
+    draft: bool,
+}
+
+#[derive(Deserialize)]
+struct GitlabPushEvent {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    project: GitlabProject,
+}
+
+#[derive(Deserialize)]
+struct GitlabProject {
+    path_with_namespace: String,
+    default_branch: String,
+}
+
+/// Check the `X-Hub-Signature-256` header, an HMAC-SHA256 of the body keyed by the secret.
+fn verify_signature(secret: &str, headers: &HeaderMap, body: &[u8]) -> bool {
+    let Some(signature) = headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("sha256="))
+        .and_then(|v| hex::decode(v).ok())
+    else {
+        return false;
+    };
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    hmac::verify(&key, body, &signature).is_ok()
+}
+
+/// Check the `X-Gitlab-Token` header, which holds the secret itself.
+fn verify_token(secret: &str, headers: &HeaderMap) -> bool {
+    headers
+        .get(GITLAB_TOKEN_HEADER)
+        .map(|token| {
+            constant_time::verify_slices_are_equal(token.as_bytes(), secret.as_bytes()).is_ok()
+        })
+        .unwrap_or(false)
+}
+
+fn invalid_signature() -> Error {
+    warn!("rejected webhook delivery with an invalid signature");
+    Error::user("invalid signature")
+        .with_status(StatusCode::UNAUTHORIZED)
+        .with_code(ErrorCode::Unauthenticated)
+}
+
+/// Queue a sync of a pushed repository, if its default branch was pushed to and it's indexed.
+fn queue_sync(
+    app: &Application,
+    reporef: RepoRef,
+    git_ref: &str,
+    default_branch: &str,
+) -> Result<StatusCode> {
+    // Only the default branch is indexed
+    if git_ref.strip_prefix("refs/heads/") != Some(default_branch) {
+        debug!(git_ref, "ignoring push to a branch that is not indexed");
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    let indexed = app
+        .repo_pool
+        .get(&reporef)
+        .map(|repo| repo.sync_status.indexable())
+        .unwrap_or(false);
+
+    if !indexed {
+        debug!(%reporef, "ignoring push to a repository that is not indexed");
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    info!(%reporef, "queueing sync after push");
+    app.write_index().queue_sync_and_index(vec![reporef]);
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Queue a review of a pull request that was opened, if reviews are enabled and its repository
+/// is indexed.
+fn queue_review(
+    app: &Application,
+    answers: &Arc<AnswerState>,
+    event: PullRequestEvent,
+) -> Result<StatusCode> {
+    // Pull requests are reviewed once, when they're ready to be
+    let ready = matches!(
+        event.action.as_str(),
+        "opened" | "reopened" | "ready_for_review"
+    );
+
+    if !app.config.review_pull_requests() || !ready || event.pull_request.draft {
+        debug!(action = event.action, "ignoring pull request event");
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    let reporef =
+        RepoRef::new(Backend::Github, &event.repository.full_name).map_err(Error::user)?;
+    if !app.repo_pool.contains_key(&reporef) {
+        debug!(%reporef, "ignoring pull request of a repository that is not indexed");
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    info!(%reporef, number = event.number, "queueing pull request review");
+    review::spawn(app, answers, reporef, event.number);
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Receive GitHub webhook deliveries, and sync repositories as soon as they are pushed to, or
+/// review pull requests as they're opened
+#[utoipa::path(post, path = "/webhooks/github",
+    responses(
+        (status = 202, description = "A sync or review has been queued"),
+        (status = 204, description = "The event was ignored"),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 401, description = "Invalid signature", body = EndpointError),
+    ),
+)]
+pub(super) async fn github(
+    State(answers): State<Arc<AnswerState>>,
+    Extension(app): Extension<Application>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode> {
+    let Some(secret) = app.config.github_webhook_secret.as_ref() else {
+        return Err(Error::new(
+            ErrorKind::Configuration,
+            "webhook secret not configured",
+        ));
+    };
+
+    if !verify_signature(secret.expose_secret(), &headers, &body) {
+        return Err(invalid_signature());
+    }
+
+    let event = headers
+        .get(EVENT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    if event == "pull_request" {
+        let event = serde_json::from_slice(&body).map_err(Error::user)?;
+        return queue_review(&app, &answers, event);
+    }
+
+    if event != "push" {
+        debug!(event, "ignoring webhook event");
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    let push: PushEvent = serde_json::from_slice(&body).map_err(Error::user)?;
+    let reporef = RepoRef::new(Backend::Github, &push.repository.full_name).map_err(Error::user)?;
+
+    queue_sync(
+        &app,
+        reporef,
+        &push.git_ref,
+        &push.repository.default_branch,
+    )
+}
+
+/// Receive GitLab webhook deliveries, and sync projects as soon as they are pushed to
+#[utoipa::path(post, path = "/webhooks/gitlab",
+    responses(
+        (status = 202, description = "A sync has been queued"),
+        (status = 204, description = "The event was ignored"),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 401, description = "Invalid token", body = EndpointError),
+    ),
+)]
+pub(super) async fn gitlab(
+    Extension(app): Extension<Application>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode> {
+    let Some(secret) = app.config.gitlab_webhook_secret.as_ref() else {
+        return Err(Error::new(
+            ErrorKind::Configuration,
+            "webhook secret not configured",
+        ));
+    };
+
+    if !verify_token(secret.expose_secret(), &headers) {
+        return Err(invalid_signature());
+    }
+
+    let event = headers
+        .get(GITLAB_EVENT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    if event != "Push Hook" {
+        debug!(event, "ignoring webhook event");
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    let push: GitlabPushEvent = serde_json::from_slice(&body).map_err(Error::user)?;
+    let reporef =
+        RepoRef::new(Backend::Gitlab, &push.project.path_with_namespace).map_err(Error::user)?;
+
+    queue_sync(&app, reporef, &push.git_ref, &push.project.default_branch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature() {
+        // Example from the GitHub documentation on validating webhook deliveries
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SIGNATURE_HEADER,
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+                .parse()
+                .unwrap(),
+        );
+
+        assert!(verify_signature(
+            "It's a Secret to Everybody",
+            &headers,
+            b"Hello, World!"
+        ));
+        assert!(!verify_signature(
+            "It's a Secret to Everybody",
+            &headers,
+            b"Hello, World"
+        ));
+        assert!(!verify_signature(
+            "It's a Secret to Everybody",
+            &HeaderMap::new(),
+            b"Hello, World!"
+        ));
+    }
+
+    #[test]
+    fn gitlab_token() {
+        let mut headers = HeaderMap::new();
+        headers.insert(GITLAB_TOKEN_HEADER, "secret".parse().unwrap());
+
+        assert!(verify_token("secret", &headers));
+        assert!(!verify_token("secret2", &headers));
+        assert!(!verify_token("secret", &HeaderMap::new()));
+    }
+}